@@ -0,0 +1,23 @@
+-- Your SQL goes here
+CREATE TABLE transactions (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    sender TEXT NOT NULL REFERENCES users(username),
+    receiver TEXT NOT NULL REFERENCES users(username),
+    amount INTEGER NOT NULL,
+    memo TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    status TEXT NOT NULL
+);
+
+CREATE INDEX transactions_sender_idx ON transactions (sender, created_at);
+CREATE INDEX transactions_receiver_idx ON transactions (receiver, created_at);
+
+CREATE TRIGGER transactions_no_update BEFORE UPDATE ON transactions
+BEGIN
+    SELECT RAISE(ABORT, 'transactions is append-only');
+END;
+
+CREATE TRIGGER transactions_no_delete BEFORE DELETE ON transactions
+BEGIN
+    SELECT RAISE(ABORT, 'transactions is append-only');
+END;
@@ -0,0 +1,17 @@
+-- Your SQL goes here
+CREATE TABLE scheduled_transfers (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    sender TEXT NOT NULL REFERENCES users(username),
+    receiver TEXT NOT NULL REFERENCES users(username),
+    amount INTEGER NOT NULL,
+    memo TEXT,
+    recurrence TEXT,
+    next_run_at TIMESTAMP NOT NULL,
+    status TEXT NOT NULL,
+    failures INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX scheduled_transfers_due_idx ON scheduled_transfers (status, next_run_at);
+CREATE INDEX scheduled_transfers_sender_idx ON scheduled_transfers (sender);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE holds;
+ALTER TABLE users DROP COLUMN held;
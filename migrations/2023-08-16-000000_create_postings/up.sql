@@ -0,0 +1,51 @@
+-- Your SQL goes here
+CREATE TABLE postings (
+    id INTEGER NOT NULL PRIMARY KEY AUTOINCREMENT,
+    debit_account TEXT NOT NULL,
+    credit_account TEXT NOT NULL,
+    amount INTEGER NOT NULL CHECK (amount > 0),
+    kind TEXT NOT NULL,
+    transaction_id INTEGER REFERENCES transactions(id),
+    created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX postings_debit_account_idx ON postings (debit_account);
+CREATE INDEX postings_credit_account_idx ON postings (credit_account);
+CREATE INDEX postings_transaction_id_idx ON postings (transaction_id);
+
+CREATE TRIGGER postings_no_update BEFORE UPDATE ON postings
+BEGIN
+    SELECT RAISE(ABORT, 'postings is append-only');
+END;
+
+CREATE TRIGGER postings_no_delete BEFORE DELETE ON postings
+BEGIN
+    SELECT RAISE(ABORT, 'postings is append-only');
+END;
+
+-- Existing transfers, then an opening posting per user for whatever the
+-- transfers don't explain, so that the postings match the stored balances.
+INSERT INTO postings (debit_account, credit_account, amount, kind, transaction_id, created_at)
+SELECT sender, receiver, amount, 'transfer', id, created_at
+FROM transactions
+WHERE status = 'completed'
+ORDER BY id;
+
+INSERT INTO postings (debit_account, credit_account, amount, kind, created_at)
+SELECT
+    CASE WHEN opening > 0 THEN 'issuance' ELSE username END,
+    CASE WHEN opening > 0 THEN username ELSE 'issuance' END,
+    ABS(opening),
+    'opening',
+    CURRENT_TIMESTAMP
+FROM (
+    SELECT
+        username,
+        balance
+            + COALESCE((SELECT SUM(amount) FROM transactions
+                        WHERE sender = username AND status = 'completed'), 0)
+            - COALESCE((SELECT SUM(amount) FROM transactions
+                        WHERE receiver = username AND status = 'completed'), 0) AS opening
+    FROM users
+) AS openings
+WHERE opening <> 0;
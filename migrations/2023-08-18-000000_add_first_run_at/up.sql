@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE scheduled_transfers ADD COLUMN first_run_at TIMESTAMP NOT NULL DEFAULT '1970-01-01 00:00:00';
+-- The first run of existing schedules is unknown, the next one is the best guess.
+UPDATE scheduled_transfers SET first_run_at = next_run_at;
@@ -0,0 +1,6 @@
+-- Your SQL goes here
+CREATE TABLE users (
+    username TEXT NOT NULL PRIMARY KEY,
+    password TEXT NOT NULL,
+    balance INTEGER NOT NULL
+);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE transactions;
+DROP FUNCTION transactions_append_only();
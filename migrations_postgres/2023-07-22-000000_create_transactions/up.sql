@@ -0,0 +1,25 @@
+-- Your SQL goes here
+CREATE TABLE transactions (
+    id SERIAL PRIMARY KEY,
+    sender TEXT NOT NULL REFERENCES users(username),
+    receiver TEXT NOT NULL REFERENCES users(username),
+    amount INTEGER NOT NULL,
+    memo TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    status TEXT NOT NULL
+);
+
+CREATE INDEX transactions_sender_idx ON transactions (sender, created_at);
+CREATE INDEX transactions_receiver_idx ON transactions (receiver, created_at);
+
+CREATE FUNCTION transactions_append_only() RETURNS TRIGGER AS $$
+BEGIN
+    RAISE EXCEPTION 'transactions is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER transactions_no_update BEFORE UPDATE ON transactions
+    FOR EACH ROW EXECUTE FUNCTION transactions_append_only();
+
+CREATE TRIGGER transactions_no_delete BEFORE DELETE ON transactions
+    FOR EACH ROW EXECUTE FUNCTION transactions_append_only();
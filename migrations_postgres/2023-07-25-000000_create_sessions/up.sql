@@ -0,0 +1,11 @@
+-- Your SQL goes here
+CREATE TABLE sessions (
+    token TEXT NOT NULL PRIMARY KEY,
+    username TEXT NOT NULL REFERENCES users(username),
+    created_at TIMESTAMP NOT NULL,
+    last_seen_at TIMESTAMP NOT NULL,
+    expires_at TIMESTAMP NOT NULL,
+    revoked_at TIMESTAMP
+);
+
+CREATE INDEX sessions_username_idx ON sessions (username);
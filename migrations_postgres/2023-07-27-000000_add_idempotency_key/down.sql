@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX transactions_idempotency_key_idx;
+ALTER TABLE transactions DROP COLUMN idempotency_key;
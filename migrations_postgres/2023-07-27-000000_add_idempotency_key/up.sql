@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE transactions ADD COLUMN idempotency_key TEXT;
+
+CREATE UNIQUE INDEX transactions_idempotency_key_idx ON transactions (sender, idempotency_key);
@@ -0,0 +1,15 @@
+-- Your SQL goes here
+CREATE TABLE payment_requests (
+    id SERIAL PRIMARY KEY,
+    requester TEXT NOT NULL REFERENCES users(username),
+    payer TEXT NOT NULL REFERENCES users(username),
+    amount INTEGER NOT NULL,
+    memo TEXT,
+    status TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    resolved_at TIMESTAMP,
+    transaction_id INTEGER REFERENCES transactions(id)
+);
+
+CREATE INDEX payment_requests_payer_idx ON payment_requests (payer, status);
+CREATE INDEX payment_requests_requester_idx ON payment_requests (requester, status);
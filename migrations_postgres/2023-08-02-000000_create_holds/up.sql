@@ -0,0 +1,19 @@
+-- Your SQL goes here
+ALTER TABLE users ADD COLUMN held INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE holds (
+    id SERIAL PRIMARY KEY,
+    payer TEXT NOT NULL REFERENCES users(username),
+    payee TEXT NOT NULL REFERENCES users(username),
+    amount INTEGER NOT NULL,
+    memo TEXT,
+    status TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    expires_at TIMESTAMP NOT NULL,
+    resolved_at TIMESTAMP,
+    transaction_id INTEGER REFERENCES transactions(id)
+);
+
+CREATE INDEX holds_payer_idx ON holds (payer, status);
+CREATE INDEX holds_payee_idx ON holds (payee, status);
+CREATE INDEX holds_expiry_idx ON holds (status, expires_at);
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX transactions_refund_of_idx;
+ALTER TABLE transactions DROP COLUMN refund_of;
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE transactions ADD COLUMN refund_of INTEGER REFERENCES transactions(id);
+
+CREATE INDEX transactions_refund_of_idx ON transactions (refund_of);
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+CREATE TABLE password_resets (
+    token TEXT NOT NULL PRIMARY KEY,
+    username TEXT NOT NULL REFERENCES users(username),
+    created_at TIMESTAMP NOT NULL,
+    expires_at TIMESTAMP NOT NULL,
+    used_at TIMESTAMP
+);
+
+CREATE INDEX password_resets_username_idx ON password_resets (username);
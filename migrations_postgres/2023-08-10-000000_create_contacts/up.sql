@@ -0,0 +1,9 @@
+-- Your SQL goes here
+CREATE TABLE contacts (
+    owner TEXT NOT NULL REFERENCES users(username),
+    alias TEXT NOT NULL,
+    username TEXT NOT NULL REFERENCES users(username),
+    note TEXT,
+    created_at TIMESTAMP NOT NULL,
+    PRIMARY KEY (owner, alias)
+);
@@ -0,0 +1,15 @@
+-- Your SQL goes here
+CREATE TABLE audit_log (
+    id SERIAL PRIMARY KEY,
+    session_id TEXT NOT NULL,
+    username TEXT,
+    function_name TEXT NOT NULL,
+    arguments TEXT NOT NULL,
+    outcome TEXT NOT NULL,
+    result TEXT NOT NULL,
+    model TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX audit_log_username_idx ON audit_log (username, created_at);
+CREATE INDEX audit_log_session_id_idx ON audit_log (session_id);
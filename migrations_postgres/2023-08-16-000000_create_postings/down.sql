@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE postings;
+DROP FUNCTION postings_append_only();
@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE scheduled_transfers DROP COLUMN first_run_at;
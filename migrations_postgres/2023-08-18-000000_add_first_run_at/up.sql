@@ -0,0 +1,5 @@
+-- Your SQL goes here
+ALTER TABLE scheduled_transfers ADD COLUMN first_run_at TIMESTAMP;
+-- The first run of existing schedules is unknown, the next one is the best guess.
+UPDATE scheduled_transfers SET first_run_at = next_run_at;
+ALTER TABLE scheduled_transfers ALTER COLUMN first_run_at SET NOT NULL;
@@ -0,0 +1,96 @@
+use std::env;
+use std::fs;
+use std::process::ExitCode;
+
+use anyhow::{anyhow, bail, Result};
+use chrono::Utc;
+
+use trading_gpt::User;
+
+static USAGE: &str = "Usage: bulk_payout <username> <file.csv> [--dry-run] [--key KEY]";
+
+struct Options {
+    username: String,
+    path: String,
+    dry_run: bool,
+    key: Option<String>,
+}
+
+/// Pays every `recipient,amount,memo` row of a CSV file from the named user,
+/// all in one transaction or not at all, and prints what happened to each
+/// row. `--dry-run` only validates the file. Rerunning with the `--key` of a
+/// run that may have failed pays every row once.
+fn main() -> ExitCode {
+    let options = match parse_options(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{e}\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let csv = match fs::read_to_string(&options.path) {
+        Ok(csv) => csv,
+        Err(e) => {
+            eprintln!("Failed to read {}: {e}", options.path);
+            return ExitCode::FAILURE;
+        }
+    };
+    let key = options
+        .key
+        .unwrap_or_else(|| format!("payout:{}", Utc::now().format("%Y%m%dT%H%M%S%.f")));
+    let result = User::retrieve_from_db(&options.username).and_then(|mut user| {
+        if options.dry_run {
+            user.validate_payout(&csv)
+        } else {
+            user.bulk_payout(&csv, &key)
+        }
+    });
+    match result {
+        Ok(batch) => {
+            println!("{batch}");
+            if options.dry_run {
+                return if batch.is_valid() {
+                    ExitCode::SUCCESS
+                } else {
+                    ExitCode::FAILURE
+                };
+            }
+            eprintln!("Idempotency key: {key}");
+            if batch.is_paid() {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            }
+        }
+        Err(e) => {
+            eprintln!("Failed to pay out {}: {e}", options.path);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn parse_options(mut args: impl Iterator<Item = String>) -> Result<Options> {
+    let mut positional = Vec::new();
+    let (mut dry_run, mut key) = (false, None);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dry-run" => dry_run = true,
+            "--key" => {
+                key = Some(
+                    args.next()
+                        .ok_or_else(|| anyhow!("Missing value for --key"))?,
+                )
+            }
+            _ if arg.starts_with("--") => bail!("Unknown argument: {}", arg),
+            _ => positional.push(arg),
+        }
+    }
+    let [username, path] = <[String; 2]>::try_from(positional)
+        .map_err(|_| anyhow!("Expected a username and a file"))?;
+    Ok(Options {
+        username,
+        path,
+        dry_run,
+        key,
+    })
+}
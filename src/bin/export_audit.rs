@@ -0,0 +1,58 @@
+use std::env;
+use std::process::ExitCode;
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{Duration, NaiveDate, NaiveDateTime};
+
+use trading_gpt::{AuditFilter, AuditRecord};
+
+static USAGE: &str = "Usage: export_audit [--username NAME] [--session ID] [--function NAME] \
+                      [--since YYYY-MM-DD] [--until YYYY-MM-DD]";
+
+/// Prints the audit log as JSON lines, oldest first, for compliance reviews.
+/// Both dates are inclusive.
+fn main() -> ExitCode {
+    let filter = match parse_filter(env::args().skip(1)) {
+        Ok(filter) => filter,
+        Err(e) => {
+            eprintln!("{e}\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+    match AuditRecord::list_from_db(&filter) {
+        Ok(records) => {
+            for record in records {
+                println!("{}", record.to_json());
+            }
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Failed to read the audit log: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn parse_filter(mut args: impl Iterator<Item = String>) -> Result<AuditFilter> {
+    let mut filter = AuditFilter::default();
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| anyhow!("Missing value for {}", flag))?;
+        match flag.as_str() {
+            "--username" => filter.username = Some(value),
+            "--session" => filter.session_id = Some(value),
+            "--function" => filter.function_name = Some(value),
+            "--since" => filter.since = Some(start_of(&value)?),
+            "--until" => filter.until = Some(start_of(&value)? + Duration::days(1)),
+            _ => bail!("Unknown argument: {}", flag),
+        }
+    }
+    Ok(filter)
+}
+
+fn start_of(day: &str) -> Result<NaiveDateTime> {
+    let day = NaiveDate::parse_from_str(day, "%Y-%m-%d")
+        .map_err(|_| anyhow!("Invalid date {}, expected YYYY-MM-DD", day))?;
+    Ok(day.and_hms_opt(0, 0, 0).unwrap())
+}
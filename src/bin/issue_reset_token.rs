@@ -0,0 +1,23 @@
+use std::env;
+use std::process::ExitCode;
+
+use trading_gpt::User;
+
+/// Prints a one-time password reset token for the user named on the command
+/// line, to be handed to them out of band.
+fn main() -> ExitCode {
+    let Some(username) = env::args().nth(1) else {
+        eprintln!("Usage: issue_reset_token <username>");
+        return ExitCode::FAILURE;
+    };
+    match User::issue_password_reset(&username) {
+        Ok(token) => {
+            println!("{token}");
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Failed to issue a reset token for {username}: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
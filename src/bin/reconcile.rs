@@ -0,0 +1,57 @@
+use std::env;
+use std::process::ExitCode;
+
+use trading_gpt::Discrepancy;
+
+static USAGE: &str = "Usage: reconcile [--repair] [--dry-run]";
+
+/// Checks stored balances against the postings, and for negative balances and
+/// rows naming missing users. `--repair` fixes what it can in the same
+/// transaction; with `--dry-run` it only prints what it would do.
+///
+/// Exits with failure while anything is left unrepaired, so it can run from cron.
+fn main() -> ExitCode {
+    let (mut repair, mut dry_run) = (false, false);
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--repair" => repair = true,
+            "--dry-run" => dry_run = true,
+            _ => {
+                eprintln!("Unknown argument: {arg}\n{USAGE}");
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+
+    let found = match Discrepancy::reconcile_db(repair && !dry_run) {
+        Ok(found) => found,
+        Err(e) => {
+            eprintln!("Failed to reconcile the ledger: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if found.is_empty() {
+        println!("The ledger is consistent");
+        return ExitCode::SUCCESS;
+    }
+    let mut unrepaired = 0;
+    for discrepancy in &found {
+        match discrepancy.repair() {
+            Some(fix) if repair && !dry_run => println!("{discrepancy}: repaired, {fix}"),
+            Some(fix) => {
+                unrepaired += 1;
+                println!("{discrepancy}: would {fix}");
+            }
+            None => {
+                unrepaired += 1;
+                println!("{discrepancy}: needs a manual fix");
+            }
+        }
+    }
+    println!("{} found, {} left", found.len(), unrepaired);
+    if unrepaired == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
@@ -0,0 +1,23 @@
+use std::env;
+use std::process::ExitCode;
+
+use trading_gpt::User;
+
+/// Makes the user named on the command line an admin, or a regular user again.
+fn main() -> ExitCode {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    let [username, role] = args.as_slice() else {
+        eprintln!("Usage: set_role <username> <user|admin>");
+        return ExitCode::FAILURE;
+    };
+    match User::set_role(username, role) {
+        Ok(user) => {
+            println!("{} is now {}", user.username, user.role);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Failed to set the role of {username}: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
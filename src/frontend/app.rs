@@ -6,6 +6,7 @@ use super::components::*;
 use super::types::*;
 use crate::trading_core::Bot;
 
+#[allow(clippy::await_holding_refcell_ref)]
 pub fn app(cx: Scope) -> Element {
     let (tx, rx) = mpsc::channel::<String>(32);
 
@@ -61,6 +62,76 @@ pub fn app(cx: Scope) -> Element {
         })
     };
 
+    // Confirming happens here rather than through the model, see `Bot::confirm_transfer`.
+    let answer_transfer = move |confirm: bool| {
+        if send_lock == true {
+            return;
+        }
+        send_lock.set(true);
+        loading.set(true);
+
+        cx.spawn({
+            to_owned![send_lock, loading, bot, messages];
+
+            async move {
+                let result = if confirm {
+                    bot.write().confirm_transfer().await
+                } else {
+                    bot.write().cancel_transfer().await
+                };
+                result.unwrap_or_else(|err| {
+                    messages
+                        .write()
+                        .push(Message::new(Role::Bot, format!("Error: {}", err)));
+                });
+
+                loading.set(false);
+                send_lock.set(false);
+            }
+        })
+    };
+
+    // Like confirming, the file goes to the bot directly, see `Bot::upload_payout`.
+    let upload_payout = move |csv: String| {
+        if send_lock == true || csv.is_empty() {
+            return;
+        }
+        send_lock.set(true);
+        loading.set(true);
+        messages.write().push(Message::new(
+            Role::User,
+            "Uploaded a payout file".to_string(),
+        ));
+
+        cx.spawn({
+            to_owned![send_lock, loading, bot, messages];
+
+            async move {
+                bot.write().upload_payout(&csv).await.unwrap_or_else(|err| {
+                    messages
+                        .write()
+                        .push(Message::new(Role::Bot, format!("Error: {}", err)));
+                });
+
+                loading.set(false);
+                send_lock.set(false);
+            }
+        })
+    };
+
+    // The bot is mutably borrowed while it is answering.
+    let pending = if loading == true {
+        None
+    } else {
+        bot.read().pending_transfer().map(ToString::to_string)
+    };
+
+    let statement = if loading == true {
+        None
+    } else {
+        bot.read().statement_download().cloned()
+    };
+
     let send_enter = move |e: Event<KeyboardData>| {
         if let Key::Enter = e.data.key() {
             send(0);
@@ -87,6 +158,19 @@ pub fn app(cx: Scope) -> Element {
                     Role::Bot => rsx!(OtherMessage { content: msg.content.clone() }),
                 }
             }
+            if let Some(description) = pending {
+                rsx!(ConfirmTransfer {
+                    description: description,
+                    on_confirm: move |_| answer_transfer(true),
+                    on_cancel: move |_| answer_transfer(false),
+                })
+            }
+            if let Some(link) = statement {
+                rsx!(DownloadStatement {
+                    url: link.url(),
+                    filename: link.filename,
+                })
+            }
             if loading == true {
                 rsx!(Loading{})
             }
@@ -98,6 +182,9 @@ pub fn app(cx: Scope) -> Element {
                 clean: clean,
                 on_press: send_enter,
             }
+            PayoutUpload {
+                on_upload: upload_payout,
+            }
             button {
                 id: "send-button",
                 onclick: send_botton, "Send" }
@@ -106,4 +193,4 @@ pub fn app(cx: Scope) -> Element {
             id: "bottom-holder"
         }
     ))
-}
\ No newline at end of file
+}
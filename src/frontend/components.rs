@@ -41,6 +41,82 @@ pub fn Loading(cx: Scope) -> Element {
     ))
 }
 
+#[derive(Props)]
+pub struct ConfirmProps<'a> {
+    description: String,
+    on_confirm: EventHandler<'a, MouseEvent>,
+    on_cancel: EventHandler<'a, MouseEvent>,
+}
+
+pub fn ConfirmTransfer<'a>(cx: Scope<'a, ConfirmProps<'a>>) -> Element<'a> {
+    cx.render(rsx!(
+        div {
+            class: "chat-message other-message",
+            "{cx.props.description}?"
+            div {
+                class: "confirm-buttons",
+                button {
+                    class: "confirm-button",
+                    onclick: move |e| cx.props.on_confirm.call(e),
+                    "Confirm"
+                }
+                button {
+                    class: "cancel-button",
+                    onclick: move |e| cx.props.on_cancel.call(e),
+                    "Cancel"
+                }
+            }
+        }
+    ))
+}
+
+#[derive(PartialEq, Props)]
+pub struct DownloadProps {
+    filename: String,
+    url: String,
+}
+
+pub fn DownloadStatement(cx: Scope<DownloadProps>) -> Element {
+    cx.render(rsx!(
+        div {
+            class: "chat-message other-message",
+            a {
+                class: "download-link",
+                href: "{cx.props.url}",
+                download: "{cx.props.filename}",
+                "Download {cx.props.filename}"
+            }
+        }
+    ))
+}
+
+#[derive(Props)]
+pub struct UploadProps<'a> {
+    on_upload: EventHandler<'a, String>,
+}
+
+/// Picks a payout file. LiveView doesn't send the content of picked files, so
+/// `upload.js` reads it and writes it into the hidden field listened to here.
+pub fn PayoutUpload<'a>(cx: Scope<'a, UploadProps<'a>>) -> Element<'a> {
+    cx.render(rsx!(
+        label {
+            id: "upload-button",
+            title: "Upload a CSV payout file",
+            "Upload payout file"
+            input {
+                id: "payout-file",
+                r#type: "file",
+                accept: ".csv,text/csv",
+            }
+        }
+        textarea {
+            id: "payout-file-content",
+            hidden: "true",
+            oninput: move |e| cx.props.on_upload.call(e.value.clone()),
+        }
+    ))
+}
+
 #[derive(Props)]
 pub struct DraftProps<'a> {
     draft: &'a UseRef<String>,
@@ -0,0 +1,11 @@
+// The LiveView glue cancels every click on a link and only asks a desktop
+// shell to open it. Keep it from seeing clicks on download links.
+document.addEventListener(
+  "click",
+  (event) => {
+    if (event.target.closest(".download-link")) {
+      event.stopPropagation();
+    }
+  },
+  true
+);
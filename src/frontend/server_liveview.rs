@@ -1,7 +1,14 @@
-use axum::{extract::ws::WebSocketUpgrade, response::Html, routing::get, Router};
+use axum::{
+    extract::{ws::WebSocketUpgrade, Path},
+    http::{header, StatusCode},
+    response::{Html, IntoResponse, Response},
+    routing::get,
+    Router,
+};
 use dotenvy::dotenv;
 
 use super::app::app;
+use crate::trading_core::{find_download, run_scheduler};
 
 pub async fn start_server() {
     dotenv().ok();
@@ -12,6 +19,8 @@ pub async fn start_server() {
 
     let view = dioxus_liveview::LiveViewPool::new();
 
+    tokio::spawn(run_scheduler());
+
     let app = Router::new()
         .route(
             "/",
@@ -31,12 +40,17 @@ pub async fn start_server() {
                 </head>
                 <body> <div id="main"></div> </body>
                 {glue}
+                <script>{upload}</script>
+                <script>{download}</script>
             </html>
             "#,
-                    glue = dioxus_liveview::interpreter_glue(&format!("ws://{reachable_addr}/ws"))
+                    glue = dioxus_liveview::interpreter_glue(&format!("ws://{reachable_addr}/ws")),
+                    upload = include_str!("./upload.js"),
+                    download = include_str!("./download.js")
                 ))
             }),
         )
+        .route("/statements/:token", get(download_statement))
         .route(
             "/ws",
             get(move |ws: WebSocketUpgrade| async move {
@@ -53,3 +67,18 @@ pub async fn start_server() {
         .await
         .unwrap();
 }
+
+/// Serves a statement prepared by the bot, under the token of its link.
+async fn download_statement(Path(token): Path<String>) -> Response {
+    match find_download(&token, chrono::Utc::now().naive_utc()) {
+        Some(download) => (
+            [
+                (header::CONTENT_TYPE, download.content_type.to_string()),
+                (header::CONTENT_DISPOSITION, download.content_disposition()),
+            ],
+            download.body,
+        )
+            .into_response(),
+        None => (StatusCode::NOT_FOUND, "This statement link has expired").into_response(),
+    }
+}
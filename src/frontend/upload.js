@@ -0,0 +1,15 @@
+// LiveView only sends the name of a picked file. Read the payout file here and
+// hand its content to the app through the hidden field it listens to.
+document.addEventListener("change", (event) => {
+  const input = event.target;
+  if (input.id !== "payout-file" || input.files.length === 0) {
+    return;
+  }
+  input.files[0].text().then((text) => {
+    const content = document.getElementById("payout-file-content");
+    content.value = text;
+    content.dispatchEvent(new Event("input", { bubbles: true }));
+    // So that picking the same file again uploads it again.
+    input.value = "";
+  });
+});
@@ -1 +1,34 @@
 pub static START_MONEY: i32 = 100;
+/// Hard limit on a session's lifetime, in seconds.
+pub static SESSION_TTL_SECS: i64 = 24 * 60 * 60;
+/// A session unused for this many seconds expires.
+pub static SESSION_IDLE_SECS: i64 = 30 * 60;
+/// Seconds a transfer waits for the user's confirmation before it is dropped.
+pub static PENDING_TRANSFER_TTL_SECS: i64 = 5 * 60;
+/// Seconds between two scheduler runs looking for due scheduled transfers.
+pub static SCHEDULER_INTERVAL_SECS: u64 = 60;
+/// Failed runs in a row after which a recurring transfer is given up.
+pub static SCHEDULE_MAX_FAILURES: i32 = 3;
+/// Seconds a hold reserves funds when no expiry is given.
+pub static HOLD_TTL_SECS: i64 = 7 * 24 * 60 * 60;
+/// Seconds a password reset token issued by an admin stays redeemable.
+pub static RESET_TOKEN_TTL_SECS: i64 = 60 * 60;
+/// Failed logins in a row allowed before each attempt has to wait.
+pub static LOGIN_FREE_ATTEMPTS: u32 = 3;
+/// Seconds to wait after the first failed login past the free ones, doubled
+/// with every further failure.
+pub static LOGIN_BACKOFF_SECS: i64 = 2;
+/// Longest wait, in seconds: once reached the login is locked out.
+pub static LOGIN_LOCKOUT_SECS: i64 = 15 * 60;
+/// Seconds without a failed login after which earlier failures are forgotten.
+pub static LOGIN_FAILURE_WINDOW_SECS: i64 = 60 * 60;
+/// Seconds a statement download link stays valid.
+pub static STATEMENT_DOWNLOAD_TTL_SECS: i64 = 10 * 60;
+/// Currency code written into OFX statements.
+pub static STATEMENT_CURRENCY: &str = "USD";
+/// Bank id written into OFX statements, whose account id is the username.
+pub static STATEMENT_BANK_ID: &str = "TRADINGGPT";
+/// Most entries a statement may have.
+pub static STATEMENT_MAX_ROWS: usize = 5000;
+/// Most rows a bulk payout file may have.
+pub static PAYOUT_MAX_ROWS: usize = 500;
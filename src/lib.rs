@@ -4,6 +4,7 @@ mod global;
 mod trading_core;
 
 pub use frontend::start_server;
+pub use trading_core::{AuditFilter, AuditRecord, Discrepancy, User};
 
 pub fn foo() {
     print!("Hello world!")
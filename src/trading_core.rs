@@ -1,7 +1,23 @@
+mod audit;
 mod behaviors;
 mod gpt_bot;
+mod lockout;
+mod memory;
+mod payout;
+#[cfg(feature = "postgres")]
+mod pg;
+mod policy;
+mod recipients;
+mod reconcile;
+mod scheduler;
 mod schema;
 mod sql;
+mod statement;
+mod storage;
 mod types;
 
 pub use gpt_bot::Bot;
+pub use reconcile::Discrepancy;
+pub use scheduler::run_scheduler;
+pub use statement::find_download;
+pub use types::{AuditFilter, AuditRecord, User};
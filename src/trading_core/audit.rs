@@ -0,0 +1,73 @@
+use serde_json::{json, Map, Value};
+
+use super::types::AuditRecord;
+
+/// Replaces the value of every argument that looks like a secret.
+pub fn redact(arguments: &Value) -> Value {
+    match arguments {
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(key, value)| {
+                    let value = if is_secret(key) {
+                        Value::String("[redacted]".to_string())
+                    } else {
+                        redact(value)
+                    };
+                    (key.clone(), value)
+                })
+                .collect::<Map<_, _>>(),
+        ),
+        Value::Array(items) => Value::Array(items.iter().map(redact).collect()),
+        other => other.clone(),
+    }
+}
+
+fn is_secret(key: &str) -> bool {
+    let key = key.to_lowercase();
+    key.contains("password") || key.contains("token")
+}
+
+impl AuditRecord {
+    /// The record as one JSON object, for exports.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "created_at": self.created_at.format("%Y-%m-%dT%H:%M:%S%.6f").to_string(),
+            "session_id": self.session_id,
+            "username": self.username,
+            "function_name": self.function_name,
+            "arguments": serde_json::from_str::<Value>(&self.arguments)
+                .unwrap_or_else(|_| Value::String(self.arguments.clone())),
+            "outcome": self.outcome,
+            "result": self.result,
+            "model": self.model,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        let arguments = json!({
+            "username": "alice",
+            "password": "hunter2",
+            "new_password": "hunter3",
+            "token": "abc",
+            "nested": [{"old_password": "x", "amount": 5}],
+        });
+        assert_eq!(
+            redact(&arguments),
+            json!({
+                "username": "alice",
+                "password": "[redacted]",
+                "new_password": "[redacted]",
+                "token": "[redacted]",
+                "nested": [{"old_password": "[redacted]", "amount": 5}],
+            })
+        );
+    }
+}
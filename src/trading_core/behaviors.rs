@@ -1,46 +1,681 @@
-use super::types::User;
+use super::lockout;
+use super::payout::{BatchFailed, PayoutBatch};
+use super::recipients;
+use super::statement::Statement;
+use super::types::{
+    Contact, Direction, Hold, NewPaymentRequest, NewScheduledTransfer, NewTransaction,
+    PasswordReset, PaymentRequest, Posting, Recurrence, ScheduledTransfer, Session, Transaction,
+    TransactionFilter, User, REQUEST_DECLINED, REQUEST_PENDING, ROLE_ADMIN, ROLE_USER,
+    SCHEDULE_ACTIVE, SYSTEM_ACCOUNTS,
+};
 use crate::global;
 
-use anyhow::{bail, Result};
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, ensure, Result};
+use argon2::password_hash::{
+    rand_core::{OsRng, RngCore},
+    PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
+};
+use argon2::Argon2;
+use chrono::{Duration, NaiveDateTime, Utc};
 
 impl User {
     pub fn signup(username: &str, password: &str) -> Result<User> {
+        ensure!(
+            !SYSTEM_ACCOUNTS.contains(&username.to_lowercase().as_str()),
+            "Username {} is reserved",
+            username
+        );
         let user = User::new(
             username.to_string(),
-            password.to_string(),
+            hash_password(password)?,
             global::START_MONEY,
         );
         user.init()?;
         Ok(user)
     }
 
+    /// Failed attempts lock further logins of `username` for a while, failing
+    /// with `LoginLocked` without checking the password.
     pub fn login(username: &str, passsword: &str) -> Result<User> {
-        let user = User::retrieve_from_db(username)?;
-        user.check_password(passsword)?;
+        let now = Utc::now().naive_utc();
+        lockout::check_username(username, now)?;
+        let checked = User::retrieve_from_db(username)
+            .and_then(|user| user.check_password(passsword).map(|_| user));
+        let mut user = match checked {
+            Ok(user) => user,
+            Err(e) => {
+                lockout::record_username_failure(username, now);
+                return Err(e);
+            }
+        };
+        lockout::reset_username(username);
+        user.check_open()?;
+        if user.password_hash.is_none() {
+            user.rehash_password(passsword)?;
+        }
         Ok(user)
     }
 
-    pub fn logout(&self) -> Result<()> {
-        unimplemented!()
+    pub fn start_session(&self) -> Result<Session> {
+        let session = Session::new(
+            generate_token(),
+            self.username.clone(),
+            Utc::now().naive_utc(),
+            Duration::seconds(global::SESSION_TTL_SECS),
+        );
+        session.insert_into_db()?;
+        Ok(session)
     }
 
-    pub fn transfer(&mut self, to: &str, amount: i32) -> Result<()> {
-        self.transfer_to_other(to, amount)
+    pub fn logout(&self, session: &Session) -> Result<()> {
+        ensure!(
+            session.username == self.username,
+            "Session belongs to another user"
+        );
+        session.revoke_to_db(Utc::now().naive_utc())
     }
 
-    fn init(&self) -> Result<()> {
-        self.insert_into_db()
+    /// Revokes every session of this user, returning how many were live.
+    pub fn logout_everywhere(&self) -> Result<usize> {
+        Session::revoke_all_to_db(&self.username, Utc::now().naive_utc())
+    }
+
+    /// Replaces the password once the current one is confirmed. Every session
+    /// is logged out, returning how many were live.
+    pub fn change_password(&mut self, old_password: &str, new_password: &str) -> Result<usize> {
+        self.refresh_from_db()?;
+        self.check_password(old_password)?;
+        self.password_hash = Some(hash_password(new_password)?);
+        self.password.clear();
+        self.change_password_to_db(Utc::now().naive_utc())
+    }
+
+    /// Issues a one-time token with which `username` can set a new password,
+    /// valid for `RESET_TOKEN_TTL_SECS`. Meant for admins, not the bot.
+    pub fn issue_password_reset(username: &str) -> Result<String> {
+        User::retrieve_from_db(username)?.check_open()?;
+        let reset = PasswordReset::new(
+            generate_token(),
+            username.to_string(),
+            Utc::now().naive_utc(),
+            Duration::seconds(global::RESET_TOKEN_TTL_SECS),
+        );
+        reset.insert_into_db()?;
+        Ok(reset.token)
+    }
+
+    /// Redeems a reset token, setting `new_password` and logging out every
+    /// session of its user.
+    pub fn reset_password(token: &str, new_password: &str) -> Result<User> {
+        PasswordReset::redeem_to_db(token, &hash_password(new_password)?, Utc::now().naive_utc())
+    }
+
+    /// Checks `session` against the stored one and refreshes its idle timer.
+    pub fn login_still_valid(&self, session: &mut Session) -> Result<()> {
+        let mut stored = Session::retrieve_from_db(&session.token)?;
+        ensure!(
+            stored.username == self.username,
+            "Session belongs to another user"
+        );
+        let now = Utc::now().naive_utc();
+        stored.check_valid(now)?;
+        stored.last_seen_at = now;
+        stored.touch_to_db()?;
+        *session = stored;
+        Ok(())
+    }
+
+    /// Transfers with the same `idempotency_key` are executed at most once.
+    pub fn transfer(
+        &mut self,
+        to: &str,
+        amount: i32,
+        memo: Option<&str>,
+        idempotency_key: Option<&str>,
+    ) -> Result<Transaction> {
+        self.transfer_to_other(to, amount, memo, idempotency_key)
+    }
+
+    /// Closes the account for good once `password` is confirmed. What is
+    /// left of the balance goes to `sweep_to`, without which only an empty
+    /// account can be closed. Returns the sweeping transfer, if any.
+    pub fn close_account(
+        &mut self,
+        password: &str,
+        sweep_to: Option<&str>,
+    ) -> Result<Option<Transaction>> {
+        self.refresh_from_db()?;
+        self.check_password(password)?;
+        self.close_in_db(sweep_to, Utc::now().naive_utc())
     }
 
-    fn login_still_valid(&self) -> Result<()> {
-        unimplemented!()
+    /// Gives back `amount` of a transfer this user received, or all that
+    /// hasn't been refunded yet if `None`.
+    pub fn refund_transfer(
+        &mut self,
+        id: i32,
+        amount: Option<i32>,
+        memo: Option<&str>,
+        idempotency_key: Option<&str>,
+    ) -> Result<Transaction> {
+        let amount = match amount {
+            Some(amount) => amount,
+            None => self.refundable_transfer(id)?.1,
+        };
+        self.refund_to_other(id, amount, memo, idempotency_key)
+    }
+
+    /// A transfer this user can refund, with the amount still refundable.
+    pub fn refundable_transfer(&self, id: i32) -> Result<(Transaction, i32)> {
+        let original = Transaction::retrieve_from_db(id)?;
+        original.check_refundable(&self.username)?;
+        let refunded = original
+            .refunds_from_db()?
+            .iter()
+            .map(|refund| refund.amount)
+            .sum::<i32>();
+        let remaining = original.amount - refunded;
+        ensure!(remaining > 0, "Transfer #{} is already fully refunded", id);
+        Ok((original, remaining))
+    }
+
+    /// Parses a payout file of `recipient,amount,memo` rows and checks each
+    /// row, and their total against the available balance, without paying.
+    pub fn validate_payout(&mut self, csv: &str) -> Result<PayoutBatch> {
+        let mut batch = PayoutBatch::parse(csv)?;
+        self.refresh_from_db()?;
+        for row in batch.rows.iter_mut() {
+            row.check(|recipient, amount| {
+                ensure!(recipient != self.username, "Can't pay yourself");
+                User::retrieve_from_db(recipient)?.check_active()?;
+                self.check_transfer_policy(recipient, amount)
+            });
+        }
+        batch.check_total(self.available());
+        Ok(batch)
+    }
+
+    /// Validates the payout file, then pays all of its rows in one
+    /// transaction or none of them. The report says what happened to each
+    /// row. Row transfers are keyed by `idempotency_key` and their line, so a
+    /// retried batch pays every row once.
+    pub fn bulk_payout(&mut self, csv: &str, idempotency_key: &str) -> Result<PayoutBatch> {
+        let mut batch = self.validate_payout(csv)?;
+        if !batch.is_valid() {
+            return Ok(batch);
+        }
+        let sender = self.username.clone();
+        let keys = batch
+            .rows
+            .iter()
+            .map(|row| format!("{}:{}", idempotency_key, row.line))
+            .collect::<Vec<_>>();
+        let records = batch
+            .rows
+            .iter()
+            .zip(&keys)
+            .map(|(row, key)| {
+                NewTransaction::completed(
+                    &sender,
+                    &row.recipient,
+                    row.amount,
+                    row.memo.as_deref(),
+                    Some(key),
+                )
+            })
+            .collect::<Vec<_>>();
+        match self.transfer_batch_to_others(&records) {
+            Ok(transfers) => batch.paid(&transfers),
+            Err(e) => match e.downcast_ref::<BatchFailed>() {
+                Some(failed) => batch.failed(failed.index, &failed.reason),
+                None => return Err(e),
+            },
+        }
+        Ok(batch)
+    }
+
+    /// Asks `payer` for `amount`. No money moves until the payer accepts.
+    pub fn request_payment(
+        &self,
+        payer: &str,
+        amount: i32,
+        memo: Option<&str>,
+    ) -> Result<PaymentRequest> {
+        ensure!(amount > 0, "Amount must be positive");
+        ensure!(payer != self.username, "Can't request money from yourself");
+        User::retrieve_from_db(payer)?.check_active()?;
+        PaymentRequest::insert_into_db(&NewPaymentRequest {
+            requester: &self.username,
+            payer,
+            amount,
+            memo,
+            status: REQUEST_PENDING,
+            created_at: Utc::now().naive_utc(),
+        })
+    }
+
+    /// Requests this user was asked to pay (`Incoming`) or made (`Outgoing`).
+    pub fn payment_requests(
+        &self,
+        direction: Option<Direction>,
+        status: Option<&str>,
+    ) -> Result<Vec<PaymentRequest>> {
+        PaymentRequest::list_from_db(&self.username, direction, status)
+    }
+
+    /// Pays a pending request addressed to this user. Accepting twice, even
+    /// concurrently, pays only once.
+    pub fn accept_payment_request(&mut self, id: i32) -> Result<Transaction> {
+        let request = self.payable_request(id)?;
+        request.accept_to_db(self, Utc::now().naive_utc())
+    }
+
+    pub fn decline_payment_request(&self, id: i32) -> Result<PaymentRequest> {
+        let request = self.payable_request(id)?;
+        if !request.resolve_to_db(REQUEST_DECLINED, None, Utc::now().naive_utc())? {
+            bail!("Payment request #{} is no longer pending", id)
+        }
+        PaymentRequest::retrieve_from_db(id)
+    }
+
+    /// The pending request `id`, if this user is its payer.
+    pub fn payable_request(&self, id: i32) -> Result<PaymentRequest> {
+        let request = PaymentRequest::retrieve_from_db(id)?;
+        ensure!(
+            request.payer == self.username,
+            "Payment request #{} is not addressed to you",
+            id
+        );
+        ensure!(
+            request.status == REQUEST_PENDING,
+            "Payment request #{} is already {}",
+            id,
+            request.status
+        );
+        Ok(request)
+    }
+
+    /// Schedules a transfer at `first_run_at`, repeated on `recurrence` if
+    /// given. The transfer policy applies when each run executes.
+    pub fn schedule_transfer(
+        &self,
+        to: &str,
+        amount: i32,
+        memo: Option<&str>,
+        first_run_at: NaiveDateTime,
+        recurrence: Option<Recurrence>,
+    ) -> Result<ScheduledTransfer> {
+        ensure!(amount > 0, "Amount must be positive");
+        ensure!(to != self.username, "Can't schedule a transfer to yourself");
+        User::retrieve_from_db(to)?.check_active()?;
+        ScheduledTransfer::insert_into_db(&NewScheduledTransfer {
+            sender: &self.username,
+            receiver: to,
+            amount,
+            memo,
+            recurrence: recurrence.as_ref().map(Recurrence::as_str),
+            next_run_at: first_run_at,
+            status: SCHEDULE_ACTIVE,
+            created_at: Utc::now().naive_utc(),
+            first_run_at,
+        })
+    }
+
+    pub fn scheduled_transfers(&self) -> Result<Vec<ScheduledTransfer>> {
+        ScheduledTransfer::list_from_db(&self.username)
+    }
+
+    pub fn cancel_scheduled_transfer(&self, id: i32) -> Result<ScheduledTransfer> {
+        let schedule = ScheduledTransfer::retrieve_from_db(id)?;
+        ensure!(
+            schedule.sender == self.username,
+            "Scheduled transfer #{} is not yours",
+            id
+        );
+        if !schedule.cancel_to_db()? {
+            let current = ScheduledTransfer::retrieve_from_db(id)?;
+            bail!("Scheduled transfer #{} is already {}", id, current.status)
+        }
+        ScheduledTransfer::retrieve_from_db(id)
+    }
+
+    /// Reserves `amount` for `payee`, who can capture it until `ttl` (by
+    /// default `HOLD_TTL_SECS`) has passed. Held funds can't be spent.
+    pub fn authorize_hold(
+        &mut self,
+        payee: &str,
+        amount: i32,
+        memo: Option<&str>,
+        ttl: Option<Duration>,
+    ) -> Result<Hold> {
+        let ttl = ttl.unwrap_or_else(|| Duration::seconds(global::HOLD_TTL_SECS));
+        let expires_at = Utc::now().naive_utc() + ttl;
+        User::retrieve_from_db(&self.username)?.check_active()?;
+        User::retrieve_from_db(payee)?.check_active()?;
+        Hold::authorize_into_db(self, payee, amount, memo, expires_at)
+    }
+
+    /// Captures `amount` of a hold placed for this user, all of it if `None`.
+    pub fn capture_hold(&mut self, id: i32, amount: Option<i32>) -> Result<Transaction> {
+        let hold = Hold::retrieve_from_db(id)?;
+        let record = hold.capture_to_db(self, amount.unwrap_or(hold.amount))?;
+        self.refresh_from_db()?;
+        Ok(record)
+    }
+
+    /// Releases a hold placed for this user without taking any money.
+    pub fn void_hold(&self, id: i32) -> Result<Hold> {
+        Hold::retrieve_from_db(id)?.void_to_db(self)
+    }
+
+    pub fn holds(&self, direction: Option<Direction>, status: Option<&str>) -> Result<Vec<Hold>> {
+        Hold::list_from_db(&self.username, direction, status)
+    }
+
+    /// Saves `username` under `alias`, so it can be used as a recipient.
+    pub fn add_contact(&self, alias: &str, username: &str, note: Option<&str>) -> Result<Contact> {
+        let alias = Contact::normalize_alias(alias);
+        ensure!(!alias.is_empty(), "The alias can't be empty");
+        ensure!(username != self.username, "Can't add yourself as a contact");
+        User::retrieve_from_db(username)?.check_open()?;
+        let contact = Contact {
+            owner: self.username.clone(),
+            alias,
+            username: username.to_string(),
+            note: note.map(str::to_string),
+            created_at: Utc::now().naive_utc(),
+        };
+        contact.insert_into_db()?;
+        Ok(contact)
+    }
+
+    pub fn remove_contact(&self, alias: &str) -> Result<()> {
+        Contact::delete_from_db(&self.username, &Contact::normalize_alias(alias))
+    }
+
+    pub fn contacts(&self) -> Result<Vec<Contact>> {
+        Contact::list_from_db(&self.username)
+    }
+
+    /// The username behind `to`: one of this user's contact aliases, which
+    /// take precedence, or else a username. A name matching no open account
+    /// but close to some fails, listing them for the user to pick from.
+    pub fn resolve_recipient(&self, to: &str) -> Result<String> {
+        if let Some(contact) =
+            Contact::retrieve_from_db(&self.username, &Contact::normalize_alias(to))?
+        {
+            return Ok(contact.username);
+        }
+        // Only a name that isn't a username is worth the scan for close ones.
+        if User::retrieve_from_db(to).is_ok() {
+            return Ok(to.to_string());
+        }
+        let suggestions = self.suggest_recipients(to)?;
+        if suggestions.is_empty() {
+            // Unknown names are left for the transfer itself to reject.
+            return Ok(to.to_string());
+        }
+        bail!(
+            "No user named {}, did you mean {}?",
+            to,
+            suggestions.join(" or ")
+        )
+    }
+
+    /// Open accounts whose username is close to `to`, best first, favouring
+    /// users this one recently dealt with.
+    pub fn suggest_recipients(&self, to: &str) -> Result<Vec<String>> {
+        let mut usernames = User::list_usernames_from_db()?;
+        usernames.retain(|name| name != &self.username);
+        let history = TransactionFilter {
+            limit: Some(recipients::HISTORY_SIZE),
+            ..Default::default()
+        };
+        let counterparties = self
+            .list_transactions(&history)?
+            .into_iter()
+            .map(|record| {
+                if record.sender == self.username {
+                    record.receiver
+                } else {
+                    record.sender
+                }
+            })
+            .collect::<Vec<_>>();
+        Ok(recipients::suggest(to, &usernames, &counterparties))
+    }
+
+    /// Makes `username` an admin or a regular user again. Meant for operators,
+    /// not the bot.
+    pub fn set_role(username: &str, role: &str) -> Result<User> {
+        ensure!(
+            role == ROLE_USER || role == ROLE_ADMIN,
+            "Unknown role: {}",
+            role
+        );
+        User::set_role_in_db(username, role)
+    }
+
+    /// Admin only: creates `amount` in the account of `username`.
+    pub fn mint(&self, username: &str, amount: i32) -> Result<User> {
+        self.check_admin_in_db()?;
+        ensure!(amount > 0, "Amount must be positive");
+        User::adjust_balance_in_db(username, amount)
+    }
+
+    /// Admin only: destroys `amount` of what `username` has available.
+    pub fn burn(&self, username: &str, amount: i32) -> Result<User> {
+        self.check_admin_in_db()?;
+        ensure!(amount > 0, "Amount must be positive");
+        User::adjust_balance_in_db(username, -amount)
+    }
+
+    /// Admin only: stops `username` from sending or receiving money.
+    pub fn freeze(&self, username: &str) -> Result<User> {
+        self.check_admin_in_db()?;
+        ensure!(username != self.username, "Can't freeze your own account");
+        User::set_frozen_in_db(username, Some(Utc::now().naive_utc()))
+    }
+
+    /// Admin only.
+    pub fn unfreeze(&self, username: &str) -> Result<User> {
+        self.check_admin_in_db()?;
+        User::set_frozen_in_db(username, None)
+    }
+
+    /// Admin only: the account of any user.
+    pub fn inspect_user(&self, username: &str) -> Result<User> {
+        self.check_admin_in_db()?;
+        User::retrieve_from_db(username)
+    }
+
+    /// Admin only: the transfers of any user.
+    pub fn inspect_transactions(
+        &self,
+        username: &str,
+        filter: &TransactionFilter,
+    ) -> Result<Vec<Transaction>> {
+        self.check_admin_in_db()?;
+        User::retrieve_from_db(username)?.list_transactions(filter)
+    }
+
+    /// The user's statement over `since..until`, built from the ledger.
+    pub fn statement(
+        &self,
+        since: Option<NaiveDateTime>,
+        until: Option<NaiveDateTime>,
+    ) -> Result<Statement> {
+        let postings = Posting::list_from_db(&self.username)?;
+        // Every transfer is an entry, so one more than fits is enough to
+        // tell that the statement is too long.
+        let filter = TransactionFilter {
+            since,
+            until,
+            limit: Some(global::STATEMENT_MAX_ROWS as i64 + 1),
+            ..Default::default()
+        };
+        let records = self.list_transactions(&filter)?;
+        ensure!(
+            records.len() <= global::STATEMENT_MAX_ROWS,
+            "The statement has more than {} entries, export a shorter period",
+            global::STATEMENT_MAX_ROWS
+        );
+        let memos = records
+            .into_iter()
+            .filter_map(|record| record.memo.map(|memo| (record.id, memo)))
+            .collect::<HashMap<_, _>>();
+        Statement::new(&self.username, &postings, &memos, since, until)
+    }
+
+    /// Admin only: the postings of any account, system accounts included.
+    pub fn inspect_postings(&self, account: &str) -> Result<Vec<Posting>> {
+        self.check_admin_in_db()?;
+        Posting::list_from_db(account)
+    }
+
+    /// Admin only: what every account holds according to its postings.
+    pub fn inspect_account_balances(&self) -> Result<Vec<(String, i64)>> {
+        self.check_admin_in_db()?;
+        Posting::account_balances_from_db()
+    }
+
+    /// Checks the stored role, which may have changed since login.
+    fn check_admin_in_db(&self) -> Result<()> {
+        User::retrieve_from_db(&self.username)?.check_admin()
+    }
+
+    fn init(&self) -> Result<()> {
+        self.insert_into_db()
     }
 
     fn check_password(&self, password: &str) -> Result<()> {
-        if self.password == password {
+        let matched = match &self.password_hash {
+            Some(hash) => verify_password(password, hash)?,
+            // An empty legacy password would match an empty input.
+            None if self.password.is_empty() => false,
+            None => constant_time_eq(self.password.as_bytes(), password.as_bytes()),
+        };
+        if matched {
             Ok(())
         } else {
             bail!("Wrong password")
         }
     }
+
+    /// Replace a legacy plaintext password with an Argon2id hash.
+    fn rehash_password(&mut self, password: &str) -> Result<()> {
+        self.password_hash = Some(hash_password(password)?);
+        self.password.clear();
+        self.update_password_to_db()
+    }
+}
+
+impl Session {
+    fn check_valid(&self, now: NaiveDateTime) -> Result<()> {
+        ensure!(
+            self.revoked_at.is_none(),
+            "Session has been logged out, please login again"
+        );
+        ensure!(
+            now < self.expires_at,
+            "Session has expired, please login again"
+        );
+        ensure!(
+            now < self.last_seen_at + Duration::seconds(global::SESSION_IDLE_SECS),
+            "Session timed out after inactivity, please login again"
+        );
+        Ok(())
+    }
+}
+
+pub(super) fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow!("Failed to hash password: {}", e))?;
+    Ok(hash.to_string())
+}
+
+fn verify_password(password: &str, hash: &str) -> Result<bool> {
+    let parsed = PasswordHash::new(hash).map_err(|e| anyhow!("Invalid password hash: {}", e))?;
+    Ok(Argon2::default()
+        .verify_password(password.as_bytes(), &parsed)
+        .is_ok())
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_and_verify() {
+        let hash = hash_password("secret").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify_password("secret", &hash).unwrap());
+        assert!(!verify_password("wrong", &hash).unwrap());
+        assert_ne!(hash, hash_password("secret").unwrap());
+    }
+
+    #[test]
+    fn test_check_legacy_password() {
+        let mut user = User::new("legacy".to_string(), String::new(), 100);
+        user.password = "plain".to_string();
+        user.password_hash = None;
+        assert!(user.check_password("plain").is_ok());
+        assert!(user.check_password("plai").is_err());
+        user.password.clear();
+        assert!(user.check_password("").is_err());
+    }
+
+    #[test]
+    fn test_reserved_usernames() {
+        for username in ["issuance", "Issuance", "ISSUANCE"] {
+            let err = User::signup(username, "secret").unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("Username {} is reserved", username)
+            );
+        }
+    }
+
+    #[test]
+    fn test_session_validity() {
+        let now = Utc::now().naive_utc();
+        let session = Session::new(
+            generate_token(),
+            "alice".to_string(),
+            now,
+            Duration::seconds(global::SESSION_TTL_SECS),
+        );
+        assert_eq!(session.token.len(), 64);
+        assert!(session.check_valid(now).is_ok());
+
+        let idle = now + Duration::seconds(global::SESSION_IDLE_SECS);
+        assert!(session.check_valid(idle).is_err());
+
+        let mut active = session.clone();
+        let late = now + Duration::seconds(global::SESSION_TTL_SECS);
+        active.last_seen_at = late - Duration::seconds(1);
+        assert!(active.check_valid(late - Duration::seconds(1)).is_ok());
+        assert!(active.check_valid(late).is_err());
+
+        let mut revoked = session;
+        revoked.revoked_at = Some(now);
+        assert!(revoked.check_valid(now).is_err());
+    }
 }
@@ -1,4 +1,5 @@
 use anyhow::{anyhow, bail, ensure, Ok, Result};
+use chrono::{Duration, NaiveDate, NaiveDateTime};
 use async_openai::{
     config::OpenAIConfig,
     types::{self as openai_types, FunctionCall},
@@ -6,11 +7,18 @@ use async_openai::{
 };
 use indoc::formatdoc;
 use lazy_static::lazy_static;
-use tracing::info;
+use tracing::{info, warn};
 use serde_json::{json, Value};
 use tokio::sync::mpsc::Sender;
 
-use super::types::User;
+use crate::global;
+
+use super::audit;
+use super::behaviors::generate_token;
+use super::lockout::{LoginAttempts, LoginLocked};
+use super::payout::PayoutBatch;
+use super::statement::{find_download, offer_download, StatementFormat, StatementLink};
+use super::types::{AuditRecord, Direction, NewAuditRecord, PaymentRequest, PendingKind, PendingTransfer, Recurrence, ScheduledTransfer, Session, SpendingSummary, Transaction, TransactionFilter, User, AUDIT_ERROR, AUDIT_OK, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE};
 
 type Response = openai_types::ChatCompletionResponseMessage;
 type Model = openai_types::CreateChatCompletionRequest;
@@ -22,18 +30,20 @@ type FunctionArgs = openai_types::ChatCompletionFunctionsArgs;
 
 static SYSTEM_INIT: &str = "You are the AI assistant of a payment system.\
 You need to assist the user based on the functions you are provided.\
-Note that you only have access to four functions: signup, login, transfer, and logout.\
+Note that you only have access to the functions you are provided.\
 Please focus on the functions you are provided.\
 If the user ask about something unrelated to the payment system, ignore them.\n";
 
+static MODEL: &str = "gpt-3.5-turbo";
+
 lazy_static! {
     static ref MODEL_INIT: ModelArgs = ModelArgs::default()
-        .model("gpt-3.5-turbo")
+        .model(MODEL)
         .to_owned();
     static ref FUCTIONS_UNLOGIN: Vec<Function> = vec![
         FunctionArgs::default()
             .name("login")
-            .description("Let the user login. User should provide username and password")
+            .description("Let the user login. User should provide username and password. After too many failed attempts logins are locked for a while: then tell the user how long to wait and don't call login again before that")
             .parameters(json!({
                 "type": "object",
                 "properties": {
@@ -56,18 +66,196 @@ lazy_static! {
                 "required": ["username", "password"],
             }))
             .build()
+            .unwrap(),
+        FunctionArgs::default()
+            .name("reset_password")
+            .description("Set a new password with a one-time reset token the user got from an admin. User should provide the token and the new password")
+            .parameters(json!({
+                "type": "object",
+                "properties": {
+                    "token": {"type": "string"},
+                    "new_password": {"type": "string"}
+                },
+                "required": ["token", "new_password"],
+            }))
+            .build()
             .unwrap()
     ];
     static ref FUCTIONS_LOGIN: Vec<Function> = vec![
         FunctionArgs::default()
             .name("transfer")
-            .description("Transfer money to another user. User should provide the receiver and the amount to transfer. Note the amount must between 1 and one's balance")
+            .description("Request a transfer of money to another user. User should provide the receiver and the amount to transfer. Note the amount must between 1 and one's balance. If the receiver isn't found but similar usernames are, ask the user which one they meant, never pick one yourself. The transfer only happens after the user confirms it outside of this chat, you cannot confirm it yourself")
             .parameters(json!({
                 "type": "object",
                 "properties": {
-                    "to": {"type": "string"},
-                    "amount": {"type": "integer"}
+                    "to": {"type": "string", "description": "Username or one of the user's contact aliases"},
+                    "amount": {"type": "integer"},
+                    "memo": {"type": "string", "description": "Optional note attached to the transfer"}
+                },
+            }))
+            .build()
+            .unwrap(),
+        FunctionArgs::default()
+            .name("refund_transfer")
+            .description("Refund a transfer the user received, by its id from list_transactions. Omit the amount to refund everything not refunded yet. Like a transfer, the refund only happens after the user confirms it outside of this chat, you cannot confirm it yourself")
+            .parameters(json!({
+                "type": "object",
+                "properties": {
+                    "id": {"type": "integer"},
+                    "amount": {"type": "integer", "description": "Optional partial amount"},
+                    "memo": {"type": "string", "description": "Optional note attached to the refund"}
+                },
+                "required": ["id"],
+            }))
+            .build()
+            .unwrap(),
+        FunctionArgs::default()
+            .name("bulk_payout")
+            .description("Pay many users at once from a CSV payout file the user pasted in the chat, with one recipient,amount,memo row per line; the header row and memos are optional. Pass the file exactly as given. Every row is checked first and the report says what is wrong with each rejected row. A valid file is paid all at once, or not at all, only after the user confirms it outside of this chat, you cannot confirm it yourself. Long files are better uploaded with the Upload payout file button next to the Send button, which hands them over unchanged")
+            .parameters(json!({
+                "type": "object",
+                "properties": {
+                    "csv": {"type": "string", "description": "The content of the payout file"}
+                },
+                "required": ["csv"],
+            }))
+            .build()
+            .unwrap(),
+        FunctionArgs::default()
+            .name("get_balance")
+            .description("Get the current balance of the user from the database")
+            .parameters(json!({
+                "type": "object",
+                "properties": {},
+            }))
+            .build()
+            .unwrap(),
+        FunctionArgs::default()
+            .name("list_transactions")
+            .description("List the user's transfers, newest first. All filters are optional. Dates are YYYY-MM-DD and both ends are inclusive")
+            .parameters(json!({
+                "type": "object",
+                "properties": {
+                    "since": {"type": "string", "description": "First day to include, YYYY-MM-DD"},
+                    "until": {"type": "string", "description": "Last day to include, YYYY-MM-DD"},
+                    "counterparty": {"type": "string", "description": "Only transfers with this username"},
+                    "direction": {"type": "string", "enum": ["incoming", "outgoing"]},
+                    "limit": {"type": "integer", "description": "Maximum number of transfers to return, between 1 and 100, default 20"},
+                    "offset": {"type": "integer", "description": "Number of transfers to skip, for paging"}
+                },
+            }))
+            .build()
+            .unwrap(),
+        FunctionArgs::default()
+            .name("summarize_spending")
+            .description("Summarize how much the user sent and received over a period, with outgoing totals per receiver. Dates are YYYY-MM-DD and both ends are inclusive")
+            .parameters(json!({
+                "type": "object",
+                "properties": {
+                    "since": {"type": "string", "description": "First day to include, YYYY-MM-DD"},
+                    "until": {"type": "string", "description": "Last day to include, YYYY-MM-DD"}
+                },
+            }))
+            .build()
+            .unwrap(),
+        FunctionArgs::default()
+            .name("export_statement")
+            .description("Prepare a statement of the user's account activity as a file to download, for spreadsheets or personal finance tools. The download link is shown to the user below the chat. Dates are YYYY-MM-DD and both ends are inclusive")
+            .parameters(json!({
+                "type": "object",
+                "properties": {
+                    "format": {"type": "string", "enum": ["csv", "json", "ofx"]},
+                    "since": {"type": "string", "description": "First day to include, YYYY-MM-DD"},
+                    "until": {"type": "string", "description": "Last day to include, YYYY-MM-DD"}
+                },
+                "required": ["format"],
+            }))
+            .build()
+            .unwrap(),
+        FunctionArgs::default()
+            .name("request_payment")
+            .description("Ask another user to pay the user an amount. No money moves until the payer accepts the request")
+            .parameters(json!({
+                "type": "object",
+                "properties": {
+                    "from": {"type": "string", "description": "The username asked to pay"},
+                    "amount": {"type": "integer"},
+                    "memo": {"type": "string", "description": "Optional note explaining the request"}
+                },
+                "required": ["from", "amount"],
+            }))
+            .build()
+            .unwrap(),
+        FunctionArgs::default()
+            .name("list_payment_requests")
+            .description("List payment requests, newest first. Incoming requests are the ones the user was asked to pay, outgoing ones the user made")
+            .parameters(json!({
+                "type": "object",
+                "properties": {
+                    "direction": {"type": "string", "enum": ["incoming", "outgoing"]},
+                    "status": {"type": "string", "enum": ["pending", "accepted", "declined"]}
+                },
+            }))
+            .build()
+            .unwrap(),
+        FunctionArgs::default()
+            .name("accept_payment_request")
+            .description("Pay an incoming pending payment request by its id. Like a transfer, it only happens after the user confirms it outside of this chat, you cannot confirm it yourself")
+            .parameters(json!({
+                "type": "object",
+                "properties": {
+                    "id": {"type": "integer"}
+                },
+                "required": ["id"],
+            }))
+            .build()
+            .unwrap(),
+        FunctionArgs::default()
+            .name("decline_payment_request")
+            .description("Decline an incoming pending payment request by its id")
+            .parameters(json!({
+                "type": "object",
+                "properties": {
+                    "id": {"type": "integer"}
+                },
+                "required": ["id"],
+            }))
+            .build()
+            .unwrap(),
+        FunctionArgs::default()
+            .name("schedule_transfer")
+            .description("Schedule a transfer to another user on a future day, once or repeating. For example \"pay alice 20 every Friday\" starts on the next Friday and repeats weekly. Like a transfer, the schedule is only created after the user confirms it outside of this chat, you cannot confirm it yourself")
+            .parameters(json!({
+                "type": "object",
+                "properties": {
+                    "to": {"type": "string", "description": "Username or one of the user's contact aliases"},
+                    "amount": {"type": "integer"},
+                    "memo": {"type": "string", "description": "Optional note attached to every transfer"},
+                    "start_date": {"type": "string", "description": "Day of the first transfer, YYYY-MM-DD, today or later"},
+                    "repeat": {"type": "string", "enum": ["daily", "weekly", "monthly"], "description": "Omit for a one-off transfer"}
+                },
+                "required": ["to", "amount", "start_date"],
+            }))
+            .build()
+            .unwrap(),
+        FunctionArgs::default()
+            .name("list_scheduled_transfers")
+            .description("List the user's scheduled transfers with their status, next run and last error")
+            .parameters(json!({
+                "type": "object",
+                "properties": {},
+            }))
+            .build()
+            .unwrap(),
+        FunctionArgs::default()
+            .name("cancel_scheduled_transfer")
+            .description("Cancel an active scheduled transfer by its id")
+            .parameters(json!({
+                "type": "object",
+                "properties": {
+                    "id": {"type": "integer"}
                 },
+                "required": ["id"],
             }))
             .build()
             .unwrap(),
@@ -79,6 +267,175 @@ lazy_static! {
                 "properties": {},
             }))
             .build()
+            .unwrap(),
+        FunctionArgs::default()
+            .name("logout_everywhere")
+            .description("Log the user out of every session on every device, including this one")
+            .parameters(json!({
+                "type": "object",
+                "properties": {},
+            }))
+            .build()
+            .unwrap(),
+        FunctionArgs::default()
+            .name("add_contact")
+            .description("Save another user under an alias, like \"mom\", which can then be used instead of their username when sending money")
+            .parameters(json!({
+                "type": "object",
+                "properties": {
+                    "alias": {"type": "string"},
+                    "username": {"type": "string"},
+                    "note": {"type": "string", "description": "Optional note about the contact"}
+                },
+                "required": ["alias", "username"],
+            }))
+            .build()
+            .unwrap(),
+        FunctionArgs::default()
+            .name("remove_contact")
+            .description("Remove one of the user's contacts by its alias")
+            .parameters(json!({
+                "type": "object",
+                "properties": {
+                    "alias": {"type": "string"}
+                },
+                "required": ["alias"],
+            }))
+            .build()
+            .unwrap(),
+        FunctionArgs::default()
+            .name("list_contacts")
+            .description("List the user's contacts with their aliases, usernames and notes")
+            .parameters(json!({
+                "type": "object",
+                "properties": {},
+            }))
+            .build()
+            .unwrap(),
+        FunctionArgs::default()
+            .name("change_password")
+            .description("Change the user's password. User should provide the current password and the new one. Every other session is logged out")
+            .parameters(json!({
+                "type": "object",
+                "properties": {
+                    "old_password": {"type": "string"},
+                    "new_password": {"type": "string"}
+                },
+                "required": ["old_password", "new_password"],
+            }))
+            .build()
+            .unwrap(),
+        FunctionArgs::default()
+            .name("close_account")
+            .description("Close the user's account for good. Only call it when the user explicitly asks to close the account and has typed their password again. A remaining balance must be swept to another user, otherwise closing fails. Pending payment requests and scheduled transfers are cancelled")
+            .parameters(json!({
+                "type": "object",
+                "properties": {
+                    "password": {"type": "string"},
+                    "sweep_to": {"type": "string", "description": "User receiving the remaining balance"}
+                },
+                "required": ["password"],
+            }))
+            .build()
+            .unwrap()
+    ];
+    static ref FUCTIONS_ADMIN: Vec<Function> = vec![
+        FunctionArgs::default()
+            .name("admin_mint")
+            .description("Admin only. Create money in a user's account out of nothing. Only call it when the admin explicitly asks to mint")
+            .parameters(json!({
+                "type": "object",
+                "properties": {
+                    "username": {"type": "string"},
+                    "amount": {"type": "integer"}
+                },
+                "required": ["username", "amount"],
+            }))
+            .build()
+            .unwrap(),
+        FunctionArgs::default()
+            .name("admin_burn")
+            .description("Admin only. Destroy money from a user's available balance. Only call it when the admin explicitly asks to burn")
+            .parameters(json!({
+                "type": "object",
+                "properties": {
+                    "username": {"type": "string"},
+                    "amount": {"type": "integer"}
+                },
+                "required": ["username", "amount"],
+            }))
+            .build()
+            .unwrap(),
+        FunctionArgs::default()
+            .name("admin_freeze")
+            .description("Admin only. Freeze a user's account so it can neither send nor receive money")
+            .parameters(json!({
+                "type": "object",
+                "properties": {
+                    "username": {"type": "string"}
+                },
+                "required": ["username"],
+            }))
+            .build()
+            .unwrap(),
+        FunctionArgs::default()
+            .name("admin_unfreeze")
+            .description("Admin only. Unfreeze a frozen account")
+            .parameters(json!({
+                "type": "object",
+                "properties": {
+                    "username": {"type": "string"}
+                },
+                "required": ["username"],
+            }))
+            .build()
+            .unwrap(),
+        FunctionArgs::default()
+            .name("admin_view_user")
+            .description("Admin only. Show any user's balance, role and whether the account is frozen or closed")
+            .parameters(json!({
+                "type": "object",
+                "properties": {
+                    "username": {"type": "string"}
+                },
+                "required": ["username"],
+            }))
+            .build()
+            .unwrap(),
+        FunctionArgs::default()
+            .name("admin_list_transactions")
+            .description("Admin only. List any user's transfers, newest first")
+            .parameters(json!({
+                "type": "object",
+                "properties": {
+                    "username": {"type": "string"},
+                    "limit": {"type": "integer", "description": "Maximum number of transfers to return, between 1 and 100, default 20"},
+                    "offset": {"type": "integer", "description": "Number of transfers to skip, for paging"}
+                },
+                "required": ["username"],
+            }))
+            .build()
+            .unwrap(),
+        FunctionArgs::default()
+            .name("admin_list_postings")
+            .description("Admin only. List the double-entry postings of any account, including the issuance system account, oldest first")
+            .parameters(json!({
+                "type": "object",
+                "properties": {
+                    "account": {"type": "string"}
+                },
+                "required": ["account"],
+            }))
+            .build()
+            .unwrap(),
+        FunctionArgs::default()
+            .name("admin_account_balances")
+            .description("Admin only. Show what every account holds according to its postings, which always sum to zero")
+            .parameters(json!({
+                "type": "object",
+                "properties": {},
+            }))
+            .build()
             .unwrap()
     ];
 }
@@ -94,6 +451,18 @@ pub struct Bot {
     functions: Vec<Function>,
 
     usermaynull: UserMayNull,
+    session: Option<Session>,
+
+    /// Random per-conversation id and user message count, from which transfer
+    /// idempotency keys are derived.
+    conversation_id: String,
+    turn: u32,
+
+    pending: Option<PendingTransfer>,
+    /// The last statement prepared for download.
+    statement: Option<StatementLink>,
+    /// Failed logins on this connection, whatever usernames were tried.
+    login_attempts: LoginAttempts,
 }
 
 impl Bot {
@@ -105,6 +474,12 @@ impl Bot {
             messages: Vec::new(),
             functions: Vec::new(),
             usermaynull: None,
+            session: None,
+            conversation_id: generate_token(),
+            turn: 0,
+            pending: None,
+            statement: None,
+            login_attempts: LoginAttempts::default(),
         };
         bot.set_system().unwrap();
         bot.set_functions().unwrap();
@@ -113,11 +488,96 @@ impl Bot {
 
     pub async fn chat(&mut self, draft: &str) -> Result<()> {
         info!("Recieved message: {:?}", draft);
+        self.turn += 1;
         self.add_message(openai_types::Role::User, draft).unwrap();
+        // Confirmations are matched here, so the model can never confirm a
+        // transfer on the user's behalf.
+        if self.pending.is_some() {
+            match draft.trim().to_lowercase().as_str() {
+                "yes" | "y" | "confirm" => return self.confirm_transfer().await,
+                "no" | "n" | "cancel" => return self.cancel_transfer().await,
+                _ => {}
+            }
+        }
         self.chat_call_loop().await?;
         Ok(())
     }
 
+    /// The transfer waiting for confirmation, unless it has expired.
+    pub fn pending_transfer(&self) -> Option<&PendingTransfer> {
+        self.pending
+            .as_ref()
+            .filter(|pending| !pending.is_expired(chrono::Utc::now().naive_utc()))
+    }
+
+    /// The last statement prepared for download, unless its link has expired.
+    pub fn statement_download(&self) -> Option<&StatementLink> {
+        self.statement
+            .as_ref()
+            .filter(|link| find_download(&link.token, chrono::Utc::now().naive_utc()).is_some())
+    }
+
+    /// Executes the pending transfer. Only call this on an explicit action of the user.
+    pub async fn confirm_transfer(&mut self) -> Result<()> {
+        let username = self.current_username();
+        let arguments = json!({"pending": self.pending.as_ref().map(ToString::to_string)});
+        let result = self.execute_pending();
+        self.audit(username, "confirm_transfer", &arguments.to_string(), &result);
+        let result = result.unwrap_or_else(|e| format!("Error: {}", e));
+        info!("Transfer confirmation: {}", result);
+        self.add_message(
+            openai_types::Role::System,
+            &format!("The user confirmed the pending transfer. Result: {result}"),
+        )?;
+        self.tx.send(result).await?;
+        Ok(())
+    }
+
+    pub async fn cancel_transfer(&mut self) -> Result<()> {
+        let result = match self.pending.take() {
+            Some(pending) => format!("{pending} cancelled"),
+            None => "No transfer is awaiting confirmation".to_string(),
+        };
+        self.add_message(
+            openai_types::Role::System,
+            &format!("The user cancelled the pending transfer. Result: {result}"),
+        )?;
+        self.tx.send(result).await?;
+        Ok(())
+    }
+
+    /// Checks a payout file the user uploaded and stages it for confirmation.
+    /// The file skips the model, which could cut or alter it on the way.
+    pub async fn upload_payout(&mut self, csv: &str) -> Result<()> {
+        self.turn += 1;
+        let username = self.current_username();
+        let arguments = json!({"bytes": csv.len()});
+        let result = self.stage_payout(csv).map(|(batch, replaced)| {
+            if !batch.is_valid() {
+                return format!("The payout file can't be paid:\n{batch}");
+            }
+            let minutes = global::PENDING_TRANSFER_TTL_SECS / 60;
+            let mut res = format!(
+                "{batch}\nConfirm within {minutes} minutes to pay it. Nothing has been paid yet."
+            );
+            if let Some(replaced) = replaced {
+                res.push_str(&format!(
+                    " It replaces the previous pending request: {replaced}."
+                ));
+            }
+            res
+        });
+        self.audit(username, "upload_payout", &arguments.to_string(), &result);
+        let result = result.unwrap_or_else(|e| format!("Error: {}", e));
+        info!("Payout upload: {}", result);
+        self.add_message(
+            openai_types::Role::System,
+            &format!("The user uploaded a payout file. Result: {result}"),
+        )?;
+        self.tx.send(result).await?;
+        Ok(())
+    }
+
     fn add_message(&mut self, role: openai_types::Role, content: &str) -> Result<()> {
         self.messages
             .push(MessageArgs::default().role(role).content(content).build()?);
@@ -137,15 +597,21 @@ impl Bot {
 
     fn set_system(&mut self) -> Result<()> {
         let mut system = SYSTEM_INIT.to_owned();
+        system.push_str(&format!(
+            "Today is {}.\n",
+            chrono::Utc::now().date_naive().format("%A, %Y-%m-%d")
+        ));
         match &self.usermaynull {
             Some(user) => {
                 system.push_str(&formatdoc!(
                     "User info:
                         username: {}
                         balance: {}
+                        available: {}
                     .",
                     user.username,
-                    user.balance
+                    user.balance,
+                    user.available()
                 ));
             }
             None => {
@@ -165,8 +631,11 @@ impl Bot {
     fn set_functions(&mut self) -> Result<()> {
         self.functions.clear();
         match &self.usermaynull {
-            Some(_) => {
+            Some(user) => {
                 self.functions.extend(FUCTIONS_LOGIN.to_owned());
+                if user.is_admin() {
+                    self.functions.extend(FUCTIONS_ADMIN.to_owned());
+                }
             }
             None => {
                 self.functions.extend(FUCTIONS_UNLOGIN.to_owned());
@@ -178,7 +647,7 @@ impl Bot {
     fn build_model(&self) -> Result<Model> {
         let model = MODEL_INIT
             .to_owned()
-            .messages(vec![self.system.to_owned(), self.messages.to_owned()].concat())
+            .messages([self.system.to_owned(), self.messages.to_owned()].concat())
             .functions(self.functions.to_owned())
             .function_call("auto")
             .build()?;
@@ -193,7 +662,7 @@ impl Bot {
             .create(model)
             .await?
             .choices
-            .get(0)
+            .first()
             .unwrap()
             .message
             .to_owned();
@@ -211,9 +680,15 @@ impl Bot {
             }
             if let Some(function_call) = response.function_call {
                 info!("Function call: {:?}", function_call);
-                let system_response = self
-                    .perform(&function_call)
-                    .unwrap_or_else(|e| format!("Error: {}", e));
+                let username = self.current_username();
+                let result = self.perform(&function_call);
+                let arguments = function_call
+                    .arguments
+                    .parse::<Value>()
+                    .map(|arguments| audit::redact(&arguments).to_string())
+                    .unwrap_or_else(|_| "[unparsable arguments]".to_string());
+                self.audit(username, &function_call.name, &arguments, &result);
+                let system_response = result.unwrap_or_else(|e| format!("Error: {}", e));
                 self.add_function_msg(&function_call.name, &system_response)?;
                 info!("System response: {}", system_response);
             } else {
@@ -222,6 +697,34 @@ impl Bot {
         }
     }
 
+    fn current_username(&self) -> Option<String> {
+        self.usermaynull.as_ref().map(|user| user.username.clone())
+    }
+
+    /// Records a call and its outcome for later review. `username` is who was
+    /// logged in before the call, or else who is now. Failing to write the
+    /// record is logged but doesn't fail the call.
+    fn audit(&self, username: Option<String>, function_name: &str, arguments: &str, result: &Result<String>) {
+        let username = username.or_else(|| self.current_username());
+        let (outcome, result) = match result {
+            Result::Ok(response) => (AUDIT_OK, response.clone()),
+            Err(e) => (AUDIT_ERROR, e.to_string()),
+        };
+        let record = NewAuditRecord {
+            session_id: &self.conversation_id,
+            username: username.as_deref(),
+            function_name,
+            arguments,
+            outcome,
+            result: &result,
+            model: MODEL,
+            created_at: chrono::Utc::now().naive_utc(),
+        };
+        if let Err(e) = AuditRecord::insert_into_db(&record) {
+            warn!("Failed to write the audit record of {}: {}", function_name, e);
+        }
+    }
+
     fn perform(&mut self, function_call: &FunctionCall) -> Result<String> {
         let args: serde_json::Value = function_call.arguments.parse()?;
         match &function_call.name[..] {
@@ -230,7 +733,7 @@ impl Bot {
                 let password = args.get_or("password", "Missing password")?;
                 self.signup(username, password)?;
                 let balance = self.usermaynull.as_ref().unwrap().balance;
-                Ok(format!("Signup successfully. User now logged in as {username}. balance: {balance}").into())
+                Ok(format!("Signup successfully. User now logged in as {username}. balance: {balance}"))
             }
 
             "login" => {
@@ -238,7 +741,22 @@ impl Bot {
                 let password = args.get_or("password", "Missing password")?;
                 self.login(username, password)?;
                 let balance = self.usermaynull.as_ref().unwrap().balance;
-                Ok(format!("Login as {username} successfully. balance: {balance}").into())
+                Ok(format!("Login as {username} successfully. balance: {balance}"))
+            }
+
+            "reset_password" => {
+                let token = args.get_or("token", "Missing token")?;
+                let new_password = args.get_or("new_password", "Missing new_password")?;
+                let user = User::reset_password(token, new_password)
+                    .or_else(|e| bail!("Password reset failed: {}", e))?;
+                Ok(format!("Password of {} reset successfully, the user can now login with it", user.username))
+            }
+
+            "change_password" => {
+                let old_password = args.get_or("old_password", "Missing old_password")?;
+                let new_password = args.get_or("new_password", "Missing new_password")?;
+                self.change_password(old_password, new_password)?;
+                Ok("Password changed successfully. Every other session has been logged out".to_string())
             }
 
             "logout" => {
@@ -246,12 +764,337 @@ impl Bot {
                 Ok("Logout successfully".to_string())
             }
 
+            "close_account" => {
+                let password = args.get_or("password", "Missing password")?;
+                let sweep_to = args.get("sweep_to").and_then(Value::as_str);
+                let sweep = self.close_account(password, sweep_to)?;
+                let mut res = "Account closed, the user has been logged out".to_string();
+                if let Some(sweep) = sweep {
+                    res.push_str(&format!(". The remaining balance was transferred: {sweep}"));
+                }
+                Ok(res)
+            }
+
+            "logout_everywhere" => {
+                let count = self.logout_everywhere()?;
+                Ok(format!("Logged out of {count} session(s) successfully"))
+            }
+
             "transfer" => {
-                let to = args.get_or("to", "Missing to")?;
+                let to = &self.resolve_recipient(args.get_or("to", "Missing to")?)?;
                 let amount = args.get_or("amount", "Missing amount")?;
-                self.transfer(to, amount)?;
-                let balance = self.usermaynull.as_ref().unwrap().balance;
-                Ok(format!("Transfer of to {to} successfully, amout: {amount}. balance now: {balance}").to_string())
+                let memo = args.get("memo").and_then(Value::as_str);
+                let replaced = self.request_transfer(to, amount, memo)?;
+                let minutes = global::PENDING_TRANSFER_TTL_SECS / 60;
+                let mut res = format!("Transfer of {amount} to {to} is awaiting confirmation. Ask the user to reply \"yes\" or press the Confirm button within {minutes} minutes, or \"no\" to cancel. It has NOT been executed yet.");
+                if let Some(replaced) = replaced {
+                    res.push_str(&format!(" It replaces the previous pending request: {replaced}."));
+                }
+                Ok(res)
+            }
+
+            "request_payment" => {
+                let from = args.get_or("from", "Missing from")?;
+                let amount = args.get_or("amount", "Missing amount")?;
+                let memo = args.get("memo").and_then(Value::as_str);
+                let request = self.logged_in_user()?.request_payment(from, amount, memo)?;
+                Ok(format!("Payment request created: {request}"))
+            }
+
+            "list_payment_requests" => {
+                let direction = match args.get("direction").and_then(Value::as_str) {
+                    Some("incoming") => Some(Direction::Incoming),
+                    Some("outgoing") => Some(Direction::Outgoing),
+                    Some(other) => bail!("Unknown direction: {}", other),
+                    None => None,
+                };
+                let status = args.get("status").and_then(Value::as_str);
+                let requests = self.list_payment_requests(direction, status)?;
+                if requests.is_empty() {
+                    return Ok("No payment requests found".to_string());
+                }
+                let lines = requests
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                Ok(format!("Payment requests:\n{lines}"))
+            }
+
+            "accept_payment_request" => {
+                let id = args.get_or("id", "Missing id")?;
+                let replaced = self.request_acceptance(id)?;
+                let minutes = global::PENDING_TRANSFER_TTL_SECS / 60;
+                let mut res = format!("Paying payment request #{id} is awaiting confirmation. Ask the user to reply \"yes\" or press the Confirm button within {minutes} minutes, or \"no\" to cancel. It has NOT been paid yet.");
+                if let Some(replaced) = replaced {
+                    res.push_str(&format!(" It replaces the previous pending request: {replaced}."));
+                }
+                Ok(res)
+            }
+
+            "refund_transfer" => {
+                let id = args.get_or("id", "Missing id")?;
+                let amount = args.get("amount").and_then(Value::as_i64).map(i32::try_from).transpose()?;
+                let memo = args.get("memo").and_then(Value::as_str);
+                let replaced = self.request_refund(id, amount, memo)?;
+                let minutes = global::PENDING_TRANSFER_TTL_SECS / 60;
+                let mut res = format!("Refunding transfer #{id} is awaiting confirmation. Ask the user to reply \"yes\" or press the Confirm button within {minutes} minutes, or \"no\" to cancel. It has NOT been refunded yet.");
+                if let Some(replaced) = replaced {
+                    res.push_str(&format!(" It replaces the previous pending request: {replaced}."));
+                }
+                Ok(res)
+            }
+
+            "bulk_payout" => {
+                let csv = args.get_or("csv", "Missing csv")?;
+                let (batch, replaced) = self.stage_payout(csv)?;
+                if !batch.is_valid() {
+                    return Ok(format!("The payout file can't be paid:\n{batch}"));
+                }
+                let minutes = global::PENDING_TRANSFER_TTL_SECS / 60;
+                let mut res = format!("{batch}\nIt is awaiting confirmation. Ask the user to reply \"yes\" or press the Confirm button within {minutes} minutes, or \"no\" to cancel. Nothing has been paid yet.");
+                if let Some(replaced) = replaced {
+                    res.push_str(&format!(
+                        " It replaces the previous pending request: {replaced}."
+                    ));
+                }
+                Ok(res)
+            }
+
+            "decline_payment_request" => {
+                let id = args.get_or("id", "Missing id")?;
+                let request = self.logged_in_user()?.decline_payment_request(id)?;
+                Ok(format!("Payment request declined: {request}"))
+            }
+
+            "schedule_transfer" => {
+                let to = &self.resolve_recipient(args.get_or("to", "Missing to")?)?;
+                let amount = args.get_or("amount", "Missing amount")?;
+                let memo = args.get("memo").and_then(Value::as_str);
+                let first_run_at = args
+                    .get_date("start_date")?
+                    .ok_or_else(|| anyhow!("Missing start_date"))?;
+                let recurrence = args
+                    .get("repeat")
+                    .and_then(Value::as_str)
+                    .map(str::parse::<Recurrence>)
+                    .transpose()?;
+                let replaced = self.request_schedule(to, amount, memo, first_run_at, recurrence)?;
+                let minutes = global::PENDING_TRANSFER_TTL_SECS / 60;
+                let mut res = format!("Scheduling the transfer of {amount} to {to} is awaiting confirmation. Ask the user to reply \"yes\" or press the Confirm button within {minutes} minutes, or \"no\" to cancel. It has NOT been scheduled yet.");
+                if let Some(replaced) = replaced {
+                    res.push_str(&format!(" It replaces the previous pending request: {replaced}."));
+                }
+                Ok(res)
+            }
+
+            "add_contact" => {
+                let alias = args.get_or("alias", "Missing alias")?;
+                let username = args.get_or("username", "Missing username")?;
+                let note = args.get("note").and_then(Value::as_str);
+                let contact = self.logged_in_user()?.add_contact(alias, username, note)?;
+                Ok(format!("Contact saved: {contact}"))
+            }
+
+            "remove_contact" => {
+                let alias = args.get_or("alias", "Missing alias")?;
+                self.logged_in_user()?.remove_contact(alias)?;
+                Ok(format!("Contact {alias} removed"))
+            }
+
+            "list_contacts" => {
+                let contacts = self.logged_in_user()?.contacts()?;
+                if contacts.is_empty() {
+                    return Ok("No contacts found".to_string());
+                }
+                let lines = contacts
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                Ok(format!("Contacts:\n{lines}"))
+            }
+
+            "list_scheduled_transfers" => {
+                let schedules = self.list_scheduled_transfers()?;
+                if schedules.is_empty() {
+                    return Ok("No scheduled transfers found".to_string());
+                }
+                let lines = schedules
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                Ok(format!("Scheduled transfers:\n{lines}"))
+            }
+
+            "cancel_scheduled_transfer" => {
+                let id = args.get_or("id", "Missing id")?;
+                let schedule = self.logged_in_user()?.cancel_scheduled_transfer(id)?;
+                Ok(format!("Scheduled transfer cancelled: {schedule}"))
+            }
+
+            "get_balance" => {
+                let (balance, held) = self.get_balance()?;
+                if held > 0 {
+                    Ok(format!("Current balance: {balance}, of which {held} is on hold ({} available)", balance - held))
+                } else {
+                    Ok(format!("Current balance: {balance}"))
+                }
+            }
+
+            "admin_mint" => {
+                let username = args.get_or("username", "Missing username")?;
+                let amount = args.get_or("amount", "Missing amount")?;
+                let user = self.logged_in_user()?.mint(username, amount)?;
+                Ok(format!("Minted {amount} to {username}. New balance: {}", user.balance))
+            }
+
+            "admin_burn" => {
+                let username = args.get_or("username", "Missing username")?;
+                let amount = args.get_or("amount", "Missing amount")?;
+                let user = self.logged_in_user()?.burn(username, amount)?;
+                Ok(format!("Burned {amount} from {username}. New balance: {}", user.balance))
+            }
+
+            "admin_freeze" => {
+                let username = args.get_or("username", "Missing username")?;
+                self.logged_in_user()?.freeze(username)?;
+                Ok(format!("Account {username} frozen"))
+            }
+
+            "admin_unfreeze" => {
+                let username = args.get_or("username", "Missing username")?;
+                self.logged_in_user()?.unfreeze(username)?;
+                Ok(format!("Account {username} unfrozen"))
+            }
+
+            "admin_view_user" => {
+                let username = args.get_or("username", "Missing username")?;
+                let user = self.logged_in_user()?.inspect_user(username)?;
+                let mut res = format!("{}: balance {}, held {}, role {}", user.username, user.balance, user.held, user.role);
+                if let Some(frozen_at) = user.frozen_at {
+                    res.push_str(&format!(", frozen since {}", frozen_at.format("%Y-%m-%d %H:%M:%S")));
+                }
+                if let Some(closed_at) = user.closed_at {
+                    res.push_str(&format!(", closed since {}", closed_at.format("%Y-%m-%d %H:%M:%S")));
+                }
+                Ok(res)
+            }
+
+            "admin_list_transactions" => {
+                let username = args.get_or("username", "Missing username")?;
+                let filter = TransactionFilter {
+                    limit: Some(args.get_limit()?),
+                    offset: Some(args.get_offset()?),
+                    ..Default::default()
+                };
+                let records = self.logged_in_user()?.inspect_transactions(username, &filter)?;
+                if records.is_empty() {
+                    return Ok(format!("No transfers found for {username}"));
+                }
+                let lines = records
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                Ok(format!("Transfers of {username}:\n{lines}"))
+            }
+
+            "admin_list_postings" => {
+                let account = args.get_or("account", "Missing account")?;
+                let postings = self.logged_in_user()?.inspect_postings(account)?;
+                if postings.is_empty() {
+                    return Ok(format!("No postings found for {account}"));
+                }
+                let lines = postings
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                Ok(format!("Postings of {account}:\n{lines}"))
+            }
+
+            "admin_account_balances" => {
+                let balances = self.logged_in_user()?.inspect_account_balances()?;
+                let total = balances.iter().map(|(_, balance)| balance).sum::<i64>();
+                let lines = balances
+                    .iter()
+                    .map(|(account, balance)| format!("{account}: {balance}"))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                Ok(format!("{lines}\nTotal: {total}"))
+            }
+
+            "list_transactions" => {
+                let direction = match args.get("direction").and_then(Value::as_str) {
+                    Some("incoming") => Some(Direction::Incoming),
+                    Some("outgoing") => Some(Direction::Outgoing),
+                    Some(other) => bail!("Unknown direction: {}", other),
+                    None => None,
+                };
+                let filter = TransactionFilter {
+                    direction,
+                    counterparty: args
+                        .get("counterparty")
+                        .and_then(Value::as_str)
+                        .map(str::to_string),
+                    since: args.get_date("since")?,
+                    until: args.get_date("until")?.map(|day| day + Duration::days(1)),
+                    limit: Some(args.get_limit()?),
+                    offset: Some(args.get_offset()?),
+                    ..Default::default()
+                };
+                let records = self.list_transactions(&filter)?;
+                if records.is_empty() {
+                    return Ok("No transfers found".to_string());
+                }
+                let lines = records
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                Ok(format!("Transfers:\n{lines}"))
+            }
+
+            "summarize_spending" => {
+                let since = args.get_date("since")?;
+                let until = args.get_date("until")?.map(|day| day + Duration::days(1));
+                let summary = self.summarize_spending(since, until)?;
+                let mut res = format!(
+                    "Total sent: {}. Total received: {}.",
+                    summary.total_out, summary.total_in
+                );
+                for (receiver, total) in summary.by_counterparty {
+                    res.push_str(&format!("\nSent to {receiver}: {total}"));
+                }
+                Ok(res)
+            }
+
+            "export_statement" => {
+                let format = StatementFormat::parse(args.get_or("format", "Missing format")?)?;
+                let since = args.get_date("since")?;
+                let until = args.get_date("until")?.map(|day| day + Duration::days(1));
+                let statement = self.logged_in_user()?.statement(since, until)?;
+                let filename = statement.filename(format);
+                let token = offer_download(
+                    filename.clone(),
+                    format.content_type(),
+                    statement.render(format),
+                    chrono::Utc::now().naive_utc(),
+                );
+                self.statement = Some(StatementLink {
+                    filename: filename.clone(),
+                    token,
+                });
+                Ok(format!(
+                    "Statement {} is ready with {} entries, opening balance {}, closing balance {}. A download link is shown to the user below the chat for {} minutes",
+                    filename,
+                    statement.entries.len(),
+                    statement.opening_balance,
+                    statement.closing_balance,
+                    global::STATEMENT_DOWNLOAD_TTL_SECS / 60
+                ))
             }
 
             _ => bail!("Unknown function call: {}", function_call.name),
@@ -260,14 +1103,27 @@ impl Bot {
 
     fn signup(&mut self, username: &str, password: &str) -> Result<()> {
         let user = User::signup(username, password).or_else(|e| bail!("Signup failed: {}", e))?;
-        self.usermaynull = Some(user);
-        self.set_system().unwrap();
-        self.set_functions().unwrap();
-        Ok(())
+        self.start_session(user)
     }
 
     fn login(&mut self, username: &str, password: &str) -> Result<()> {
-        let user = User::login(username, password).or_else(|e| bail!("Login failed: {}", e))?;
+        let now = chrono::Utc::now().naive_utc();
+        self.login_attempts.check(now)?;
+        let user = User::login(username, password).map_err(|e| {
+            // Relayed as is: the attempt wasn't even tried.
+            if e.is::<LoginLocked>() {
+                return e;
+            }
+            let connection = format!("connection {}", self.conversation_id);
+            self.login_attempts.record_failure(&connection, now);
+            anyhow!("Login failed: {}", e)
+        })?;
+        self.login_attempts = LoginAttempts::default();
+        self.start_session(user)
+    }
+
+    fn start_session(&mut self, user: User) -> Result<()> {
+        self.session = Some(user.start_session()?);
         self.usermaynull = Some(user);
         self.set_system().unwrap();
         self.set_functions().unwrap();
@@ -275,22 +1131,299 @@ impl Bot {
     }
 
     fn logout(&mut self) -> Result<()> {
+        if let (Some(user), Some(session)) = (&self.usermaynull, &self.session) {
+            user.logout(session)?;
+        }
+        self.clear_session();
+        Ok(())
+    }
+
+    fn logout_everywhere(&mut self) -> Result<usize> {
+        let count = self.logged_in_user()?.logout_everywhere()?;
+        self.clear_session();
+        Ok(count)
+    }
+
+    /// Changes the password, then starts a fresh session in place of the
+    /// current one, which was revoked with all the others.
+    fn change_password(&mut self, old_password: &str, new_password: &str) -> Result<()> {
+        let mut user = self.logged_in_user()?.clone();
+        user.change_password(old_password, new_password)
+            .or_else(|e| bail!("Changing the password failed: {}", e))?;
+        self.start_session(user)
+    }
+
+    fn close_account(&mut self, password: &str, sweep_to: Option<&str>) -> Result<Option<Transaction>> {
+        let sweep = self
+            .logged_in_user()?
+            .close_account(password, sweep_to)
+            .or_else(|e| bail!("Closing the account failed: {}", e))?;
+        self.clear_session();
+        Ok(sweep)
+    }
+
+    fn clear_session(&mut self) {
         self.usermaynull = None;
+        self.session = None;
+        self.pending = None;
+        self.statement = None;
         self.set_system().unwrap();
         self.set_functions().unwrap();
-        Ok(())
     }
 
-    fn transfer(&mut self, to: &str, amount: i32) -> Result<()> {
-        let user = self
-            .usermaynull
-            .as_mut()
-            .ok_or_else(|| anyhow!("User not logged in"))?;
-        ensure!(user.balance >= amount, "Insufficient balance");
-        ensure!(user.balance > 0, "Balance must be positive");
-        user.transfer(to, amount)?;
+    /// Resolves a contact alias of the logged-in user to a username.
+    fn resolve_recipient(&mut self, to: &str) -> Result<String> {
+        self.logged_in_user()?.resolve_recipient(to)
+    }
+
+    /// Keeps `pending` until the user confirms or cancels it. Only one request
+    /// waits at a time: a new one replaces the last, which is returned.
+    fn stage(&mut self, pending: PendingTransfer) -> Option<PendingTransfer> {
+        self.pending.replace(pending)
+    }
+
+    /// Stores the transfer until the user confirms it, returning the pending
+    /// transfer it replaces, if any.
+    fn request_transfer(
+        &mut self,
+        to: &str,
+        amount: i32,
+        memo: Option<&str>,
+    ) -> Result<Option<PendingTransfer>> {
+        let user = self.logged_in_user()?;
+        User::retrieve_from_db(to)?.check_active()?;
+        // Rejected early so the user isn't asked to confirm a doomed transfer;
+        // the policy is enforced again when the transfer executes.
+        user.check_transfer_policy(to, amount)?;
+        // The model may repeat a call, or a request may be retried, within one
+        // turn; the idempotency key makes sure the money only moves once.
+        let idempotency_key = format!("{}:{}:{}:{}", self.conversation_id, self.turn, to, amount);
+        let pending = PendingTransfer {
+            kind: PendingKind::Transfer,
+            to: to.to_string(),
+            amount,
+            memo: memo.map(str::to_string),
+            idempotency_key,
+            expires_at: chrono::Utc::now().naive_utc()
+                + Duration::seconds(global::PENDING_TRANSFER_TTL_SECS),
+        };
+        Ok(self.stage(pending))
+    }
+
+    /// Like `request_transfer`, for paying the payment request `id`.
+    fn request_acceptance(&mut self, id: i32) -> Result<Option<PendingTransfer>> {
+        let user = self.logged_in_user()?;
+        let request = user.payable_request(id)?;
+        user.check_transfer_policy(&request.requester, request.amount)?;
+        let pending = PendingTransfer {
+            kind: PendingKind::PaymentRequest(request.id),
+            // The key `User::accept_payment_request` pays with.
+            idempotency_key: format!("payment-request:{}", request.id),
+            to: request.requester,
+            amount: request.amount,
+            memo: request.memo,
+            expires_at: chrono::Utc::now().naive_utc()
+                + Duration::seconds(global::PENDING_TRANSFER_TTL_SECS),
+        };
+        Ok(self.stage(pending))
+    }
+
+    /// Like `request_transfer`, for refunding `amount` of the transfer `id`,
+    /// or all that is left of it.
+    fn request_refund(
+        &mut self,
+        id: i32,
+        amount: Option<i32>,
+        memo: Option<&str>,
+    ) -> Result<Option<PendingTransfer>> {
+        let user = self.logged_in_user()?;
+        let (original, remaining) = user.refundable_transfer(id)?;
+        let amount = amount.unwrap_or(remaining);
+        ensure!(
+            amount <= remaining,
+            "Only {} of transfer #{} can still be refunded",
+            remaining,
+            id
+        );
+        // Refunds are exempt from the transfer policy.
+        let idempotency_key = format!("{}:{}:refund:{}:{}", self.conversation_id, self.turn, id, amount);
+        let pending = PendingTransfer {
+            kind: PendingKind::Refund(id),
+            to: original.sender,
+            amount,
+            memo: memo.map(str::to_string),
+            idempotency_key,
+            expires_at: chrono::Utc::now().naive_utc()
+                + Duration::seconds(global::PENDING_TRANSFER_TTL_SECS),
+        };
+        Ok(self.stage(pending))
+    }
+
+    /// Checks every row of the payout file `csv`, and stages it for
+    /// confirmation if they all pass. Returns the report and the pending
+    /// transfer the payout replaced, if any.
+    fn stage_payout(&mut self, csv: &str) -> Result<(PayoutBatch, Option<PendingTransfer>)> {
+        let batch = self.logged_in_user()?.validate_payout(csv)?;
+        let replaced = if batch.is_valid() {
+            self.request_payout(csv, &batch)?
+        } else {
+            None
+        };
+        Ok((batch, replaced))
+    }
+
+    /// Like `request_transfer`, for paying every row of the validated payout
+    /// file `csv`.
+    fn request_payout(
+        &mut self,
+        csv: &str,
+        batch: &PayoutBatch,
+    ) -> Result<Option<PendingTransfer>> {
+        let pending = PendingTransfer {
+            kind: PendingKind::Payout {
+                csv: csv.to_string(),
+            },
+            to: format!("{} recipients", batch.rows.len()),
+            amount: i32::try_from(batch.total())?,
+            memo: None,
+            idempotency_key: format!("{}:{}:payout", self.conversation_id, self.turn),
+            expires_at: chrono::Utc::now().naive_utc()
+                + Duration::seconds(global::PENDING_TRANSFER_TTL_SECS),
+        };
+        Ok(self.stage(pending))
+    }
+
+    /// Like `request_transfer`, for creating a scheduled transfer.
+    fn request_schedule(
+        &mut self,
+        to: &str,
+        amount: i32,
+        memo: Option<&str>,
+        first_run_at: NaiveDateTime,
+        recurrence: Option<Recurrence>,
+    ) -> Result<Option<PendingTransfer>> {
+        let user = self.logged_in_user()?;
+        ensure!(
+            to != user.username,
+            "Can't schedule a transfer to yourself"
+        );
+        ensure!(
+            first_run_at.date() >= chrono::Utc::now().date_naive(),
+            "The first transfer can't be in the past"
+        );
+        User::retrieve_from_db(to)?.check_active()?;
+        let idempotency_key = format!("{}:{}:{}:{}", self.conversation_id, self.turn, to, amount);
+        let pending = PendingTransfer {
+            kind: PendingKind::Schedule {
+                first_run_at,
+                recurrence,
+            },
+            to: to.to_string(),
+            amount,
+            memo: memo.map(str::to_string),
+            idempotency_key,
+            expires_at: chrono::Utc::now().naive_utc()
+                + Duration::seconds(global::PENDING_TRANSFER_TTL_SECS),
+        };
+        Ok(self.stage(pending))
+    }
+
+    fn execute_pending(&mut self) -> Result<String> {
+        let pending = self
+            .pending
+            .take()
+            .ok_or_else(|| anyhow!("No transfer is awaiting confirmation"))?;
+        ensure!(
+            !pending.is_expired(chrono::Utc::now().naive_utc()),
+            "The transfer request has expired, please request it again"
+        );
+        let user = self.logged_in_user()?;
+        match pending.kind {
+            PendingKind::Transfer => user.transfer(
+                &pending.to,
+                pending.amount,
+                pending.memo.as_deref(),
+                Some(&pending.idempotency_key),
+            )?,
+            PendingKind::PaymentRequest(id) => user.accept_payment_request(id)?,
+            PendingKind::Refund(id) => user.refund_transfer(
+                id,
+                Some(pending.amount),
+                pending.memo.as_deref(),
+                Some(&pending.idempotency_key),
+            )?,
+            PendingKind::Schedule {
+                first_run_at,
+                recurrence,
+            } => {
+                let schedule = user.schedule_transfer(
+                    &pending.to,
+                    pending.amount,
+                    pending.memo.as_deref(),
+                    first_run_at,
+                    recurrence,
+                )?;
+                return Ok(format!("Transfer scheduled successfully: {schedule}"));
+            }
+            PendingKind::Payout { csv } => {
+                let batch = user.bulk_payout(&csv, &pending.idempotency_key)?;
+                self.set_system().unwrap();
+                return Ok(batch.to_string());
+            }
+        };
+        let balance = user.balance;
         self.set_system().unwrap();
-        Ok(())
+        Ok(format!(
+            "Transfer to {} successfully, amount: {}. balance now: {}",
+            pending.to, pending.amount, balance
+        ))
+    }
+
+    /// The current user, once their session has been checked against the database.
+    /// An invalid session logs the bot out.
+    fn logged_in_user(&mut self) -> Result<&mut User> {
+        let (user, session) = match (&self.usermaynull, self.session.as_mut()) {
+            (Some(user), Some(session)) => (user, session),
+            _ => bail!("User not logged in"),
+        };
+        if let Err(e) = user.login_still_valid(session) {
+            self.clear_session();
+            return Err(e);
+        }
+        Ok(self.usermaynull.as_mut().unwrap())
+    }
+
+    /// The balance and the part of it reserved by holds.
+    fn get_balance(&mut self) -> Result<(i32, i32)> {
+        let user = self.logged_in_user()?;
+        user.refresh_from_db()?;
+        let balance = (user.balance, user.held);
+        self.set_system().unwrap();
+        Ok(balance)
+    }
+
+    fn list_transactions(&mut self, filter: &TransactionFilter) -> Result<Vec<Transaction>> {
+        self.logged_in_user()?.list_transactions(filter)
+    }
+
+    fn list_payment_requests(
+        &mut self,
+        direction: Option<Direction>,
+        status: Option<&str>,
+    ) -> Result<Vec<PaymentRequest>> {
+        self.logged_in_user()?.payment_requests(direction, status)
+    }
+
+    fn list_scheduled_transfers(&mut self) -> Result<Vec<ScheduledTransfer>> {
+        self.logged_in_user()?.scheduled_transfers()
+    }
+
+    fn summarize_spending(
+        &mut self,
+        since: Option<NaiveDateTime>,
+        until: Option<NaiveDateTime>,
+    ) -> Result<SpendingSummary> {
+        self.logged_in_user()?.summarize_spending(since, until)
     }
 }
 
@@ -320,3 +1453,139 @@ impl GetOr<'_, i32> for Value {
         Ok(res.try_into()?)
     }
 }
+
+trait GetDate {
+    fn get_date(&self, arg: &str) -> Result<Option<NaiveDateTime>>;
+}
+
+impl GetDate for Value {
+    /// Parse an optional `YYYY-MM-DD` argument as the start of that day.
+    fn get_date(&self, arg: &str) -> Result<Option<NaiveDateTime>> {
+        match self.get(arg).and_then(Value::as_str) {
+            Some(date) => {
+                let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                    .map_err(|_| anyhow!("Invalid {}: expected YYYY-MM-DD", arg))?;
+                Ok(Some(date.and_hms_opt(0, 0, 0).unwrap()))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+trait GetPage {
+    fn get_limit(&self) -> Result<i64>;
+    fn get_offset(&self) -> Result<i64>;
+}
+
+impl GetPage for Value {
+    /// The `limit` argument, clamped to 1..=MAX_PAGE_SIZE so that the model
+    /// can neither ask for everything nor for a negative, unlimited page.
+    fn get_limit(&self) -> Result<i64> {
+        match self.get("limit") {
+            Some(limit) => {
+                let limit = limit.as_i64().ok_or_else(|| anyhow!("Invalid limit"))?;
+                Ok(limit.clamp(1, MAX_PAGE_SIZE))
+            }
+            None => Ok(DEFAULT_PAGE_SIZE),
+        }
+    }
+
+    fn get_offset(&self) -> Result<i64> {
+        match self.get("offset") {
+            Some(offset) => {
+                let offset = offset.as_i64().ok_or_else(|| anyhow!("Invalid offset"))?;
+                ensure!(offset >= 0, "The offset can't be negative");
+                Ok(offset)
+            }
+            None => Ok(0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+
+    fn pending(to: &str, amount: i32, expires_at: NaiveDateTime) -> PendingTransfer {
+        PendingTransfer {
+            kind: PendingKind::Transfer,
+            to: to.to_string(),
+            amount,
+            memo: None,
+            idempotency_key: format!("test:{to}:{amount}"),
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn test_pending_expiry() {
+        let now = chrono::Utc::now().naive_utc();
+        let transfer = pending("bob", 10, now);
+        assert!(!transfer.is_expired(now - Duration::seconds(1)));
+        assert!(transfer.is_expired(now));
+    }
+
+    #[test]
+    fn test_pending_replaced() {
+        let (tx, _rx) = mpsc::channel(1);
+        let mut bot = Bot::new(tx);
+        let now = chrono::Utc::now().naive_utc();
+        assert_eq!(
+            bot.stage(pending("bob", 10, now + Duration::minutes(5))),
+            None
+        );
+        assert_eq!(bot.pending_transfer().unwrap().to, "bob");
+
+        let replaced = bot.stage(pending("carol", 20, now + Duration::minutes(5)));
+        assert_eq!(replaced.unwrap().to, "bob");
+        let current = bot.pending_transfer().unwrap();
+        assert_eq!((current.to.as_str(), current.amount), ("carol", 20));
+    }
+
+    #[test]
+    fn test_confirm_after_ttl() {
+        let (tx, _rx) = mpsc::channel(1);
+        let mut bot = Bot::new(tx);
+        let expires_at = chrono::Utc::now().naive_utc() - Duration::seconds(1);
+        bot.stage(pending("bob", 10, expires_at));
+        // Hidden from the confirmation UI, and refused if confirmed anyway.
+        assert_eq!(bot.pending_transfer(), None);
+        let e = bot.execute_pending().unwrap_err();
+        assert!(e.to_string().contains("expired"));
+        // The expired request is dropped rather than kept for a retry.
+        let e = bot.execute_pending().unwrap_err();
+        assert_eq!(e.to_string(), "No transfer is awaiting confirmation");
+    }
+
+    #[tokio::test]
+    async fn test_cancel_pending() {
+        let (tx, mut rx) = mpsc::channel(2);
+        let mut bot = Bot::new(tx);
+        let expires_at = chrono::Utc::now().naive_utc() + Duration::minutes(5);
+        bot.stage(pending("bob", 10, expires_at));
+        bot.cancel_transfer().await.unwrap();
+        assert_eq!(rx.recv().await.unwrap(), "Transfer 10 to bob cancelled");
+        assert_eq!(bot.pending_transfer(), None);
+
+        bot.cancel_transfer().await.unwrap();
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            "No transfer is awaiting confirmation"
+        );
+    }
+
+    #[test]
+    fn test_get_page() {
+        assert_eq!(json!({}).get_limit().unwrap(), DEFAULT_PAGE_SIZE);
+        assert_eq!(json!({"limit": -1}).get_limit().unwrap(), 1);
+        assert_eq!(
+            json!({"limit": 1000000}).get_limit().unwrap(),
+            MAX_PAGE_SIZE
+        );
+        assert_eq!(json!({"limit": 5}).get_limit().unwrap(), 5);
+        assert_eq!(json!({}).get_offset().unwrap(), 0);
+        assert_eq!(json!({"offset": 40}).get_offset().unwrap(), 40);
+        assert!(json!({"offset": -1}).get_offset().is_err());
+    }
+}
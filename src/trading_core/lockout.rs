@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+
+use chrono::{Duration, NaiveDateTime};
+use lazy_static::lazy_static;
+use tracing::warn;
+
+use crate::global;
+
+lazy_static! {
+    /// Failed logins per username, shared by every connection.
+    static ref BY_USERNAME: Mutex<HashMap<String, LoginAttempts>> = Mutex::new(HashMap::new());
+}
+
+/// Failed logins in a row for one username or connection.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoginAttempts {
+    failures: u32,
+    last_failure_at: Option<NaiveDateTime>,
+    locked_until: Option<NaiveDateTime>,
+}
+
+/// Returned instead of trying a login while it is locked. Kept as the error so
+/// callers can downcast it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoginLocked {
+    pub retry_after_secs: i64,
+}
+
+impl fmt::Display for LoginLocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Too many failed login attempts, try again in {} seconds",
+            self.retry_after_secs
+        )
+    }
+}
+
+impl std::error::Error for LoginLocked {}
+
+impl LoginAttempts {
+    pub fn check(&self, now: NaiveDateTime) -> Result<(), LoginLocked> {
+        match self.locked_until {
+            Some(until) if until > now => Err(LoginLocked {
+                // Rounded up, so that retrying right on time succeeds.
+                retry_after_secs: ((until - now).num_milliseconds() + 999) / 1000,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts a failure, locking further attempts once the free ones are used
+    /// up. `who` only names the attempts in the log.
+    pub fn record_failure(&mut self, who: &str, now: NaiveDateTime) {
+        if self.is_stale(now) {
+            *self = LoginAttempts::default();
+        }
+        self.failures += 1;
+        self.last_failure_at = Some(now);
+        if let Some(wait) = backoff(self.failures) {
+            self.locked_until = Some(now + wait);
+            warn!(
+                "Locked logins of {} for {}s after {} failed attempts",
+                who,
+                wait.num_seconds(),
+                self.failures
+            );
+        }
+    }
+
+    /// Whether the last failure is old enough to be forgotten.
+    fn is_stale(&self, now: NaiveDateTime) -> bool {
+        self.last_failure_at
+            .is_none_or(|last| now - last >= Duration::seconds(global::LOGIN_FAILURE_WINDOW_SECS))
+    }
+}
+
+/// How long to lock logins after `failures` failed attempts in a row.
+fn backoff(failures: u32) -> Option<Duration> {
+    let over = failures.checked_sub(global::LOGIN_FREE_ATTEMPTS + 1)?;
+    let secs = global::LOGIN_BACKOFF_SECS
+        .saturating_mul(2i64.checked_pow(over).unwrap_or(i64::MAX))
+        .min(global::LOGIN_LOCKOUT_SECS);
+    Some(Duration::seconds(secs))
+}
+
+/// Fails while logins of `username` are locked.
+pub fn check_username(username: &str, now: NaiveDateTime) -> Result<(), LoginLocked> {
+    let attempts = BY_USERNAME.lock().unwrap_or_else(|e| e.into_inner());
+    attempts
+        .get(username)
+        .map_or(Ok(()), |attempts| attempts.check(now))
+}
+
+pub fn record_username_failure(username: &str, now: NaiveDateTime) {
+    let mut attempts = BY_USERNAME.lock().unwrap_or_else(|e| e.into_inner());
+    // Usernames that stopped failing are dropped, so guessing many of them
+    // doesn't grow the map forever.
+    attempts.retain(|_, attempts| !attempts.is_stale(now));
+    attempts
+        .entry(username.to_string())
+        .or_default()
+        .record_failure(username, now);
+}
+
+pub fn reset_username(username: &str) {
+    let mut attempts = BY_USERNAME.lock().unwrap_or_else(|e| e.into_inner());
+    attempts.remove(username);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(global::LOGIN_FREE_ATTEMPTS), None);
+        let first = global::LOGIN_FREE_ATTEMPTS + 1;
+        assert_eq!(
+            backoff(first),
+            Some(Duration::seconds(global::LOGIN_BACKOFF_SECS))
+        );
+        assert_eq!(
+            backoff(first + 2),
+            Some(Duration::seconds(global::LOGIN_BACKOFF_SECS * 4))
+        );
+        assert_eq!(
+            backoff(u32::MAX),
+            Some(Duration::seconds(global::LOGIN_LOCKOUT_SECS))
+        );
+    }
+
+    #[test]
+    fn test_login_attempts() {
+        let now = chrono::Utc::now().naive_utc();
+        let mut attempts = LoginAttempts::default();
+        for _ in 0..global::LOGIN_FREE_ATTEMPTS {
+            attempts.record_failure("alice", now);
+            assert!(attempts.check(now).is_ok());
+        }
+        attempts.record_failure("alice", now);
+        assert_eq!(
+            attempts.check(now),
+            Err(LoginLocked {
+                retry_after_secs: global::LOGIN_BACKOFF_SECS
+            })
+        );
+        let later = now + Duration::seconds(global::LOGIN_BACKOFF_SECS);
+        assert!(attempts.check(later).is_ok());
+
+        // Failures are forgotten after a quiet window.
+        let quiet = now + Duration::seconds(global::LOGIN_FAILURE_WINDOW_SECS);
+        attempts.record_failure("alice", quiet);
+        assert_eq!(attempts.failures, 1);
+        assert!(attempts.check(quiet).is_ok());
+    }
+
+    #[test]
+    fn test_username_lockout() {
+        let now = chrono::Utc::now().naive_utc();
+        for _ in 0..=global::LOGIN_FREE_ATTEMPTS {
+            record_username_failure("lockout-test", now);
+        }
+        assert!(check_username("lockout-test", now).is_err());
+        assert!(check_username("someone-else", now).is_ok());
+        reset_username("lockout-test");
+        assert!(check_username("lockout-test", now).is_ok());
+    }
+}
@@ -0,0 +1,1752 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+use anyhow::{anyhow, bail, ensure, Result};
+use chrono::NaiveDateTime;
+
+use super::payout::BatchFailed;
+use super::policy::{period_starts, OutgoingUsage, TransferPolicy};
+use super::reconcile::{self, Discrepancy, LedgerSnapshot};
+use super::storage::Storage;
+use super::types::{
+    AuditFilter, AuditRecord, Contact, Direction, Hold, NewAuditRecord, NewPaymentRequest,
+    NewPosting, NewScheduledTransfer, NewTransaction, PasswordReset, PaymentRequest, Posting,
+    ScheduledTransfer, Session, SpendingSummary, Transaction, TransactionFilter, User,
+    DEFAULT_PAGE_SIZE, HOLD_ACTIVE, HOLD_CAPTURED, HOLD_EXPIRED, HOLD_VOIDED, REQUEST_ACCEPTED,
+    REQUEST_CANCELLED, REQUEST_PENDING, SCHEDULE_ACTIVE, SCHEDULE_CANCELLED, STATUS_COMPLETED,
+};
+
+/// Keeps everything in the process, for tests and demos without a database file.
+/// A single lock around the whole state makes every operation atomic.
+pub struct MemoryStorage {
+    state: Mutex<State>,
+    policy: TransferPolicy,
+}
+
+#[derive(Default, Clone)]
+struct State {
+    users: HashMap<String, User>,
+    transactions: Vec<Transaction>,
+    postings: Vec<Posting>,
+    sessions: HashMap<String, Session>,
+    password_resets: HashMap<String, PasswordReset>,
+    contacts: Vec<Contact>,
+    audit_log: Vec<AuditRecord>,
+    payment_requests: Vec<PaymentRequest>,
+    scheduled_transfers: Vec<ScheduledTransfer>,
+    holds: Vec<Hold>,
+}
+
+impl MemoryStorage {
+    pub fn new(policy: TransferPolicy) -> MemoryStorage {
+        MemoryStorage {
+            state: Mutex::new(State::default()),
+            policy,
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl State {
+    fn user(&self, username: &str) -> Result<&User> {
+        self.users
+            .get(username)
+            .ok_or_else(|| anyhow!("Username doesn't exist"))
+    }
+
+    fn user_mut(&mut self, username: &str) -> Result<&mut User> {
+        self.users
+            .get_mut(username)
+            .ok_or_else(|| anyhow!("Username doesn't exist"))
+    }
+
+    fn session_mut(&mut self, token: &str) -> Result<&mut Session> {
+        self.sessions
+            .get_mut(token)
+            .ok_or_else(|| anyhow!("Session doesn't exist"))
+    }
+
+    fn revoke_sessions(&mut self, username: &str, now: NaiveDateTime) -> usize {
+        let mut revoked = 0;
+        for session in self.sessions.values_mut() {
+            if session.username == username
+                && session.revoked_at.is_none()
+                && session.expires_at > now
+            {
+                session.revoked_at = Some(now);
+                revoked += 1;
+            }
+        }
+        revoked
+    }
+
+    fn outgoing_since(&self, username: &str, since: NaiveDateTime) -> i64 {
+        let filter = TransactionFilter {
+            direction: Some(Direction::Outgoing),
+            since: Some(since),
+            status: Some(STATUS_COMPLETED.to_string()),
+            ..Default::default()
+        };
+        self.transactions
+            .iter()
+            .filter(|record| filter.matches(username, record))
+            .map(|record| i64::from(record.amount))
+            .sum()
+    }
+
+    fn transfer(
+        &mut self,
+        policy: &TransferPolicy,
+        record: &NewTransaction,
+    ) -> Result<Transaction> {
+        let (sender, to_username, amount) = (record.sender, record.receiver, record.amount);
+        ensure!(amount > 0, "Amount must be positive");
+        if let Some(key) = record.idempotency_key {
+            let original = self.transactions.iter().find(|original| {
+                original.sender == sender && original.idempotency_key.as_deref() == Some(key)
+            });
+            if let Some(original) = original.cloned() {
+                ensure!(
+                    original.receiver == to_username
+                        && original.amount == amount
+                        && original.refund_of == record.refund_of,
+                    "Idempotency key already used for a different transfer"
+                );
+                return Ok(original);
+            }
+        }
+
+        self.expire_holds(Some(sender), record.created_at);
+        self.user(sender)?.check_active()?;
+        if self.user(sender)?.available() < amount {
+            bail!("Insufficient balance")
+        }
+        // A refund only gives back what the sender was sent.
+        if record.refund_of.is_none() {
+            self.check_policy(policy, sender, to_username, amount)?;
+        }
+        let receiver = self.user(to_username)?;
+        receiver.check_active()?;
+        // A transfer to oneself is debited first, so it can't overflow.
+        ensure!(
+            sender == to_username || receiver.balance.checked_add(amount).is_some(),
+            "Can't credit {} to {}, the balance would overflow",
+            amount,
+            to_username
+        );
+
+        self.user_mut(sender)?.balance -= amount;
+        self.user_mut(to_username)?.balance += amount;
+        let inserted = Transaction {
+            id: self.transactions.len() as i32 + 1,
+            sender: sender.to_string(),
+            receiver: to_username.to_string(),
+            amount,
+            memo: record.memo.map(str::to_string),
+            created_at: record.created_at,
+            status: record.status.to_string(),
+            idempotency_key: record.idempotency_key.map(str::to_string),
+            refund_of: record.refund_of,
+        };
+        self.transactions.push(inserted.clone());
+        self.post(&NewPosting::transfer(&inserted));
+        Ok(inserted)
+    }
+
+    fn post(&mut self, posting: &NewPosting) {
+        let id = self.postings.len() as i32 + 1;
+        self.postings.push(Posting {
+            id,
+            debit_account: posting.debit_account.to_string(),
+            credit_account: posting.credit_account.to_string(),
+            amount: posting.amount,
+            kind: posting.kind.to_string(),
+            transaction_id: posting.transaction_id,
+            created_at: posting.created_at,
+        });
+    }
+
+    fn transaction(&self, id: i32) -> Result<&Transaction> {
+        self.transactions
+            .iter()
+            .find(|record| record.id == id)
+            .ok_or_else(|| anyhow!("Transfer #{} doesn't exist", id))
+    }
+
+    fn snapshot(&self) -> LedgerSnapshot {
+        let mut active_holds = HashMap::<String, i64>::new();
+        for hold in self.holds.iter().filter(|hold| hold.status == HOLD_ACTIVE) {
+            *active_holds.entry(hold.payer.clone()).or_default() += i64::from(hold.amount);
+        }
+        let debits = self
+            .postings
+            .iter()
+            .map(|posting| (posting.debit_account.clone(), i64::from(posting.amount)));
+        let credits = self
+            .postings
+            .iter()
+            .map(|posting| (posting.credit_account.clone(), i64::from(posting.amount)));
+        let unposted_transfers = self
+            .transactions
+            .iter()
+            .filter(|record| record.status == STATUS_COMPLETED)
+            .filter(|record| {
+                !self
+                    .postings
+                    .iter()
+                    .any(|posting| posting.transaction_id == Some(record.id))
+            })
+            .map(|record| record.id)
+            .collect();
+
+        let named = self
+            .sessions
+            .values()
+            .map(|session| ("sessions", &session.username))
+            .chain(
+                self.password_resets
+                    .values()
+                    .map(|reset| ("password_resets", &reset.username)),
+            )
+            .chain(self.contacts.iter().flat_map(|contact| {
+                [
+                    ("contacts", &contact.owner),
+                    ("contacts", &contact.username),
+                ]
+            }))
+            .chain(self.transactions.iter().flat_map(|record| {
+                [
+                    ("transactions", &record.sender),
+                    ("transactions", &record.receiver),
+                ]
+            }));
+        let mut orphans = HashMap::<(&'static str, String), i64>::new();
+        for (table, username) in named {
+            if !self.users.contains_key(username) {
+                *orphans.entry((table, username.clone())).or_default() += 1;
+            }
+        }
+
+        LedgerSnapshot {
+            users: self.users.values().cloned().collect(),
+            account_balances: Posting::net_balances(debits, credits),
+            active_holds: active_holds.into_iter().collect(),
+            unposted_transfers,
+            orphans: orphans
+                .into_iter()
+                .map(|((table, username), count)| (table, username, count))
+                .collect(),
+        }
+    }
+
+    /// Does what `discrepancy.repair()` describes, or nothing.
+    fn repair(&mut self, discrepancy: &Discrepancy) {
+        match discrepancy {
+            Discrepancy::BalanceMismatch {
+                username, posted, ..
+            } => {
+                if let (Ok(posted), Some(user)) =
+                    (i32::try_from(*posted), self.users.get_mut(username))
+                {
+                    user.balance = posted;
+                }
+            }
+            Discrepancy::HeldMismatch { username, .. } => self.sync_held(username),
+            Discrepancy::Orphaned {
+                table: "sessions",
+                username,
+                ..
+            } => self
+                .sessions
+                .retain(|_, session| &session.username != username),
+            Discrepancy::Orphaned {
+                table: "password_resets",
+                username,
+                ..
+            } => self
+                .password_resets
+                .retain(|_, reset| &reset.username != username),
+            Discrepancy::Orphaned {
+                table: "contacts",
+                username,
+                ..
+            } => self
+                .contacts
+                .retain(|contact| &contact.owner != username && &contact.username != username),
+            _ => {}
+        }
+    }
+
+    fn refunds(&self, original_id: i32) -> impl Iterator<Item = &Transaction> {
+        self.transactions.iter().filter(move |record| {
+            record.refund_of == Some(original_id) && record.status == STATUS_COMPLETED
+        })
+    }
+
+    /// Recomputes the `held` of `username` from its active holds.
+    fn sync_held(&mut self, username: &str) {
+        let held = self
+            .holds
+            .iter()
+            .filter(|hold| hold.payer == username && hold.status == HOLD_ACTIVE)
+            .map(|hold| hold.amount)
+            .sum();
+        if let Some(user) = self.users.get_mut(username) {
+            user.held = held;
+        }
+    }
+
+    /// Expires active holds past `expires_at`, of `payer` only if given.
+    fn expire_holds(&mut self, payer: Option<&str>, now: NaiveDateTime) -> usize {
+        let mut payers = Vec::new();
+        for hold in self.holds.iter_mut() {
+            if hold.status == HOLD_ACTIVE
+                && hold.expires_at <= now
+                && payer.is_none_or(|payer| hold.payer == payer)
+            {
+                hold.status = HOLD_EXPIRED.to_string();
+                hold.resolved_at = Some(now);
+                payers.push(hold.payer.clone());
+            }
+        }
+        for payer in &payers {
+            self.sync_held(payer);
+        }
+        payers.len()
+    }
+
+    fn hold_mut(&mut self, id: i32) -> Result<&mut Hold> {
+        self.holds
+            .iter_mut()
+            .find(|hold| hold.id == id)
+            .ok_or_else(|| anyhow!("Hold #{} doesn't exist", id))
+    }
+
+    fn check_policy(
+        &self,
+        policy: &TransferPolicy,
+        sender: &str,
+        to_username: &str,
+        amount: i32,
+    ) -> Result<()> {
+        let usage = if policy.has_limits() {
+            let (today, this_month) = period_starts(chrono::Utc::now().naive_utc());
+            OutgoingUsage {
+                today: self.outgoing_since(sender, today),
+                this_month: self.outgoing_since(sender, this_month),
+            }
+        } else {
+            OutgoingUsage::default()
+        };
+        policy.evaluate(sender, to_username, amount, usage)?;
+        Ok(())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn insert_user(&self, user: &User) -> Result<()> {
+        let mut state = self.lock();
+        if state.users.contains_key(&user.username) {
+            bail!("Username already exists")
+        }
+        state.users.insert(user.username.clone(), user.clone());
+        if user.balance > 0 {
+            let now = chrono::Utc::now().naive_utc();
+            state.post(&NewPosting::grant(&user.username, user.balance, now));
+        }
+        Ok(())
+    }
+
+    fn retrieve_user(&self, username: &str) -> Result<User> {
+        self.lock().user(username).cloned()
+    }
+
+    fn list_usernames(&self) -> Result<Vec<String>> {
+        let mut usernames = self
+            .lock()
+            .users
+            .values()
+            .filter(|user| user.closed_at.is_none())
+            .map(|user| user.username.clone())
+            .collect::<Vec<_>>();
+        usernames.sort();
+        Ok(usernames)
+    }
+
+    fn update_user(&self, user: &User) -> Result<()> {
+        *self.lock().user_mut(&user.username)? = user.clone();
+        Ok(())
+    }
+
+    fn update_password(&self, user: &User) -> Result<()> {
+        let mut state = self.lock();
+        let stored = state.user_mut(&user.username)?;
+        stored.password = user.password.clone();
+        stored.password_hash = user.password_hash.clone();
+        Ok(())
+    }
+
+    fn adjust_balance(&self, username: &str, amount: i32) -> Result<User> {
+        let mut state = self.lock();
+        let now = chrono::Utc::now().naive_utc();
+        state.expire_holds(Some(username), now);
+        let user = state.user_mut(username)?;
+        user.check_open()?;
+        let balance = user.balance.checked_add(amount).ok_or_else(|| {
+            anyhow!(
+                "Can't mint {} into {}, the balance would overflow",
+                amount,
+                username
+            )
+        })?;
+        ensure!(
+            user.available() + amount >= 0,
+            "Can't burn {} from {}, only {} available",
+            amount.unsigned_abs(),
+            username,
+            user.available()
+        );
+        user.balance = balance;
+        let user = user.clone();
+        if amount != 0 {
+            state.post(&NewPosting::adjustment(username, amount, now));
+        }
+        Ok(user)
+    }
+
+    fn set_frozen(&self, username: &str, frozen_at: Option<NaiveDateTime>) -> Result<User> {
+        let mut state = self.lock();
+        let user = state.user_mut(username)?;
+        match frozen_at {
+            Some(now) => {
+                user.frozen_at.get_or_insert(now);
+            }
+            None => user.frozen_at = None,
+        }
+        Ok(user.clone())
+    }
+
+    fn set_role(&self, username: &str, role: &str) -> Result<User> {
+        let mut state = self.lock();
+        let user = state.user_mut(username)?;
+        user.role = role.to_string();
+        Ok(user.clone())
+    }
+
+    fn delete_user(&self, user: &User) -> Result<()> {
+        self.lock().users.remove(&user.username);
+        Ok(())
+    }
+
+    fn close_user(
+        &self,
+        user: &mut User,
+        sweep_to: Option<&str>,
+        now: NaiveDateTime,
+    ) -> Result<Option<Transaction>> {
+        let mut state = self.lock();
+        let username = user.username.clone();
+        state.user(&username)?.check_open()?;
+        state.expire_holds(Some(&username), now);
+        let stored = state.user(&username)?.clone();
+        ensure!(
+            stored.held == 0,
+            "Can't close an account with {} on hold",
+            stored.held
+        );
+        let sweep = match sweep_to {
+            _ if stored.balance == 0 => None,
+            Some(to) => {
+                self.policy.check_sweep(to)?;
+                Some(state.transfer(
+                    &self.policy.for_closure(),
+                    &NewTransaction::completed(
+                        &username,
+                        to,
+                        stored.balance,
+                        Some("Account closure"),
+                        None,
+                    ),
+                )?)
+            }
+            None => bail!(
+                "The account still holds {}, name an account to sweep it to",
+                stored.balance
+            ),
+        };
+
+        for request in state.payment_requests.iter_mut() {
+            if (request.payer == username || request.requester == username)
+                && request.status == REQUEST_PENDING
+            {
+                request.status = REQUEST_CANCELLED.to_string();
+                request.resolved_at = Some(now);
+            }
+        }
+        for schedule in state.scheduled_transfers.iter_mut() {
+            if (schedule.sender == username || schedule.receiver == username)
+                && schedule.status == SCHEDULE_ACTIVE
+            {
+                schedule.status = SCHEDULE_CANCELLED.to_string();
+            }
+        }
+        let mut payers = Vec::new();
+        for hold in state.holds.iter_mut() {
+            if hold.payee == username && hold.status == HOLD_ACTIVE {
+                hold.status = HOLD_VOIDED.to_string();
+                hold.resolved_at = Some(now);
+                payers.push(hold.payer.clone());
+            }
+        }
+        for payer in &payers {
+            state.sync_held(payer);
+        }
+        for session in state.sessions.values_mut() {
+            if session.username == username && session.revoked_at.is_none() {
+                session.revoked_at = Some(now);
+            }
+        }
+        state.user_mut(&username)?.closed_at = Some(now);
+        *user = state.user(&username)?.clone();
+        Ok(sweep)
+    }
+
+    fn transfer(
+        &self,
+        sender: &mut User,
+        to_username: &str,
+        amount: i32,
+        memo: Option<&str>,
+        idempotency_key: Option<&str>,
+    ) -> Result<Transaction> {
+        let mut state = self.lock();
+        let record = state.transfer(
+            &self.policy,
+            &NewTransaction::completed(
+                &sender.username,
+                to_username,
+                amount,
+                memo,
+                idempotency_key,
+            ),
+        )?;
+        *sender = state.user(&sender.username)?.clone();
+        Ok(record)
+    }
+
+    fn transfer_batch(
+        &self,
+        sender: &mut User,
+        records: &[NewTransaction],
+    ) -> Result<Vec<Transaction>> {
+        let mut state = self.lock();
+        // Put back if any transfer fails.
+        let before = state.clone();
+        let mut transfers = Vec::new();
+        for (index, record) in records.iter().enumerate() {
+            match state.transfer(&self.policy, record) {
+                Ok(transfer) => transfers.push(transfer),
+                Err(e) => {
+                    *state = before;
+                    bail!(BatchFailed {
+                        index,
+                        reason: e.to_string(),
+                    })
+                }
+            }
+        }
+        *sender = state.user(&sender.username)?.clone();
+        Ok(transfers)
+    }
+
+    fn check_transfer_policy(&self, sender: &User, to_username: &str, amount: i32) -> Result<()> {
+        self.lock()
+            .check_policy(&self.policy, &sender.username, to_username, amount)
+    }
+
+    fn refund(
+        &self,
+        refunder: &mut User,
+        original_id: i32,
+        amount: i32,
+        memo: Option<&str>,
+        idempotency_key: Option<&str>,
+    ) -> Result<Transaction> {
+        let mut state = self.lock();
+        let original = state.transaction(original_id)?.clone();
+        original.check_refundable(&refunder.username)?;
+        let refunds = state.refunds(original_id).cloned().collect::<Vec<_>>();
+        original.check_refund_fits(&refunds, amount, idempotency_key)?;
+        let record = NewTransaction {
+            refund_of: Some(original_id),
+            ..NewTransaction::completed(
+                &refunder.username,
+                &original.sender,
+                amount,
+                memo,
+                idempotency_key,
+            )
+        };
+        let record = state.transfer(&self.policy, &record)?;
+        *refunder = state.user(&refunder.username)?.clone();
+        Ok(record)
+    }
+
+    fn retrieve_transaction(&self, id: i32) -> Result<Transaction> {
+        self.lock().transaction(id).cloned()
+    }
+
+    fn list_refunds(&self, original_id: i32) -> Result<Vec<Transaction>> {
+        Ok(self.lock().refunds(original_id).cloned().collect())
+    }
+
+    fn list_transactions(
+        &self,
+        user: &User,
+        filter: &TransactionFilter,
+    ) -> Result<Vec<Transaction>> {
+        let state = self.lock();
+        let records = state
+            .transactions
+            .iter()
+            .rev()
+            .filter(|record| filter.matches(&user.username, record))
+            .skip(filter.offset.unwrap_or(0).max(0) as usize)
+            .take(filter.limit.unwrap_or(DEFAULT_PAGE_SIZE).max(0) as usize)
+            .cloned()
+            .collect();
+        Ok(records)
+    }
+
+    fn summarize_spending(
+        &self,
+        user: &User,
+        since: Option<NaiveDateTime>,
+        until: Option<NaiveDateTime>,
+    ) -> Result<SpendingSummary> {
+        let filter = TransactionFilter {
+            since,
+            until,
+            status: Some(STATUS_COMPLETED.to_string()),
+            ..Default::default()
+        };
+        let state = self.lock();
+        let records = state
+            .transactions
+            .iter()
+            .filter(|record| filter.matches(&user.username, record))
+            .cloned()
+            .collect::<Vec<_>>();
+        Ok(SpendingSummary::from_ledger(&user.username, &records))
+    }
+
+    fn list_postings(&self, account: &str) -> Result<Vec<Posting>> {
+        let postings = self
+            .lock()
+            .postings
+            .iter()
+            .filter(|posting| posting.debit_account == account || posting.credit_account == account)
+            .cloned()
+            .collect();
+        Ok(postings)
+    }
+
+    fn account_balances(&self) -> Result<Vec<(String, i64)>> {
+        let state = self.lock();
+        let debits = state
+            .postings
+            .iter()
+            .map(|posting| (posting.debit_account.clone(), i64::from(posting.amount)));
+        let credits = state
+            .postings
+            .iter()
+            .map(|posting| (posting.credit_account.clone(), i64::from(posting.amount)));
+        Ok(Posting::net_balances(debits, credits))
+    }
+
+    fn insert_session(&self, session: &Session) -> Result<()> {
+        self.lock()
+            .sessions
+            .insert(session.token.clone(), session.clone());
+        Ok(())
+    }
+
+    fn retrieve_session(&self, token: &str) -> Result<Session> {
+        Ok(self.lock().session_mut(token)?.clone())
+    }
+
+    fn touch_session(&self, session: &Session) -> Result<()> {
+        self.lock().session_mut(&session.token)?.last_seen_at = session.last_seen_at;
+        Ok(())
+    }
+
+    fn revoke_session(&self, session: &Session, now: NaiveDateTime) -> Result<()> {
+        let mut state = self.lock();
+        let stored = state.session_mut(&session.token)?;
+        stored.revoked_at.get_or_insert(now);
+        Ok(())
+    }
+
+    fn revoke_all_sessions(&self, username: &str, now: NaiveDateTime) -> Result<usize> {
+        Ok(self.lock().revoke_sessions(username, now))
+    }
+
+    fn change_password(&self, user: &User, now: NaiveDateTime) -> Result<usize> {
+        let mut state = self.lock();
+        let stored = state.user_mut(&user.username)?;
+        stored.password = user.password.clone();
+        stored.password_hash = user.password_hash.clone();
+        Ok(state.revoke_sessions(&user.username, now))
+    }
+
+    fn insert_password_reset(&self, reset: &PasswordReset) -> Result<()> {
+        let mut state = self.lock();
+        state.user(&reset.username)?;
+        state
+            .password_resets
+            .insert(reset.token.clone(), reset.clone());
+        Ok(())
+    }
+
+    fn redeem_password_reset(
+        &self,
+        token: &str,
+        password_hash: &str,
+        now: NaiveDateTime,
+    ) -> Result<User> {
+        let mut state = self.lock();
+        let username = match state.password_resets.get(token) {
+            Some(reset) if reset.is_redeemable(now) => reset.username.clone(),
+            _ => bail!("Reset token is invalid, expired or already used"),
+        };
+        let stored = state.user_mut(&username)?;
+        stored.check_open()?;
+        stored.password.clear();
+        stored.password_hash = Some(password_hash.to_string());
+        let user = stored.clone();
+        state.revoke_sessions(&username, now);
+        if let Some(reset) = state.password_resets.get_mut(token) {
+            reset.used_at = Some(now);
+        }
+        Ok(user)
+    }
+
+    fn insert_audit_record(&self, record: &NewAuditRecord) -> Result<()> {
+        let mut state = self.lock();
+        let inserted = AuditRecord {
+            id: state.audit_log.len() as i32 + 1,
+            session_id: record.session_id.to_string(),
+            username: record.username.map(str::to_string),
+            function_name: record.function_name.to_string(),
+            arguments: record.arguments.to_string(),
+            outcome: record.outcome.to_string(),
+            result: record.result.to_string(),
+            model: record.model.to_string(),
+            created_at: record.created_at,
+        };
+        state.audit_log.push(inserted);
+        Ok(())
+    }
+
+    fn list_audit_records(&self, filter: &AuditFilter) -> Result<Vec<AuditRecord>> {
+        let records = self
+            .lock()
+            .audit_log
+            .iter()
+            .filter(|record| filter.matches(record))
+            .skip(filter.offset.unwrap_or(0).max(0) as usize)
+            .take(
+                filter
+                    .limit
+                    .map_or(usize::MAX, |limit| limit.max(0) as usize),
+            )
+            .cloned()
+            .collect();
+        Ok(records)
+    }
+
+    fn insert_contact(&self, contact: &Contact) -> Result<()> {
+        let mut state = self.lock();
+        state.user(&contact.owner)?;
+        state.user(&contact.username)?;
+        if state
+            .contacts
+            .iter()
+            .any(|stored| stored.owner == contact.owner && stored.alias == contact.alias)
+        {
+            bail!("You already have a contact named {}", contact.alias)
+        }
+        state.contacts.push(contact.clone());
+        Ok(())
+    }
+
+    fn delete_contact(&self, owner: &str, alias: &str) -> Result<()> {
+        let mut state = self.lock();
+        let before = state.contacts.len();
+        state
+            .contacts
+            .retain(|contact| contact.owner != owner || contact.alias != alias);
+        ensure!(state.contacts.len() < before, "No contact named {}", alias);
+        Ok(())
+    }
+
+    fn retrieve_contact(&self, owner: &str, alias: &str) -> Result<Option<Contact>> {
+        Ok(self
+            .lock()
+            .contacts
+            .iter()
+            .find(|contact| contact.owner == owner && contact.alias == alias)
+            .cloned())
+    }
+
+    fn list_contacts(&self, owner: &str) -> Result<Vec<Contact>> {
+        let mut contacts = self
+            .lock()
+            .contacts
+            .iter()
+            .filter(|contact| contact.owner == owner)
+            .cloned()
+            .collect::<Vec<_>>();
+        contacts.sort_by(|a, b| a.alias.cmp(&b.alias));
+        Ok(contacts)
+    }
+
+    fn insert_payment_request(&self, request: &NewPaymentRequest) -> Result<PaymentRequest> {
+        let mut state = self.lock();
+        state.user(request.requester)?;
+        state.user(request.payer)?;
+        let inserted = PaymentRequest {
+            id: state.payment_requests.len() as i32 + 1,
+            requester: request.requester.to_string(),
+            payer: request.payer.to_string(),
+            amount: request.amount,
+            memo: request.memo.map(str::to_string),
+            status: request.status.to_string(),
+            created_at: request.created_at,
+            resolved_at: None,
+            transaction_id: None,
+        };
+        state.payment_requests.push(inserted.clone());
+        Ok(inserted)
+    }
+
+    fn retrieve_payment_request(&self, id: i32) -> Result<PaymentRequest> {
+        self.lock()
+            .payment_requests
+            .iter()
+            .find(|request| request.id == id)
+            .cloned()
+            .ok_or_else(|| anyhow!("Payment request #{} doesn't exist", id))
+    }
+
+    fn list_payment_requests(
+        &self,
+        username: &str,
+        direction: Option<Direction>,
+        status: Option<&str>,
+    ) -> Result<Vec<PaymentRequest>> {
+        let state = self.lock();
+        let requests = state
+            .payment_requests
+            .iter()
+            .rev()
+            .filter(|request| match direction {
+                Some(Direction::Incoming) => request.payer == username,
+                Some(Direction::Outgoing) => request.requester == username,
+                None => request.payer == username || request.requester == username,
+            })
+            .filter(|request| status.is_none_or(|status| request.status == status))
+            .cloned()
+            .collect();
+        Ok(requests)
+    }
+
+    fn resolve_payment_request(
+        &self,
+        id: i32,
+        status: &str,
+        transaction_id: Option<i32>,
+        now: NaiveDateTime,
+    ) -> Result<bool> {
+        let mut state = self.lock();
+        let request = state
+            .payment_requests
+            .iter_mut()
+            .find(|request| request.id == id && request.status == REQUEST_PENDING);
+        match request {
+            Some(request) => {
+                request.status = status.to_string();
+                request.transaction_id = transaction_id;
+                request.resolved_at = Some(now);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn accept_payment_request(
+        &self,
+        payer: &mut User,
+        id: i32,
+        now: NaiveDateTime,
+    ) -> Result<Transaction> {
+        let mut state = self.lock();
+        let request = state
+            .payment_requests
+            .iter()
+            .find(|request| {
+                request.id == id
+                    && request.payer == payer.username
+                    && request.status == REQUEST_PENDING
+            })
+            .cloned()
+            .ok_or_else(|| anyhow!("Payment request #{} is no longer pending", id))?;
+        let idempotency_key = format!("payment-request:{}", id);
+        let record = state.transfer(
+            &self.policy,
+            &NewTransaction::completed(
+                &request.payer,
+                &request.requester,
+                request.amount,
+                request.memo.as_deref(),
+                Some(&idempotency_key),
+            ),
+        )?;
+        let request = state
+            .payment_requests
+            .iter_mut()
+            .find(|request| request.id == id)
+            .unwrap();
+        request.status = REQUEST_ACCEPTED.to_string();
+        request.transaction_id = Some(record.id);
+        request.resolved_at = Some(now);
+        *payer = state.user(&payer.username)?.clone();
+        Ok(record)
+    }
+
+    fn insert_scheduled_transfer(
+        &self,
+        schedule: &NewScheduledTransfer,
+    ) -> Result<ScheduledTransfer> {
+        let mut state = self.lock();
+        state.user(schedule.sender)?;
+        state.user(schedule.receiver)?;
+        let inserted = ScheduledTransfer {
+            id: state.scheduled_transfers.len() as i32 + 1,
+            sender: schedule.sender.to_string(),
+            receiver: schedule.receiver.to_string(),
+            amount: schedule.amount,
+            memo: schedule.memo.map(str::to_string),
+            recurrence: schedule.recurrence.map(str::to_string),
+            next_run_at: schedule.next_run_at,
+            status: schedule.status.to_string(),
+            failures: 0,
+            last_error: None,
+            created_at: schedule.created_at,
+            first_run_at: schedule.first_run_at,
+        };
+        state.scheduled_transfers.push(inserted.clone());
+        Ok(inserted)
+    }
+
+    fn retrieve_scheduled_transfer(&self, id: i32) -> Result<ScheduledTransfer> {
+        self.lock()
+            .scheduled_transfers
+            .iter()
+            .find(|schedule| schedule.id == id)
+            .cloned()
+            .ok_or_else(|| anyhow!("Scheduled transfer #{} doesn't exist", id))
+    }
+
+    fn list_scheduled_transfers(&self, username: &str) -> Result<Vec<ScheduledTransfer>> {
+        let state = self.lock();
+        let schedules = state
+            .scheduled_transfers
+            .iter()
+            .rev()
+            .filter(|schedule| schedule.sender == username)
+            .cloned()
+            .collect();
+        Ok(schedules)
+    }
+
+    fn due_scheduled_transfers(&self, now: NaiveDateTime) -> Result<Vec<ScheduledTransfer>> {
+        let state = self.lock();
+        let mut schedules = state
+            .scheduled_transfers
+            .iter()
+            .filter(|schedule| schedule.status == SCHEDULE_ACTIVE && schedule.next_run_at <= now)
+            .cloned()
+            .collect::<Vec<_>>();
+        schedules.sort_by_key(|schedule| (schedule.next_run_at, schedule.id));
+        Ok(schedules)
+    }
+
+    fn update_scheduled_transfer(
+        &self,
+        schedule: &ScheduledTransfer,
+        ran_at: NaiveDateTime,
+    ) -> Result<bool> {
+        let mut state = self.lock();
+        let stored = state.scheduled_transfers.iter_mut().find(|stored| {
+            stored.id == schedule.id
+                && stored.status == SCHEDULE_ACTIVE
+                && stored.next_run_at == ran_at
+        });
+        match stored {
+            Some(stored) => {
+                stored.next_run_at = schedule.next_run_at;
+                stored.status = schedule.status.clone();
+                stored.failures = schedule.failures;
+                stored.last_error = schedule.last_error.clone();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn cancel_scheduled_transfer(&self, id: i32) -> Result<bool> {
+        let mut state = self.lock();
+        let stored = state
+            .scheduled_transfers
+            .iter_mut()
+            .find(|stored| stored.id == id && stored.status == SCHEDULE_ACTIVE);
+        match stored {
+            Some(stored) => {
+                stored.status = SCHEDULE_CANCELLED.to_string();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn authorize_hold(
+        &self,
+        payer: &mut User,
+        payee: &str,
+        amount: i32,
+        memo: Option<&str>,
+        expires_at: NaiveDateTime,
+    ) -> Result<Hold> {
+        ensure!(amount > 0, "Amount must be positive");
+        ensure!(payee != payer.username, "Can't place a hold for yourself");
+        let now = chrono::Utc::now().naive_utc();
+        ensure!(expires_at > now, "Expiry must be in the future");
+        let mut state = self.lock();
+        state.expire_holds(Some(&payer.username), now);
+        if state.user(&payer.username)?.available() < amount {
+            bail!("Insufficient balance")
+        }
+        state.user(payee)?;
+        state.check_policy(&self.policy, &payer.username, payee, amount)?;
+        let hold = Hold {
+            id: state.holds.len() as i32 + 1,
+            payer: payer.username.clone(),
+            payee: payee.to_string(),
+            amount,
+            memo: memo.map(str::to_string),
+            status: HOLD_ACTIVE.to_string(),
+            created_at: now,
+            expires_at,
+            resolved_at: None,
+            transaction_id: None,
+        };
+        state.holds.push(hold.clone());
+        state.sync_held(&payer.username);
+        *payer = state.user(&payer.username)?.clone();
+        Ok(hold)
+    }
+
+    fn capture_hold(&self, payee: &User, id: i32, amount: i32) -> Result<Transaction> {
+        ensure!(amount > 0, "Amount must be positive");
+        let now = chrono::Utc::now().naive_utc();
+        let mut state = self.lock();
+        let hold = state.hold_mut(id)?.clone();
+        hold.check_capturable(&payee.username, now)?;
+        ensure!(
+            amount <= hold.amount,
+            "Can't capture more than the {} held",
+            hold.amount
+        );
+
+        state.hold_mut(id)?.status = HOLD_CAPTURED.to_string();
+        state.sync_held(&hold.payer);
+        let capture_key = hold.capture_key();
+        let result = state.transfer(
+            &self.policy,
+            &NewTransaction::completed(
+                &hold.payer,
+                &hold.payee,
+                amount,
+                hold.memo.as_deref(),
+                Some(&capture_key),
+            ),
+        );
+        let stored = state.hold_mut(id)?;
+        match &result {
+            Ok(record) => {
+                stored.resolved_at = Some(now);
+                stored.transaction_id = Some(record.id);
+            }
+            Err(_) => stored.status = HOLD_ACTIVE.to_string(),
+        }
+        state.sync_held(&hold.payer);
+        result
+    }
+
+    fn void_hold(&self, payee: &User, id: i32) -> Result<Hold> {
+        let mut state = self.lock();
+        let hold = state.hold_mut(id)?;
+        hold.check_active(&payee.username)?;
+        hold.status = HOLD_VOIDED.to_string();
+        hold.resolved_at = Some(chrono::Utc::now().naive_utc());
+        let hold = hold.clone();
+        state.sync_held(&hold.payer);
+        Ok(hold)
+    }
+
+    fn retrieve_hold(&self, id: i32) -> Result<Hold> {
+        self.lock().hold_mut(id).cloned()
+    }
+
+    fn list_holds(
+        &self,
+        username: &str,
+        direction: Option<Direction>,
+        status: Option<&str>,
+    ) -> Result<Vec<Hold>> {
+        let state = self.lock();
+        let holds = state
+            .holds
+            .iter()
+            .rev()
+            .filter(|hold| match direction {
+                Some(Direction::Outgoing) => hold.payer == username,
+                Some(Direction::Incoming) => hold.payee == username,
+                None => hold.payer == username || hold.payee == username,
+            })
+            .filter(|hold| status.is_none_or(|status| hold.status == status))
+            .cloned()
+            .collect();
+        Ok(holds)
+    }
+
+    fn expire_holds(&self, now: NaiveDateTime) -> Result<usize> {
+        Ok(self.lock().expire_holds(None, now))
+    }
+
+    fn reconcile(&self, repair: bool) -> Result<Vec<Discrepancy>> {
+        let mut state = self.lock();
+        let found = reconcile::check(&state.snapshot());
+        if repair {
+            for discrepancy in &found {
+                state.repair(discrepancy);
+            }
+        }
+        Ok(found)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trading_core::types::{AUDIT_OK, ISSUANCE_ACCOUNT, REQUEST_DECLINED, ROLE_ADMIN};
+
+    fn storage_with(users: &[&str]) -> MemoryStorage {
+        let storage = MemoryStorage::new(TransferPolicy::default());
+        for username in users {
+            storage
+                .insert_user(&User::new(username.to_string(), "hash".to_string(), 100))
+                .unwrap();
+        }
+        storage
+    }
+
+    #[test]
+    fn test_crud() {
+        let storage = storage_with(&["alice"]);
+        assert!(storage
+            .insert_user(&User::new("alice".to_string(), "other".to_string(), 5))
+            .is_err());
+
+        let mut alice = storage.retrieve_user("alice").unwrap();
+        alice.balance = 50;
+        alice.password_hash = Some("new".to_string());
+        storage.update_password(&alice).unwrap();
+        let stored = storage.retrieve_user("alice").unwrap();
+        assert_eq!(stored.balance, 100);
+        assert_eq!(stored.password_hash.as_deref(), Some("new"));
+
+        storage.update_user(&alice).unwrap();
+        assert_eq!(storage.retrieve_user("alice").unwrap(), alice);
+        storage.delete_user(&alice).unwrap();
+        assert!(storage.retrieve_user("alice").is_err());
+    }
+
+    #[test]
+    fn test_transfer() {
+        let storage = storage_with(&["alice", "bob", "carol"]);
+        let mut alice = storage.retrieve_user("alice").unwrap();
+        let mut bob = storage.retrieve_user("bob").unwrap();
+
+        storage.transfer(&mut bob, "alice", 50, None, None).unwrap();
+        let record = storage
+            .transfer(&mut alice, "carol", 120, Some("rent"), Some("key"))
+            .unwrap();
+        assert_eq!(alice.balance, 30);
+        assert_eq!(
+            storage
+                .transfer(&mut alice, "carol", 120, Some("rent"), Some("key"))
+                .unwrap(),
+            record
+        );
+        assert_eq!(storage.retrieve_user("carol").unwrap().balance, 220);
+
+        assert!(storage.transfer(&mut alice, "bob", 31, None, None).is_err());
+        assert!(storage
+            .transfer(&mut alice, "nobody", 1, None, None)
+            .is_err());
+        assert!(storage
+            .transfer(&mut alice, "alice", 1, None, None)
+            .is_err());
+        assert!(storage.transfer(&mut alice, "bob", 0, None, None).is_err());
+        assert_eq!(storage.retrieve_user("alice").unwrap().balance, 30);
+
+        let history = storage
+            .list_transactions(&alice, &TransactionFilter::default())
+            .unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0], record);
+        let summary = storage.summarize_spending(&alice, None, None).unwrap();
+        assert_eq!(summary.total_out, 120);
+        assert_eq!(summary.total_in, 50);
+    }
+
+    #[test]
+    fn test_daily_limit() {
+        let storage = MemoryStorage::new(TransferPolicy {
+            daily_limit: Some(50),
+            ..Default::default()
+        });
+        for username in ["alice", "bob"] {
+            storage
+                .insert_user(&User::new(username.to_string(), "hash".to_string(), 100))
+                .unwrap();
+        }
+        let mut alice = storage.retrieve_user("alice").unwrap();
+        storage.transfer(&mut alice, "bob", 40, None, None).unwrap();
+        assert!(storage.check_transfer_policy(&alice, "bob", 10).is_ok());
+        assert!(storage.check_transfer_policy(&alice, "bob", 11).is_err());
+        assert!(storage.transfer(&mut alice, "bob", 11, None, None).is_err());
+        assert_eq!(alice.balance, 60);
+    }
+
+    #[test]
+    fn test_sessions() {
+        let storage = storage_with(&["alice"]);
+        let now = chrono::Utc::now().naive_utc();
+        let ttl = chrono::Duration::hours(1);
+        let first = Session::new("t1".to_string(), "alice".to_string(), now, ttl);
+        let second = Session::new("t2".to_string(), "alice".to_string(), now, ttl);
+        storage.insert_session(&first).unwrap();
+        storage.insert_session(&second).unwrap();
+
+        storage.revoke_session(&first, now).unwrap();
+        assert_eq!(
+            storage.retrieve_session("t1").unwrap().revoked_at,
+            Some(now)
+        );
+        assert_eq!(storage.revoke_all_sessions("alice", now).unwrap(), 1);
+        assert!(storage.retrieve_session("t2").unwrap().revoked_at.is_some());
+        assert!(storage.retrieve_session("t3").is_err());
+    }
+
+    #[test]
+    fn test_password_reset() {
+        let storage = storage_with(&["alice"]);
+        let now = chrono::Utc::now().naive_utc();
+        let ttl = chrono::Duration::hours(1);
+        storage
+            .insert_session(&Session::new(
+                "t1".to_string(),
+                "alice".to_string(),
+                now,
+                ttl,
+            ))
+            .unwrap();
+        storage
+            .insert_password_reset(&PasswordReset::new(
+                "r1".to_string(),
+                "alice".to_string(),
+                now,
+                ttl,
+            ))
+            .unwrap();
+
+        let user = storage.redeem_password_reset("r1", "reset", now).unwrap();
+        assert_eq!(user.password_hash.as_deref(), Some("reset"));
+        assert!(storage.retrieve_session("t1").unwrap().revoked_at.is_some());
+        assert!(storage.redeem_password_reset("r1", "again", now).is_err());
+        assert!(storage.redeem_password_reset("r2", "unknown", now).is_err());
+    }
+
+    #[test]
+    fn test_admin_controls() {
+        let storage = storage_with(&["alice", "bob"]);
+        assert_eq!(storage.adjust_balance("alice", 50).unwrap().balance, 150);
+        let err = storage.adjust_balance("alice", -200).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Can't burn 200 from alice, only 150 available"
+        );
+        let err = storage.adjust_balance("alice", i32::MIN).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Can't burn 2147483648 from alice, only 150 available"
+        );
+        let err = storage.adjust_balance("alice", i32::MAX).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Can't mint {} into alice, the balance would overflow",
+                i32::MAX
+            )
+        );
+        assert_eq!(storage.retrieve_user("alice").unwrap().balance, 150);
+
+        // A full account takes no transfer, and the sender keeps the money.
+        storage.adjust_balance("bob", i32::MAX - 100).unwrap();
+        let mut alice = storage.retrieve_user("alice").unwrap();
+        let err = storage
+            .transfer(&mut alice, "bob", 10, None, None)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Can't credit 10 to bob, the balance would overflow"
+        );
+        assert_eq!(storage.retrieve_user("alice").unwrap().balance, 150);
+        assert_eq!(storage.retrieve_user("bob").unwrap().balance, i32::MAX);
+        storage.adjust_balance("bob", -(i32::MAX - 100)).unwrap();
+        assert_eq!(
+            storage.set_role("alice", ROLE_ADMIN).unwrap().role,
+            ROLE_ADMIN
+        );
+
+        let now = chrono::Utc::now().naive_utc();
+        storage.set_frozen("bob", Some(now)).unwrap();
+        let mut alice = storage.retrieve_user("alice").unwrap();
+        let mut bob = storage.retrieve_user("bob").unwrap();
+        assert!(storage.transfer(&mut alice, "bob", 10, None, None).is_err());
+        assert!(storage.transfer(&mut bob, "alice", 10, None, None).is_err());
+        storage.set_frozen("bob", None).unwrap();
+        storage.transfer(&mut alice, "bob", 10, None, None).unwrap();
+        assert_eq!(alice.balance, 140);
+    }
+
+    #[test]
+    fn test_contacts() {
+        let storage = storage_with(&["alice", "bob"]);
+        let mom = Contact {
+            owner: "alice".to_string(),
+            alias: "mom".to_string(),
+            username: "bob".to_string(),
+            note: None,
+            created_at: chrono::Utc::now().naive_utc(),
+        };
+        storage.insert_contact(&mom).unwrap();
+        assert!(storage.insert_contact(&mom).is_err());
+        assert_eq!(
+            storage.retrieve_contact("alice", "mom").unwrap(),
+            Some(mom.clone())
+        );
+        assert_eq!(storage.list_contacts("alice").unwrap(), [mom]);
+        assert_eq!(storage.retrieve_contact("bob", "mom").unwrap(), None);
+
+        storage.delete_contact("alice", "mom").unwrap();
+        assert!(storage.delete_contact("alice", "mom").is_err());
+        assert!(storage.list_contacts("alice").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_payment_requests() {
+        let storage = storage_with(&["alice", "bob"]);
+        let now = chrono::Utc::now().naive_utc();
+        let request = storage
+            .insert_payment_request(&NewPaymentRequest {
+                requester: "alice",
+                payer: "bob",
+                amount: 10,
+                memo: None,
+                status: REQUEST_PENDING,
+                created_at: now,
+            })
+            .unwrap();
+        let incoming = storage
+            .list_payment_requests("bob", Some(Direction::Incoming), Some(REQUEST_PENDING))
+            .unwrap();
+        assert_eq!(incoming, vec![request.clone()]);
+
+        assert!(storage
+            .resolve_payment_request(request.id, REQUEST_DECLINED, None, now)
+            .unwrap());
+        assert!(!storage
+            .resolve_payment_request(request.id, REQUEST_DECLINED, None, now)
+            .unwrap());
+        // A declined request can't be paid after all.
+        let mut bob = storage.retrieve_user("bob").unwrap();
+        assert!(storage
+            .accept_payment_request(&mut bob, request.id, now)
+            .is_err());
+        assert_eq!(bob.balance, 100);
+        let declined = storage.retrieve_payment_request(request.id).unwrap();
+        assert_eq!(declined.status, REQUEST_DECLINED);
+
+        let second = storage
+            .insert_payment_request(&NewPaymentRequest {
+                requester: "alice",
+                payer: "bob",
+                amount: 10,
+                memo: None,
+                status: REQUEST_PENDING,
+                created_at: now,
+            })
+            .unwrap();
+        let record = storage
+            .accept_payment_request(&mut bob, second.id, now)
+            .unwrap();
+        assert_eq!(bob.balance, 90);
+        let accepted = storage.retrieve_payment_request(second.id).unwrap();
+        assert_eq!(accepted.status, REQUEST_ACCEPTED);
+        assert_eq!(accepted.transaction_id, Some(record.id));
+        assert!(storage
+            .accept_payment_request(&mut bob, second.id, now)
+            .is_err());
+        assert!(storage.retrieve_payment_request(3).is_err());
+    }
+
+    #[test]
+    fn test_scheduled_transfers() {
+        let storage = storage_with(&["alice", "bob"]);
+        let now = chrono::Utc::now().naive_utc();
+        let schedule = storage
+            .insert_scheduled_transfer(&NewScheduledTransfer {
+                sender: "alice",
+                receiver: "bob",
+                amount: 10,
+                memo: None,
+                recurrence: None,
+                next_run_at: now,
+                status: SCHEDULE_ACTIVE,
+                created_at: now,
+                first_run_at: now,
+            })
+            .unwrap();
+        assert_eq!(
+            storage.due_scheduled_transfers(now).unwrap(),
+            vec![schedule.clone()]
+        );
+        assert!(storage
+            .due_scheduled_transfers(now - chrono::Duration::seconds(1))
+            .unwrap()
+            .is_empty());
+
+        let mut ran = schedule.clone();
+        ran.failures = 1;
+        assert!(storage.update_scheduled_transfer(&ran, now).unwrap());
+        assert!(storage.cancel_scheduled_transfer(schedule.id).unwrap());
+        assert!(!storage.update_scheduled_transfer(&ran, now).unwrap());
+        let cancelled = storage.list_scheduled_transfers("alice").unwrap();
+        assert_eq!(cancelled[0].status, SCHEDULE_CANCELLED);
+        assert_eq!(cancelled[0].failures, 1);
+    }
+
+    #[test]
+    fn test_holds() {
+        let storage = storage_with(&["alice", "bob"]);
+        let mut alice = storage.retrieve_user("alice").unwrap();
+        let bob = storage.retrieve_user("bob").unwrap();
+        let now = chrono::Utc::now().naive_utc();
+        let hold = storage
+            .authorize_hold(
+                &mut alice,
+                "bob",
+                60,
+                None,
+                now + chrono::Duration::hours(1),
+            )
+            .unwrap();
+        assert_eq!(alice.available(), 40);
+        assert!(storage.transfer(&mut alice, "bob", 50, None, None).is_err());
+
+        assert!(storage.capture_hold(&alice, hold.id, 60).is_err());
+        assert!(storage.capture_hold(&bob, hold.id, 61).is_err());
+        let record = storage.capture_hold(&bob, hold.id, 45).unwrap();
+        assert_eq!(record.amount, 45);
+        let alice = storage.retrieve_user("alice").unwrap();
+        assert_eq!((alice.balance, alice.held), (55, 0));
+        let captured = storage.retrieve_hold(hold.id).unwrap();
+        assert_eq!(captured.status, HOLD_CAPTURED);
+        assert_eq!(captured.transaction_id, Some(record.id));
+
+        let mut alice = alice;
+        let hold = storage
+            .authorize_hold(
+                &mut alice,
+                "bob",
+                20,
+                None,
+                now + chrono::Duration::hours(1),
+            )
+            .unwrap();
+        assert_eq!(
+            storage.void_hold(&bob, hold.id).unwrap().status,
+            HOLD_VOIDED
+        );
+        assert!(storage.void_hold(&bob, hold.id).is_err());
+
+        storage
+            .authorize_hold(
+                &mut alice,
+                "bob",
+                30,
+                None,
+                now + chrono::Duration::hours(1),
+            )
+            .unwrap();
+        assert_eq!(
+            storage
+                .expire_holds(now + chrono::Duration::hours(2))
+                .unwrap(),
+            1
+        );
+        assert_eq!(storage.retrieve_user("alice").unwrap().held, 0);
+        assert_eq!(
+            storage
+                .list_holds("alice", Some(Direction::Outgoing), Some(HOLD_EXPIRED))
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_refunds() {
+        let storage = storage_with(&["alice", "bob"]);
+        let mut alice = storage.retrieve_user("alice").unwrap();
+        let mut bob = storage.retrieve_user("bob").unwrap();
+        let original = storage.transfer(&mut alice, "bob", 50, None, None).unwrap();
+
+        assert!(storage
+            .refund(&mut alice, original.id, 10, None, None)
+            .is_err());
+        let partial = storage
+            .refund(&mut bob, original.id, 20, None, None)
+            .unwrap();
+        assert_eq!(partial.refund_of, Some(original.id));
+        assert!(storage
+            .refund(&mut bob, original.id, 31, None, None)
+            .is_err());
+        assert!(storage
+            .refund(&mut alice, partial.id, 10, None, None)
+            .is_err());
+        storage
+            .refund(&mut bob, original.id, 30, None, None)
+            .unwrap();
+        assert_eq!(bob.balance, 100);
+        assert_eq!(storage.list_refunds(original.id).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_refunds_skip_policy() {
+        let storage = MemoryStorage::new(TransferPolicy {
+            daily_limit: Some(60),
+            blocklist: ["alice".to_string()].into(),
+            ..Default::default()
+        });
+        for username in ["alice", "bob", "carol"] {
+            storage
+                .insert_user(&User::new(username.to_string(), "hash".to_string(), 100))
+                .unwrap();
+        }
+        let mut alice = storage.retrieve_user("alice").unwrap();
+        let mut bob = storage.retrieve_user("bob").unwrap();
+        let original = storage.transfer(&mut alice, "bob", 50, None, None).unwrap();
+        storage.transfer(&mut bob, "carol", 55, None, None).unwrap();
+
+        // Past the daily limit of bob, and to a blocked account.
+        assert!(storage.check_transfer_policy(&bob, "alice", 50).is_err());
+        storage
+            .refund(&mut bob, original.id, 50, None, None)
+            .unwrap();
+        assert_eq!(storage.retrieve_user("alice").unwrap().balance, 100);
+    }
+
+    #[test]
+    fn test_close_account() {
+        let storage = storage_with(&["alice", "bob"]);
+        let mut alice = storage.retrieve_user("alice").unwrap();
+        let mut bob = storage.retrieve_user("bob").unwrap();
+        let now = chrono::Utc::now().naive_utc();
+        let request = storage
+            .insert_payment_request(&NewPaymentRequest {
+                requester: "bob",
+                payer: "alice",
+                amount: 10,
+                memo: None,
+                status: REQUEST_PENDING,
+                created_at: now,
+            })
+            .unwrap();
+
+        assert!(storage.close_user(&mut alice, None, now).is_err());
+        let sweep = storage
+            .close_user(&mut alice, Some("bob"), now)
+            .unwrap()
+            .unwrap();
+        assert_eq!(sweep.amount, 100);
+        assert_eq!((alice.balance, alice.closed_at), (0, Some(now)));
+        assert!(!storage.list_usernames().unwrap().contains(&alice.username));
+        assert_eq!(
+            storage.retrieve_payment_request(request.id).unwrap().status,
+            REQUEST_CANCELLED
+        );
+        assert!(storage.transfer(&mut bob, "alice", 10, None, None).is_err());
+        assert!(storage.close_user(&mut alice, None, now).is_err());
+    }
+
+    #[test]
+    fn test_close_account_policy() {
+        let storage = MemoryStorage::new(TransferPolicy {
+            max_amount: Some(50),
+            daily_limit: Some(60),
+            blocklist: ["mallory".to_string()].into(),
+            ..Default::default()
+        });
+        for username in ["alice", "bob", "mallory"] {
+            storage
+                .insert_user(&User::new(username.to_string(), "hash".to_string(), 100))
+                .unwrap();
+        }
+        let mut alice = storage.retrieve_user("alice").unwrap();
+        let now = chrono::Utc::now().naive_utc();
+        storage.transfer(&mut alice, "bob", 40, None, None).unwrap();
+
+        let err = storage
+            .close_user(&mut alice, Some("mallory"), now)
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("Can't sweep the balance to mallory"));
+        assert_eq!(alice.closed_at, None);
+        let sweep = storage
+            .close_user(&mut alice, Some("bob"), now)
+            .unwrap()
+            .unwrap();
+        assert_eq!(sweep.amount, 60);
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let storage = storage_with(&[]);
+        let now = chrono::Utc::now().naive_utc();
+        for (username, function_name) in
+            [("alice", "login"), ("bob", "login"), ("alice", "transfer")]
+        {
+            storage
+                .insert_audit_record(&NewAuditRecord {
+                    session_id: "s1",
+                    username: Some(username),
+                    function_name,
+                    arguments: "{}",
+                    outcome: AUDIT_OK,
+                    result: "",
+                    model: "test",
+                    created_at: now,
+                })
+                .unwrap();
+        }
+        let alice = AuditFilter {
+            username: Some("alice".to_string()),
+            offset: Some(1),
+            ..Default::default()
+        };
+        let records = storage.list_audit_records(&alice).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].function_name, "transfer");
+        assert_eq!(
+            storage
+                .list_audit_records(&AuditFilter::default())
+                .unwrap()
+                .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn test_postings_balance() {
+        let storage = storage_with(&["alice", "bob"]);
+        let mut alice = storage.retrieve_user("alice").unwrap();
+        storage.transfer(&mut alice, "bob", 30, None, None).unwrap();
+        storage.adjust_balance("bob", 20).unwrap();
+        storage.adjust_balance("alice", -10).unwrap();
+
+        assert_eq!(
+            storage.account_balances().unwrap(),
+            [
+                ("alice".to_string(), 60),
+                ("bob".to_string(), 150),
+                (ISSUANCE_ACCOUNT.to_string(), -210),
+            ]
+        );
+        assert_eq!(storage.list_postings("bob").unwrap().len(), 3);
+        assert_eq!(storage.list_postings(ISSUANCE_ACCOUNT).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let storage = storage_with(&["alice", "bob"]);
+        assert!(storage.reconcile(false).unwrap().is_empty());
+        let mut alice = storage.retrieve_user("alice").unwrap();
+        alice.balance = 90;
+        alice.held = 5;
+        storage.update_user(&alice).unwrap();
+
+        let found = storage.reconcile(false).unwrap();
+        assert_eq!(found.len(), 2);
+        assert_eq!(storage.retrieve_user("alice").unwrap().balance, 90);
+        assert_eq!(storage.reconcile(true).unwrap(), found);
+        assert!(storage.reconcile(false).unwrap().is_empty());
+        let alice = storage.retrieve_user("alice").unwrap();
+        assert_eq!((alice.balance, alice.held), (100, 0));
+    }
+
+    #[test]
+    fn test_transfer_batch() {
+        let storage = storage_with(&["alice", "bob", "carol"]);
+        let mut alice = storage.retrieve_user("alice").unwrap();
+        let now = chrono::Utc::now().naive_utc();
+        storage.set_frozen("carol", Some(now)).unwrap();
+        let records = [
+            NewTransaction::completed("alice", "bob", 30, None, Some("batch:1")),
+            NewTransaction::completed("alice", "carol", 20, Some("bonus"), Some("batch:2")),
+        ];
+
+        let e = storage.transfer_batch(&mut alice, &records).unwrap_err();
+        assert_eq!(e.downcast_ref::<BatchFailed>().unwrap().index, 1);
+        assert_eq!(storage.retrieve_user("bob").unwrap().balance, 100);
+        assert_eq!(storage.list_postings("alice").unwrap().len(), 1);
+
+        storage.set_frozen("carol", None).unwrap();
+        let transfers = storage.transfer_batch(&mut alice, &records).unwrap();
+        assert_eq!(transfers.len(), 2);
+        assert_eq!(alice.balance, 50);
+        assert_eq!(
+            storage.transfer_batch(&mut alice, &records).unwrap(),
+            transfers
+        );
+        assert_eq!(storage.retrieve_user("alice").unwrap().balance, 50);
+    }
+}
@@ -0,0 +1,348 @@
+use std::fmt;
+
+use anyhow::{bail, Result};
+
+use super::types::Transaction;
+use crate::global;
+
+/// Where a row of a payout batch stands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RowStatus {
+    /// Passed every check, not paid yet.
+    Valid,
+    /// Paid by this transfer.
+    Paid(i32),
+    Rejected(String),
+    /// Valid, but not paid because another row failed.
+    RolledBack,
+}
+
+/// One `recipient,amount,memo` record of a payout file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PayoutRow {
+    /// Line of the file the record starts on.
+    pub line: usize,
+    pub recipient: String,
+    /// 0 when the amount doesn't parse.
+    pub amount: i32,
+    pub memo: Option<String>,
+    pub status: RowStatus,
+}
+
+impl PayoutRow {
+    /// Rejects a still valid row if `check` fails.
+    pub fn check(&mut self, check: impl FnOnce(&str, i32) -> Result<()>) {
+        if self.status == RowStatus::Valid {
+            if let Err(e) = check(&self.recipient, self.amount) {
+                self.status = RowStatus::Rejected(e.to_string());
+            }
+        }
+    }
+}
+
+impl fmt::Display for PayoutRow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Line {}: {} to {}",
+            self.line, self.amount, self.recipient
+        )?;
+        if let Some(memo) = &self.memo {
+            write!(f, " (memo: {})", memo)?;
+        }
+        match &self.status {
+            RowStatus::Valid => write!(f, ", valid"),
+            RowStatus::Paid(id) => write!(f, ", paid as transfer #{}", id),
+            RowStatus::Rejected(reason) => write!(f, ", rejected: {}", reason),
+            RowStatus::RolledBack => write!(f, ", not paid"),
+        }
+    }
+}
+
+/// Paying many users at once from a CSV file. Either every row is paid or
+/// none is; the rows double as the per-row report.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PayoutBatch {
+    pub rows: Vec<PayoutRow>,
+    /// What keeps the batch as a whole from being paid, like a total above
+    /// the available balance.
+    pub problem: Option<String>,
+}
+
+impl PayoutBatch {
+    /// Reads `recipient,amount[,memo]` records, with an optional header row.
+    /// Fields may be quoted as in RFC 4180 and blank lines are skipped. Rows
+    /// that don't parse are kept as rejected, so every line gets reported.
+    pub fn parse(csv: &str) -> Result<PayoutBatch> {
+        let mut records = parse_csv(csv);
+        let is_header = |fields: &[String]| {
+            let names = fields
+                .iter()
+                .map(|field| field.trim().to_lowercase())
+                .collect::<Vec<_>>();
+            names.starts_with(&["recipient".to_string(), "amount".to_string()])
+        };
+        if records.first().is_some_and(|(_, fields)| is_header(fields)) {
+            records.remove(0);
+        }
+        if records.is_empty() {
+            bail!("The payout file has no rows")
+        }
+        if records.len() > global::PAYOUT_MAX_ROWS {
+            bail!(
+                "The payout file has {} rows, at most {} are allowed",
+                records.len(),
+                global::PAYOUT_MAX_ROWS
+            )
+        }
+
+        let rows = records
+            .into_iter()
+            .map(|(line, fields)| {
+                let field = |i: usize| fields.get(i).map(|field| field.trim()).unwrap_or("");
+                let amount = field(1).parse::<i32>();
+                let status = match &amount {
+                    _ if !(2..=3).contains(&fields.len()) => RowStatus::Rejected(format!(
+                        "Expected recipient,amount,memo but found {} fields",
+                        fields.len()
+                    )),
+                    _ if field(0).is_empty() => RowStatus::Rejected("Missing recipient".into()),
+                    Err(_) => RowStatus::Rejected(format!("Invalid amount \"{}\"", field(1))),
+                    Ok(amount) if *amount <= 0 => {
+                        RowStatus::Rejected("Amount must be positive".into())
+                    }
+                    Ok(_) => RowStatus::Valid,
+                };
+                PayoutRow {
+                    line,
+                    recipient: field(0).to_string(),
+                    amount: amount.unwrap_or(0),
+                    memo: Some(field(2))
+                        .filter(|memo| !memo.is_empty())
+                        .map(str::to_string),
+                    status,
+                }
+            })
+            .collect();
+        Ok(PayoutBatch {
+            rows,
+            problem: None,
+        })
+    }
+
+    /// The sum of the amounts, rejected rows included.
+    pub fn total(&self) -> i64 {
+        self.rows.iter().map(|row| i64::from(row.amount)).sum()
+    }
+
+    /// Sets the problem if the batch needs more than `available`.
+    pub fn check_total(&mut self, available: i32) {
+        let total = self.total();
+        if total > i64::from(available) {
+            self.problem = Some(format!(
+                "The total of {} exceeds the available balance of {}",
+                total, available
+            ));
+        }
+    }
+
+    /// Whether the batch can be paid as it is.
+    pub fn is_valid(&self) -> bool {
+        self.problem.is_none() && self.rows.iter().all(|row| row.status == RowStatus::Valid)
+    }
+
+    /// Whether every row has been paid.
+    pub fn is_paid(&self) -> bool {
+        self.rows
+            .iter()
+            .all(|row| matches!(row.status, RowStatus::Paid(_)))
+    }
+
+    /// Marks every row paid by the transfer at the same position.
+    pub fn paid(&mut self, transfers: &[Transaction]) {
+        for (row, transfer) in self.rows.iter_mut().zip(transfers) {
+            row.status = RowStatus::Paid(transfer.id);
+        }
+    }
+
+    /// Marks the row at `index` rejected for `reason`, and the others as not
+    /// paid since the whole batch was rolled back.
+    pub fn failed(&mut self, index: usize, reason: &str) {
+        for (i, row) in self.rows.iter_mut().enumerate() {
+            row.status = if i == index {
+                RowStatus::Rejected(reason.to_string())
+            } else {
+                RowStatus::RolledBack
+            };
+        }
+    }
+}
+
+impl fmt::Display for PayoutBatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for row in &self.rows {
+            writeln!(f, "{}", row)?;
+        }
+        match &self.problem {
+            Some(problem) => write!(f, "{}. Nothing was paid", problem),
+            None if self.is_paid() => {
+                write!(f, "Paid {} to {} recipients", self.total(), self.rows.len())
+            }
+            None if self.is_valid() => write!(
+                f,
+                "{} in total to {} recipients, nothing paid yet",
+                self.total(),
+                self.rows.len()
+            ),
+            None => write!(f, "Nothing was paid"),
+        }
+    }
+}
+
+/// Returned by a batch transfer when one of its transfers fails, after
+/// everything was rolled back. Kept as the error so callers can downcast it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchFailed {
+    /// Position of the failed transfer in the batch.
+    pub index: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for BatchFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Transfer {} of the batch failed, nothing was transferred: {}",
+            self.index + 1,
+            self.reason
+        )
+    }
+}
+
+impl std::error::Error for BatchFailed {}
+
+/// The records of `csv` with the line each starts on, blank lines skipped.
+fn parse_csv(csv: &str) -> Vec<(usize, Vec<String>)> {
+    let mut records = Vec::new();
+    let (mut fields, mut field) = (Vec::new(), String::new());
+    let (mut line, mut start) = (1, 1);
+    let mut quoted = false;
+    let mut chars = csv.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' if quoted => quoted = false,
+            '"' if field.trim().is_empty() => {
+                field.clear();
+                quoted = true;
+            }
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+            '\n' if !quoted => {
+                fields.push(std::mem::take(&mut field));
+                if fields.iter().any(|field| !field.trim().is_empty()) {
+                    records.push((start, std::mem::take(&mut fields)));
+                }
+                fields.clear();
+                line += 1;
+                start = line;
+            }
+            c => {
+                if c == '\n' {
+                    line += 1;
+                }
+                field.push(c);
+            }
+        }
+    }
+    fields.push(field);
+    if fields.iter().any(|field| !field.trim().is_empty()) {
+        records.push((start, fields));
+    }
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let csv = "Recipient,Amount,Memo\r\n\
+                   bob,30,June\r\n\
+                   \r\n\
+                   carol, 20 ,\"rent, \"\"flat\"\"\nsecond line\"\r\n\
+                   dave,x\n\
+                   erin,-5\n\
+                   ,5\n\
+                   frank,1,a,b\n\
+                   gina,7";
+        let batch = PayoutBatch::parse(csv).unwrap();
+        let summary = batch
+            .rows
+            .iter()
+            .map(|row| (row.line, row.recipient.as_str(), row.amount, &row.status))
+            .collect::<Vec<_>>();
+        let rejected = |reason: &str| RowStatus::Rejected(reason.to_string());
+        assert_eq!(
+            summary,
+            [
+                (2, "bob", 30, &RowStatus::Valid),
+                (4, "carol", 20, &RowStatus::Valid),
+                (6, "dave", 0, &rejected("Invalid amount \"x\"")),
+                (7, "erin", -5, &rejected("Amount must be positive")),
+                (8, "", 5, &rejected("Missing recipient")),
+                (
+                    9,
+                    "frank",
+                    1,
+                    &rejected("Expected recipient,amount,memo but found 4 fields")
+                ),
+                (10, "gina", 7, &RowStatus::Valid),
+            ]
+        );
+        assert_eq!(batch.rows[0].memo.as_deref(), Some("June"));
+        assert_eq!(
+            batch.rows[1].memo.as_deref(),
+            Some("rent, \"flat\"\nsecond line")
+        );
+        assert_eq!(batch.rows[6].memo, None);
+        assert!(!batch.is_valid());
+
+        assert!(PayoutBatch::parse("recipient,amount\n\n").is_err());
+        let too_many = "bob,1\n".repeat(global::PAYOUT_MAX_ROWS + 1);
+        assert!(PayoutBatch::parse(&too_many).is_err());
+    }
+
+    #[test]
+    fn test_report() {
+        let mut batch = PayoutBatch::parse("bob,30\ncarol,20,bonus").unwrap();
+        assert!(batch.is_valid());
+        batch.rows[1].check(|_, _| bail!("Username doesn't exist"));
+        assert!(!batch.is_valid());
+        assert_eq!(
+            batch.to_string(),
+            "Line 1: 30 to bob, valid\n\
+             Line 2: 20 to carol (memo: bonus), rejected: Username doesn't exist\n\
+             Nothing was paid"
+        );
+
+        let mut batch = PayoutBatch::parse("bob,30\ncarol,20").unwrap();
+        batch.check_total(40);
+        assert!(!batch.is_valid());
+        assert!(batch
+            .to_string()
+            .ends_with("The total of 50 exceeds the available balance of 40. Nothing was paid"));
+
+        batch.problem = None;
+        batch.failed(1, "Account is frozen");
+        assert_eq!(batch.rows[0].status, RowStatus::RolledBack);
+        assert_eq!(
+            batch.rows[1].status,
+            RowStatus::Rejected("Account is frozen".to_string())
+        );
+    }
+}
@@ -0,0 +1,1927 @@
+use anyhow::{anyhow, bail, ensure, Error, Result};
+
+use chrono::NaiveDateTime;
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, Pool};
+
+use super::payout::BatchFailed;
+use super::policy::{period_starts, OutgoingUsage, TransferPolicy};
+use super::reconcile::{self, Discrepancy, LedgerSnapshot};
+use super::schema::{
+    audit_log, contacts, holds, password_resets, payment_requests, postings, scheduled_transfers,
+    sessions, transactions, users,
+};
+use super::storage::Storage;
+use super::types::{
+    AuditFilter, AuditRecord, Contact, Direction, Hold, NewAuditRecord, NewHold, NewPaymentRequest,
+    NewPosting, NewScheduledTransfer, NewTransaction, PasswordReset, PaymentRequest, Posting,
+    ScheduledTransfer, Session, SpendingSummary, Transaction, TransactionFilter, User,
+    DEFAULT_PAGE_SIZE, HOLD_ACTIVE, HOLD_CAPTURED, HOLD_EXPIRED, HOLD_VOIDED, REQUEST_ACCEPTED,
+    REQUEST_CANCELLED, REQUEST_PENDING, SCHEDULE_ACTIVE, SCHEDULE_CANCELLED, STATUS_COMPLETED,
+};
+
+/// The diesel PostgreSQL backend, migrated from `migrations_postgres`.
+pub struct PgStorage {
+    pool: Pool<ConnectionManager<PgConnection>>,
+    policy: TransferPolicy,
+}
+
+impl PgStorage {
+    pub fn new(database_url: &str, policy: TransferPolicy) -> Result<PgStorage> {
+        let manager = ConnectionManager::<PgConnection>::new(database_url);
+        let pool = Pool::builder().build(manager)?;
+        Ok(PgStorage { pool, policy })
+    }
+}
+
+impl Storage for PgStorage {
+    fn insert_user(&self, user: &User) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        if check_existence(&user.username, &mut conn)? {
+            bail!("Username already exists")
+        }
+        conn.transaction::<_, Error, _>(|conn| {
+            diesel::insert_into(users::table)
+                .values(user)
+                .execute(conn)?;
+            if user.balance > 0 {
+                let now = chrono::Utc::now().naive_utc();
+                insert_posting(&NewPosting::grant(&user.username, user.balance, now), conn)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn retrieve_user(&self, username: &str) -> Result<User> {
+        let mut conn = self.pool.get()?;
+        retrieve_user(username, &mut conn)
+    }
+
+    fn list_usernames(&self) -> Result<Vec<String>> {
+        let mut conn = self.pool.get()?;
+        let usernames = users::table
+            .filter(users::closed_at.is_null())
+            .order(users::username)
+            .select(users::username)
+            .load(&mut conn)?;
+        Ok(usernames)
+    }
+
+    fn update_user(&self, user: &User) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        diesel::update(users::table)
+            .filter(users::username.eq(&user.username))
+            .set(user)
+            .execute(&mut conn)?;
+        Ok(())
+    }
+
+    fn update_password(&self, user: &User) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        update_password(user, &mut conn)
+    }
+
+    fn adjust_balance(&self, username: &str, amount: i32) -> Result<User> {
+        let mut conn = self.pool.get()?;
+        let now = chrono::Utc::now().naive_utc();
+        conn.transaction::<_, Error, _>(|conn| {
+            lock_users(&[username], conn)?;
+            expire_holds(username, now, conn)?;
+            let adjusted = diesel::update(users::table)
+                .filter(users::username.eq(username))
+                .filter(users::closed_at.is_null())
+                // Split so that neither side can overflow the column type.
+                .filter((users::balance + amount.min(0)).ge(users::held))
+                .filter(users::balance.le(i32::MAX - amount.max(0)))
+                .set(users::balance.eq(users::balance + amount))
+                .execute(conn)?;
+            let user = retrieve_user(username, conn)?;
+            if adjusted == 0 {
+                user.check_open()?;
+                ensure!(
+                    amount < 0,
+                    "Can't mint {} into {}, the balance would overflow",
+                    amount,
+                    username
+                );
+                bail!(
+                    "Can't burn {} from {}, only {} available",
+                    amount.unsigned_abs(),
+                    username,
+                    user.available()
+                )
+            }
+            if amount != 0 {
+                insert_posting(&NewPosting::adjustment(username, amount, now), conn)?;
+            }
+            Ok(user)
+        })
+    }
+
+    fn set_frozen(&self, username: &str, frozen_at: Option<NaiveDateTime>) -> Result<User> {
+        let mut conn = self.pool.get()?;
+        let query = diesel::update(users::table)
+            .filter(users::username.eq(username))
+            .into_boxed();
+        let query = match frozen_at {
+            Some(_) => query.filter(users::frozen_at.is_null()),
+            None => query,
+        };
+        query
+            .set(users::frozen_at.eq(frozen_at))
+            .execute(&mut conn)?;
+        retrieve_user(username, &mut conn)
+    }
+
+    fn set_role(&self, username: &str, role: &str) -> Result<User> {
+        let mut conn = self.pool.get()?;
+        diesel::update(users::table)
+            .filter(users::username.eq(username))
+            .set(users::role.eq(role))
+            .execute(&mut conn)?;
+        retrieve_user(username, &mut conn)
+    }
+
+    fn delete_user(&self, user: &User) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        diesel::delete(users::table)
+            .filter(users::username.eq(&user.username))
+            .execute(&mut conn)?;
+        Ok(())
+    }
+
+    /// Same semantics as the SQLite closure.
+    fn close_user(
+        &self,
+        user: &mut User,
+        sweep_to: Option<&str>,
+        now: NaiveDateTime,
+    ) -> Result<Option<Transaction>> {
+        let mut conn = self.pool.get()?;
+        let username = user.username.clone();
+        let result = conn.transaction::<_, Error, _>(|conn| {
+            lock_users(&[&username], conn)?;
+            retrieve_user(&username, conn)?.check_open()?;
+            expire_holds(&username, now, conn)?;
+            let stored = retrieve_user(&username, conn)?;
+            ensure!(
+                stored.held == 0,
+                "Can't close an account with {} on hold",
+                stored.held
+            );
+            let sweep = match sweep_to {
+                _ if stored.balance == 0 => None,
+                Some(to) => {
+                    self.policy.check_sweep(to)?;
+                    let record = NewTransaction::completed(
+                        &username,
+                        to,
+                        stored.balance,
+                        Some("Account closure"),
+                        None,
+                    );
+                    Some(transfer(user, &record, &self.policy.for_closure(), conn)?)
+                }
+                None => bail!(
+                    "The account still holds {}, name an account to sweep it to",
+                    stored.balance
+                ),
+            };
+
+            diesel::update(payment_requests::table)
+                .filter(
+                    payment_requests::payer
+                        .eq(&username)
+                        .or(payment_requests::requester.eq(&username)),
+                )
+                .filter(payment_requests::status.eq(REQUEST_PENDING))
+                .set((
+                    payment_requests::status.eq(REQUEST_CANCELLED),
+                    payment_requests::resolved_at.eq(now),
+                ))
+                .execute(conn)?;
+            diesel::update(scheduled_transfers::table)
+                .filter(
+                    scheduled_transfers::sender
+                        .eq(&username)
+                        .or(scheduled_transfers::receiver.eq(&username)),
+                )
+                .filter(scheduled_transfers::status.eq(SCHEDULE_ACTIVE))
+                .set(scheduled_transfers::status.eq(SCHEDULE_CANCELLED))
+                .execute(conn)?;
+            // Voided one payer at a time, each row lock taken before its holds.
+            let payers = holds::table
+                .filter(holds::payee.eq(&username))
+                .filter(holds::status.eq(HOLD_ACTIVE))
+                .select(holds::payer)
+                .distinct()
+                .load::<String>(conn)?;
+            for payer in payers {
+                lock_users(&[&payer], conn)?;
+                diesel::update(holds::table)
+                    .filter(holds::payer.eq(&payer))
+                    .filter(holds::payee.eq(&username))
+                    .filter(holds::status.eq(HOLD_ACTIVE))
+                    .set((holds::status.eq(HOLD_VOIDED), holds::resolved_at.eq(now)))
+                    .execute(conn)?;
+                sync_held(&payer, conn)?;
+            }
+            diesel::update(sessions::table)
+                .filter(sessions::username.eq(&username))
+                .filter(sessions::revoked_at.is_null())
+                .set(sessions::revoked_at.eq(now))
+                .execute(conn)?;
+            diesel::update(users::table)
+                .filter(users::username.eq(&username))
+                .set(users::closed_at.eq(now))
+                .execute(conn)?;
+            Ok(sweep)
+        });
+        *user = retrieve_user(&username, &mut conn)?;
+        result
+    }
+
+    fn transfer(
+        &self,
+        sender: &mut User,
+        to_username: &str,
+        amount: i32,
+        memo: Option<&str>,
+        idempotency_key: Option<&str>,
+    ) -> Result<Transaction> {
+        let mut conn = self.pool.get()?;
+        let username = sender.username.clone();
+        let record =
+            NewTransaction::completed(&username, to_username, amount, memo, idempotency_key);
+        transfer(sender, &record, &self.policy, &mut conn)
+    }
+
+    /// Locks the sender and every recipient up front, in username order like
+    /// `transfer` does, so no concurrent transfer can deadlock against it.
+    fn transfer_batch(
+        &self,
+        sender: &mut User,
+        records: &[NewTransaction],
+    ) -> Result<Vec<Transaction>> {
+        let mut conn = self.pool.get()?;
+        let result = conn.transaction::<_, Error, _>(|conn| {
+            let mut usernames = records
+                .iter()
+                .map(|record| record.receiver)
+                .collect::<Vec<_>>();
+            usernames.push(&sender.username);
+            lock_users(&usernames, conn)?;
+            records
+                .iter()
+                .enumerate()
+                .map(|(index, record)| {
+                    transfer(sender, record, &self.policy, conn).map_err(|e| {
+                        anyhow!(BatchFailed {
+                            index,
+                            reason: e.to_string(),
+                        })
+                    })
+                })
+                .collect()
+        });
+        *sender = retrieve_user(&sender.username, &mut conn)?;
+        result
+    }
+
+    fn check_transfer_policy(&self, sender: &User, to_username: &str, amount: i32) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        check_policy(&self.policy, sender, to_username, amount, &mut conn)
+    }
+
+    /// Same semantics as the SQLite refund. Locking the refunder's row
+    /// serializes refunds of one transfer.
+    fn refund(
+        &self,
+        refunder: &mut User,
+        original_id: i32,
+        amount: i32,
+        memo: Option<&str>,
+        idempotency_key: Option<&str>,
+    ) -> Result<Transaction> {
+        let mut conn = self.pool.get()?;
+        let original = retrieve_transaction(original_id, &mut conn)?;
+        original.check_refundable(&refunder.username)?;
+        let username = refunder.username.clone();
+        let record = NewTransaction {
+            refund_of: Some(original_id),
+            ..NewTransaction::completed(&username, &original.sender, amount, memo, idempotency_key)
+        };
+        let result = conn.transaction::<_, Error, _>(|conn| {
+            // In the order `transfer` locks them, which then holds the locks
+            // already: the remaining amount can't change until the refund is in.
+            lock_users(&[&username, &original.sender], conn)?;
+            let refunds = list_refunds(original_id, conn)?;
+            original.check_refund_fits(&refunds, amount, idempotency_key)?;
+            transfer(refunder, &record, &self.policy, conn)
+        });
+        *refunder = retrieve_user(&refunder.username, &mut conn)?;
+        result
+    }
+
+    fn retrieve_transaction(&self, id: i32) -> Result<Transaction> {
+        let mut conn = self.pool.get()?;
+        retrieve_transaction(id, &mut conn)
+    }
+
+    fn list_refunds(&self, original_id: i32) -> Result<Vec<Transaction>> {
+        let mut conn = self.pool.get()?;
+        list_refunds(original_id, &mut conn)
+    }
+
+    fn list_transactions(
+        &self,
+        user: &User,
+        filter: &TransactionFilter,
+    ) -> Result<Vec<Transaction>> {
+        let mut conn = self.pool.get()?;
+        let records = ledger_query(user, filter)
+            .order(transactions::id.desc())
+            .limit(filter.limit.unwrap_or(DEFAULT_PAGE_SIZE))
+            .offset(filter.offset.unwrap_or(0))
+            .select(Transaction::as_select())
+            .load(&mut conn)?;
+        Ok(records)
+    }
+
+    fn summarize_spending(
+        &self,
+        user: &User,
+        since: Option<NaiveDateTime>,
+        until: Option<NaiveDateTime>,
+    ) -> Result<SpendingSummary> {
+        let mut conn = self.pool.get()?;
+        let filter = TransactionFilter {
+            since,
+            until,
+            status: Some(STATUS_COMPLETED.to_string()),
+            ..Default::default()
+        };
+        let records = ledger_query(user, &filter)
+            .select(Transaction::as_select())
+            .load(&mut conn)?;
+        Ok(SpendingSummary::from_ledger(&user.username, &records))
+    }
+
+    fn list_postings(&self, account: &str) -> Result<Vec<Posting>> {
+        let mut conn = self.pool.get()?;
+        let postings = postings::table
+            .filter(
+                postings::debit_account
+                    .eq(account)
+                    .or(postings::credit_account.eq(account)),
+            )
+            .order(postings::id)
+            .select(Posting::as_select())
+            .load(&mut conn)?;
+        Ok(postings)
+    }
+
+    fn account_balances(&self) -> Result<Vec<(String, i64)>> {
+        let mut conn = self.pool.get()?;
+        account_balances(&mut conn)
+    }
+
+    fn insert_session(&self, session: &Session) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        diesel::insert_into(sessions::table)
+            .values(session)
+            .execute(&mut conn)?;
+        Ok(())
+    }
+
+    fn retrieve_session(&self, token: &str) -> Result<Session> {
+        let mut conn = self.pool.get()?;
+        let session = sessions::table
+            .filter(sessions::token.eq(token))
+            .select(Session::as_select())
+            .first(&mut conn)
+            .optional()?;
+        session.ok_or_else(|| anyhow!("Session doesn't exist"))
+    }
+
+    fn touch_session(&self, session: &Session) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        diesel::update(sessions::table)
+            .filter(sessions::token.eq(&session.token))
+            .set(sessions::last_seen_at.eq(session.last_seen_at))
+            .execute(&mut conn)?;
+        Ok(())
+    }
+
+    fn revoke_session(&self, session: &Session, now: NaiveDateTime) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        diesel::update(sessions::table)
+            .filter(sessions::token.eq(&session.token))
+            .filter(sessions::revoked_at.is_null())
+            .set(sessions::revoked_at.eq(now))
+            .execute(&mut conn)?;
+        Ok(())
+    }
+
+    fn revoke_all_sessions(&self, username: &str, now: NaiveDateTime) -> Result<usize> {
+        let mut conn = self.pool.get()?;
+        revoke_sessions(username, now, &mut conn)
+    }
+
+    fn change_password(&self, user: &User, now: NaiveDateTime) -> Result<usize> {
+        let mut conn = self.pool.get()?;
+        conn.transaction::<_, Error, _>(|conn| {
+            update_password(user, conn)?;
+            revoke_sessions(&user.username, now, conn)
+        })
+    }
+
+    fn insert_password_reset(&self, reset: &PasswordReset) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        diesel::insert_into(password_resets::table)
+            .values(reset)
+            .execute(&mut conn)?;
+        Ok(())
+    }
+
+    fn redeem_password_reset(
+        &self,
+        token: &str,
+        password_hash: &str,
+        now: NaiveDateTime,
+    ) -> Result<User> {
+        let mut conn = self.pool.get()?;
+        conn.transaction::<_, Error, _>(|conn| {
+            let username = diesel::update(password_resets::table)
+                .filter(password_resets::token.eq(token))
+                .filter(password_resets::used_at.is_null())
+                .filter(password_resets::expires_at.gt(now))
+                .set(password_resets::used_at.eq(now))
+                .returning(password_resets::username)
+                .get_result::<String>(conn)
+                .optional()?
+                .ok_or_else(|| anyhow!("Reset token is invalid, expired or already used"))?;
+            lock_users(&[&username], conn)?;
+            let mut user = retrieve_user(&username, conn)?;
+            user.check_open()?;
+            user.password.clear();
+            user.password_hash = Some(password_hash.to_string());
+            update_password(&user, conn)?;
+            revoke_sessions(&username, now, conn)?;
+            Ok(user)
+        })
+    }
+
+    fn insert_audit_record(&self, record: &NewAuditRecord) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        diesel::insert_into(audit_log::table)
+            .values(record)
+            .execute(&mut conn)?;
+        Ok(())
+    }
+
+    fn list_audit_records(&self, filter: &AuditFilter) -> Result<Vec<AuditRecord>> {
+        let mut conn = self.pool.get()?;
+        let mut query = audit_log::table.into_boxed();
+        if let Some(username) = &filter.username {
+            query = query.filter(audit_log::username.eq(username));
+        }
+        if let Some(session_id) = &filter.session_id {
+            query = query.filter(audit_log::session_id.eq(session_id));
+        }
+        if let Some(name) = &filter.function_name {
+            query = query.filter(audit_log::function_name.eq(name));
+        }
+        if let Some(since) = filter.since {
+            query = query.filter(audit_log::created_at.ge(since));
+        }
+        if let Some(until) = filter.until {
+            query = query.filter(audit_log::created_at.lt(until));
+        }
+        if let Some(limit) = filter.limit {
+            query = query.limit(limit);
+        }
+        if let Some(offset) = filter.offset {
+            query = query.offset(offset);
+        }
+        let records = query
+            .order(audit_log::id.asc())
+            .select(AuditRecord::as_select())
+            .load(&mut conn)?;
+        Ok(records)
+    }
+
+    fn insert_contact(&self, contact: &Contact) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        match diesel::insert_into(contacts::table)
+            .values(contact)
+            .execute(&mut conn)
+            .map_err(Error::from)
+        {
+            Err(e) if is_unique_violation(&e) => {
+                bail!("You already have a contact named {}", contact.alias)
+            }
+            result => result.map(|_| ()),
+        }
+    }
+
+    fn delete_contact(&self, owner: &str, alias: &str) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        let deleted = diesel::delete(contacts::table)
+            .filter(contacts::owner.eq(owner))
+            .filter(contacts::alias.eq(alias))
+            .execute(&mut conn)?;
+        ensure!(deleted == 1, "No contact named {}", alias);
+        Ok(())
+    }
+
+    fn retrieve_contact(&self, owner: &str, alias: &str) -> Result<Option<Contact>> {
+        let mut conn = self.pool.get()?;
+        let contact = contacts::table
+            .filter(contacts::owner.eq(owner))
+            .filter(contacts::alias.eq(alias))
+            .select(Contact::as_select())
+            .first(&mut conn)
+            .optional()?;
+        Ok(contact)
+    }
+
+    fn list_contacts(&self, owner: &str) -> Result<Vec<Contact>> {
+        let mut conn = self.pool.get()?;
+        let contacts = contacts::table
+            .filter(contacts::owner.eq(owner))
+            .order(contacts::alias)
+            .select(Contact::as_select())
+            .load(&mut conn)?;
+        Ok(contacts)
+    }
+
+    fn insert_payment_request(&self, request: &NewPaymentRequest) -> Result<PaymentRequest> {
+        let mut conn = self.pool.get()?;
+        let inserted = diesel::insert_into(payment_requests::table)
+            .values(request)
+            .returning(PaymentRequest::as_returning())
+            .get_result(&mut conn)?;
+        Ok(inserted)
+    }
+
+    fn retrieve_payment_request(&self, id: i32) -> Result<PaymentRequest> {
+        let mut conn = self.pool.get()?;
+        let request = payment_requests::table
+            .filter(payment_requests::id.eq(id))
+            .select(PaymentRequest::as_select())
+            .first(&mut conn)
+            .optional()?;
+        request.ok_or_else(|| anyhow!("Payment request #{} doesn't exist", id))
+    }
+
+    fn list_payment_requests(
+        &self,
+        username: &str,
+        direction: Option<Direction>,
+        status: Option<&str>,
+    ) -> Result<Vec<PaymentRequest>> {
+        let mut conn = self.pool.get()?;
+        let mut query = payment_requests::table.into_boxed();
+        query = match direction {
+            Some(Direction::Incoming) => query.filter(payment_requests::payer.eq(username)),
+            Some(Direction::Outgoing) => query.filter(payment_requests::requester.eq(username)),
+            None => query.filter(
+                payment_requests::payer
+                    .eq(username)
+                    .or(payment_requests::requester.eq(username)),
+            ),
+        };
+        if let Some(status) = status {
+            query = query.filter(payment_requests::status.eq(status));
+        }
+        let requests = query
+            .order(payment_requests::id.desc())
+            .select(PaymentRequest::as_select())
+            .load(&mut conn)?;
+        Ok(requests)
+    }
+
+    fn resolve_payment_request(
+        &self,
+        id: i32,
+        status: &str,
+        transaction_id: Option<i32>,
+        now: NaiveDateTime,
+    ) -> Result<bool> {
+        let mut conn = self.pool.get()?;
+        let updated = diesel::update(payment_requests::table)
+            .filter(payment_requests::id.eq(id))
+            .filter(payment_requests::status.eq(REQUEST_PENDING))
+            .set((
+                payment_requests::status.eq(status),
+                payment_requests::transaction_id.eq(transaction_id),
+                payment_requests::resolved_at.eq(now),
+            ))
+            .execute(&mut conn)?;
+        Ok(updated > 0)
+    }
+
+    /// Same semantics as the SQLite accept: the conditional status update
+    /// locks the request row, so a concurrent decline waits for it.
+    fn accept_payment_request(
+        &self,
+        payer: &mut User,
+        id: i32,
+        now: NaiveDateTime,
+    ) -> Result<Transaction> {
+        let mut conn = self.pool.get()?;
+        let result = conn.transaction::<_, Error, _>(|conn| {
+            let accepted = diesel::update(payment_requests::table)
+                .filter(payment_requests::id.eq(id))
+                .filter(payment_requests::payer.eq(&payer.username))
+                .filter(payment_requests::status.eq(REQUEST_PENDING))
+                .set((
+                    payment_requests::status.eq(REQUEST_ACCEPTED),
+                    payment_requests::resolved_at.eq(now),
+                ))
+                .returning(PaymentRequest::as_returning())
+                .get_result(conn)
+                .optional()?;
+            let Some(request) = accepted else {
+                bail!("Payment request #{} is no longer pending", id)
+            };
+            let idempotency_key = format!("payment-request:{}", id);
+            let record = NewTransaction::completed(
+                &request.payer,
+                &request.requester,
+                request.amount,
+                request.memo.as_deref(),
+                Some(&idempotency_key),
+            );
+            let record = transfer(payer, &record, &self.policy, conn)?;
+            diesel::update(payment_requests::table)
+                .filter(payment_requests::id.eq(id))
+                .set(payment_requests::transaction_id.eq(record.id))
+                .execute(conn)?;
+            Ok(record)
+        });
+        *payer = retrieve_user(&payer.username, &mut conn)?;
+        result
+    }
+
+    fn insert_scheduled_transfer(
+        &self,
+        schedule: &NewScheduledTransfer,
+    ) -> Result<ScheduledTransfer> {
+        let mut conn = self.pool.get()?;
+        let inserted = diesel::insert_into(scheduled_transfers::table)
+            .values(schedule)
+            .returning(ScheduledTransfer::as_returning())
+            .get_result(&mut conn)?;
+        Ok(inserted)
+    }
+
+    fn retrieve_scheduled_transfer(&self, id: i32) -> Result<ScheduledTransfer> {
+        let mut conn = self.pool.get()?;
+        let schedule = scheduled_transfers::table
+            .filter(scheduled_transfers::id.eq(id))
+            .select(ScheduledTransfer::as_select())
+            .first(&mut conn)
+            .optional()?;
+        schedule.ok_or_else(|| anyhow!("Scheduled transfer #{} doesn't exist", id))
+    }
+
+    fn list_scheduled_transfers(&self, username: &str) -> Result<Vec<ScheduledTransfer>> {
+        let mut conn = self.pool.get()?;
+        let schedules = scheduled_transfers::table
+            .filter(scheduled_transfers::sender.eq(username))
+            .order(scheduled_transfers::id.desc())
+            .select(ScheduledTransfer::as_select())
+            .load(&mut conn)?;
+        Ok(schedules)
+    }
+
+    fn due_scheduled_transfers(&self, now: NaiveDateTime) -> Result<Vec<ScheduledTransfer>> {
+        let mut conn = self.pool.get()?;
+        let schedules = scheduled_transfers::table
+            .filter(scheduled_transfers::status.eq(SCHEDULE_ACTIVE))
+            .filter(scheduled_transfers::next_run_at.le(now))
+            .order((scheduled_transfers::next_run_at, scheduled_transfers::id))
+            .select(ScheduledTransfer::as_select())
+            .load(&mut conn)?;
+        Ok(schedules)
+    }
+
+    fn update_scheduled_transfer(
+        &self,
+        schedule: &ScheduledTransfer,
+        ran_at: NaiveDateTime,
+    ) -> Result<bool> {
+        let mut conn = self.pool.get()?;
+        let updated = diesel::update(scheduled_transfers::table)
+            .filter(scheduled_transfers::id.eq(schedule.id))
+            .filter(scheduled_transfers::status.eq(SCHEDULE_ACTIVE))
+            .filter(scheduled_transfers::next_run_at.eq(ran_at))
+            .set((
+                scheduled_transfers::next_run_at.eq(schedule.next_run_at),
+                scheduled_transfers::status.eq(&schedule.status),
+                scheduled_transfers::failures.eq(schedule.failures),
+                scheduled_transfers::last_error.eq(&schedule.last_error),
+            ))
+            .execute(&mut conn)?;
+        Ok(updated > 0)
+    }
+
+    fn cancel_scheduled_transfer(&self, id: i32) -> Result<bool> {
+        let mut conn = self.pool.get()?;
+        let updated = diesel::update(scheduled_transfers::table)
+            .filter(scheduled_transfers::id.eq(id))
+            .filter(scheduled_transfers::status.eq(SCHEDULE_ACTIVE))
+            .set(scheduled_transfers::status.eq(SCHEDULE_CANCELLED))
+            .execute(&mut conn)?;
+        Ok(updated > 0)
+    }
+
+    fn authorize_hold(
+        &self,
+        payer: &mut User,
+        payee: &str,
+        amount: i32,
+        memo: Option<&str>,
+        expires_at: NaiveDateTime,
+    ) -> Result<Hold> {
+        ensure!(amount > 0, "Amount must be positive");
+        ensure!(payee != payer.username, "Can't place a hold for yourself");
+        let now = chrono::Utc::now().naive_utc();
+        ensure!(expires_at > now, "Expiry must be in the future");
+        let mut conn = self.pool.get()?;
+        let hold = conn.transaction::<_, Error, _>(|conn| {
+            lock_users(&[&payer.username], conn)?;
+            expire_holds(&payer.username, now, conn)?;
+            if !check_existence(payee, conn)? {
+                bail!("Username doesn't exist")
+            }
+            let hold = diesel::insert_into(holds::table)
+                .values(&NewHold {
+                    payer: &payer.username,
+                    payee,
+                    amount,
+                    memo,
+                    status: HOLD_ACTIVE,
+                    created_at: now,
+                    expires_at,
+                })
+                .returning(Hold::as_returning())
+                .get_result(conn)?;
+            sync_held(&payer.username, conn)?;
+            check_balance(payer, 0, conn)?;
+            check_policy(&self.policy, payer, payee, amount, conn)?;
+            Ok(hold)
+        })?;
+        *payer = retrieve_user(&payer.username, &mut conn)?;
+        Ok(hold)
+    }
+
+    fn capture_hold(&self, payee: &User, id: i32, amount: i32) -> Result<Transaction> {
+        ensure!(amount > 0, "Amount must be positive");
+        let now = chrono::Utc::now().naive_utc();
+        let mut conn = self.pool.get()?;
+        conn.transaction::<_, Error, _>(|conn| {
+            let hold = retrieve_hold(id, conn)?;
+            lock_users(&[&hold.payer, &hold.payee], conn)?;
+            let claimed = diesel::update(holds::table)
+                .filter(holds::id.eq(id))
+                .filter(holds::payee.eq(&payee.username))
+                .filter(holds::status.eq(HOLD_ACTIVE))
+                .filter(holds::expires_at.gt(now))
+                .set((holds::status.eq(HOLD_CAPTURED), holds::resolved_at.eq(now)))
+                .execute(conn)?;
+            if claimed == 0 {
+                retrieve_hold(id, conn)?.check_capturable(&payee.username, now)?;
+                bail!("Failed to capture hold #{}", id)
+            }
+            ensure!(
+                amount <= hold.amount,
+                "Can't capture more than the {} held",
+                hold.amount
+            );
+            sync_held(&hold.payer, conn)?;
+            let mut payer = retrieve_user(&hold.payer, conn)?;
+            let capture_key = hold.capture_key();
+            let record = NewTransaction::completed(
+                &hold.payer,
+                &hold.payee,
+                amount,
+                hold.memo.as_deref(),
+                Some(&capture_key),
+            );
+            let record = transfer(&mut payer, &record, &self.policy, conn)?;
+            diesel::update(holds::table)
+                .filter(holds::id.eq(id))
+                .set(holds::transaction_id.eq(record.id))
+                .execute(conn)?;
+            Ok(record)
+        })
+    }
+
+    fn void_hold(&self, payee: &User, id: i32) -> Result<Hold> {
+        let now = chrono::Utc::now().naive_utc();
+        let mut conn = self.pool.get()?;
+        conn.transaction::<_, Error, _>(|conn| {
+            let hold = retrieve_hold(id, conn)?;
+            lock_users(&[&hold.payer], conn)?;
+            let voided = diesel::update(holds::table)
+                .filter(holds::id.eq(id))
+                .filter(holds::payee.eq(&payee.username))
+                .filter(holds::status.eq(HOLD_ACTIVE))
+                .set((holds::status.eq(HOLD_VOIDED), holds::resolved_at.eq(now)))
+                .execute(conn)?;
+            let hold = retrieve_hold(id, conn)?;
+            if voided == 0 {
+                hold.check_active(&payee.username)?;
+                bail!("Failed to void hold #{}", id)
+            }
+            sync_held(&hold.payer, conn)?;
+            Ok(hold)
+        })
+    }
+
+    fn retrieve_hold(&self, id: i32) -> Result<Hold> {
+        let mut conn = self.pool.get()?;
+        retrieve_hold(id, &mut conn)
+    }
+
+    fn list_holds(
+        &self,
+        username: &str,
+        direction: Option<Direction>,
+        status: Option<&str>,
+    ) -> Result<Vec<Hold>> {
+        let mut conn = self.pool.get()?;
+        let mut query = holds::table.into_boxed();
+        query = match direction {
+            Some(Direction::Outgoing) => query.filter(holds::payer.eq(username)),
+            Some(Direction::Incoming) => query.filter(holds::payee.eq(username)),
+            None => query.filter(holds::payer.eq(username).or(holds::payee.eq(username))),
+        };
+        if let Some(status) = status {
+            query = query.filter(holds::status.eq(status));
+        }
+        let holds = query
+            .order(holds::id.desc())
+            .select(Hold::as_select())
+            .load(&mut conn)?;
+        Ok(holds)
+    }
+
+    /// One transaction per payer, so the user row is always locked before
+    /// its holds, as in `transfer`.
+    fn expire_holds(&self, now: NaiveDateTime) -> Result<usize> {
+        let mut conn = self.pool.get()?;
+        let payers = holds::table
+            .filter(holds::status.eq(HOLD_ACTIVE))
+            .filter(holds::expires_at.le(now))
+            .select(holds::payer)
+            .distinct()
+            .load::<String>(&mut conn)?;
+        let mut expired = 0;
+        for payer in payers {
+            expired += conn.transaction::<_, Error, _>(|conn| {
+                lock_users(&[&payer], conn)?;
+                expire_holds(&payer, now, conn)
+            })?;
+        }
+        Ok(expired)
+    }
+
+    /// Locks the users table against concurrent balance changes, which all
+    /// update it, so that balances and postings are read consistently.
+    fn reconcile(&self, repair: bool) -> Result<Vec<Discrepancy>> {
+        let mut conn = self.pool.get()?;
+        conn.transaction::<_, Error, _>(|conn| {
+            diesel::sql_query("LOCK TABLE users IN SHARE ROW EXCLUSIVE MODE").execute(conn)?;
+            let found = reconcile::check(&ledger_snapshot(conn)?);
+            if repair {
+                for discrepancy in &found {
+                    repair_discrepancy(discrepancy, conn)?;
+                }
+            }
+            Ok(found)
+        })
+    }
+}
+
+/// Same semantics as the SQLite transfer. The row locks serialize concurrent
+/// transfers from one account before the policy reads the ledger.
+fn transfer(
+    sender: &mut User,
+    record: &NewTransaction,
+    policy: &TransferPolicy,
+    conn: &mut PgConnection,
+) -> Result<Transaction> {
+    let (to_username, amount) = (record.receiver, record.amount);
+    ensure!(amount > 0, "Amount must be positive");
+    if let Some(original) = replayed_transfer(record, conn)? {
+        *sender = retrieve_user(&sender.username, conn)?;
+        return Ok(original);
+    }
+    let result = conn.transaction::<_, Error, _>(|conn| {
+        lock_users(&[&sender.username, to_username], conn)?;
+        expire_holds(&sender.username, record.created_at, conn)?;
+        let debited = diesel::update(users::table)
+            .filter(users::username.eq(&sender.username))
+            .filter(users::frozen_at.is_null())
+            .filter((users::balance - users::held).ge(amount))
+            .set(users::balance.eq(users::balance - amount))
+            .execute(conn)?;
+        if debited == 0 {
+            retrieve_user(&sender.username, conn)?.check_active()?;
+            check_balance(sender, amount, conn)?;
+            bail!("Failed to debit {}", sender.username)
+        }
+        // A refund only gives back what the sender was sent.
+        if record.refund_of.is_none() {
+            check_policy(policy, sender, to_username, amount, conn)?;
+        }
+        let credited = diesel::update(users::table)
+            .filter(users::username.eq(to_username))
+            .filter(users::closed_at.is_null())
+            .filter(users::frozen_at.is_null())
+            .filter(users::balance.le(i32::MAX - amount))
+            .set(users::balance.eq(users::balance + amount))
+            .execute(conn)?;
+        if credited == 0 {
+            retrieve_user(to_username, conn)?.check_active()?;
+            bail!(
+                "Can't credit {} to {}, the balance would overflow",
+                amount,
+                to_username
+            )
+        }
+
+        let inserted = diesel::insert_into(transactions::table)
+            .values(record)
+            .returning(Transaction::as_returning())
+            .get_result(conn)?;
+        insert_posting(&NewPosting::transfer(&inserted), conn)?;
+        Ok(inserted)
+    });
+    let record = match result {
+        // Lost a race against a concurrent call with the same key.
+        Err(e) if is_unique_violation(&e) => replayed_transfer(record, conn)?.ok_or(e)?,
+        result => result?,
+    };
+    *sender = retrieve_user(&sender.username, conn)?;
+    Ok(record)
+}
+
+fn account_balances(conn: &mut PgConnection) -> Result<Vec<(String, i64)>> {
+    let debits = postings::table
+        .group_by(postings::debit_account)
+        .select((postings::debit_account, diesel::dsl::sum(postings::amount)))
+        .load::<(String, Option<i64>)>(conn)?;
+    let credits = postings::table
+        .group_by(postings::credit_account)
+        .select((postings::credit_account, diesel::dsl::sum(postings::amount)))
+        .load::<(String, Option<i64>)>(conn)?;
+    Ok(Posting::net_balances(
+        debits
+            .into_iter()
+            .map(|(account, total)| (account, total.unwrap_or(0))),
+        credits
+            .into_iter()
+            .map(|(account, total)| (account, total.unwrap_or(0))),
+    ))
+}
+
+fn ledger_snapshot(conn: &mut PgConnection) -> Result<LedgerSnapshot> {
+    use diesel::dsl::{count_star, not};
+
+    let active_holds = holds::table
+        .filter(holds::status.eq(HOLD_ACTIVE))
+        .group_by(holds::payer)
+        .select((holds::payer, diesel::dsl::sum(holds::amount)))
+        .load::<(String, Option<i64>)>(conn)?;
+    let unposted_transfers = transactions::table
+        .filter(transactions::status.eq(STATUS_COMPLETED))
+        .filter(not(transactions::id.nullable().eq_any(
+            postings::table
+                .filter(postings::transaction_id.is_not_null())
+                .select(postings::transaction_id),
+        )))
+        .order(transactions::id)
+        .select(transactions::id)
+        .load(conn)?;
+
+    let usernames = || users::table.select(users::username);
+    let mut orphans = Vec::new();
+    let sessions = sessions::table
+        .filter(not(sessions::username.eq_any(usernames())))
+        .group_by(sessions::username)
+        .select((sessions::username, count_star()))
+        .load::<(String, i64)>(conn)?;
+    orphans.extend(sessions.into_iter().map(|(u, n)| ("sessions", u, n)));
+    let resets = password_resets::table
+        .filter(not(password_resets::username.eq_any(usernames())))
+        .group_by(password_resets::username)
+        .select((password_resets::username, count_star()))
+        .load::<(String, i64)>(conn)?;
+    orphans.extend(resets.into_iter().map(|(u, n)| ("password_resets", u, n)));
+    let owners = contacts::table
+        .filter(not(contacts::owner.eq_any(usernames())))
+        .group_by(contacts::owner)
+        .select((contacts::owner, count_star()))
+        .load::<(String, i64)>(conn)?;
+    orphans.extend(owners.into_iter().map(|(u, n)| ("contacts", u, n)));
+    let targets = contacts::table
+        .filter(not(contacts::username.eq_any(usernames())))
+        .group_by(contacts::username)
+        .select((contacts::username, count_star()))
+        .load::<(String, i64)>(conn)?;
+    orphans.extend(targets.into_iter().map(|(u, n)| ("contacts", u, n)));
+    let senders = transactions::table
+        .filter(not(transactions::sender.eq_any(usernames())))
+        .group_by(transactions::sender)
+        .select((transactions::sender, count_star()))
+        .load::<(String, i64)>(conn)?;
+    orphans.extend(senders.into_iter().map(|(u, n)| ("transactions", u, n)));
+    let receivers = transactions::table
+        .filter(not(transactions::receiver.eq_any(usernames())))
+        .group_by(transactions::receiver)
+        .select((transactions::receiver, count_star()))
+        .load::<(String, i64)>(conn)?;
+    orphans.extend(receivers.into_iter().map(|(u, n)| ("transactions", u, n)));
+
+    Ok(LedgerSnapshot {
+        users: users::table.load::<User>(conn)?,
+        account_balances: account_balances(conn)?,
+        active_holds: active_holds
+            .into_iter()
+            .map(|(payer, total)| (payer, total.unwrap_or(0)))
+            .collect(),
+        unposted_transfers,
+        orphans,
+    })
+}
+
+/// Does what `repair` describes, or nothing.
+fn repair_discrepancy(discrepancy: &Discrepancy, conn: &mut PgConnection) -> Result<()> {
+    match discrepancy {
+        Discrepancy::BalanceMismatch {
+            username, posted, ..
+        } => {
+            if let Ok(posted) = i32::try_from(*posted) {
+                diesel::update(users::table)
+                    .filter(users::username.eq(username))
+                    .set(users::balance.eq(posted))
+                    .execute(conn)?;
+            }
+        }
+        Discrepancy::HeldMismatch {
+            username, on_hold, ..
+        } => {
+            if let Ok(on_hold) = i32::try_from(*on_hold) {
+                diesel::update(users::table)
+                    .filter(users::username.eq(username))
+                    .set(users::held.eq(on_hold))
+                    .execute(conn)?;
+            }
+        }
+        Discrepancy::Orphaned {
+            table: "sessions",
+            username,
+            ..
+        } => {
+            diesel::delete(sessions::table.filter(sessions::username.eq(username)))
+                .execute(conn)?;
+        }
+        Discrepancy::Orphaned {
+            table: "password_resets",
+            username,
+            ..
+        } => {
+            diesel::delete(password_resets::table.filter(password_resets::username.eq(username)))
+                .execute(conn)?;
+        }
+        Discrepancy::Orphaned {
+            table: "contacts",
+            username,
+            ..
+        } => {
+            diesel::delete(
+                contacts::table.filter(
+                    contacts::owner
+                        .eq(username)
+                        .or(contacts::username.eq(username)),
+                ),
+            )
+            .execute(conn)?;
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn insert_posting(posting: &NewPosting, conn: &mut PgConnection) -> Result<()> {
+    diesel::insert_into(postings::table)
+        .values(posting)
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Locks the rows in a fixed order, otherwise opposite transfers between the
+/// same two users deadlock.
+fn lock_users(usernames: &[&str], conn: &mut PgConnection) -> Result<()> {
+    users::table
+        .filter(users::username.eq_any(usernames))
+        .order(users::username)
+        .select(users::username)
+        .for_update()
+        .load::<String>(conn)?;
+    Ok(())
+}
+
+fn replayed_transfer(
+    record: &NewTransaction,
+    conn: &mut PgConnection,
+) -> Result<Option<Transaction>> {
+    let Some(key) = record.idempotency_key else {
+        return Ok(None);
+    };
+    let original = transactions::table
+        .filter(transactions::sender.eq(record.sender))
+        .filter(transactions::idempotency_key.eq(key))
+        .select(Transaction::as_select())
+        .first(conn)
+        .optional()?;
+    if let Some(original) = &original {
+        ensure!(
+            original.receiver == record.receiver
+                && original.amount == record.amount
+                && original.refund_of == record.refund_of,
+            "Idempotency key already used for a different transfer"
+        );
+    }
+    Ok(original)
+}
+
+fn retrieve_transaction(id: i32, conn: &mut PgConnection) -> Result<Transaction> {
+    let record = transactions::table
+        .filter(transactions::id.eq(id))
+        .select(Transaction::as_select())
+        .first(conn)
+        .optional()?;
+    record.ok_or_else(|| anyhow!("Transfer #{} doesn't exist", id))
+}
+
+fn list_refunds(original_id: i32, conn: &mut PgConnection) -> Result<Vec<Transaction>> {
+    let refunds = transactions::table
+        .filter(transactions::refund_of.eq(original_id))
+        .filter(transactions::status.eq(STATUS_COMPLETED))
+        .order(transactions::id)
+        .select(Transaction::as_select())
+        .load(conn)?;
+    Ok(refunds)
+}
+
+fn ledger_query<'a>(
+    user: &'a User,
+    filter: &'a TransactionFilter,
+) -> transactions::BoxedQuery<'a, Pg> {
+    let mut query = transactions::table.into_boxed();
+    query = match filter.direction {
+        Some(Direction::Outgoing) => query.filter(transactions::sender.eq(&user.username)),
+        Some(Direction::Incoming) => query.filter(transactions::receiver.eq(&user.username)),
+        None => query.filter(
+            transactions::sender
+                .eq(&user.username)
+                .or(transactions::receiver.eq(&user.username)),
+        ),
+    };
+    if let Some(other) = &filter.counterparty {
+        query = query.filter(
+            transactions::sender
+                .eq(other)
+                .or(transactions::receiver.eq(other)),
+        );
+    }
+    if let Some(since) = filter.since {
+        query = query.filter(transactions::created_at.ge(since));
+    }
+    if let Some(until) = filter.until {
+        query = query.filter(transactions::created_at.lt(until));
+    }
+    if let Some(status) = &filter.status {
+        query = query.filter(transactions::status.eq(status));
+    }
+    query
+}
+
+fn check_policy(
+    policy: &TransferPolicy,
+    sender: &User,
+    to_username: &str,
+    amount: i32,
+    conn: &mut PgConnection,
+) -> Result<()> {
+    let usage = if policy.has_limits() {
+        let (today, this_month) = period_starts(chrono::Utc::now().naive_utc());
+        OutgoingUsage {
+            today: outgoing_since(sender, today, conn)?,
+            this_month: outgoing_since(sender, this_month, conn)?,
+        }
+    } else {
+        OutgoingUsage::default()
+    };
+    policy.evaluate(&sender.username, to_username, amount, usage)?;
+    Ok(())
+}
+
+fn outgoing_since(user: &User, since: NaiveDateTime, conn: &mut PgConnection) -> Result<i64> {
+    let filter = TransactionFilter {
+        direction: Some(Direction::Outgoing),
+        since: Some(since),
+        status: Some(STATUS_COMPLETED.to_string()),
+        ..Default::default()
+    };
+    let total = ledger_query(user, &filter)
+        .select(transactions::amount)
+        .load::<i32>(conn)?
+        .into_iter()
+        .map(i64::from)
+        .sum();
+    Ok(total)
+}
+
+fn update_password(user: &User, conn: &mut PgConnection) -> Result<()> {
+    diesel::update(users::table)
+        .filter(users::username.eq(&user.username))
+        .set((
+            users::password.eq(&user.password),
+            users::password_hash.eq(&user.password_hash),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+fn revoke_sessions(username: &str, now: NaiveDateTime, conn: &mut PgConnection) -> Result<usize> {
+    let revoked = diesel::update(sessions::table)
+        .filter(sessions::username.eq(username))
+        .filter(sessions::revoked_at.is_null())
+        .filter(sessions::expires_at.gt(now))
+        .set(sessions::revoked_at.eq(now))
+        .execute(conn)?;
+    Ok(revoked)
+}
+
+fn check_existence(username: &str, conn: &mut PgConnection) -> Result<bool> {
+    let count = users::table
+        .filter(users::username.eq(username))
+        .count()
+        .get_result::<i64>(conn)?;
+    Ok(count > 0)
+}
+
+fn retrieve_user(username: &str, conn: &mut PgConnection) -> Result<User> {
+    let user = users::table
+        .filter(users::username.eq(username))
+        .first::<User>(conn)
+        .optional()?;
+    user.ok_or_else(|| anyhow!("Username doesn't exist"))
+}
+
+fn check_balance(user: &User, amount: i32, conn: &mut PgConnection) -> Result<()> {
+    let available = users::table
+        .filter(users::username.eq(&user.username))
+        .select(users::balance - users::held)
+        .first::<i32>(conn)?;
+    if available >= amount {
+        Ok(())
+    } else {
+        bail!("Insufficient balance")
+    }
+}
+
+fn retrieve_hold(id: i32, conn: &mut PgConnection) -> Result<Hold> {
+    let hold = holds::table
+        .filter(holds::id.eq(id))
+        .select(Hold::as_select())
+        .first(conn)
+        .optional()?;
+    hold.ok_or_else(|| anyhow!("Hold #{} doesn't exist", id))
+}
+
+/// Recomputes the stored `held` of `username` from its active holds.
+fn sync_held(username: &str, conn: &mut PgConnection) -> Result<()> {
+    let held = holds::table
+        .filter(holds::payer.eq(username))
+        .filter(holds::status.eq(HOLD_ACTIVE))
+        .select(holds::amount)
+        .load::<i32>(conn)?
+        .into_iter()
+        .sum::<i32>();
+    diesel::update(users::table)
+        .filter(users::username.eq(username))
+        .set(users::held.eq(held))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Expires the overdue holds of `payer`. Lock the payer's row first.
+fn expire_holds(payer: &str, now: NaiveDateTime, conn: &mut PgConnection) -> Result<usize> {
+    let expired = diesel::update(holds::table)
+        .filter(holds::payer.eq(payer))
+        .filter(holds::status.eq(HOLD_ACTIVE))
+        .filter(holds::expires_at.le(now))
+        .set((holds::status.eq(HOLD_EXPIRED), holds::resolved_at.eq(now)))
+        .execute(conn)?;
+    if expired > 0 {
+        sync_held(payer, conn)?;
+    }
+    Ok(expired)
+}
+
+fn is_unique_violation(e: &Error) -> bool {
+    matches!(
+        e.downcast_ref::<diesel::result::Error>(),
+        Some(diesel::result::Error::DatabaseError(
+            diesel::result::DatabaseErrorKind::UniqueViolation,
+            _
+        ))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trading_core::policy::PolicyRejection;
+    use crate::trading_core::types::{
+        AUDIT_OK, ISSUANCE_ACCOUNT, REQUEST_DECLINED, ROLE_ADMIN, SCHEDULE_COMPLETED,
+    };
+    use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+    use dotenvy::dotenv;
+
+    const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations_postgres");
+
+    /// A fresh, migrated database on the server at `POSTGRES_TEST_URL`,
+    /// dropped again by `drop_test_db`.
+    fn test_storage(name: &str, policy: TransferPolicy) -> (PgStorage, String) {
+        dotenv().ok();
+        let server_url = std::env::var("POSTGRES_TEST_URL")
+            .expect("POSTGRES_TEST_URL must point at a local Postgres server");
+        let database = format!("trading_gpt_{name}_{}", std::process::id());
+        let mut admin = PgConnection::establish(&server_url).unwrap();
+        diesel::sql_query(format!("DROP DATABASE IF EXISTS {database}"))
+            .execute(&mut admin)
+            .unwrap();
+        diesel::sql_query(format!("CREATE DATABASE {database}"))
+            .execute(&mut admin)
+            .unwrap();
+
+        let (server, _) = server_url.rsplit_once('/').unwrap();
+        let storage = PgStorage::new(&format!("{server}/{database}"), policy).unwrap();
+        storage
+            .pool
+            .get()
+            .unwrap()
+            .run_pending_migrations(MIGRATIONS)
+            .unwrap();
+        (storage, database)
+    }
+
+    fn drop_test_db(storage: PgStorage, database: &str) {
+        drop(storage);
+        let mut admin =
+            PgConnection::establish(&std::env::var("POSTGRES_TEST_URL").unwrap()).unwrap();
+        diesel::sql_query(format!("DROP DATABASE {database}"))
+            .execute(&mut admin)
+            .unwrap();
+    }
+
+    fn insert_users(storage: &PgStorage, names: &[&str], balance: i32) {
+        for name in names {
+            storage
+                .insert_user(&User::new(name.to_string(), "hash".to_string(), balance))
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_crud_and_sessions() {
+        let (storage, database) = test_storage("crud", TransferPolicy::default());
+        insert_users(&storage, &["alice"], 100);
+        assert!(storage
+            .insert_user(&User::new("alice".to_string(), "other".to_string(), 5))
+            .is_err());
+
+        let mut alice = storage.retrieve_user("alice").unwrap();
+        alice.balance = 50;
+        storage.update_user(&alice).unwrap();
+        assert_eq!(storage.retrieve_user("alice").unwrap(), alice);
+        storage.delete_user(&alice).unwrap();
+        assert!(storage.retrieve_user("alice").is_err());
+
+        insert_users(&storage, &["bob"], 100);
+        let now = chrono::Utc::now().naive_utc();
+        let session = Session::new(
+            "token".to_string(),
+            "bob".to_string(),
+            now,
+            chrono::Duration::hours(1),
+        );
+        storage.insert_session(&session).unwrap();
+        assert_eq!(storage.revoke_all_sessions("bob", now).unwrap(), 1);
+        assert!(storage
+            .retrieve_session("token")
+            .unwrap()
+            .revoked_at
+            .is_some());
+        drop_test_db(storage, &database);
+    }
+
+    #[test]
+    fn test_password_reset() {
+        let (storage, database) = test_storage("reset", TransferPolicy::default());
+        insert_users(&storage, &["alice"], 100);
+        let now = chrono::Utc::now().naive_utc();
+        let ttl = chrono::Duration::hours(1);
+        storage
+            .insert_session(&Session::new(
+                "t1".to_string(),
+                "alice".to_string(),
+                now,
+                ttl,
+            ))
+            .unwrap();
+        storage
+            .insert_password_reset(&PasswordReset::new(
+                "r1".to_string(),
+                "alice".to_string(),
+                now,
+                ttl,
+            ))
+            .unwrap();
+
+        let user = storage.redeem_password_reset("r1", "reset", now).unwrap();
+        assert_eq!(user.password_hash.as_deref(), Some("reset"));
+        assert_eq!(
+            storage.retrieve_user("alice").unwrap().password_hash,
+            user.password_hash
+        );
+        assert!(storage.retrieve_session("t1").unwrap().revoked_at.is_some());
+        assert!(storage.redeem_password_reset("r1", "again", now).is_err());
+        drop_test_db(storage, &database);
+    }
+
+    #[test]
+    fn test_admin_controls() {
+        let (storage, database) = test_storage("admin", TransferPolicy::default());
+        insert_users(&storage, &["alice", "bob"], 100);
+        assert_eq!(storage.adjust_balance("alice", 50).unwrap().balance, 150);
+        let err = storage.adjust_balance("alice", -200).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Can't burn 200 from alice, only 150 available"
+        );
+        let err = storage.adjust_balance("alice", i32::MIN).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Can't burn 2147483648 from alice, only 150 available"
+        );
+        let err = storage.adjust_balance("alice", i32::MAX).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Can't mint {} into alice, the balance would overflow",
+                i32::MAX
+            )
+        );
+        assert_eq!(storage.retrieve_user("alice").unwrap().balance, 150);
+
+        // A full account takes no transfer, and the sender keeps the money.
+        storage.adjust_balance("bob", i32::MAX - 100).unwrap();
+        let mut alice = storage.retrieve_user("alice").unwrap();
+        let err = storage
+            .transfer(&mut alice, "bob", 10, None, None)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Can't credit 10 to bob, the balance would overflow"
+        );
+        assert_eq!(storage.retrieve_user("alice").unwrap().balance, 150);
+        assert_eq!(storage.retrieve_user("bob").unwrap().balance, i32::MAX);
+        storage.adjust_balance("bob", -(i32::MAX - 100)).unwrap();
+        assert_eq!(
+            storage.set_role("alice", ROLE_ADMIN).unwrap().role,
+            ROLE_ADMIN
+        );
+
+        let now = chrono::Utc::now().naive_utc();
+        assert!(storage
+            .set_frozen("bob", Some(now))
+            .unwrap()
+            .frozen_at
+            .is_some());
+        let mut alice = storage.retrieve_user("alice").unwrap();
+        let err = storage
+            .transfer(&mut alice, "bob", 10, None, None)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Account bob is frozen");
+        storage.set_frozen("bob", None).unwrap();
+        storage.transfer(&mut alice, "bob", 10, None, None).unwrap();
+        assert_eq!(alice.balance, 140);
+        drop_test_db(storage, &database);
+    }
+
+    #[test]
+    fn test_contacts() {
+        let (storage, database) = test_storage("contacts", TransferPolicy::default());
+        insert_users(&storage, &["alice", "bob"], 100);
+        let mom = Contact {
+            owner: "alice".to_string(),
+            alias: "mom".to_string(),
+            username: "bob".to_string(),
+            note: None,
+            created_at: chrono::Utc::now().naive_utc(),
+        };
+        storage.insert_contact(&mom).unwrap();
+        assert_eq!(
+            storage.insert_contact(&mom).unwrap_err().to_string(),
+            "You already have a contact named mom"
+        );
+        assert_eq!(storage.list_contacts("alice").unwrap().len(), 1);
+        assert!(storage.retrieve_contact("alice", "mom").unwrap().is_some());
+        storage.delete_contact("alice", "mom").unwrap();
+        assert!(storage.delete_contact("alice", "mom").is_err());
+        drop_test_db(storage, &database);
+    }
+
+    #[test]
+    fn test_transfer_records_ledger() {
+        let policy = TransferPolicy {
+            daily_limit: Some(90),
+            ..Default::default()
+        };
+        let (storage, database) = test_storage("ledger", policy);
+        insert_users(&storage, &["alice", "bob"], 100);
+        let mut alice = storage.retrieve_user("alice").unwrap();
+
+        let record = storage
+            .transfer(&mut alice, "bob", 60, Some("rent"), Some("key"))
+            .unwrap();
+        assert_eq!(alice.balance, 40);
+        assert_eq!(record.memo.as_deref(), Some("rent"));
+        let replay = storage
+            .transfer(&mut alice, "bob", 60, Some("rent"), Some("key"))
+            .unwrap();
+        assert_eq!(replay, record);
+        assert!(storage
+            .transfer(&mut alice, "bob", 30, None, Some("key"))
+            .is_err());
+
+        let err = storage
+            .transfer(&mut alice, "bob", 40, None, None)
+            .unwrap_err();
+        assert!(err.downcast_ref::<PolicyRejection>().is_some());
+        assert!(storage
+            .transfer(&mut alice, "nobody", 1, None, None)
+            .is_err());
+        assert_eq!(storage.retrieve_user("alice").unwrap().balance, 40);
+        assert_eq!(storage.retrieve_user("bob").unwrap().balance, 160);
+
+        let history = storage
+            .list_transactions(&alice, &TransactionFilter::default())
+            .unwrap();
+        assert_eq!(history, vec![record.clone()]);
+        let summary = storage.summarize_spending(&alice, None, None).unwrap();
+        assert_eq!(summary.total_out, 60);
+
+        let request = storage
+            .insert_payment_request(&NewPaymentRequest {
+                requester: "bob",
+                payer: "alice",
+                amount: 10,
+                memo: None,
+                status: REQUEST_PENDING,
+                created_at: chrono::Utc::now().naive_utc(),
+            })
+            .unwrap();
+        assert_eq!(
+            storage
+                .list_payment_requests("alice", Some(Direction::Incoming), None)
+                .unwrap(),
+            vec![request.clone()]
+        );
+        let now = chrono::Utc::now().naive_utc();
+        let paid = storage
+            .accept_payment_request(&mut alice, request.id, now)
+            .unwrap();
+        assert_eq!(
+            storage
+                .retrieve_payment_request(request.id)
+                .unwrap()
+                .transaction_id,
+            Some(paid.id)
+        );
+        assert!(!storage
+            .resolve_payment_request(request.id, REQUEST_DECLINED, None, now)
+            .unwrap());
+        assert!(storage
+            .accept_payment_request(&mut alice, request.id, now)
+            .is_err());
+        drop_test_db(storage, &database);
+    }
+
+    #[test]
+    fn test_concurrent_transfers_conserve_money() {
+        let (storage, database) = test_storage("concurrent", TransferPolicy::default());
+        insert_users(&storage, &["alice", "bob"], 100);
+
+        std::thread::scope(|scope| {
+            for (from, to) in [("alice", "bob"), ("bob", "alice")].repeat(4) {
+                let storage = &storage;
+                scope.spawn(move || {
+                    let mut user = storage.retrieve_user(from).unwrap();
+                    for _ in 0..10 {
+                        let _ = storage.transfer(&mut user, to, 15, None, None);
+                    }
+                });
+            }
+        });
+
+        let alice = storage.retrieve_user("alice").unwrap();
+        let bob = storage.retrieve_user("bob").unwrap();
+        assert_eq!(alice.balance + bob.balance, 200);
+        assert!(alice.balance >= 0 && bob.balance >= 0);
+        drop_test_db(storage, &database);
+    }
+
+    #[test]
+    fn test_scheduled_transfers() {
+        let (storage, database) = test_storage("schedules", TransferPolicy::default());
+        insert_users(&storage, &["alice", "bob"], 100);
+        let now = chrono::Utc::now().naive_utc();
+        let schedule = storage
+            .insert_scheduled_transfer(&NewScheduledTransfer {
+                sender: "alice",
+                receiver: "bob",
+                amount: 10,
+                memo: None,
+                recurrence: None,
+                next_run_at: now,
+                status: SCHEDULE_ACTIVE,
+                created_at: now,
+                first_run_at: now,
+            })
+            .unwrap();
+        assert_eq!(
+            storage.due_scheduled_transfers(now).unwrap(),
+            vec![schedule.clone()]
+        );
+
+        let mut ran = schedule.clone();
+        ran.status = SCHEDULE_COMPLETED.to_string();
+        assert!(storage.update_scheduled_transfer(&ran, now).unwrap());
+        assert!(!storage.cancel_scheduled_transfer(schedule.id).unwrap());
+        assert_eq!(
+            storage.list_scheduled_transfers("alice").unwrap(),
+            vec![ran]
+        );
+        drop_test_db(storage, &database);
+    }
+
+    #[test]
+    fn test_holds() {
+        let (storage, database) = test_storage("holds", TransferPolicy::default());
+        insert_users(&storage, &["alice", "bob"], 100);
+        let mut alice = storage.retrieve_user("alice").unwrap();
+        let bob = storage.retrieve_user("bob").unwrap();
+        let now = chrono::Utc::now().naive_utc();
+        let hold = storage
+            .authorize_hold(
+                &mut alice,
+                "bob",
+                60,
+                None,
+                now + chrono::Duration::hours(1),
+            )
+            .unwrap();
+        assert_eq!(alice.available(), 40);
+        assert!(storage.transfer(&mut alice, "bob", 50, None, None).is_err());
+        assert!(storage
+            .authorize_hold(
+                &mut alice,
+                "bob",
+                50,
+                None,
+                now + chrono::Duration::hours(1)
+            )
+            .is_err());
+
+        assert!(storage.capture_hold(&alice, hold.id, 60).is_err());
+        let record = storage.capture_hold(&bob, hold.id, 45).unwrap();
+        let alice = storage.retrieve_user("alice").unwrap();
+        assert_eq!((alice.balance, alice.held), (55, 0));
+        assert_eq!(
+            storage.retrieve_hold(hold.id).unwrap().transaction_id,
+            Some(record.id)
+        );
+
+        let mut alice = alice;
+        storage
+            .authorize_hold(
+                &mut alice,
+                "bob",
+                30,
+                None,
+                now + chrono::Duration::hours(1),
+            )
+            .unwrap();
+        assert_eq!(
+            storage
+                .expire_holds(now + chrono::Duration::hours(2))
+                .unwrap(),
+            1
+        );
+        assert_eq!(storage.retrieve_user("alice").unwrap().held, 0);
+        drop_test_db(storage, &database);
+    }
+
+    #[test]
+    fn test_refunds() {
+        // Refunds to a blocked account still go through.
+        let policy = TransferPolicy {
+            blocklist: ["alice".to_string()].into(),
+            ..Default::default()
+        };
+        let (storage, database) = test_storage("refunds", policy);
+        insert_users(&storage, &["alice", "bob"], 100);
+        let mut alice = storage.retrieve_user("alice").unwrap();
+        let mut bob = storage.retrieve_user("bob").unwrap();
+        let original = storage.transfer(&mut alice, "bob", 50, None, None).unwrap();
+        assert!(storage.check_transfer_policy(&bob, "alice", 10).is_err());
+
+        assert!(storage
+            .refund(&mut alice, original.id, 10, None, None)
+            .is_err());
+        let partial = storage
+            .refund(&mut bob, original.id, 20, None, None)
+            .unwrap();
+        assert_eq!(partial.refund_of, Some(original.id));
+        assert!(storage
+            .refund(&mut bob, original.id, 31, None, None)
+            .is_err());
+        assert_eq!(bob.balance, 130);
+        storage
+            .refund(&mut bob, original.id, 30, None, None)
+            .unwrap();
+        assert_eq!(storage.retrieve_transaction(partial.id).unwrap(), partial);
+        assert_eq!(storage.list_refunds(original.id).unwrap().len(), 2);
+        drop_test_db(storage, &database);
+    }
+
+    #[test]
+    fn test_close_account() {
+        let (storage, database) = test_storage("closure", TransferPolicy::default());
+        insert_users(&storage, &["alice", "bob", "carol"], 100);
+        let mut alice = storage.retrieve_user("alice").unwrap();
+        let mut carol = storage.retrieve_user("carol").unwrap();
+        let now = chrono::Utc::now().naive_utc();
+        let hold = storage
+            .authorize_hold(
+                &mut carol,
+                "alice",
+                30,
+                None,
+                now + chrono::Duration::hours(1),
+            )
+            .unwrap();
+
+        assert!(storage.close_user(&mut alice, None, now).is_err());
+        let sweep = storage
+            .close_user(&mut alice, Some("bob"), now)
+            .unwrap()
+            .unwrap();
+        assert_eq!(sweep.amount, 100);
+        assert_eq!(alice.balance, 0);
+        assert!(alice.closed_at.is_some());
+        assert_eq!(storage.retrieve_hold(hold.id).unwrap().status, HOLD_VOIDED);
+        assert_eq!(storage.retrieve_user("carol").unwrap().held, 0);
+        assert!(storage
+            .transfer(&mut carol, "alice", 10, None, None)
+            .is_err());
+        drop_test_db(storage, &database);
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let (storage, database) = test_storage("audit", TransferPolicy::default());
+        let now = chrono::Utc::now().naive_utc();
+        for (username, function_name) in [
+            (None, "login"),
+            (Some("alice"), "login"),
+            (Some("alice"), "transfer"),
+        ] {
+            storage
+                .insert_audit_record(&NewAuditRecord {
+                    session_id: "s1",
+                    username,
+                    function_name,
+                    arguments: "{}",
+                    outcome: AUDIT_OK,
+                    result: "",
+                    model: "test",
+                    created_at: now,
+                })
+                .unwrap();
+        }
+        let alice = AuditFilter {
+            username: Some("alice".to_string()),
+            limit: Some(1),
+            ..Default::default()
+        };
+        let records = storage.list_audit_records(&alice).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].function_name, "login");
+        let transfers = AuditFilter {
+            function_name: Some("transfer".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(storage.list_audit_records(&transfers).unwrap().len(), 1);
+        drop_test_db(storage, &database);
+    }
+
+    #[test]
+    fn test_postings_balance() {
+        let (storage, database) = test_storage("postings", TransferPolicy::default());
+        insert_users(&storage, &["alice", "bob"], 100);
+        let mut alice = storage.retrieve_user("alice").unwrap();
+        let record = storage.transfer(&mut alice, "bob", 30, None, None).unwrap();
+        storage.adjust_balance("bob", 20).unwrap();
+        storage.adjust_balance("alice", -10).unwrap();
+
+        assert_eq!(
+            storage.account_balances().unwrap(),
+            [
+                ("alice".to_string(), 60),
+                ("bob".to_string(), 150),
+                (ISSUANCE_ACCOUNT.to_string(), -210),
+            ]
+        );
+        let postings = storage.list_postings("alice").unwrap();
+        assert_eq!(postings.len(), 3);
+        assert_eq!(postings[1].transaction_id, Some(record.id));
+        drop_test_db(storage, &database);
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let (storage, database) = test_storage("reconcile", TransferPolicy::default());
+        insert_users(&storage, &["alice", "bob"], 100);
+        assert!(storage.reconcile(false).unwrap().is_empty());
+        let mut alice = storage.retrieve_user("alice").unwrap();
+        alice.balance = 90;
+        storage.update_user(&alice).unwrap();
+
+        let found = storage.reconcile(false).unwrap();
+        assert_eq!(
+            found,
+            [Discrepancy::BalanceMismatch {
+                username: "alice".to_string(),
+                stored: 90,
+                posted: 100,
+            }]
+        );
+        assert_eq!(storage.reconcile(true).unwrap(), found);
+        assert!(storage.reconcile(false).unwrap().is_empty());
+        assert_eq!(storage.retrieve_user("alice").unwrap().balance, 100);
+        drop_test_db(storage, &database);
+    }
+
+    #[test]
+    fn test_transfer_batch() {
+        let (storage, database) = test_storage("transfer_batch", TransferPolicy::default());
+        insert_users(&storage, &["alice", "bob", "carol"], 100);
+        let now = chrono::Utc::now().naive_utc();
+        storage.set_frozen("carol", Some(now)).unwrap();
+        let mut alice = storage.retrieve_user("alice").unwrap();
+        let records = [
+            NewTransaction::completed("alice", "bob", 30, None, Some("batch:1")),
+            NewTransaction::completed("alice", "carol", 20, Some("bonus"), Some("batch:2")),
+        ];
+
+        let e = storage.transfer_batch(&mut alice, &records).unwrap_err();
+        assert_eq!(e.downcast_ref::<BatchFailed>().unwrap().index, 1);
+        assert_eq!(storage.retrieve_user("bob").unwrap().balance, 100);
+        assert_eq!(storage.list_postings("alice").unwrap().len(), 1);
+
+        storage.set_frozen("carol", None).unwrap();
+        let transfers = storage.transfer_batch(&mut alice, &records).unwrap();
+        assert_eq!(transfers.len(), 2);
+        assert_eq!(alice.balance, 50);
+        let replayed = storage.transfer_batch(&mut alice, &records).unwrap();
+        assert_eq!(
+            replayed.iter().map(|record| record.id).collect::<Vec<_>>(),
+            transfers.iter().map(|record| record.id).collect::<Vec<_>>()
+        );
+        assert_eq!(storage.retrieve_user("alice").unwrap().balance, 50);
+        drop_test_db(storage, &database);
+    }
+}
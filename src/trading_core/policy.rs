@@ -0,0 +1,346 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use anyhow::{ensure, Context, Result};
+use chrono::{Datelike, NaiveDateTime};
+use dotenvy::dotenv;
+use lazy_static::lazy_static;
+
+lazy_static! {
+    pub static ref POLICY: TransferPolicy = {
+        dotenv().ok();
+        TransferPolicy::from_env().expect("Invalid transfer policy configuration")
+    };
+}
+
+/// Rules every outgoing transfer but a refund has to satisfy.
+///
+/// Configured through the environment:
+/// `TRANSFER_MIN_AMOUNT`, `TRANSFER_MAX_AMOUNT`, `TRANSFER_DAILY_LIMIT`,
+/// `TRANSFER_MONTHLY_LIMIT`, `TRANSFER_ALLOW_SELF` and `TRANSFER_BLOCKLIST`
+/// (comma separated usernames). Unset limits don't apply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransferPolicy {
+    pub min_amount: i32,
+    pub max_amount: Option<i32>,
+    pub daily_limit: Option<i64>,
+    pub monthly_limit: Option<i64>,
+    pub allow_self_transfer: bool,
+    pub blocklist: HashSet<String>,
+}
+
+impl Default for TransferPolicy {
+    fn default() -> Self {
+        TransferPolicy {
+            min_amount: 1,
+            max_amount: None,
+            daily_limit: None,
+            monthly_limit: None,
+            allow_self_transfer: false,
+            blocklist: HashSet::new(),
+        }
+    }
+}
+
+/// What the sender has already sent in the current periods.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OutgoingUsage {
+    pub today: i64,
+    pub this_month: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyViolation {
+    BelowMinimum { min: i32, amount: i32 },
+    AboveMaximum { max: i32, amount: i32 },
+    DailyLimit { limit: i64, used: i64, amount: i32 },
+    MonthlyLimit { limit: i64, used: i64, amount: i32 },
+    SelfTransfer,
+    BlockedRecipient(String),
+}
+
+impl fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyViolation::BelowMinimum { min, amount } => {
+                write!(f, "amount {amount} is below the minimum of {min} per transfer")
+            }
+            PolicyViolation::AboveMaximum { max, amount } => {
+                write!(f, "amount {amount} is above the maximum of {max} per transfer")
+            }
+            PolicyViolation::DailyLimit {
+                limit,
+                used,
+                amount,
+            } => write!(
+                f,
+                "sending {amount} would exceed the daily limit of {limit} ({used} already sent today)"
+            ),
+            PolicyViolation::MonthlyLimit {
+                limit,
+                used,
+                amount,
+            } => write!(
+                f,
+                "sending {amount} would exceed the monthly limit of {limit} ({used} already sent this month)"
+            ),
+            PolicyViolation::SelfTransfer => write!(f, "transfers to yourself are not allowed"),
+            PolicyViolation::BlockedRecipient(name) => {
+                write!(f, "transfers to {name} are not allowed")
+            }
+        }
+    }
+}
+
+/// Every rule a transfer broke. Kept as the error so callers can downcast it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyRejection(pub Vec<PolicyViolation>);
+
+impl fmt::Display for PolicyRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reasons = self
+            .0
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("; ");
+        write!(f, "Transfer rejected: {reasons}")
+    }
+}
+
+impl std::error::Error for PolicyRejection {}
+
+impl TransferPolicy {
+    pub fn from_env() -> Result<TransferPolicy> {
+        let default = TransferPolicy::default();
+        let policy = TransferPolicy {
+            min_amount: env_var("TRANSFER_MIN_AMOUNT")?.unwrap_or(default.min_amount),
+            max_amount: env_var("TRANSFER_MAX_AMOUNT")?,
+            daily_limit: env_var("TRANSFER_DAILY_LIMIT")?,
+            monthly_limit: env_var("TRANSFER_MONTHLY_LIMIT")?,
+            allow_self_transfer: env_var("TRANSFER_ALLOW_SELF")?
+                .unwrap_or(default.allow_self_transfer),
+            blocklist: std::env::var("TRANSFER_BLOCKLIST")
+                .map(|list| {
+                    list.split(',')
+                        .map(str::trim)
+                        .filter(|name| !name.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                })
+                .unwrap_or_default(),
+        };
+        ensure!(
+            policy.min_amount >= 1,
+            "TRANSFER_MIN_AMOUNT must be at least 1"
+        );
+        Ok(policy)
+    }
+
+    pub fn evaluate(
+        &self,
+        sender: &str,
+        receiver: &str,
+        amount: i32,
+        usage: OutgoingUsage,
+    ) -> std::result::Result<(), PolicyRejection> {
+        let mut violations = Vec::new();
+        if amount < self.min_amount {
+            violations.push(PolicyViolation::BelowMinimum {
+                min: self.min_amount,
+                amount,
+            });
+        }
+        if let Some(max) = self.max_amount.filter(|max| amount > *max) {
+            violations.push(PolicyViolation::AboveMaximum { max, amount });
+        }
+        if let Some(limit) = self
+            .daily_limit
+            .filter(|limit| usage.today + i64::from(amount) > *limit)
+        {
+            violations.push(PolicyViolation::DailyLimit {
+                limit,
+                used: usage.today,
+                amount,
+            });
+        }
+        if let Some(limit) = self
+            .monthly_limit
+            .filter(|limit| usage.this_month + i64::from(amount) > *limit)
+        {
+            violations.push(PolicyViolation::MonthlyLimit {
+                limit,
+                used: usage.this_month,
+                amount,
+            });
+        }
+        if !self.allow_self_transfer && sender == receiver {
+            violations.push(PolicyViolation::SelfTransfer);
+        }
+        if self.blocklist.contains(receiver) {
+            violations.push(PolicyViolation::BlockedRecipient(receiver.to_string()));
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(PolicyRejection(violations))
+        }
+    }
+
+    /// Whether the outgoing totals are needed at all, to skip the queries.
+    pub fn has_limits(&self) -> bool {
+        self.daily_limit.is_some() || self.monthly_limit.is_some()
+    }
+
+    /// The rules for sweeping the balance out of a closing account, which has
+    /// to go somewhere whatever its size: neither the amount bounds nor the
+    /// limits apply.
+    pub fn for_closure(&self) -> TransferPolicy {
+        TransferPolicy {
+            min_amount: 1,
+            max_amount: None,
+            daily_limit: None,
+            monthly_limit: None,
+            ..self.clone()
+        }
+    }
+
+    /// Why the balance of a closing account can't be swept to `to`, if it
+    /// can't: unlike the limits, the blocklist still applies.
+    pub fn check_sweep(&self, to: &str) -> Result<()> {
+        ensure!(
+            !self.blocklist.contains(to),
+            "Can't sweep the balance to {to}, transfers to it are not allowed. Name another account"
+        );
+        Ok(())
+    }
+}
+
+/// Start of the UTC day and month containing `now`.
+pub fn period_starts(now: NaiveDateTime) -> (NaiveDateTime, NaiveDateTime) {
+    let day = now.date();
+    let month = day.with_day(1).unwrap();
+    (
+        day.and_hms_opt(0, 0, 0).unwrap(),
+        month.and_hms_opt(0, 0, 0).unwrap(),
+    )
+}
+
+fn env_var<T: std::str::FromStr>(name: &str) -> Result<Option<T>>
+where
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match std::env::var(name) {
+        Ok(value) => Ok(Some(
+            value
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid {name}"))?,
+        )),
+        Err(_) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_policy() {
+        let policy = TransferPolicy::default();
+        let usage = OutgoingUsage::default();
+        assert!(policy.evaluate("alice", "bob", 1, usage).is_ok());
+        assert_eq!(
+            policy.evaluate("alice", "bob", 0, usage),
+            Err(PolicyRejection(vec![PolicyViolation::BelowMinimum {
+                min: 1,
+                amount: 0
+            }]))
+        );
+        assert_eq!(
+            policy.evaluate("alice", "alice", 10, usage),
+            Err(PolicyRejection(vec![PolicyViolation::SelfTransfer]))
+        );
+    }
+
+    #[test]
+    fn test_limits_and_blocklist() {
+        let policy = TransferPolicy {
+            max_amount: Some(50),
+            daily_limit: Some(100),
+            monthly_limit: Some(300),
+            blocklist: HashSet::from(["mallory".to_string()]),
+            ..Default::default()
+        };
+        let usage = OutgoingUsage {
+            today: 80,
+            this_month: 200,
+        };
+        assert!(policy.evaluate("alice", "bob", 20, usage).is_ok());
+
+        let rejection = policy.evaluate("alice", "mallory", 60, usage).unwrap_err();
+        assert_eq!(
+            rejection.0,
+            vec![
+                PolicyViolation::AboveMaximum {
+                    max: 50,
+                    amount: 60
+                },
+                PolicyViolation::DailyLimit {
+                    limit: 100,
+                    used: 80,
+                    amount: 60
+                },
+                PolicyViolation::BlockedRecipient("mallory".to_string()),
+            ]
+        );
+        assert!(rejection.to_string().starts_with("Transfer rejected: "));
+
+        let usage = OutgoingUsage {
+            today: 0,
+            this_month: 290,
+        };
+        assert_eq!(
+            policy.evaluate("alice", "bob", 20, usage),
+            Err(PolicyRejection(vec![PolicyViolation::MonthlyLimit {
+                limit: 300,
+                used: 290,
+                amount: 20
+            }]))
+        );
+    }
+
+    #[test]
+    fn test_closure_policy() {
+        let policy = TransferPolicy {
+            min_amount: 5,
+            max_amount: Some(50),
+            daily_limit: Some(100),
+            blocklist: HashSet::from(["mallory".to_string()]),
+            ..Default::default()
+        };
+        let usage = OutgoingUsage {
+            today: 80,
+            this_month: 80,
+        };
+        let closure = policy.for_closure();
+        assert!(closure.evaluate("alice", "bob", 1, usage).is_ok());
+        assert!(closure.evaluate("alice", "bob", 500, usage).is_ok());
+        assert!(policy.check_sweep("bob").is_ok());
+        assert!(policy
+            .check_sweep("mallory")
+            .unwrap_err()
+            .to_string()
+            .starts_with("Can't sweep the balance to mallory"));
+    }
+
+    #[test]
+    fn test_period_starts() {
+        let now =
+            NaiveDateTime::parse_from_str("2023-07-19 15:30:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let (day, month) = period_starts(now);
+        assert_eq!(day.to_string(), "2023-07-19 00:00:00");
+        assert_eq!(month.to_string(), "2023-07-01 00:00:00");
+    }
+}
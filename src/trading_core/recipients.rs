@@ -0,0 +1,76 @@
+use std::collections::HashSet;
+
+/// Suggestions offered when a recipient isn't found, at most.
+const MAX_SUGGESTIONS: usize = 5;
+/// Recent transfers whose counterparties rank first among suggestions.
+pub const HISTORY_SIZE: i64 = 100;
+
+/// Usernames close to `typed`, best first, for the user to pick from.
+///
+/// Names equal to `typed` ignoring case come first, then names within a small
+/// edit distance of it. At the same distance `counterparties`, the users this
+/// user has dealt with before, rank ahead of the others.
+pub fn suggest(typed: &str, usernames: &[String], counterparties: &[String]) -> Vec<String> {
+    let typed = typed.trim().to_lowercase();
+    let known = counterparties
+        .iter()
+        .map(String::as_str)
+        .collect::<HashSet<_>>();
+    let max_distance = if typed.chars().count() <= 4 { 1 } else { 2 };
+    let mut candidates = usernames
+        .iter()
+        .filter_map(|name| {
+            let distance = edit_distance(&typed, &name.to_lowercase());
+            (distance <= max_distance).then_some((distance, !known.contains(name.as_str()), name))
+        })
+        .collect::<Vec<_>>();
+    candidates.sort();
+    candidates
+        .into_iter()
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, _, name)| name.clone())
+        .collect()
+}
+
+/// Levenshtein distance between `a` and `b`, counted in chars.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("alice", "alice"), 0);
+        assert_eq!(edit_distance("alice", "alise"), 1);
+        assert_eq!(edit_distance("alice", "alce"), 1);
+        assert_eq!(edit_distance("alice", "alicia"), 2);
+        assert_eq!(edit_distance("", "bob"), 3);
+    }
+
+    #[test]
+    fn test_suggest() {
+        let usernames = names(&["Alice", "alicia", "bob", "bobby", "rob", "carol"]);
+        assert_eq!(suggest("alice", &usernames, &[]), ["Alice", "alicia"]);
+        assert_eq!(suggest("bobb", &usernames, &[]), ["bob", "bobby"]);
+        assert_eq!(suggest("bob", &usernames, &names(&["rob"])), ["bob", "rob"]);
+        assert_eq!(suggest("tob", &usernames, &names(&["rob"])), ["rob", "bob"]);
+        assert!(suggest("dave", &usernames, &[]).is_empty());
+    }
+}
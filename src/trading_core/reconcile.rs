@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use super::types::{User, SYSTEM_ACCOUNTS};
+
+/// Tables whose orphaned rows `reconcile` deletes. Ledger rows are only
+/// reported, since the ledger is append-only.
+pub static REPAIRABLE_ORPHANS: [&str; 3] = ["sessions", "password_resets", "contacts"];
+
+/// Everything the invariants are checked against, read in one transaction.
+#[derive(Debug, Clone, Default)]
+pub struct LedgerSnapshot {
+    pub users: Vec<User>,
+    /// Credits minus debits of every account with postings.
+    pub account_balances: Vec<(String, i64)>,
+    /// Total of the active holds of every payer with any.
+    pub active_holds: Vec<(String, i64)>,
+    /// Completed transfers that no posting records.
+    pub unposted_transfers: Vec<i32>,
+    /// Rows naming a user that doesn't exist, as (table, username, count).
+    pub orphans: Vec<(&'static str, String, i64)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Discrepancy {
+    /// The stored balance differs from what the postings add up to.
+    BalanceMismatch {
+        username: String,
+        stored: i32,
+        posted: i64,
+    },
+    /// The stored `held` differs from the total of the active holds.
+    HeldMismatch {
+        username: String,
+        stored: i32,
+        on_hold: i64,
+    },
+    NegativeBalance {
+        username: String,
+        balance: i32,
+        held: i32,
+    },
+    UnbalancedPostings {
+        total: i64,
+    },
+    /// Postings for an account that is neither a user nor a system account.
+    UnknownAccount {
+        account: String,
+        balance: i64,
+    },
+    UnpostedTransfer {
+        transaction_id: i32,
+    },
+    Orphaned {
+        table: &'static str,
+        username: String,
+        count: i64,
+    },
+}
+
+impl Discrepancy {
+    /// What a repair does about it, if it can do anything.
+    pub fn repair(&self) -> Option<String> {
+        match self {
+            Discrepancy::BalanceMismatch {
+                username, posted, ..
+            } if i32::try_from(*posted).is_ok() => {
+                Some(format!("set the balance of {username} to {posted}"))
+            }
+            Discrepancy::HeldMismatch {
+                username, on_hold, ..
+            } if i32::try_from(*on_hold).is_ok() => {
+                Some(format!("set the held amount of {username} to {on_hold}"))
+            }
+            Discrepancy::Orphaned {
+                table, username, ..
+            } if REPAIRABLE_ORPHANS.contains(table) => {
+                Some(format!("delete the {table} rows of {username}"))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Discrepancy::BalanceMismatch {
+                username,
+                stored,
+                posted,
+            } => write!(
+                f,
+                "{username} has a balance of {stored} but postings add up to {posted}"
+            ),
+            Discrepancy::HeldMismatch {
+                username,
+                stored,
+                on_hold,
+            } => write!(
+                f,
+                "{username} has {stored} held but {on_hold} on active holds"
+            ),
+            Discrepancy::NegativeBalance {
+                username,
+                balance,
+                held,
+            } => write!(
+                f,
+                "{username} has a negative balance or availability: balance {balance}, held {held}"
+            ),
+            Discrepancy::UnbalancedPostings { total } => {
+                write!(f, "postings sum to {total} instead of 0")
+            }
+            Discrepancy::UnknownAccount { account, balance } => write!(
+                f,
+                "postings of unknown account {account} add up to {balance}"
+            ),
+            Discrepancy::UnpostedTransfer { transaction_id } => {
+                write!(f, "transfer #{transaction_id} has no posting")
+            }
+            Discrepancy::Orphaned {
+                table,
+                username,
+                count,
+            } => write!(f, "{count} {table} rows name missing user {username}"),
+        }
+    }
+}
+
+/// Every broken invariant in `snapshot`, users first, in username order.
+pub fn check(snapshot: &LedgerSnapshot) -> Vec<Discrepancy> {
+    let posted = snapshot
+        .account_balances
+        .iter()
+        .map(|(account, balance)| (account.as_str(), *balance))
+        .collect::<HashMap<_, _>>();
+    let on_hold = snapshot
+        .active_holds
+        .iter()
+        .map(|(payer, amount)| (payer.as_str(), *amount))
+        .collect::<HashMap<_, _>>();
+
+    let mut users = snapshot.users.iter().collect::<Vec<_>>();
+    users.sort_by(|a, b| a.username.cmp(&b.username));
+    let mut found = Vec::new();
+    for user in users {
+        let username = user.username.as_str();
+        let posted = posted.get(username).copied().unwrap_or(0);
+        if i64::from(user.balance) != posted {
+            found.push(Discrepancy::BalanceMismatch {
+                username: user.username.clone(),
+                stored: user.balance,
+                posted,
+            });
+        }
+        let on_hold = on_hold.get(username).copied().unwrap_or(0);
+        if i64::from(user.held) != on_hold {
+            found.push(Discrepancy::HeldMismatch {
+                username: user.username.clone(),
+                stored: user.held,
+                on_hold,
+            });
+        }
+        if user.balance < 0 || user.held < 0 || user.available() < 0 {
+            found.push(Discrepancy::NegativeBalance {
+                username: user.username.clone(),
+                balance: user.balance,
+                held: user.held,
+            });
+        }
+    }
+
+    let total = snapshot
+        .account_balances
+        .iter()
+        .map(|(_, balance)| balance)
+        .sum::<i64>();
+    if total != 0 {
+        found.push(Discrepancy::UnbalancedPostings { total });
+    }
+    for (account, balance) in &snapshot.account_balances {
+        let known = SYSTEM_ACCOUNTS.contains(&account.as_str())
+            || snapshot.users.iter().any(|user| &user.username == account);
+        if !known {
+            found.push(Discrepancy::UnknownAccount {
+                account: account.clone(),
+                balance: *balance,
+            });
+        }
+    }
+    found.extend(
+        snapshot
+            .unposted_transfers
+            .iter()
+            .map(|&transaction_id| Discrepancy::UnpostedTransfer { transaction_id }),
+    );
+    found.extend(
+        snapshot
+            .orphans
+            .iter()
+            .map(|(table, username, count)| Discrepancy::Orphaned {
+                table,
+                username: username.clone(),
+                count: *count,
+            }),
+    );
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trading_core::types::ISSUANCE_ACCOUNT;
+
+    #[test]
+    fn test_check() {
+        let mut alice = User::new("alice".to_string(), "hash".to_string(), 100);
+        alice.held = 10;
+        let mut bob = User::new("bob".to_string(), "hash".to_string(), 90);
+        bob.held = 95;
+        let snapshot = LedgerSnapshot {
+            users: vec![bob, alice],
+            account_balances: vec![
+                ("alice".to_string(), 100),
+                ("bob".to_string(), 100),
+                ("carol".to_string(), 5),
+                (ISSUANCE_ACCOUNT.to_string(), -200),
+            ],
+            active_holds: vec![("alice".to_string(), 10), ("bob".to_string(), 95)],
+            unposted_transfers: vec![7],
+            orphans: vec![("sessions", "carol".to_string(), 2)],
+        };
+        let found = check(&snapshot);
+        assert_eq!(
+            found,
+            [
+                Discrepancy::BalanceMismatch {
+                    username: "bob".to_string(),
+                    stored: 90,
+                    posted: 100,
+                },
+                Discrepancy::NegativeBalance {
+                    username: "bob".to_string(),
+                    balance: 90,
+                    held: 95,
+                },
+                Discrepancy::UnbalancedPostings { total: 5 },
+                Discrepancy::UnknownAccount {
+                    account: "carol".to_string(),
+                    balance: 5,
+                },
+                Discrepancy::UnpostedTransfer { transaction_id: 7 },
+                Discrepancy::Orphaned {
+                    table: "sessions",
+                    username: "carol".to_string(),
+                    count: 2,
+                },
+            ]
+        );
+        let repairs = found
+            .iter()
+            .filter_map(Discrepancy::repair)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            repairs,
+            [
+                "set the balance of bob to 100",
+                "delete the sessions rows of carol"
+            ]
+        );
+    }
+}
@@ -0,0 +1,192 @@
+use anyhow::Result;
+use chrono::{NaiveDateTime, Utc};
+use tracing::{error, info, warn};
+
+use super::types::{
+    Hold, Recurrence, ScheduledTransfer, Transaction, User, SCHEDULE_COMPLETED, SCHEDULE_FAILED,
+};
+use crate::global;
+
+/// Expires overdue holds and executes due scheduled transfers every
+/// `SCHEDULER_INTERVAL_SECS`, forever.
+pub async fn run_scheduler() {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(
+        global::SCHEDULER_INTERVAL_SECS,
+    ));
+    loop {
+        interval.tick().await;
+        let now = Utc::now().naive_utc();
+        match tokio::task::spawn_blocking(move || Hold::expire_to_db(now)).await {
+            Ok(Ok(0)) => {}
+            Ok(Ok(count)) => info!("Expired {} hold(s)", count),
+            Ok(Err(e)) => error!("Expiring holds failed: {}", e),
+            Err(e) => error!("Expiring holds panicked: {}", e),
+        }
+        match tokio::task::spawn_blocking(move || run_due_transfers(now)).await {
+            Ok(Ok(0)) => {}
+            Ok(Ok(count)) => info!("Ran {} scheduled transfer(s)", count),
+            Ok(Err(e)) => error!("Scheduler failed: {}", e),
+            Err(e) => error!("Scheduler panicked: {}", e),
+        }
+    }
+}
+
+/// Runs every schedule due at `now` once, returning how many ran.
+pub fn run_due_transfers(now: NaiveDateTime) -> Result<usize> {
+    let due = ScheduledTransfer::due_from_db(now)?;
+    for mut schedule in due.iter().cloned() {
+        let ran_at = schedule.next_run_at;
+        let outcome = schedule.execute();
+        if let Err(e) = &outcome {
+            warn!("Scheduled transfer #{} failed: {}", schedule.id, e);
+        }
+        schedule.advance(outcome.map(|_| ()).map_err(|e| e.to_string()), now)?;
+        if !schedule.update_to_db(ran_at)? {
+            warn!(
+                "Scheduled transfer #{} changed while running, outcome not stored",
+                schedule.id
+            );
+        }
+    }
+    Ok(due.len())
+}
+
+impl ScheduledTransfer {
+    /// Each run has its own idempotency key, so a run interrupted between the
+    /// transfer and `update_to_db` doesn't pay twice when retried.
+    fn execute(&self) -> Result<Transaction> {
+        let mut sender = User::retrieve_from_db(&self.sender)?;
+        let idempotency_key = format!(
+            "scheduled:{}:{}",
+            self.id,
+            self.next_run_at.format("%Y-%m-%dT%H:%M:%S")
+        );
+        sender.transfer_to_other(
+            &self.receiver,
+            self.amount,
+            self.memo.as_deref(),
+            Some(&idempotency_key),
+        )
+    }
+
+    /// Moves to the next run after the one that just happened. Runs missed
+    /// while the scheduler was down are skipped, not caught up on.
+    ///
+    /// A failed one-off transfer fails for good. A recurring one skips the
+    /// run and fails after `SCHEDULE_MAX_FAILURES` failed runs in a row.
+    fn advance(&mut self, outcome: Result<(), String>, now: NaiveDateTime) -> Result<()> {
+        let recurrence = self
+            .recurrence
+            .as_deref()
+            .map(str::parse::<Recurrence>)
+            .transpose()?;
+        match outcome {
+            Ok(()) => {
+                self.failures = 0;
+                self.last_error = None;
+            }
+            Err(e) => {
+                self.failures += 1;
+                self.last_error = Some(e);
+            }
+        }
+        match recurrence {
+            _ if self.failures >= global::SCHEDULE_MAX_FAILURES => {
+                self.status = SCHEDULE_FAILED.to_string()
+            }
+            None if self.failures > 0 => self.status = SCHEDULE_FAILED.to_string(),
+            None => self.status = SCHEDULE_COMPLETED.to_string(),
+            Some(recurrence) => {
+                while self.next_run_at <= now {
+                    self.next_run_at = recurrence.next_after(self.first_run_at, self.next_run_at);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trading_core::types::SCHEDULE_ACTIVE;
+
+    fn schedule(recurrence: Option<&str>, next_run_at: NaiveDateTime) -> ScheduledTransfer {
+        ScheduledTransfer {
+            id: 1,
+            sender: "alice".to_string(),
+            receiver: "bob".to_string(),
+            amount: 10,
+            memo: None,
+            recurrence: recurrence.map(str::to_string),
+            next_run_at,
+            status: SCHEDULE_ACTIVE.to_string(),
+            failures: 0,
+            last_error: None,
+            created_at: next_run_at,
+            first_run_at: next_run_at,
+        }
+    }
+
+    fn at(datetime: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(datetime, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    #[test]
+    fn test_one_off() {
+        let mut done = schedule(None, at("2023-07-28 00:00"));
+        done.advance(Ok(()), at("2023-07-28 00:01")).unwrap();
+        assert_eq!(done.status, SCHEDULE_COMPLETED);
+
+        let mut failed = schedule(None, at("2023-07-28 00:00"));
+        failed
+            .advance(
+                Err("Insufficient balance".to_string()),
+                at("2023-07-28 00:01"),
+            )
+            .unwrap();
+        assert_eq!(failed.status, SCHEDULE_FAILED);
+        assert_eq!(failed.last_error.as_deref(), Some("Insufficient balance"));
+    }
+
+    #[test]
+    fn test_recurring() {
+        let mut weekly = schedule(Some("weekly"), at("2023-07-28 00:00"));
+        // Down for two weeks: the missed runs are skipped.
+        weekly.advance(Ok(()), at("2023-08-12 09:00")).unwrap();
+        assert_eq!(weekly.next_run_at, at("2023-08-18 00:00"));
+        assert_eq!(weekly.status, SCHEDULE_ACTIVE);
+
+        let mut monthly = schedule(Some("monthly"), at("2023-01-31 00:00"));
+        monthly.advance(Ok(()), at("2023-01-31 00:01")).unwrap();
+        assert_eq!(monthly.next_run_at, at("2023-02-28 00:00"));
+        // February's shorter month doesn't move the following runs.
+        monthly.advance(Ok(()), at("2023-02-28 00:01")).unwrap();
+        assert_eq!(monthly.next_run_at, at("2023-03-31 00:00"));
+        monthly.advance(Ok(()), at("2023-03-31 00:01")).unwrap();
+        assert_eq!(monthly.next_run_at, at("2023-04-30 00:00"));
+        // Down over two months, including a leap-year February.
+        let mut leap = schedule(Some("monthly"), at("2024-01-30 00:00"));
+        leap.advance(Ok(()), at("2024-03-01 09:00")).unwrap();
+        assert_eq!(leap.next_run_at, at("2024-03-30 00:00"));
+
+        let mut failing = schedule(Some("daily"), at("2023-07-28 00:00"));
+        for _ in 1..global::SCHEDULE_MAX_FAILURES {
+            let now = failing.next_run_at;
+            failing
+                .advance(Err("Insufficient balance".to_string()), now)
+                .unwrap();
+            assert_eq!(failing.status, SCHEDULE_ACTIVE);
+        }
+        let mut recovered = failing.clone();
+        recovered.advance(Ok(()), recovered.next_run_at).unwrap();
+        assert_eq!(recovered.failures, 0);
+        assert_eq!(recovered.last_error, None);
+
+        let now = failing.next_run_at;
+        failing
+            .advance(Err("Insufficient balance".to_string()), now)
+            .unwrap();
+        assert_eq!(failing.status, SCHEDULE_FAILED);
+    }
+}
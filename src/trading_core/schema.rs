@@ -1,9 +1,144 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    audit_log (id) {
+        id -> Integer,
+        session_id -> Text,
+        username -> Nullable<Text>,
+        function_name -> Text,
+        arguments -> Text,
+        outcome -> Text,
+        result -> Text,
+        model -> Text,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    contacts (owner, alias) {
+        owner -> Text,
+        alias -> Text,
+        username -> Text,
+        note -> Nullable<Text>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    holds (id) {
+        id -> Integer,
+        payer -> Text,
+        payee -> Text,
+        amount -> Integer,
+        memo -> Nullable<Text>,
+        status -> Text,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        resolved_at -> Nullable<Timestamp>,
+        transaction_id -> Nullable<Integer>,
+    }
+}
+
+diesel::table! {
+    password_resets (token) {
+        token -> Text,
+        username -> Text,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        used_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    payment_requests (id) {
+        id -> Integer,
+        requester -> Text,
+        payer -> Text,
+        amount -> Integer,
+        memo -> Nullable<Text>,
+        status -> Text,
+        created_at -> Timestamp,
+        resolved_at -> Nullable<Timestamp>,
+        transaction_id -> Nullable<Integer>,
+    }
+}
+
+diesel::table! {
+    postings (id) {
+        id -> Integer,
+        debit_account -> Text,
+        credit_account -> Text,
+        amount -> Integer,
+        kind -> Text,
+        transaction_id -> Nullable<Integer>,
+        created_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    scheduled_transfers (id) {
+        id -> Integer,
+        sender -> Text,
+        receiver -> Text,
+        amount -> Integer,
+        memo -> Nullable<Text>,
+        recurrence -> Nullable<Text>,
+        next_run_at -> Timestamp,
+        status -> Text,
+        failures -> Integer,
+        last_error -> Nullable<Text>,
+        created_at -> Timestamp,
+        first_run_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    sessions (token) {
+        token -> Text,
+        username -> Text,
+        created_at -> Timestamp,
+        last_seen_at -> Timestamp,
+        expires_at -> Timestamp,
+        revoked_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    transactions (id) {
+        id -> Integer,
+        sender -> Text,
+        receiver -> Text,
+        amount -> Integer,
+        memo -> Nullable<Text>,
+        created_at -> Timestamp,
+        status -> Text,
+        idempotency_key -> Nullable<Text>,
+        refund_of -> Nullable<Integer>,
+    }
+}
+
 diesel::table! {
     users (username) {
         username -> Text,
         password -> Text,
         balance -> Integer,
+        password_hash -> Nullable<Text>,
+        held -> Integer,
+        closed_at -> Nullable<Timestamp>,
+        role -> Text,
+        frozen_at -> Nullable<Timestamp>,
     }
 }
+
+diesel::allow_tables_to_appear_in_same_query!(
+    audit_log,
+    contacts,
+    holds,
+    password_resets,
+    payment_requests,
+    postings,
+    scheduled_transfers,
+    sessions,
+    transactions,
+    users,
+);
@@ -1,69 +1,738 @@
-use anyhow::{bail, Error, Result};
-use dotenvy::dotenv;
-use lazy_static::lazy_static;
+use anyhow::{anyhow, bail, ensure, Error, Result};
 
+use chrono::NaiveDateTime;
+use diesel::connection::SimpleConnection;
 use diesel::prelude::*;
-use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::r2d2::{ConnectionManager, CustomizeConnection, Pool};
+use diesel::sqlite::Sqlite;
 
-use super::schema::users;
-use super::types::User;
+use super::payout::BatchFailed;
+use super::policy::{period_starts, OutgoingUsage, TransferPolicy};
+use super::reconcile::{self, Discrepancy, LedgerSnapshot};
+use super::schema::{
+    audit_log, contacts, holds, password_resets, payment_requests, postings, scheduled_transfers,
+    sessions, transactions, users,
+};
+use super::storage::Storage;
+use super::types::{
+    AuditFilter, AuditRecord, Contact, Direction, Hold, NewAuditRecord, NewHold, NewPaymentRequest,
+    NewPosting, NewScheduledTransfer, NewTransaction, PasswordReset, PaymentRequest, Posting,
+    ScheduledTransfer, Session, SpendingSummary, Transaction, TransactionFilter, User,
+    DEFAULT_PAGE_SIZE, HOLD_ACTIVE, HOLD_CAPTURED, HOLD_EXPIRED, HOLD_VOIDED, REQUEST_ACCEPTED,
+    REQUEST_CANCELLED, REQUEST_PENDING, SCHEDULE_ACTIVE, SCHEDULE_CANCELLED, STATUS_COMPLETED,
+};
 
-lazy_static! {
-    static ref POOL: Pool<ConnectionManager<SqliteConnection>> = {
-        dotenv().ok();
+/// Milliseconds a connection waits for another writer before giving up with
+/// "database is locked".
+static BUSY_TIMEOUT_MS: u32 = 5000;
+
+#[derive(Debug)]
+struct ConnectionOptions;
+
+impl CustomizeConnection<SqliteConnection, diesel::r2d2::Error> for ConnectionOptions {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), diesel::r2d2::Error> {
+        conn.batch_execute(&format!("PRAGMA busy_timeout = {BUSY_TIMEOUT_MS};"))
+            .map_err(diesel::r2d2::Error::QueryError)
+    }
+}
+
+/// The diesel SQLite backend.
+pub struct SqliteStorage {
+    pool: Pool<ConnectionManager<SqliteConnection>>,
+    policy: TransferPolicy,
+}
 
-        let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL hasn't been set");
+impl SqliteStorage {
+    pub fn new(database_url: &str, policy: TransferPolicy) -> Result<SqliteStorage> {
         let manager = ConnectionManager::<SqliteConnection>::new(database_url);
-        Pool::builder()
-            .build(manager)
-            .expect("Failed to create pool.")
-    };
+        let pool = Pool::builder()
+            .connection_customizer(Box::new(ConnectionOptions))
+            .build(manager)?;
+        Ok(SqliteStorage { pool, policy })
+    }
 }
 
-type Pooled = diesel::r2d2::PooledConnection<ConnectionManager<SqliteConnection>>;
+impl Storage for SqliteStorage {
+    fn insert_user(&self, user: &User) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        if User::check_existence_conn(&user.username, &mut conn)? {
+            bail!("Username already exists")
+        }
+        user.insert_into_db_conn(&mut conn)
+    }
+
+    fn retrieve_user(&self, username: &str) -> Result<User> {
+        let mut conn = self.pool.get()?;
+        User::retrieve_from_db_conn(username, &mut conn)
+    }
+
+    fn list_usernames(&self) -> Result<Vec<String>> {
+        let mut conn = self.pool.get()?;
+        User::list_usernames_conn(&mut conn)
+    }
+
+    fn update_user(&self, user: &User) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        user.update_to_db_conn(&mut conn)
+    }
+
+    fn update_password(&self, user: &User) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        user.update_password_to_db_conn(&mut conn)
+    }
+
+    fn adjust_balance(&self, username: &str, amount: i32) -> Result<User> {
+        let mut conn = self.pool.get()?;
+        User::adjust_balance_conn(username, amount, &mut conn)
+    }
+
+    fn set_frozen(&self, username: &str, frozen_at: Option<NaiveDateTime>) -> Result<User> {
+        let mut conn = self.pool.get()?;
+        User::set_frozen_conn(username, frozen_at, &mut conn)
+    }
+
+    fn set_role(&self, username: &str, role: &str) -> Result<User> {
+        let mut conn = self.pool.get()?;
+        diesel::update(users::table)
+            .filter(users::username.eq(username))
+            .set(users::role.eq(role))
+            .execute(&mut conn)?;
+        User::retrieve_from_db_conn(username, &mut conn)
+    }
+
+    fn delete_user(&self, user: &User) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        user.delete_from_db_conn(&mut conn)
+    }
+
+    fn close_user(
+        &self,
+        user: &mut User,
+        sweep_to: Option<&str>,
+        now: NaiveDateTime,
+    ) -> Result<Option<Transaction>> {
+        let mut conn = self.pool.get()?;
+        user.close_conn(sweep_to, now, &self.policy, &mut conn)
+    }
+
+    fn transfer(
+        &self,
+        sender: &mut User,
+        to_username: &str,
+        amount: i32,
+        memo: Option<&str>,
+        idempotency_key: Option<&str>,
+    ) -> Result<Transaction> {
+        let mut conn = self.pool.get()?;
+        sender.transfer_to_other_conn(
+            to_username,
+            amount,
+            memo,
+            idempotency_key,
+            &self.policy,
+            &mut conn,
+        )
+    }
+
+    fn transfer_batch(
+        &self,
+        sender: &mut User,
+        records: &[NewTransaction],
+    ) -> Result<Vec<Transaction>> {
+        let mut conn = self.pool.get()?;
+        sender.transfer_batch_conn(records, &self.policy, &mut conn)
+    }
+
+    fn check_transfer_policy(&self, sender: &User, to_username: &str, amount: i32) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        sender.check_policy_conn(&self.policy, to_username, amount, &mut conn)
+    }
+
+    fn refund(
+        &self,
+        refunder: &mut User,
+        original_id: i32,
+        amount: i32,
+        memo: Option<&str>,
+        idempotency_key: Option<&str>,
+    ) -> Result<Transaction> {
+        let mut conn = self.pool.get()?;
+        refunder.refund_conn(
+            original_id,
+            amount,
+            memo,
+            idempotency_key,
+            &self.policy,
+            &mut conn,
+        )
+    }
+
+    fn retrieve_transaction(&self, id: i32) -> Result<Transaction> {
+        let mut conn = self.pool.get()?;
+        Transaction::retrieve_from_db_conn(id, &mut conn)
+    }
+
+    fn list_refunds(&self, original_id: i32) -> Result<Vec<Transaction>> {
+        let mut conn = self.pool.get()?;
+        Transaction::list_refunds_conn(original_id, &mut conn)
+    }
+
+    fn list_transactions(
+        &self,
+        user: &User,
+        filter: &TransactionFilter,
+    ) -> Result<Vec<Transaction>> {
+        let mut conn = self.pool.get()?;
+        user.list_transactions_conn(filter, &mut conn)
+    }
+
+    fn summarize_spending(
+        &self,
+        user: &User,
+        since: Option<NaiveDateTime>,
+        until: Option<NaiveDateTime>,
+    ) -> Result<SpendingSummary> {
+        let mut conn = self.pool.get()?;
+        user.summarize_spending_conn(since, until, &mut conn)
+    }
+
+    fn list_postings(&self, account: &str) -> Result<Vec<Posting>> {
+        let mut conn = self.pool.get()?;
+        Posting::list_conn(account, &mut conn)
+    }
+
+    fn account_balances(&self) -> Result<Vec<(String, i64)>> {
+        let mut conn = self.pool.get()?;
+        Posting::account_balances_conn(&mut conn)
+    }
+
+    fn insert_session(&self, session: &Session) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        session.insert_into_db_conn(&mut conn)
+    }
+
+    fn retrieve_session(&self, token: &str) -> Result<Session> {
+        let mut conn = self.pool.get()?;
+        Session::retrieve_from_db_conn(token, &mut conn)
+    }
+
+    fn touch_session(&self, session: &Session) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        session.touch_to_db_conn(&mut conn)
+    }
+
+    fn revoke_session(&self, session: &Session, now: NaiveDateTime) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        session.revoke_to_db_conn(now, &mut conn)
+    }
+
+    fn revoke_all_sessions(&self, username: &str, now: NaiveDateTime) -> Result<usize> {
+        let mut conn = self.pool.get()?;
+        Session::revoke_all_to_db_conn(username, now, &mut conn)
+    }
+
+    fn change_password(&self, user: &User, now: NaiveDateTime) -> Result<usize> {
+        let mut conn = self.pool.get()?;
+        user.change_password_conn(now, &mut conn)
+    }
+
+    fn insert_password_reset(&self, reset: &PasswordReset) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        reset.insert_into_db_conn(&mut conn)
+    }
+
+    fn redeem_password_reset(
+        &self,
+        token: &str,
+        password_hash: &str,
+        now: NaiveDateTime,
+    ) -> Result<User> {
+        let mut conn = self.pool.get()?;
+        PasswordReset::redeem_conn(token, password_hash, now, &mut conn)
+    }
+
+    fn insert_audit_record(&self, record: &NewAuditRecord) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        AuditRecord::insert_conn(record, &mut conn)
+    }
+
+    fn list_audit_records(&self, filter: &AuditFilter) -> Result<Vec<AuditRecord>> {
+        let mut conn = self.pool.get()?;
+        AuditRecord::list_conn(filter, &mut conn)
+    }
+
+    fn insert_contact(&self, contact: &Contact) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        contact.insert_into_db_conn(&mut conn)
+    }
+
+    fn delete_contact(&self, owner: &str, alias: &str) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        Contact::delete_from_db_conn(owner, alias, &mut conn)
+    }
+
+    fn retrieve_contact(&self, owner: &str, alias: &str) -> Result<Option<Contact>> {
+        let mut conn = self.pool.get()?;
+        Contact::retrieve_from_db_conn(owner, alias, &mut conn)
+    }
+
+    fn list_contacts(&self, owner: &str) -> Result<Vec<Contact>> {
+        let mut conn = self.pool.get()?;
+        Contact::list_conn(owner, &mut conn)
+    }
+
+    fn insert_payment_request(&self, request: &NewPaymentRequest) -> Result<PaymentRequest> {
+        let mut conn = self.pool.get()?;
+        PaymentRequest::insert_conn(request, &mut conn)
+    }
+
+    fn retrieve_payment_request(&self, id: i32) -> Result<PaymentRequest> {
+        let mut conn = self.pool.get()?;
+        PaymentRequest::retrieve_from_db_conn(id, &mut conn)
+    }
+
+    fn list_payment_requests(
+        &self,
+        username: &str,
+        direction: Option<Direction>,
+        status: Option<&str>,
+    ) -> Result<Vec<PaymentRequest>> {
+        let mut conn = self.pool.get()?;
+        PaymentRequest::list_conn(username, direction, status, &mut conn)
+    }
+
+    fn resolve_payment_request(
+        &self,
+        id: i32,
+        status: &str,
+        transaction_id: Option<i32>,
+        now: NaiveDateTime,
+    ) -> Result<bool> {
+        let mut conn = self.pool.get()?;
+        PaymentRequest::resolve_conn(id, status, transaction_id, now, &mut conn)
+    }
+
+    fn accept_payment_request(
+        &self,
+        payer: &mut User,
+        id: i32,
+        now: NaiveDateTime,
+    ) -> Result<Transaction> {
+        let mut conn = self.pool.get()?;
+        payer.accept_payment_request_conn(id, now, &self.policy, &mut conn)
+    }
+
+    fn insert_scheduled_transfer(
+        &self,
+        schedule: &NewScheduledTransfer,
+    ) -> Result<ScheduledTransfer> {
+        let mut conn = self.pool.get()?;
+        ScheduledTransfer::insert_conn(schedule, &mut conn)
+    }
+
+    fn retrieve_scheduled_transfer(&self, id: i32) -> Result<ScheduledTransfer> {
+        let mut conn = self.pool.get()?;
+        ScheduledTransfer::retrieve_from_db_conn(id, &mut conn)
+    }
+
+    fn list_scheduled_transfers(&self, username: &str) -> Result<Vec<ScheduledTransfer>> {
+        let mut conn = self.pool.get()?;
+        ScheduledTransfer::list_conn(username, &mut conn)
+    }
+
+    fn due_scheduled_transfers(&self, now: NaiveDateTime) -> Result<Vec<ScheduledTransfer>> {
+        let mut conn = self.pool.get()?;
+        ScheduledTransfer::due_conn(now, &mut conn)
+    }
+
+    fn update_scheduled_transfer(
+        &self,
+        schedule: &ScheduledTransfer,
+        ran_at: NaiveDateTime,
+    ) -> Result<bool> {
+        let mut conn = self.pool.get()?;
+        schedule.update_to_db_conn(ran_at, &mut conn)
+    }
+
+    fn cancel_scheduled_transfer(&self, id: i32) -> Result<bool> {
+        let mut conn = self.pool.get()?;
+        ScheduledTransfer::cancel_conn(id, &mut conn)
+    }
+
+    fn authorize_hold(
+        &self,
+        payer: &mut User,
+        payee: &str,
+        amount: i32,
+        memo: Option<&str>,
+        expires_at: NaiveDateTime,
+    ) -> Result<Hold> {
+        let mut conn = self.pool.get()?;
+        payer.authorize_hold_conn(payee, amount, memo, expires_at, &self.policy, &mut conn)
+    }
+
+    fn capture_hold(&self, payee: &User, id: i32, amount: i32) -> Result<Transaction> {
+        let mut conn = self.pool.get()?;
+        Hold::capture_conn(id, &payee.username, amount, &self.policy, &mut conn)
+    }
+
+    fn void_hold(&self, payee: &User, id: i32) -> Result<Hold> {
+        let mut conn = self.pool.get()?;
+        Hold::void_conn(id, &payee.username, &mut conn)
+    }
+
+    fn retrieve_hold(&self, id: i32) -> Result<Hold> {
+        let mut conn = self.pool.get()?;
+        Hold::retrieve_from_db_conn(id, &mut conn)
+    }
+
+    fn list_holds(
+        &self,
+        username: &str,
+        direction: Option<Direction>,
+        status: Option<&str>,
+    ) -> Result<Vec<Hold>> {
+        let mut conn = self.pool.get()?;
+        Hold::list_conn(username, direction, status, &mut conn)
+    }
+
+    fn expire_holds(&self, now: NaiveDateTime) -> Result<usize> {
+        let mut conn = self.pool.get()?;
+        conn.transaction(|conn| Hold::expire_conn(None, now, conn))
+    }
+
+    fn reconcile(&self, repair: bool) -> Result<Vec<Discrepancy>> {
+        let mut conn = self.pool.get()?;
+        Discrepancy::reconcile_conn(repair, &mut conn)
+    }
+}
 
 impl User {
-    pub fn insert_into_db(&self) -> Result<()> {
-        let mut conn = POOL.get()?;
-        if User::check_existence_conn(&self.username, &mut conn)? {
-            bail!("Username already exists")
+    fn transfer_to_other_conn(
+        &mut self,
+        to_username: &str,
+        amount: i32,
+        memo: Option<&str>,
+        idempotency_key: Option<&str>,
+        policy: &TransferPolicy,
+        conn: &mut SqliteConnection,
+    ) -> Result<Transaction> {
+        let sender = self.username.clone();
+        let record = NewTransaction::completed(&sender, to_username, amount, memo, idempotency_key);
+        self.record_transfer_conn(&record, policy, conn)
+    }
+
+    /// Records every transfer inside one transaction, each in a savepoint of
+    /// its own, so the first failure rolls all of them back.
+    fn transfer_batch_conn(
+        &mut self,
+        records: &[NewTransaction],
+        policy: &TransferPolicy,
+        conn: &mut SqliteConnection,
+    ) -> Result<Vec<Transaction>> {
+        let now = chrono::Utc::now().naive_utc();
+        let result = conn.transaction::<_, Error, _>(|conn| {
+            // Write first: each transfer looks for a replay before it writes.
+            Hold::expire_conn(Some(&self.username), now, conn)?;
+            records
+                .iter()
+                .enumerate()
+                .map(|(index, record)| {
+                    self.record_transfer_conn(record, policy, conn)
+                        .map_err(|e| {
+                            anyhow!(BatchFailed {
+                                index,
+                                reason: e.to_string(),
+                            })
+                        })
+                })
+                .collect()
+        });
+        *self = User::retrieve_from_db_conn(&self.username, conn)?;
+        result
+    }
+
+    /// Moves `record.amount` with conditional updates on the stored balances,
+    /// so concurrent sessions can neither overdraw the account nor overwrite
+    /// each other's credits. The in-memory user is refreshed afterwards.
+    ///
+    /// A transfer with an idempotency key this user has already used is not
+    /// executed again; the original ledger entry is returned instead.
+    fn record_transfer_conn(
+        &mut self,
+        record: &NewTransaction,
+        policy: &TransferPolicy,
+        conn: &mut SqliteConnection,
+    ) -> Result<Transaction> {
+        let amount = record.amount;
+        ensure!(amount > 0, "Amount must be positive");
+        if let Some(original) = self.replayed_transfer_conn(record, conn)? {
+            return Ok(original);
         }
-        self.insert_into_db_conn(&mut conn)
+        let result = conn.transaction::<_, Error, _>(|conn| {
+            // Write first: a deferred transaction that reads before writing can
+            // fail with SQLITE_BUSY instead of waiting for the busy timeout.
+            Hold::expire_conn(Some(&self.username), record.created_at, conn)?;
+            let debited = diesel::update(users::table)
+                .filter(users::username.eq(&self.username))
+                .filter(users::frozen_at.is_null())
+                .filter((users::balance - users::held).ge(amount))
+                .set(users::balance.eq(users::balance - amount))
+                .execute(conn)?;
+            if debited == 0 {
+                User::retrieve_from_db_conn(&self.username, conn)?.check_active()?;
+                self.check_balance_conn(amount, conn)?;
+                bail!("Failed to debit {}", self.username)
+            }
+            // Checked after the debit so that concurrent transfers can't
+            // both slip under a limit. A refund only gives back what the
+            // sender was sent, so neither the limits nor the blocklist apply.
+            if record.refund_of.is_none() {
+                self.check_policy_conn(policy, record.receiver, amount, conn)?;
+            }
+            let credited = diesel::update(users::table)
+                .filter(users::username.eq(record.receiver))
+                .filter(users::closed_at.is_null())
+                .filter(users::frozen_at.is_null())
+                .filter(users::balance.le(i32::MAX - amount))
+                .set(users::balance.eq(users::balance + amount))
+                .execute(conn)?;
+            if credited == 0 {
+                User::retrieve_from_db_conn(record.receiver, conn)?.check_active()?;
+                bail!(
+                    "Can't credit {} to {}, the balance would overflow",
+                    amount,
+                    record.receiver
+                )
+            }
+            let inserted = Transaction::insert_conn(record, conn)?;
+            NewPosting::transfer(&inserted).insert_conn(conn)?;
+            Ok(inserted)
+        });
+        let record = match result {
+            // Lost a race against a concurrent call with the same key.
+            Err(e) if is_unique_violation(&e) => {
+                self.replayed_transfer_conn(record, conn)?.ok_or(e)?
+            }
+            result => result?,
+        };
+        *self = User::retrieve_from_db_conn(&self.username, conn)?;
+        Ok(record)
     }
 
-    pub fn retrieve_from_db(username: &str) -> Result<User> {
-        let mut conn = POOL.get()?;
-        if !User::check_existence_conn(username, &mut conn)? {
-            bail!("Username doesn't exist")
+    /// Marks the request accepted, only while it is still pending, and pays it
+    /// in the same transaction: a concurrent decline either comes first and
+    /// nothing is paid, or finds the request no longer pending.
+    fn accept_payment_request_conn(
+        &mut self,
+        id: i32,
+        now: NaiveDateTime,
+        policy: &TransferPolicy,
+        conn: &mut SqliteConnection,
+    ) -> Result<Transaction> {
+        let result = conn.transaction::<_, Error, _>(|conn| {
+            // Write first.
+            let accepted = diesel::update(payment_requests::table)
+                .filter(payment_requests::id.eq(id))
+                .filter(payment_requests::payer.eq(&self.username))
+                .filter(payment_requests::status.eq(REQUEST_PENDING))
+                .set((
+                    payment_requests::status.eq(REQUEST_ACCEPTED),
+                    payment_requests::resolved_at.eq(now),
+                ))
+                .execute(conn)?;
+            if accepted == 0 {
+                bail!("Payment request #{} is no longer pending", id)
+            }
+            let request = PaymentRequest::retrieve_from_db_conn(id, conn)?;
+            let idempotency_key = format!("payment-request:{}", id);
+            let record = NewTransaction::completed(
+                &request.payer,
+                &request.requester,
+                request.amount,
+                request.memo.as_deref(),
+                Some(&idempotency_key),
+            );
+            let record = self.record_transfer_conn(&record, policy, conn)?;
+            diesel::update(payment_requests::table)
+                .filter(payment_requests::id.eq(id))
+                .set(payment_requests::transaction_id.eq(record.id))
+                .execute(conn)?;
+            Ok(record)
+        });
+        *self = User::retrieve_from_db_conn(&self.username, conn)?;
+        result
+    }
+
+    /// A transfer back to the sender of `original_id`, linked to it.
+    fn refund_conn(
+        &mut self,
+        original_id: i32,
+        amount: i32,
+        memo: Option<&str>,
+        idempotency_key: Option<&str>,
+        policy: &TransferPolicy,
+        conn: &mut SqliteConnection,
+    ) -> Result<Transaction> {
+        // Ledger entries never change, so this read can stay outside the
+        // transaction, which must write first.
+        let original = Transaction::retrieve_from_db_conn(original_id, conn)?;
+        original.check_refundable(&self.username)?;
+        let refunder = self.username.clone();
+        let record = NewTransaction {
+            refund_of: Some(original_id),
+            ..NewTransaction::completed(&refunder, &original.sender, amount, memo, idempotency_key)
+        };
+        let result = conn.transaction::<_, Error, _>(|conn| {
+            // Write first: from here on concurrent refunds wait for this one,
+            // so the remaining amount can't change between the check and the
+            // transfer.
+            Hold::expire_conn(Some(&self.username), record.created_at, conn)?;
+            let refunds = Transaction::list_refunds_conn(original_id, conn)?;
+            original.check_refund_fits(&refunds, amount, idempotency_key)?;
+            self.record_transfer_conn(&record, policy, conn)
+        });
+        *self = User::retrieve_from_db_conn(&self.username, conn)?;
+        result
+    }
+
+    /// The transfer already recorded under the idempotency key of `record`,
+    /// if any. Reusing a key for a different transfer is an error.
+    fn replayed_transfer_conn(
+        &self,
+        record: &NewTransaction,
+        conn: &mut SqliteConnection,
+    ) -> Result<Option<Transaction>> {
+        let Some(key) = record.idempotency_key else {
+            return Ok(None);
+        };
+        let original = transactions::table
+            .filter(transactions::sender.eq(&self.username))
+            .filter(transactions::idempotency_key.eq(key))
+            .select(Transaction::as_select())
+            .first(conn)
+            .optional()?;
+        if let Some(original) = &original {
+            ensure!(
+                original.receiver == record.receiver
+                    && original.amount == record.amount
+                    && original.refund_of == record.refund_of,
+                "Idempotency key already used for a different transfer"
+            );
         }
-        User::retrieve_from_db_conn(username, &mut conn)
+        Ok(original)
     }
 
-    pub fn update_to_db(&self) -> Result<()> {
-        let mut conn = POOL.get()?;
-        self.update_to_db_conn(&mut conn)
+    fn list_transactions_conn(
+        &self,
+        filter: &TransactionFilter,
+        conn: &mut SqliteConnection,
+    ) -> Result<Vec<Transaction>> {
+        let records = self
+            .ledger_query(filter)
+            .order(transactions::id.desc())
+            .limit(filter.limit.unwrap_or(DEFAULT_PAGE_SIZE))
+            .offset(filter.offset.unwrap_or(0))
+            .select(Transaction::as_select())
+            .load(conn)?;
+        Ok(records)
     }
 
-    pub fn delete_from_db(&self) -> Result<()> {
-        let mut conn = POOL.get()?;
-        self.delete_from_db_conn(&mut conn)
+    /// Ledger entries involving this user that match `filter`, ignoring pagination.
+    fn ledger_query<'a>(
+        &'a self,
+        filter: &'a TransactionFilter,
+    ) -> transactions::BoxedQuery<'a, Sqlite> {
+        let mut query = transactions::table.into_boxed();
+        query = match filter.direction {
+            Some(Direction::Outgoing) => query.filter(transactions::sender.eq(&self.username)),
+            Some(Direction::Incoming) => query.filter(transactions::receiver.eq(&self.username)),
+            None => query.filter(
+                transactions::sender
+                    .eq(&self.username)
+                    .or(transactions::receiver.eq(&self.username)),
+            ),
+        };
+        if let Some(other) = &filter.counterparty {
+            query = query.filter(
+                transactions::sender
+                    .eq(other)
+                    .or(transactions::receiver.eq(other)),
+            );
+        }
+        if let Some(since) = filter.since {
+            query = query.filter(transactions::created_at.ge(since));
+        }
+        if let Some(until) = filter.until {
+            query = query.filter(transactions::created_at.lt(until));
+        }
+        if let Some(status) = &filter.status {
+            query = query.filter(transactions::status.eq(status));
+        }
+        query
     }
 
-    pub fn transfer_to_other(&mut self, to_username: &str, amount: i32) -> Result<()> {
-        let mut conn = POOL.get().unwrap();
-        conn.transaction::<_, Error, _>(|conn| {
-            self.check_balance_conn(amount, conn)?;
-            let mut other = User::retrieve_from_db_conn(to_username, conn)?;
-            self.balance -= amount;
-            self.update_to_db_conn(conn).unwrap();
-            other.balance += amount;
-            other.update_to_db_conn(conn).unwrap();
+    fn summarize_spending_conn(
+        &self,
+        since: Option<NaiveDateTime>,
+        until: Option<NaiveDateTime>,
+        conn: &mut SqliteConnection,
+    ) -> Result<SpendingSummary> {
+        let filter = TransactionFilter {
+            since,
+            until,
+            status: Some(STATUS_COMPLETED.to_string()),
+            ..Default::default()
+        };
+        let records = self
+            .ledger_query(&filter)
+            .select(Transaction::as_select())
+            .load(conn)?;
+        Ok(SpendingSummary::from_ledger(&self.username, &records))
+    }
 
-            Ok(())
-        })
+    fn check_policy_conn(
+        &self,
+        policy: &TransferPolicy,
+        to_username: &str,
+        amount: i32,
+        conn: &mut SqliteConnection,
+    ) -> Result<()> {
+        let usage = if policy.has_limits() {
+            let (today, this_month) = period_starts(chrono::Utc::now().naive_utc());
+            OutgoingUsage {
+                today: self.outgoing_since_conn(today, conn)?,
+                this_month: self.outgoing_since_conn(this_month, conn)?,
+            }
+        } else {
+            OutgoingUsage::default()
+        };
+        policy.evaluate(&self.username, to_username, amount, usage)?;
+        Ok(())
+    }
+
+    fn outgoing_since_conn(
+        &self,
+        since: NaiveDateTime,
+        conn: &mut SqliteConnection,
+    ) -> Result<i64> {
+        let filter = TransactionFilter {
+            direction: Some(Direction::Outgoing),
+            since: Some(since),
+            status: Some(STATUS_COMPLETED.to_string()),
+            ..Default::default()
+        };
+        let total = self
+            .ledger_query(&filter)
+            .select(transactions::amount)
+            .load::<i32>(conn)?
+            .into_iter()
+            .map(i64::from)
+            .sum();
+        Ok(total)
     }
 
-    fn check_existence_conn(username: &str, conn: &mut Pooled) -> Result<bool> {
+    fn check_existence_conn(username: &str, conn: &mut SqliteConnection) -> Result<bool> {
         let count = users::table
             .filter(users::username.eq(username))
             .count()
@@ -71,14 +740,21 @@ impl User {
         Ok(count > 0)
     }
 
-    fn insert_into_db_conn(&self, conn: &mut Pooled) -> Result<()> {
-        diesel::insert_into(users::table)
-            .values(self)
-            .execute(conn)?;
-        Ok(())
+    /// Grants the starting balance from issuance along with the insert.
+    fn insert_into_db_conn(&self, conn: &mut SqliteConnection) -> Result<()> {
+        conn.transaction::<_, Error, _>(|conn| {
+            diesel::insert_into(users::table)
+                .values(self)
+                .execute(conn)?;
+            if self.balance > 0 {
+                let now = chrono::Utc::now().naive_utc();
+                NewPosting::grant(&self.username, self.balance, now).insert_conn(conn)?;
+            }
+            Ok(())
+        })
     }
 
-    fn retrieve_from_db_conn(username: &str, conn: &mut Pooled) -> Result<User> {
+    fn retrieve_from_db_conn(username: &str, conn: &mut SqliteConnection) -> Result<User> {
         if !User::check_existence_conn(username, conn)? {
             bail!("Username doesn't exist")
         }
@@ -88,7 +764,7 @@ impl User {
         Ok(user)
     }
 
-    fn update_to_db_conn(&self, conn: &mut Pooled) -> Result<()> {
+    fn update_to_db_conn(&self, conn: &mut SqliteConnection) -> Result<()> {
         diesel::update(users::table)
             .filter(users::username.eq(&self.username))
             .set(self)
@@ -96,39 +772,1030 @@ impl User {
         Ok(())
     }
 
-    fn delete_from_db_conn(&self, conn: &mut Pooled) -> Result<()> {
+    fn list_usernames_conn(conn: &mut SqliteConnection) -> Result<Vec<String>> {
+        let usernames = users::table
+            .filter(users::closed_at.is_null())
+            .order(users::username)
+            .select(users::username)
+            .load(conn)?;
+        Ok(usernames)
+    }
+
+    /// Unlike `update_to_db_conn`, leaves the stored balance alone.
+    fn update_password_to_db_conn(&self, conn: &mut SqliteConnection) -> Result<()> {
+        diesel::update(users::table)
+            .filter(users::username.eq(&self.username))
+            .set((
+                users::password.eq(&self.password),
+                users::password_hash.eq(&self.password_hash),
+            ))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    fn adjust_balance_conn(
+        username: &str,
+        amount: i32,
+        conn: &mut SqliteConnection,
+    ) -> Result<User> {
+        let now = chrono::Utc::now().naive_utc();
+        conn.transaction::<_, Error, _>(|conn| {
+            // Write first, as in `transfer_to_other_conn`.
+            Hold::expire_conn(Some(username), now, conn)?;
+            let adjusted = diesel::update(users::table)
+                .filter(users::username.eq(username))
+                .filter(users::closed_at.is_null())
+                // Split so that neither side can overflow the column type.
+                .filter((users::balance + amount.min(0)).ge(users::held))
+                .filter(users::balance.le(i32::MAX - amount.max(0)))
+                .set(users::balance.eq(users::balance + amount))
+                .execute(conn)?;
+            let user = User::retrieve_from_db_conn(username, conn)?;
+            if adjusted == 0 {
+                user.check_open()?;
+                ensure!(
+                    amount < 0,
+                    "Can't mint {} into {}, the balance would overflow",
+                    amount,
+                    username
+                );
+                bail!(
+                    "Can't burn {} from {}, only {} available",
+                    amount.unsigned_abs(),
+                    username,
+                    user.available()
+                )
+            }
+            if amount != 0 {
+                NewPosting::adjustment(username, amount, now).insert_conn(conn)?;
+            }
+            Ok(user)
+        })
+    }
+
+    fn set_frozen_conn(
+        username: &str,
+        frozen_at: Option<NaiveDateTime>,
+        conn: &mut SqliteConnection,
+    ) -> Result<User> {
+        let query = diesel::update(users::table)
+            .filter(users::username.eq(username))
+            .into_boxed();
+        let query = match frozen_at {
+            Some(_) => query.filter(users::frozen_at.is_null()),
+            None => query,
+        };
+        query.set(users::frozen_at.eq(frozen_at)).execute(conn)?;
+        User::retrieve_from_db_conn(username, conn)
+    }
+
+    fn change_password_conn(
+        &self,
+        now: NaiveDateTime,
+        conn: &mut SqliteConnection,
+    ) -> Result<usize> {
+        conn.transaction::<_, Error, _>(|conn| {
+            self.update_password_to_db_conn(conn)?;
+            Session::revoke_all_to_db_conn(&self.username, now, conn)
+        })
+    }
+
+    /// Soft-deletes the user: the row stays for the ledger, and everything
+    /// still pending with other users is cancelled.
+    fn close_conn(
+        &mut self,
+        sweep_to: Option<&str>,
+        now: NaiveDateTime,
+        policy: &TransferPolicy,
+        conn: &mut SqliteConnection,
+    ) -> Result<Option<Transaction>> {
+        let result = conn.transaction::<_, Error, _>(|conn| {
+            // Write first, and only once.
+            let closed = diesel::update(users::table)
+                .filter(users::username.eq(&self.username))
+                .filter(users::closed_at.is_null())
+                .set(users::closed_at.eq(now))
+                .execute(conn)?;
+            if closed == 0 {
+                User::retrieve_from_db_conn(&self.username, conn)?.check_open()?;
+                bail!("Failed to close {}", self.username)
+            }
+            Hold::expire_conn(Some(&self.username), now, conn)?;
+            let stored = User::retrieve_from_db_conn(&self.username, conn)?;
+            ensure!(
+                stored.held == 0,
+                "Can't close an account with {} on hold",
+                stored.held
+            );
+            let sweep = match sweep_to {
+                _ if stored.balance == 0 => None,
+                Some(to) => {
+                    policy.check_sweep(to)?;
+                    Some(self.transfer_to_other_conn(
+                        to,
+                        stored.balance,
+                        Some("Account closure"),
+                        None,
+                        &policy.for_closure(),
+                        conn,
+                    )?)
+                }
+                None => bail!(
+                    "The account still holds {}, name an account to sweep it to",
+                    stored.balance
+                ),
+            };
+
+            diesel::update(payment_requests::table)
+                .filter(
+                    payment_requests::payer
+                        .eq(&self.username)
+                        .or(payment_requests::requester.eq(&self.username)),
+                )
+                .filter(payment_requests::status.eq(REQUEST_PENDING))
+                .set((
+                    payment_requests::status.eq(REQUEST_CANCELLED),
+                    payment_requests::resolved_at.eq(now),
+                ))
+                .execute(conn)?;
+            diesel::update(scheduled_transfers::table)
+                .filter(
+                    scheduled_transfers::sender
+                        .eq(&self.username)
+                        .or(scheduled_transfers::receiver.eq(&self.username)),
+                )
+                .filter(scheduled_transfers::status.eq(SCHEDULE_ACTIVE))
+                .set(scheduled_transfers::status.eq(SCHEDULE_CANCELLED))
+                .execute(conn)?;
+            // Nobody else could capture the holds placed for this user.
+            let payers = holds::table
+                .filter(holds::payee.eq(&self.username))
+                .filter(holds::status.eq(HOLD_ACTIVE))
+                .select(holds::payer)
+                .distinct()
+                .load::<String>(conn)?;
+            diesel::update(holds::table)
+                .filter(holds::payee.eq(&self.username))
+                .filter(holds::status.eq(HOLD_ACTIVE))
+                .set((holds::status.eq(HOLD_VOIDED), holds::resolved_at.eq(now)))
+                .execute(conn)?;
+            for payer in payers {
+                User::sync_held_conn(&payer, conn)?;
+            }
+            Session::revoke_all_to_db_conn(&self.username, now, conn)?;
+            Ok(sweep)
+        });
+        *self = User::retrieve_from_db_conn(&self.username, conn)?;
+        result
+    }
+
+    fn delete_from_db_conn(&self, conn: &mut SqliteConnection) -> Result<()> {
         diesel::delete(users::table)
             .filter(users::username.eq(&self.username))
             .execute(conn)?;
         Ok(())
     }
 
-    fn check_balance_conn(&self, amount: i32, conn: &mut Pooled) -> Result<()> {
-        let balance = users::table
+    /// Fails unless `amount` is covered by the balance not reserved by holds.
+    fn check_balance_conn(&self, amount: i32, conn: &mut SqliteConnection) -> Result<()> {
+        let available = users::table
             .filter(users::username.eq(&self.username))
-            .select(users::balance)
+            .select(users::balance - users::held)
             .first::<i32>(conn)?;
-        if balance >= amount {
+        if available >= amount {
             Ok(())
         } else {
             bail!("Insufficient balance")
         }
     }
+
+    fn authorize_hold_conn(
+        &mut self,
+        payee: &str,
+        amount: i32,
+        memo: Option<&str>,
+        expires_at: NaiveDateTime,
+        policy: &TransferPolicy,
+        conn: &mut SqliteConnection,
+    ) -> Result<Hold> {
+        ensure!(amount > 0, "Amount must be positive");
+        ensure!(payee != self.username, "Can't place a hold for yourself");
+        let now = chrono::Utc::now().naive_utc();
+        ensure!(expires_at > now, "Expiry must be in the future");
+        let hold = conn.transaction::<_, Error, _>(|conn| {
+            // Write first, as in `transfer_to_other_conn`.
+            Hold::expire_conn(Some(&self.username), now, conn)?;
+            let hold = Hold::insert_conn(
+                &NewHold {
+                    payer: &self.username,
+                    payee,
+                    amount,
+                    memo,
+                    status: HOLD_ACTIVE,
+                    created_at: now,
+                    expires_at,
+                },
+                conn,
+            )?;
+            User::sync_held_conn(&self.username, conn)?;
+            self.check_balance_conn(0, conn)?;
+            if !User::check_existence_conn(payee, conn)? {
+                bail!("Username doesn't exist")
+            }
+            self.check_policy_conn(policy, payee, amount, conn)?;
+            Ok(hold)
+        })?;
+        *self = User::retrieve_from_db_conn(&self.username, conn)?;
+        Ok(hold)
+    }
+
+    /// Recomputes the stored `held` of `username` from its active holds.
+    fn sync_held_conn(username: &str, conn: &mut SqliteConnection) -> Result<()> {
+        let held = holds::table
+            .filter(holds::payer.eq(username))
+            .filter(holds::status.eq(HOLD_ACTIVE))
+            .select(holds::amount)
+            .load::<i32>(conn)?
+            .into_iter()
+            .sum::<i32>();
+        diesel::update(users::table)
+            .filter(users::username.eq(username))
+            .set(users::held.eq(held))
+            .execute(conn)?;
+        Ok(())
+    }
+}
+
+impl Transaction {
+    fn retrieve_from_db_conn(id: i32, conn: &mut SqliteConnection) -> Result<Transaction> {
+        let record = transactions::table
+            .filter(transactions::id.eq(id))
+            .select(Transaction::as_select())
+            .first(conn)
+            .optional()?;
+        record.ok_or_else(|| anyhow!("Transfer #{} doesn't exist", id))
+    }
+
+    fn list_refunds_conn(
+        original_id: i32,
+        conn: &mut SqliteConnection,
+    ) -> Result<Vec<Transaction>> {
+        let refunds = transactions::table
+            .filter(transactions::refund_of.eq(original_id))
+            .filter(transactions::status.eq(STATUS_COMPLETED))
+            .order(transactions::id)
+            .select(Transaction::as_select())
+            .load(conn)?;
+        Ok(refunds)
+    }
+
+    fn insert_conn(record: &NewTransaction, conn: &mut SqliteConnection) -> Result<Transaction> {
+        diesel::insert_into(transactions::table)
+            .values(record)
+            .execute(conn)?;
+        let inserted = transactions::table
+            .order(transactions::id.desc())
+            .select(Transaction::as_select())
+            .first(conn)?;
+        Ok(inserted)
+    }
+}
+
+impl NewPosting<'_> {
+    fn insert_conn(&self, conn: &mut SqliteConnection) -> Result<()> {
+        diesel::insert_into(postings::table)
+            .values(self)
+            .execute(conn)?;
+        Ok(())
+    }
+}
+
+impl Posting {
+    fn list_conn(account: &str, conn: &mut SqliteConnection) -> Result<Vec<Posting>> {
+        let postings = postings::table
+            .filter(
+                postings::debit_account
+                    .eq(account)
+                    .or(postings::credit_account.eq(account)),
+            )
+            .order(postings::id)
+            .select(Posting::as_select())
+            .load(conn)?;
+        Ok(postings)
+    }
+
+    fn account_balances_conn(conn: &mut SqliteConnection) -> Result<Vec<(String, i64)>> {
+        let debits = postings::table
+            .group_by(postings::debit_account)
+            .select((postings::debit_account, diesel::dsl::sum(postings::amount)))
+            .load::<(String, Option<i64>)>(conn)?;
+        let credits = postings::table
+            .group_by(postings::credit_account)
+            .select((postings::credit_account, diesel::dsl::sum(postings::amount)))
+            .load::<(String, Option<i64>)>(conn)?;
+        Ok(Posting::net_balances(
+            debits
+                .into_iter()
+                .map(|(account, total)| (account, total.unwrap_or(0))),
+            credits
+                .into_iter()
+                .map(|(account, total)| (account, total.unwrap_or(0))),
+        ))
+    }
+}
+
+impl Session {
+    fn insert_into_db_conn(&self, conn: &mut SqliteConnection) -> Result<()> {
+        diesel::insert_into(sessions::table)
+            .values(self)
+            .execute(conn)?;
+        Ok(())
+    }
+
+    fn retrieve_from_db_conn(token: &str, conn: &mut SqliteConnection) -> Result<Session> {
+        let session = sessions::table
+            .filter(sessions::token.eq(token))
+            .select(Session::as_select())
+            .first(conn)
+            .optional()?;
+        session.ok_or_else(|| anyhow!("Session doesn't exist"))
+    }
+
+    fn touch_to_db_conn(&self, conn: &mut SqliteConnection) -> Result<()> {
+        diesel::update(sessions::table)
+            .filter(sessions::token.eq(&self.token))
+            .set(sessions::last_seen_at.eq(self.last_seen_at))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    fn revoke_to_db_conn(&self, now: NaiveDateTime, conn: &mut SqliteConnection) -> Result<()> {
+        diesel::update(sessions::table)
+            .filter(sessions::token.eq(&self.token))
+            .filter(sessions::revoked_at.is_null())
+            .set(sessions::revoked_at.eq(now))
+            .execute(conn)?;
+        Ok(())
+    }
+
+    fn revoke_all_to_db_conn(
+        username: &str,
+        now: NaiveDateTime,
+        conn: &mut SqliteConnection,
+    ) -> Result<usize> {
+        let revoked = diesel::update(sessions::table)
+            .filter(sessions::username.eq(username))
+            .filter(sessions::revoked_at.is_null())
+            .filter(sessions::expires_at.gt(now))
+            .set(sessions::revoked_at.eq(now))
+            .execute(conn)?;
+        Ok(revoked)
+    }
+}
+
+impl PasswordReset {
+    fn insert_into_db_conn(&self, conn: &mut SqliteConnection) -> Result<()> {
+        diesel::insert_into(password_resets::table)
+            .values(self)
+            .execute(conn)?;
+        Ok(())
+    }
+
+    fn redeem_conn(
+        token: &str,
+        password_hash: &str,
+        now: NaiveDateTime,
+        conn: &mut SqliteConnection,
+    ) -> Result<User> {
+        conn.transaction::<_, Error, _>(|conn| {
+            // Write first, so a token can only be redeemed once.
+            let redeemed = diesel::update(password_resets::table)
+                .filter(password_resets::token.eq(token))
+                .filter(password_resets::used_at.is_null())
+                .filter(password_resets::expires_at.gt(now))
+                .set(password_resets::used_at.eq(now))
+                .execute(conn)?;
+            ensure!(
+                redeemed == 1,
+                "Reset token is invalid, expired or already used"
+            );
+            let username = password_resets::table
+                .filter(password_resets::token.eq(token))
+                .select(password_resets::username)
+                .first::<String>(conn)?;
+            let mut user = User::retrieve_from_db_conn(&username, conn)?;
+            user.check_open()?;
+            user.password.clear();
+            user.password_hash = Some(password_hash.to_string());
+            user.change_password_conn(now, conn)?;
+            Ok(user)
+        })
+    }
+}
+
+impl AuditRecord {
+    fn insert_conn(record: &NewAuditRecord, conn: &mut SqliteConnection) -> Result<()> {
+        diesel::insert_into(audit_log::table)
+            .values(record)
+            .execute(conn)?;
+        Ok(())
+    }
+
+    fn list_conn(filter: &AuditFilter, conn: &mut SqliteConnection) -> Result<Vec<AuditRecord>> {
+        let mut query = audit_log::table.into_boxed();
+        if let Some(username) = &filter.username {
+            query = query.filter(audit_log::username.eq(username));
+        }
+        if let Some(session_id) = &filter.session_id {
+            query = query.filter(audit_log::session_id.eq(session_id));
+        }
+        if let Some(name) = &filter.function_name {
+            query = query.filter(audit_log::function_name.eq(name));
+        }
+        if let Some(since) = filter.since {
+            query = query.filter(audit_log::created_at.ge(since));
+        }
+        if let Some(until) = filter.until {
+            query = query.filter(audit_log::created_at.lt(until));
+        }
+        if let Some(limit) = filter.limit {
+            query = query.limit(limit);
+        }
+        if let Some(offset) = filter.offset {
+            query = query.offset(offset);
+        }
+        let records = query
+            .order(audit_log::id.asc())
+            .select(AuditRecord::as_select())
+            .load(conn)?;
+        Ok(records)
+    }
+}
+
+impl Contact {
+    fn insert_into_db_conn(&self, conn: &mut SqliteConnection) -> Result<()> {
+        match diesel::insert_into(contacts::table)
+            .values(self)
+            .execute(conn)
+            .map_err(Error::from)
+        {
+            Err(e) if is_unique_violation(&e) => {
+                bail!("You already have a contact named {}", self.alias)
+            }
+            result => result.map(|_| ()),
+        }
+    }
+
+    fn delete_from_db_conn(owner: &str, alias: &str, conn: &mut SqliteConnection) -> Result<()> {
+        let deleted = diesel::delete(contacts::table)
+            .filter(contacts::owner.eq(owner))
+            .filter(contacts::alias.eq(alias))
+            .execute(conn)?;
+        ensure!(deleted == 1, "No contact named {}", alias);
+        Ok(())
+    }
+
+    fn retrieve_from_db_conn(
+        owner: &str,
+        alias: &str,
+        conn: &mut SqliteConnection,
+    ) -> Result<Option<Contact>> {
+        let contact = contacts::table
+            .filter(contacts::owner.eq(owner))
+            .filter(contacts::alias.eq(alias))
+            .select(Contact::as_select())
+            .first(conn)
+            .optional()?;
+        Ok(contact)
+    }
+
+    fn list_conn(owner: &str, conn: &mut SqliteConnection) -> Result<Vec<Contact>> {
+        let contacts = contacts::table
+            .filter(contacts::owner.eq(owner))
+            .order(contacts::alias)
+            .select(Contact::as_select())
+            .load(conn)?;
+        Ok(contacts)
+    }
+}
+
+impl PaymentRequest {
+    fn insert_conn(
+        request: &NewPaymentRequest,
+        conn: &mut SqliteConnection,
+    ) -> Result<PaymentRequest> {
+        // In one transaction, so that the newest row can't be a concurrent
+        // caller's.
+        conn.transaction::<_, Error, _>(|conn| {
+            diesel::insert_into(payment_requests::table)
+                .values(request)
+                .execute(conn)?;
+            let inserted = payment_requests::table
+                .order(payment_requests::id.desc())
+                .select(PaymentRequest::as_select())
+                .first(conn)?;
+            Ok(inserted)
+        })
+    }
+
+    fn retrieve_from_db_conn(id: i32, conn: &mut SqliteConnection) -> Result<PaymentRequest> {
+        let request = payment_requests::table
+            .filter(payment_requests::id.eq(id))
+            .select(PaymentRequest::as_select())
+            .first(conn)
+            .optional()?;
+        request.ok_or_else(|| anyhow!("Payment request #{} doesn't exist", id))
+    }
+
+    fn list_conn(
+        username: &str,
+        direction: Option<Direction>,
+        status: Option<&str>,
+        conn: &mut SqliteConnection,
+    ) -> Result<Vec<PaymentRequest>> {
+        let mut query = payment_requests::table.into_boxed();
+        query = match direction {
+            Some(Direction::Incoming) => query.filter(payment_requests::payer.eq(username)),
+            Some(Direction::Outgoing) => query.filter(payment_requests::requester.eq(username)),
+            None => query.filter(
+                payment_requests::payer
+                    .eq(username)
+                    .or(payment_requests::requester.eq(username)),
+            ),
+        };
+        if let Some(status) = status {
+            query = query.filter(payment_requests::status.eq(status));
+        }
+        let requests = query
+            .order(payment_requests::id.desc())
+            .select(PaymentRequest::as_select())
+            .load(conn)?;
+        Ok(requests)
+    }
+
+    fn resolve_conn(
+        id: i32,
+        status: &str,
+        transaction_id: Option<i32>,
+        now: NaiveDateTime,
+        conn: &mut SqliteConnection,
+    ) -> Result<bool> {
+        let updated = diesel::update(payment_requests::table)
+            .filter(payment_requests::id.eq(id))
+            .filter(payment_requests::status.eq(REQUEST_PENDING))
+            .set((
+                payment_requests::status.eq(status),
+                payment_requests::transaction_id.eq(transaction_id),
+                payment_requests::resolved_at.eq(now),
+            ))
+            .execute(conn)?;
+        Ok(updated > 0)
+    }
+}
+
+impl ScheduledTransfer {
+    fn insert_conn(
+        schedule: &NewScheduledTransfer,
+        conn: &mut SqliteConnection,
+    ) -> Result<ScheduledTransfer> {
+        // As for payment requests, the newest row must be this one.
+        conn.transaction::<_, Error, _>(|conn| {
+            diesel::insert_into(scheduled_transfers::table)
+                .values(schedule)
+                .execute(conn)?;
+            let inserted = scheduled_transfers::table
+                .order(scheduled_transfers::id.desc())
+                .select(ScheduledTransfer::as_select())
+                .first(conn)?;
+            Ok(inserted)
+        })
+    }
+
+    fn retrieve_from_db_conn(id: i32, conn: &mut SqliteConnection) -> Result<ScheduledTransfer> {
+        let schedule = scheduled_transfers::table
+            .filter(scheduled_transfers::id.eq(id))
+            .select(ScheduledTransfer::as_select())
+            .first(conn)
+            .optional()?;
+        schedule.ok_or_else(|| anyhow!("Scheduled transfer #{} doesn't exist", id))
+    }
+
+    fn list_conn(username: &str, conn: &mut SqliteConnection) -> Result<Vec<ScheduledTransfer>> {
+        let schedules = scheduled_transfers::table
+            .filter(scheduled_transfers::sender.eq(username))
+            .order(scheduled_transfers::id.desc())
+            .select(ScheduledTransfer::as_select())
+            .load(conn)?;
+        Ok(schedules)
+    }
+
+    fn due_conn(now: NaiveDateTime, conn: &mut SqliteConnection) -> Result<Vec<ScheduledTransfer>> {
+        let schedules = scheduled_transfers::table
+            .filter(scheduled_transfers::status.eq(SCHEDULE_ACTIVE))
+            .filter(scheduled_transfers::next_run_at.le(now))
+            .order((scheduled_transfers::next_run_at, scheduled_transfers::id))
+            .select(ScheduledTransfer::as_select())
+            .load(conn)?;
+        Ok(schedules)
+    }
+
+    fn update_to_db_conn(
+        &self,
+        ran_at: NaiveDateTime,
+        conn: &mut SqliteConnection,
+    ) -> Result<bool> {
+        let updated = diesel::update(scheduled_transfers::table)
+            .filter(scheduled_transfers::id.eq(self.id))
+            .filter(scheduled_transfers::status.eq(SCHEDULE_ACTIVE))
+            .filter(scheduled_transfers::next_run_at.eq(ran_at))
+            .set((
+                scheduled_transfers::next_run_at.eq(self.next_run_at),
+                scheduled_transfers::status.eq(&self.status),
+                scheduled_transfers::failures.eq(self.failures),
+                scheduled_transfers::last_error.eq(&self.last_error),
+            ))
+            .execute(conn)?;
+        Ok(updated > 0)
+    }
+
+    fn cancel_conn(id: i32, conn: &mut SqliteConnection) -> Result<bool> {
+        let updated = diesel::update(scheduled_transfers::table)
+            .filter(scheduled_transfers::id.eq(id))
+            .filter(scheduled_transfers::status.eq(SCHEDULE_ACTIVE))
+            .set(scheduled_transfers::status.eq(SCHEDULE_CANCELLED))
+            .execute(conn)?;
+        Ok(updated > 0)
+    }
+}
+
+impl Hold {
+    fn insert_conn(hold: &NewHold, conn: &mut SqliteConnection) -> Result<Hold> {
+        diesel::insert_into(holds::table)
+            .values(hold)
+            .execute(conn)?;
+        let inserted = holds::table
+            .order(holds::id.desc())
+            .select(Hold::as_select())
+            .first(conn)?;
+        Ok(inserted)
+    }
+
+    fn retrieve_from_db_conn(id: i32, conn: &mut SqliteConnection) -> Result<Hold> {
+        let hold = holds::table
+            .filter(holds::id.eq(id))
+            .select(Hold::as_select())
+            .first(conn)
+            .optional()?;
+        hold.ok_or_else(|| anyhow!("Hold #{} doesn't exist", id))
+    }
+
+    fn list_conn(
+        username: &str,
+        direction: Option<Direction>,
+        status: Option<&str>,
+        conn: &mut SqliteConnection,
+    ) -> Result<Vec<Hold>> {
+        let mut query = holds::table.into_boxed();
+        query = match direction {
+            Some(Direction::Outgoing) => query.filter(holds::payer.eq(username)),
+            Some(Direction::Incoming) => query.filter(holds::payee.eq(username)),
+            None => query.filter(holds::payer.eq(username).or(holds::payee.eq(username))),
+        };
+        if let Some(status) = status {
+            query = query.filter(holds::status.eq(status));
+        }
+        let holds = query
+            .order(holds::id.desc())
+            .select(Hold::as_select())
+            .load(conn)?;
+        Ok(holds)
+    }
+
+    /// Claims the hold for `payee` and transfers `amount` of it; whatever
+    /// isn't captured goes back to the payer's available balance.
+    fn capture_conn(
+        id: i32,
+        payee: &str,
+        amount: i32,
+        policy: &TransferPolicy,
+        conn: &mut SqliteConnection,
+    ) -> Result<Transaction> {
+        ensure!(amount > 0, "Amount must be positive");
+        let now = chrono::Utc::now().naive_utc();
+        conn.transaction::<_, Error, _>(|conn| {
+            let claimed = diesel::update(holds::table)
+                .filter(holds::id.eq(id))
+                .filter(holds::payee.eq(payee))
+                .filter(holds::status.eq(HOLD_ACTIVE))
+                .filter(holds::expires_at.gt(now))
+                .set((holds::status.eq(HOLD_CAPTURED), holds::resolved_at.eq(now)))
+                .execute(conn)?;
+            let hold = Hold::retrieve_from_db_conn(id, conn)?;
+            if claimed == 0 {
+                hold.check_capturable(payee, now)?;
+                bail!("Failed to capture hold #{}", id)
+            }
+            ensure!(
+                amount <= hold.amount,
+                "Can't capture more than the {} held",
+                hold.amount
+            );
+            User::sync_held_conn(&hold.payer, conn)?;
+            let mut payer = User::retrieve_from_db_conn(&hold.payer, conn)?;
+            let record = payer.transfer_to_other_conn(
+                &hold.payee,
+                amount,
+                hold.memo.as_deref(),
+                Some(&hold.capture_key()),
+                policy,
+                conn,
+            )?;
+            diesel::update(holds::table)
+                .filter(holds::id.eq(id))
+                .set(holds::transaction_id.eq(record.id))
+                .execute(conn)?;
+            Ok(record)
+        })
+    }
+
+    fn void_conn(id: i32, payee: &str, conn: &mut SqliteConnection) -> Result<Hold> {
+        let now = chrono::Utc::now().naive_utc();
+        conn.transaction::<_, Error, _>(|conn| {
+            let voided = diesel::update(holds::table)
+                .filter(holds::id.eq(id))
+                .filter(holds::payee.eq(payee))
+                .filter(holds::status.eq(HOLD_ACTIVE))
+                .set((holds::status.eq(HOLD_VOIDED), holds::resolved_at.eq(now)))
+                .execute(conn)?;
+            let hold = Hold::retrieve_from_db_conn(id, conn)?;
+            if voided == 0 {
+                hold.check_active(payee)?;
+                bail!("Failed to void hold #{}", id)
+            }
+            User::sync_held_conn(&hold.payer, conn)?;
+            Ok(hold)
+        })
+    }
+
+    /// Expires active holds past `expires_at`, of `payer` only if given, and
+    /// releases their funds. Run it inside a transaction.
+    fn expire_conn(
+        payer: Option<&str>,
+        now: NaiveDateTime,
+        conn: &mut SqliteConnection,
+    ) -> Result<usize> {
+        let mut query = diesel::update(holds::table)
+            .filter(holds::status.eq(HOLD_ACTIVE))
+            .filter(holds::expires_at.le(now))
+            .into_boxed();
+        if let Some(payer) = payer {
+            query = query.filter(holds::payer.eq(payer));
+        }
+        let expired = query
+            .set((holds::status.eq(HOLD_EXPIRED), holds::resolved_at.eq(now)))
+            .execute(conn)?;
+        if expired > 0 {
+            let payers = holds::table
+                .filter(holds::status.eq(HOLD_EXPIRED))
+                .filter(holds::resolved_at.eq(now))
+                .select(holds::payer)
+                .distinct()
+                .load::<String>(conn)?;
+            for payer in payers {
+                User::sync_held_conn(&payer, conn)?;
+            }
+        }
+        Ok(expired)
+    }
+}
+
+impl LedgerSnapshot {
+    fn load_conn(conn: &mut SqliteConnection) -> Result<LedgerSnapshot> {
+        use diesel::dsl::{count_star, not};
+
+        let active_holds = holds::table
+            .filter(holds::status.eq(HOLD_ACTIVE))
+            .group_by(holds::payer)
+            .select((holds::payer, diesel::dsl::sum(holds::amount)))
+            .load::<(String, Option<i64>)>(conn)?;
+        let unposted_transfers = transactions::table
+            .filter(transactions::status.eq(STATUS_COMPLETED))
+            .filter(not(transactions::id.nullable().eq_any(
+                postings::table
+                    .filter(postings::transaction_id.is_not_null())
+                    .select(postings::transaction_id),
+            )))
+            .order(transactions::id)
+            .select(transactions::id)
+            .load(conn)?;
+
+        let usernames = || users::table.select(users::username);
+        let mut orphans = Vec::new();
+        let sessions = sessions::table
+            .filter(not(sessions::username.eq_any(usernames())))
+            .group_by(sessions::username)
+            .select((sessions::username, count_star()))
+            .load::<(String, i64)>(conn)?;
+        orphans.extend(sessions.into_iter().map(|(u, n)| ("sessions", u, n)));
+        let resets = password_resets::table
+            .filter(not(password_resets::username.eq_any(usernames())))
+            .group_by(password_resets::username)
+            .select((password_resets::username, count_star()))
+            .load::<(String, i64)>(conn)?;
+        orphans.extend(resets.into_iter().map(|(u, n)| ("password_resets", u, n)));
+        let owners = contacts::table
+            .filter(not(contacts::owner.eq_any(usernames())))
+            .group_by(contacts::owner)
+            .select((contacts::owner, count_star()))
+            .load::<(String, i64)>(conn)?;
+        orphans.extend(owners.into_iter().map(|(u, n)| ("contacts", u, n)));
+        let targets = contacts::table
+            .filter(not(contacts::username.eq_any(usernames())))
+            .group_by(contacts::username)
+            .select((contacts::username, count_star()))
+            .load::<(String, i64)>(conn)?;
+        orphans.extend(targets.into_iter().map(|(u, n)| ("contacts", u, n)));
+        let senders = transactions::table
+            .filter(not(transactions::sender.eq_any(usernames())))
+            .group_by(transactions::sender)
+            .select((transactions::sender, count_star()))
+            .load::<(String, i64)>(conn)?;
+        orphans.extend(senders.into_iter().map(|(u, n)| ("transactions", u, n)));
+        let receivers = transactions::table
+            .filter(not(transactions::receiver.eq_any(usernames())))
+            .group_by(transactions::receiver)
+            .select((transactions::receiver, count_star()))
+            .load::<(String, i64)>(conn)?;
+        orphans.extend(receivers.into_iter().map(|(u, n)| ("transactions", u, n)));
+
+        Ok(LedgerSnapshot {
+            users: users::table.load::<User>(conn)?,
+            account_balances: Posting::account_balances_conn(conn)?,
+            active_holds: active_holds
+                .into_iter()
+                .map(|(payer, total)| (payer, total.unwrap_or(0)))
+                .collect(),
+            unposted_transfers,
+            orphans,
+        })
+    }
+}
+
+impl Discrepancy {
+    /// Reads and repairs in one deferred transaction: if anything is written
+    /// in between, the repair fails with SQLITE_BUSY instead of acting on
+    /// stale balances.
+    fn reconcile_conn(repair: bool, conn: &mut SqliteConnection) -> Result<Vec<Discrepancy>> {
+        conn.transaction::<_, Error, _>(|conn| {
+            let found = reconcile::check(&LedgerSnapshot::load_conn(conn)?);
+            if repair {
+                for discrepancy in &found {
+                    discrepancy.repair_conn(conn)?;
+                }
+            }
+            Ok(found)
+        })
+    }
+
+    /// Does what `repair` describes, or nothing.
+    fn repair_conn(&self, conn: &mut SqliteConnection) -> Result<()> {
+        match self {
+            Discrepancy::BalanceMismatch {
+                username, posted, ..
+            } => {
+                if let Ok(posted) = i32::try_from(*posted) {
+                    diesel::update(users::table)
+                        .filter(users::username.eq(username))
+                        .set(users::balance.eq(posted))
+                        .execute(conn)?;
+                }
+            }
+            Discrepancy::HeldMismatch {
+                username, on_hold, ..
+            } => {
+                if let Ok(on_hold) = i32::try_from(*on_hold) {
+                    diesel::update(users::table)
+                        .filter(users::username.eq(username))
+                        .set(users::held.eq(on_hold))
+                        .execute(conn)?;
+                }
+            }
+            Discrepancy::Orphaned {
+                table: "sessions",
+                username,
+                ..
+            } => {
+                diesel::delete(sessions::table.filter(sessions::username.eq(username)))
+                    .execute(conn)?;
+            }
+            Discrepancy::Orphaned {
+                table: "password_resets",
+                username,
+                ..
+            } => {
+                diesel::delete(
+                    password_resets::table.filter(password_resets::username.eq(username)),
+                )
+                .execute(conn)?;
+            }
+            Discrepancy::Orphaned {
+                table: "contacts",
+                username,
+                ..
+            } => {
+                diesel::delete(
+                    contacts::table.filter(
+                        contacts::owner
+                            .eq(username)
+                            .or(contacts::username.eq(username)),
+                    ),
+                )
+                .execute(conn)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+fn is_unique_violation(e: &Error) -> bool {
+    matches!(
+        e.downcast_ref::<diesel::result::Error>(),
+        Some(diesel::result::Error::DatabaseError(
+            diesel::result::DatabaseErrorKind::UniqueViolation,
+            _
+        ))
+    )
+}
+
+/// Tests share one SQLite file, and concurrent test transactions would fail
+/// with "database is locked". Hold this guard for the duration of a DB test.
+#[cfg(test)]
+pub(super) fn lock_test_db() -> std::sync::MutexGuard<'static, ()> {
+    static TEST_DB: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    TEST_DB.lock().unwrap_or_else(|e| e.into_inner())
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashSet;
+
     use super::*;
+    use crate::trading_core::policy::{PolicyRejection, PolicyViolation};
+    use crate::trading_core::types::{
+        AUDIT_ERROR, AUDIT_OK, ISSUANCE_ACCOUNT, POSTING_BURN, POSTING_GRANT, POSTING_TRANSFER,
+        REQUEST_DECLINED,
+    };
+    use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+    use dotenvy::dotenv;
+
+    const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+    fn test_conn() -> SqliteConnection {
+        dotenv().ok();
+        let database_url = std::env::var("DATABASE_URL").unwrap();
+        let mut conn = SqliteConnection::establish(&database_url).unwrap();
+        conn.begin_test_transaction().unwrap();
+        conn
+    }
+
+    /// A fresh, migrated database file, for tests that commit from several
+    /// connections at once.
+    fn temp_pool(
+        name: &str,
+        size: usize,
+    ) -> (
+        Pool<ConnectionManager<SqliteConnection>>,
+        std::path::PathBuf,
+    ) {
+        let path =
+            std::env::temp_dir().join(format!("trading-gpt-{name}-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let manager = ConnectionManager::<SqliteConnection>::new(path.to_str().unwrap());
+        let pool = Pool::builder()
+            .max_size(size as u32)
+            .connection_customizer(Box::new(ConnectionOptions))
+            .build(manager)
+            .unwrap();
+        pool.get()
+            .unwrap()
+            .run_pending_migrations(MIGRATIONS)
+            .unwrap();
+        (pool, path)
+    }
 
     #[test]
-    fn test_pool() {
-        POOL.get().unwrap();
+    fn test_storage() {
+        dotenv().ok();
+        let database_url = std::env::var("DATABASE_URL").unwrap();
+        let storage = SqliteStorage::new(&database_url, TransferPolicy::default()).unwrap();
+        assert!(storage.retrieve_user("nobody").is_err());
     }
 
     #[test]
     fn test_crud() {
-        let mut conn = POOL.get().unwrap();
-        conn.begin_test_transaction().unwrap();
+        let _guard = lock_test_db();
+        let mut conn = test_conn();
 
         let user = User::new("test".to_string(), "test".to_string(), 100);
         user.insert_into_db_conn(&mut conn).unwrap();
@@ -144,4 +1811,1509 @@ mod tests {
         let count: i64 = users::table.count().get_result(&mut conn).unwrap();
         assert_eq!(count, 0);
     }
+
+    #[test]
+    fn test_transfer_records_ledger() {
+        let _guard = lock_test_db();
+        let mut conn = test_conn();
+
+        let mut alice = User::new("alice".to_string(), "hash".to_string(), 100);
+        let bob = User::new("bob".to_string(), "hash".to_string(), 100);
+        let carol = User::new("carol".to_string(), "hash".to_string(), 100);
+        for user in [&alice, &bob, &carol] {
+            user.insert_into_db_conn(&mut conn).unwrap();
+        }
+
+        let record = alice
+            .transfer_to_other_conn(
+                "bob",
+                30,
+                Some("lunch"),
+                None,
+                &TransferPolicy::default(),
+                &mut conn,
+            )
+            .unwrap();
+        assert_eq!(record.sender, "alice");
+        assert_eq!(record.receiver, "bob");
+        assert_eq!(record.amount, 30);
+        assert_eq!(record.memo.as_deref(), Some("lunch"));
+        assert_eq!(record.status, STATUS_COMPLETED);
+        alice
+            .transfer_to_other_conn(
+                "carol",
+                20,
+                None,
+                None,
+                &TransferPolicy::default(),
+                &mut conn,
+            )
+            .unwrap();
+        assert!(alice
+            .transfer_to_other_conn(
+                "bob",
+                1000,
+                None,
+                None,
+                &TransferPolicy::default(),
+                &mut conn
+            )
+            .is_err());
+
+        let all = alice
+            .list_transactions_conn(&TransactionFilter::default(), &mut conn)
+            .unwrap();
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].receiver, "carol");
+
+        let filter = TransactionFilter {
+            counterparty: Some("bob".to_string()),
+            ..Default::default()
+        };
+        let with_bob = alice.list_transactions_conn(&filter, &mut conn).unwrap();
+        assert_eq!(with_bob, vec![record.clone()]);
+
+        let filter = TransactionFilter {
+            direction: Some(Direction::Incoming),
+            ..Default::default()
+        };
+        let incoming = bob.list_transactions_conn(&filter, &mut conn).unwrap();
+        assert_eq!(incoming, vec![record]);
+        assert!(carol
+            .list_transactions_conn(
+                &TransactionFilter {
+                    direction: Some(Direction::Outgoing),
+                    ..Default::default()
+                },
+                &mut conn
+            )
+            .unwrap()
+            .is_empty());
+
+        let filter = TransactionFilter {
+            limit: Some(1),
+            offset: Some(1),
+            ..Default::default()
+        };
+        let page = alice.list_transactions_conn(&filter, &mut conn).unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].receiver, "bob");
+    }
+
+    #[test]
+    fn test_summarize_spending() {
+        let _guard = lock_test_db();
+        let mut conn = test_conn();
+
+        let mut alice = User::new("alice".to_string(), "hash".to_string(), 100);
+        let mut bob = User::new("bob".to_string(), "hash".to_string(), 100);
+        let carol = User::new("carol".to_string(), "hash".to_string(), 100);
+        for user in [&alice, &bob, &carol] {
+            user.insert_into_db_conn(&mut conn).unwrap();
+        }
+        alice
+            .transfer_to_other_conn("bob", 10, None, None, &TransferPolicy::default(), &mut conn)
+            .unwrap();
+        alice
+            .transfer_to_other_conn(
+                "carol",
+                25,
+                None,
+                None,
+                &TransferPolicy::default(),
+                &mut conn,
+            )
+            .unwrap();
+        alice
+            .transfer_to_other_conn("bob", 5, None, None, &TransferPolicy::default(), &mut conn)
+            .unwrap();
+        bob.transfer_to_other_conn(
+            "alice",
+            7,
+            None,
+            None,
+            &TransferPolicy::default(),
+            &mut conn,
+        )
+        .unwrap();
+
+        let summary = alice
+            .summarize_spending_conn(None, None, &mut conn)
+            .unwrap();
+        assert_eq!(
+            summary,
+            SpendingSummary {
+                total_out: 40,
+                total_in: 7,
+                by_counterparty: vec![("carol".to_string(), 25), ("bob".to_string(), 15)],
+            }
+        );
+
+        let tomorrow = chrono::Utc::now().naive_utc() + chrono::Duration::days(1);
+        let summary = alice
+            .summarize_spending_conn(Some(tomorrow), None, &mut conn)
+            .unwrap();
+        assert_eq!(summary.total_out, 0);
+        assert!(summary.by_counterparty.is_empty());
+    }
+
+    #[test]
+    fn test_transfer_keeps_concurrent_credit() {
+        let _guard = lock_test_db();
+        let mut conn = test_conn();
+
+        let alice = User::new("alice".to_string(), "hash".to_string(), 100);
+        let bob = User::new("bob".to_string(), "hash".to_string(), 100);
+        alice.insert_into_db_conn(&mut conn).unwrap();
+        bob.insert_into_db_conn(&mut conn).unwrap();
+
+        // Both sessions hold a copy loaded before the other one transfers.
+        let mut alice_session = User::retrieve_from_db_conn("alice", &mut conn).unwrap();
+        let mut bob_session = User::retrieve_from_db_conn("bob", &mut conn).unwrap();
+        bob_session
+            .transfer_to_other_conn(
+                "alice",
+                50,
+                None,
+                None,
+                &TransferPolicy::default(),
+                &mut conn,
+            )
+            .unwrap();
+        alice_session
+            .transfer_to_other_conn(
+                "bob",
+                120,
+                None,
+                None,
+                &TransferPolicy::default(),
+                &mut conn,
+            )
+            .unwrap();
+
+        assert_eq!(alice_session.balance, 30);
+        assert_eq!(
+            User::retrieve_from_db_conn("alice", &mut conn)
+                .unwrap()
+                .balance,
+            30
+        );
+        assert_eq!(
+            User::retrieve_from_db_conn("bob", &mut conn)
+                .unwrap()
+                .balance,
+            170
+        );
+        assert!(alice_session
+            .transfer_to_other_conn(
+                "bob",
+                -10,
+                None,
+                None,
+                &TransferPolicy::default(),
+                &mut conn
+            )
+            .is_err());
+        assert!(alice_session
+            .transfer_to_other_conn(
+                "nobody",
+                10,
+                None,
+                None,
+                &TransferPolicy::default(),
+                &mut conn
+            )
+            .is_err());
+        assert_eq!(
+            User::retrieve_from_db_conn("alice", &mut conn)
+                .unwrap()
+                .balance,
+            30
+        );
+    }
+
+    #[test]
+    fn test_concurrent_transfers_conserve_money() {
+        const USERS: usize = 5;
+        const THREADS: usize = 8;
+        const ROUNDS: usize = 50;
+        const START: i32 = 100;
+
+        let (pool, path) = temp_pool("stress", THREADS);
+        let mut conn = pool.get().unwrap();
+        for i in 0..USERS {
+            User::new(format!("user{i}"), "hash".to_string(), START)
+                .insert_into_db_conn(&mut conn)
+                .unwrap();
+        }
+
+        let handles = (0..THREADS)
+            .map(|t| {
+                let pool = pool.clone();
+                std::thread::spawn(move || {
+                    let mut conn = pool.get().unwrap();
+                    let mut completed = 0;
+                    for round in 0..ROUNDS {
+                        let from = (t + round) % USERS;
+                        let to = (from + 1 + (t * 7 + round * 3) % (USERS - 1)) % USERS;
+                        let amount = ((t * 13 + round * 17) % 60 + 1) as i32;
+                        let mut sender =
+                            User::retrieve_from_db_conn(&format!("user{from}"), &mut conn).unwrap();
+                        match sender.transfer_to_other_conn(
+                            &format!("user{to}"),
+                            amount,
+                            None,
+                            None,
+                            &TransferPolicy::default(),
+                            &mut conn,
+                        ) {
+                            Ok(_) => completed += 1,
+                            Err(e) => assert_eq!(e.to_string(), "Insufficient balance"),
+                        }
+                    }
+                    completed
+                })
+            })
+            .collect::<Vec<_>>();
+        let completed: usize = handles.into_iter().map(|h| h.join().unwrap()).sum();
+
+        let balances = users::table
+            .select((users::username, users::balance))
+            .load::<(String, i32)>(&mut conn)
+            .unwrap();
+        let ledger = transactions::table
+            .select(Transaction::as_select())
+            .load(&mut conn)
+            .unwrap();
+        assert_eq!(ledger.len(), completed);
+        assert_eq!(
+            balances.iter().map(|(_, b)| b).sum::<i32>(),
+            START * USERS as i32
+        );
+        for (username, balance) in &balances {
+            assert!(*balance >= 0);
+            let sent: i32 = ledger
+                .iter()
+                .filter(|t| &t.sender == username)
+                .map(|t| t.amount)
+                .sum();
+            let received: i32 = ledger
+                .iter()
+                .filter(|t| &t.receiver == username)
+                .map(|t| t.amount)
+                .sum();
+            assert_eq!(*balance, START - sent + received);
+        }
+
+        drop(conn);
+        drop(pool);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_session_revocation() {
+        let _guard = lock_test_db();
+        let mut conn = test_conn();
+
+        User::new("alice".to_string(), "hash".to_string(), 100)
+            .insert_into_db_conn(&mut conn)
+            .unwrap();
+        let now = chrono::Utc::now().naive_utc();
+        let ttl = chrono::Duration::hours(1);
+        let first = Session::new("t1".to_string(), "alice".to_string(), now, ttl);
+        let second = Session::new("t2".to_string(), "alice".to_string(), now, ttl);
+        first.insert_into_db_conn(&mut conn).unwrap();
+        second.insert_into_db_conn(&mut conn).unwrap();
+        assert_eq!(
+            Session::retrieve_from_db_conn("t1", &mut conn).unwrap(),
+            first
+        );
+        assert!(Session::retrieve_from_db_conn("t3", &mut conn).is_err());
+
+        first.revoke_to_db_conn(now, &mut conn).unwrap();
+        let revoked = Session::retrieve_from_db_conn("t1", &mut conn).unwrap();
+        assert_eq!(revoked.revoked_at, Some(now));
+        assert!(Session::retrieve_from_db_conn("t2", &mut conn)
+            .unwrap()
+            .revoked_at
+            .is_none());
+
+        let count = Session::revoke_all_to_db_conn("alice", now, &mut conn).unwrap();
+        assert_eq!(count, 1);
+        assert!(Session::retrieve_from_db_conn("t2", &mut conn)
+            .unwrap()
+            .revoked_at
+            .is_some());
+    }
+
+    #[test]
+    fn test_password_reset() {
+        let _guard = lock_test_db();
+        let mut conn = test_conn();
+
+        let mut alice = User::new("alice".to_string(), "hash".to_string(), 100);
+        alice.insert_into_db_conn(&mut conn).unwrap();
+        let now = chrono::Utc::now().naive_utc();
+        let ttl = chrono::Duration::hours(1);
+        Session::new("t1".to_string(), "alice".to_string(), now, ttl)
+            .insert_into_db_conn(&mut conn)
+            .unwrap();
+        alice.password_hash = Some("changed".to_string());
+        assert_eq!(alice.change_password_conn(now, &mut conn).unwrap(), 1);
+        assert!(Session::retrieve_from_db_conn("t1", &mut conn)
+            .unwrap()
+            .revoked_at
+            .is_some());
+
+        PasswordReset::new("r1".to_string(), "alice".to_string(), now, ttl)
+            .insert_into_db_conn(&mut conn)
+            .unwrap();
+        let reset = PasswordReset::redeem_conn("r1", "reset", now, &mut conn).unwrap();
+        assert_eq!(reset.password_hash.as_deref(), Some("reset"));
+        let stored = User::retrieve_from_db_conn("alice", &mut conn).unwrap();
+        assert_eq!(stored.password_hash.as_deref(), Some("reset"));
+        assert!(PasswordReset::redeem_conn("r1", "again", now, &mut conn).is_err());
+
+        let expired = now - chrono::Duration::hours(2);
+        PasswordReset::new("r2".to_string(), "alice".to_string(), expired, ttl)
+            .insert_into_db_conn(&mut conn)
+            .unwrap();
+        assert!(PasswordReset::redeem_conn("r2", "late", now, &mut conn).is_err());
+        assert!(PasswordReset::redeem_conn("r3", "unknown", now, &mut conn).is_err());
+    }
+
+    #[test]
+    fn test_contacts() {
+        let _guard = lock_test_db();
+        let mut conn = test_conn();
+
+        for name in ["alice", "bob", "carol"] {
+            User::new(name.to_string(), "hash".to_string(), 100)
+                .insert_into_db_conn(&mut conn)
+                .unwrap();
+        }
+        let now = chrono::Utc::now().naive_utc();
+        let mom = Contact {
+            owner: "alice".to_string(),
+            alias: "mom".to_string(),
+            username: "bob".to_string(),
+            note: Some("birthday in May".to_string()),
+            created_at: now,
+        };
+        mom.insert_into_db_conn(&mut conn).unwrap();
+        let duplicate = Contact {
+            username: "carol".to_string(),
+            ..mom.clone()
+        };
+        assert_eq!(
+            duplicate
+                .insert_into_db_conn(&mut conn)
+                .unwrap_err()
+                .to_string(),
+            "You already have a contact named mom"
+        );
+        Contact {
+            alias: "boss".to_string(),
+            username: "carol".to_string(),
+            note: None,
+            ..mom.clone()
+        }
+        .insert_into_db_conn(&mut conn)
+        .unwrap();
+
+        let aliases = Contact::list_conn("alice", &mut conn)
+            .unwrap()
+            .into_iter()
+            .map(|contact| contact.alias)
+            .collect::<Vec<_>>();
+        assert_eq!(aliases, ["boss", "mom"]);
+        assert!(Contact::list_conn("bob", &mut conn).unwrap().is_empty());
+        assert_eq!(
+            Contact::retrieve_from_db_conn("alice", "mom", &mut conn)
+                .unwrap()
+                .map(|contact| contact.username),
+            Some("bob".to_string())
+        );
+
+        Contact::delete_from_db_conn("alice", "mom", &mut conn).unwrap();
+        assert!(Contact::retrieve_from_db_conn("alice", "mom", &mut conn)
+            .unwrap()
+            .is_none());
+        assert!(Contact::delete_from_db_conn("alice", "mom", &mut conn).is_err());
+    }
+
+    #[test]
+    fn test_transfer_idempotency_key() {
+        let _guard = lock_test_db();
+        let mut conn = test_conn();
+
+        let mut alice = User::new("alice".to_string(), "hash".to_string(), 100);
+        let bob = User::new("bob".to_string(), "hash".to_string(), 100);
+        alice.insert_into_db_conn(&mut conn).unwrap();
+        bob.insert_into_db_conn(&mut conn).unwrap();
+
+        let first = alice
+            .transfer_to_other_conn(
+                "bob",
+                30,
+                None,
+                Some("turn-1"),
+                &TransferPolicy::default(),
+                &mut conn,
+            )
+            .unwrap();
+        let replay = alice
+            .transfer_to_other_conn(
+                "bob",
+                30,
+                None,
+                Some("turn-1"),
+                &TransferPolicy::default(),
+                &mut conn,
+            )
+            .unwrap();
+        assert_eq!(first, replay);
+        assert_eq!(alice.balance, 70);
+        assert!(alice
+            .transfer_to_other_conn(
+                "bob",
+                40,
+                None,
+                Some("turn-1"),
+                &TransferPolicy::default(),
+                &mut conn
+            )
+            .is_err());
+
+        alice
+            .transfer_to_other_conn(
+                "bob",
+                30,
+                None,
+                Some("turn-2"),
+                &TransferPolicy::default(),
+                &mut conn,
+            )
+            .unwrap();
+        alice
+            .transfer_to_other_conn("bob", 5, None, None, &TransferPolicy::default(), &mut conn)
+            .unwrap();
+        alice
+            .transfer_to_other_conn("bob", 5, None, None, &TransferPolicy::default(), &mut conn)
+            .unwrap();
+        assert_eq!(alice.balance, 30);
+        let count: i64 = transactions::table.count().get_result(&mut conn).unwrap();
+        assert_eq!(count, 4);
+    }
+
+    #[test]
+    fn test_concurrent_replays_execute_once() {
+        const THREADS: usize = 8;
+
+        let (pool, path) = temp_pool("replay", THREADS);
+        let mut conn = pool.get().unwrap();
+        for username in ["alice", "bob"] {
+            User::new(username.to_string(), "hash".to_string(), 100)
+                .insert_into_db_conn(&mut conn)
+                .unwrap();
+        }
+
+        let handles = (0..THREADS)
+            .map(|_| {
+                let pool = pool.clone();
+                std::thread::spawn(move || {
+                    let mut conn = pool.get().unwrap();
+                    let mut alice = User::retrieve_from_db_conn("alice", &mut conn).unwrap();
+                    alice
+                        .transfer_to_other_conn(
+                            "bob",
+                            10,
+                            None,
+                            Some("turn-1"),
+                            &TransferPolicy::default(),
+                            &mut conn,
+                        )
+                        .unwrap()
+                })
+            })
+            .collect::<Vec<_>>();
+        let records = handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .collect::<Vec<_>>();
+        assert!(records.iter().all(|r| r == &records[0]));
+        assert_eq!(
+            User::retrieve_from_db_conn("alice", &mut conn)
+                .unwrap()
+                .balance,
+            90
+        );
+
+        drop(conn);
+        drop(pool);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_transfer_policy_limits() {
+        let _guard = lock_test_db();
+        let mut conn = test_conn();
+
+        let mut alice = User::new("alice".to_string(), "hash".to_string(), 100);
+        let bob = User::new("bob".to_string(), "hash".to_string(), 100);
+        alice.insert_into_db_conn(&mut conn).unwrap();
+        bob.insert_into_db_conn(&mut conn).unwrap();
+        alice
+            .transfer_to_other_conn("bob", 40, None, None, &TransferPolicy::default(), &mut conn)
+            .unwrap();
+
+        let policy = TransferPolicy {
+            daily_limit: Some(50),
+            ..Default::default()
+        };
+        assert!(alice
+            .check_policy_conn(&policy, "bob", 10, &mut conn)
+            .is_ok());
+        let err = alice
+            .check_policy_conn(&policy, "bob", 11, &mut conn)
+            .unwrap_err();
+        let rejection = err.downcast_ref::<PolicyRejection>().unwrap();
+        assert_eq!(
+            rejection.0,
+            vec![PolicyViolation::DailyLimit {
+                limit: 50,
+                used: 40,
+                amount: 11
+            }]
+        );
+
+        // The default policy bans self transfers, and nothing moves.
+        assert!(alice
+            .transfer_to_other_conn(
+                "alice",
+                10,
+                None,
+                None,
+                &TransferPolicy::default(),
+                &mut conn
+            )
+            .is_err());
+        assert_eq!(
+            User::retrieve_from_db_conn("alice", &mut conn)
+                .unwrap()
+                .balance,
+            60
+        );
+    }
+
+    #[test]
+    fn test_payment_requests() {
+        let _guard = lock_test_db();
+        let mut conn = test_conn();
+
+        for name in ["alice", "bob"] {
+            User::new(name.to_string(), "hash".to_string(), 100)
+                .insert_into_db_conn(&mut conn)
+                .unwrap();
+        }
+        let now = chrono::Utc::now().naive_utc();
+        let new_request = |amount| NewPaymentRequest {
+            requester: "alice",
+            payer: "bob",
+            amount,
+            memo: Some("lunch"),
+            status: REQUEST_PENDING,
+            created_at: now,
+        };
+        let first = PaymentRequest::insert_conn(&new_request(10), &mut conn).unwrap();
+        let second = PaymentRequest::insert_conn(&new_request(20), &mut conn).unwrap();
+        assert_eq!(second.amount, 20);
+        assert_eq!(
+            PaymentRequest::retrieve_from_db_conn(first.id, &mut conn).unwrap(),
+            first
+        );
+
+        let incoming =
+            PaymentRequest::list_conn("bob", Some(Direction::Incoming), None, &mut conn).unwrap();
+        assert_eq!(incoming, vec![second.clone(), first.clone()]);
+        assert!(
+            PaymentRequest::list_conn("bob", Some(Direction::Outgoing), None, &mut conn)
+                .unwrap()
+                .is_empty()
+        );
+
+        assert!(
+            PaymentRequest::resolve_conn(first.id, REQUEST_DECLINED, None, now, &mut conn).unwrap()
+        );
+        assert!(
+            !PaymentRequest::resolve_conn(first.id, REQUEST_DECLINED, None, now, &mut conn)
+                .unwrap()
+        );
+        // A declined request can't be paid after all.
+        let policy = TransferPolicy::default();
+        let mut bob = User::retrieve_from_db_conn("bob", &mut conn).unwrap();
+        assert!(bob
+            .accept_payment_request_conn(first.id, now, &policy, &mut conn)
+            .is_err());
+        let declined = PaymentRequest::retrieve_from_db_conn(first.id, &mut conn).unwrap();
+        assert_eq!(declined.status, REQUEST_DECLINED);
+        assert_eq!(bob.balance, 100);
+
+        let record = bob
+            .accept_payment_request_conn(second.id, now, &policy, &mut conn)
+            .unwrap();
+        assert_eq!(bob.balance, 100 - second.amount);
+        let accepted = PaymentRequest::retrieve_from_db_conn(second.id, &mut conn).unwrap();
+        assert_eq!(accepted.status, REQUEST_ACCEPTED);
+        assert_eq!(accepted.transaction_id, Some(record.id));
+        assert!(
+            !PaymentRequest::resolve_conn(second.id, REQUEST_DECLINED, None, now, &mut conn)
+                .unwrap()
+        );
+        assert!(bob
+            .accept_payment_request_conn(second.id, now, &policy, &mut conn)
+            .is_err());
+        let pending =
+            PaymentRequest::list_conn("alice", None, Some(REQUEST_PENDING), &mut conn).unwrap();
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn test_scheduled_transfers() {
+        let _guard = lock_test_db();
+        let mut conn = test_conn();
+
+        for name in ["alice", "bob"] {
+            User::new(name.to_string(), "hash".to_string(), 100)
+                .insert_into_db_conn(&mut conn)
+                .unwrap();
+        }
+        let now = chrono::Utc::now().naive_utc();
+        let new_schedule = |next_run_at| NewScheduledTransfer {
+            sender: "alice",
+            receiver: "bob",
+            amount: 10,
+            memo: None,
+            recurrence: Some("weekly"),
+            next_run_at,
+            status: SCHEDULE_ACTIVE,
+            created_at: now,
+            first_run_at: next_run_at,
+        };
+        let due = ScheduledTransfer::insert_conn(&new_schedule(now), &mut conn).unwrap();
+        let later = ScheduledTransfer::insert_conn(
+            &new_schedule(now + chrono::Duration::days(1)),
+            &mut conn,
+        )
+        .unwrap();
+        assert_eq!(
+            ScheduledTransfer::due_conn(now, &mut conn).unwrap(),
+            vec![due.clone()]
+        );
+        assert_eq!(
+            ScheduledTransfer::list_conn("alice", &mut conn).unwrap(),
+            vec![later.clone(), due.clone()]
+        );
+
+        let mut ran = due.clone();
+        ran.next_run_at = now + chrono::Duration::weeks(1);
+        assert!(ran.update_to_db_conn(due.next_run_at, &mut conn).unwrap());
+        // A second update for the same run is a no-op.
+        assert!(!ran.update_to_db_conn(due.next_run_at, &mut conn).unwrap());
+        assert!(ScheduledTransfer::due_conn(now, &mut conn)
+            .unwrap()
+            .is_empty());
+
+        assert!(ScheduledTransfer::cancel_conn(later.id, &mut conn).unwrap());
+        assert!(!ScheduledTransfer::cancel_conn(later.id, &mut conn).unwrap());
+        let cancelled = ScheduledTransfer::retrieve_from_db_conn(later.id, &mut conn).unwrap();
+        assert_eq!(cancelled.status, SCHEDULE_CANCELLED);
+    }
+
+    #[test]
+    fn test_holds() {
+        let _guard = lock_test_db();
+        let mut conn = test_conn();
+        let policy = TransferPolicy::default();
+
+        for name in ["alice", "bob"] {
+            User::new(name.to_string(), "hash".to_string(), 100)
+                .insert_into_db_conn(&mut conn)
+                .unwrap();
+        }
+        let mut alice = User::retrieve_from_db_conn("alice", &mut conn).unwrap();
+        let now = chrono::Utc::now().naive_utc();
+        let in_an_hour = now + chrono::Duration::hours(1);
+        let hold = alice
+            .authorize_hold_conn("bob", 60, Some("deposit"), in_an_hour, &policy, &mut conn)
+            .unwrap();
+        assert_eq!(
+            (alice.balance, alice.held, alice.available()),
+            (100, 60, 40)
+        );
+
+        // Held funds can neither be spent nor held twice.
+        let err = alice
+            .transfer_to_other_conn("bob", 50, None, None, &policy, &mut conn)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Insufficient balance");
+        let err = alice
+            .authorize_hold_conn("bob", 50, None, in_an_hour, &policy, &mut conn)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Insufficient balance");
+        assert_eq!(
+            User::retrieve_from_db_conn("alice", &mut conn)
+                .unwrap()
+                .held,
+            60
+        );
+
+        // Only the payee captures, at most what is held.
+        assert!(Hold::capture_conn(hold.id, "alice", 10, &policy, &mut conn).is_err());
+        assert!(Hold::capture_conn(hold.id, "bob", 61, &policy, &mut conn).is_err());
+        let record = Hold::capture_conn(hold.id, "bob", 45, &policy, &mut conn).unwrap();
+        assert_eq!(record.idempotency_key, Some(hold.capture_key()));
+        assert_eq!(record.memo.as_deref(), Some("deposit"));
+        let alice = User::retrieve_from_db_conn("alice", &mut conn).unwrap();
+        assert_eq!((alice.balance, alice.held), (55, 0));
+        let captured = Hold::retrieve_from_db_conn(hold.id, &mut conn).unwrap();
+        assert_eq!(captured.status, HOLD_CAPTURED);
+        assert_eq!(captured.transaction_id, Some(record.id));
+        let err = Hold::capture_conn(hold.id, "bob", 10, &policy, &mut conn).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("Hold #{} is already captured", hold.id)
+        );
+
+        let mut alice = alice;
+        let voided = alice
+            .authorize_hold_conn("bob", 20, None, in_an_hour, &policy, &mut conn)
+            .unwrap();
+        assert!(Hold::void_conn(voided.id, "alice", &mut conn).is_err());
+        let voided = Hold::void_conn(voided.id, "bob", &mut conn).unwrap();
+        assert_eq!(voided.status, HOLD_VOIDED);
+        assert_eq!(
+            User::retrieve_from_db_conn("alice", &mut conn)
+                .unwrap()
+                .held,
+            0
+        );
+
+        // An overdue hold can't be captured and is released on expiry.
+        let overdue = Hold::insert_conn(
+            &NewHold {
+                payer: "alice",
+                payee: "bob",
+                amount: 30,
+                memo: None,
+                status: HOLD_ACTIVE,
+                created_at: now,
+                expires_at: now,
+            },
+            &mut conn,
+        )
+        .unwrap();
+        User::sync_held_conn("alice", &mut conn).unwrap();
+        let err = Hold::capture_conn(overdue.id, "bob", 30, &policy, &mut conn).unwrap_err();
+        assert_eq!(err.to_string(), format!("Hold #{} has expired", overdue.id));
+        assert_eq!(Hold::expire_conn(None, now, &mut conn).unwrap(), 1);
+        assert_eq!(
+            User::retrieve_from_db_conn("alice", &mut conn)
+                .unwrap()
+                .held,
+            0
+        );
+        assert_eq!(
+            Hold::list_conn(
+                "bob",
+                Some(Direction::Incoming),
+                Some(HOLD_EXPIRED),
+                &mut conn
+            )
+            .unwrap(),
+            vec![Hold::retrieve_from_db_conn(overdue.id, &mut conn).unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_admin_controls() {
+        let _guard = lock_test_db();
+        let mut conn = test_conn();
+        let policy = TransferPolicy::default();
+
+        for name in ["alice", "bob"] {
+            User::new(name.to_string(), "hash".to_string(), 100)
+                .insert_into_db_conn(&mut conn)
+                .unwrap();
+        }
+        assert_eq!(
+            User::adjust_balance_conn("alice", 50, &mut conn)
+                .unwrap()
+                .balance,
+            150
+        );
+        let err = User::adjust_balance_conn("alice", -200, &mut conn).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Can't burn 200 from alice, only 150 available"
+        );
+        let err = User::adjust_balance_conn("alice", i32::MIN, &mut conn).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Can't burn 2147483648 from alice, only 150 available"
+        );
+        let err = User::adjust_balance_conn("alice", i32::MAX, &mut conn).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Can't mint {} into alice, the balance would overflow",
+                i32::MAX
+            )
+        );
+
+        // A full account takes no transfer, and the sender keeps the money.
+        User::adjust_balance_conn("bob", i32::MAX - 100, &mut conn).unwrap();
+        let mut sender = User::retrieve_from_db_conn("alice", &mut conn).unwrap();
+        let err = sender
+            .transfer_to_other_conn("bob", 10, None, None, &policy, &mut conn)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Can't credit 10 to bob, the balance would overflow"
+        );
+        assert_eq!(sender.balance, 150);
+        assert_eq!(
+            User::retrieve_from_db_conn("bob", &mut conn)
+                .unwrap()
+                .balance,
+            i32::MAX
+        );
+        User::adjust_balance_conn("bob", -(i32::MAX - 100), &mut conn).unwrap();
+        assert_eq!(
+            User::adjust_balance_conn("alice", -150, &mut conn)
+                .unwrap()
+                .balance,
+            0
+        );
+
+        let now = chrono::Utc::now().naive_utc();
+        let frozen = User::set_frozen_conn("bob", Some(now), &mut conn).unwrap();
+        assert_eq!(frozen.frozen_at, Some(now));
+        let later = now + chrono::Duration::hours(1);
+        let refrozen = User::set_frozen_conn("bob", Some(later), &mut conn).unwrap();
+        assert_eq!(refrozen.frozen_at, Some(now));
+        let mut bob = User::retrieve_from_db_conn("bob", &mut conn).unwrap();
+        let err = bob
+            .transfer_to_other_conn("alice", 10, None, None, &policy, &mut conn)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Account bob is frozen");
+        User::adjust_balance_conn("alice", 10, &mut conn).unwrap();
+        let mut alice = User::retrieve_from_db_conn("alice", &mut conn).unwrap();
+        let err = alice
+            .transfer_to_other_conn("bob", 10, None, None, &policy, &mut conn)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Account bob is frozen");
+        assert_eq!(alice.balance, 10);
+
+        User::set_frozen_conn("bob", None, &mut conn).unwrap();
+        alice
+            .transfer_to_other_conn("bob", 10, None, None, &policy, &mut conn)
+            .unwrap();
+        assert_eq!(alice.balance, 0);
+    }
+
+    #[test]
+    fn test_refunds() {
+        let _guard = lock_test_db();
+        let mut conn = test_conn();
+        let policy = TransferPolicy::default();
+
+        for name in ["alice", "bob"] {
+            User::new(name.to_string(), "hash".to_string(), 100)
+                .insert_into_db_conn(&mut conn)
+                .unwrap();
+        }
+        let mut alice = User::retrieve_from_db_conn("alice", &mut conn).unwrap();
+        let mut bob = User::retrieve_from_db_conn("bob", &mut conn).unwrap();
+        let original = alice
+            .transfer_to_other_conn("bob", 50, None, None, &policy, &mut conn)
+            .unwrap();
+
+        // Only the receiver refunds, and a refund can't be refunded.
+        assert!(alice
+            .refund_conn(original.id, 10, None, None, &policy, &mut conn)
+            .is_err());
+        let partial = bob
+            .refund_conn(original.id, 20, Some("sorry"), None, &policy, &mut conn)
+            .unwrap();
+        assert_eq!(partial.receiver, "alice");
+        assert_eq!(partial.refund_of, Some(original.id));
+        assert_eq!(bob.balance, 130);
+        assert!(alice
+            .refund_conn(partial.id, 20, None, None, &policy, &mut conn)
+            .is_err());
+
+        let err = bob
+            .refund_conn(original.id, 31, None, None, &policy, &mut conn)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("Only 30 of transfer #{} can still be refunded", original.id)
+        );
+        assert_eq!(bob.balance, 130);
+        let rest = bob
+            .refund_conn(original.id, 30, None, Some("refund-1"), &policy, &mut conn)
+            .unwrap();
+        // A replay returns the same refund instead of failing the cap.
+        assert_eq!(
+            bob.refund_conn(original.id, 30, None, Some("refund-1"), &policy, &mut conn)
+                .unwrap(),
+            rest
+        );
+        assert_eq!(
+            Transaction::list_refunds_conn(original.id, &mut conn).unwrap(),
+            vec![partial, rest]
+        );
+        assert_eq!(
+            User::retrieve_from_db_conn("alice", &mut conn)
+                .unwrap()
+                .balance,
+            100
+        );
+
+        // The limits and blocklist of the refunder don't hold a refund back.
+        let original = alice
+            .transfer_to_other_conn("bob", 50, None, None, &policy, &mut conn)
+            .unwrap();
+        let strict = TransferPolicy {
+            daily_limit: Some(10),
+            blocklist: HashSet::from(["alice".to_string()]),
+            ..Default::default()
+        };
+        assert!(bob
+            .transfer_to_other_conn("alice", 5, None, None, &strict, &mut conn)
+            .is_err());
+        bob.refund_conn(original.id, 50, None, None, &strict, &mut conn)
+            .unwrap();
+        assert_eq!(bob.balance, 100);
+    }
+
+    #[test]
+    fn test_concurrent_partial_refunds() {
+        const THREADS: usize = 8;
+
+        let (pool, path) = temp_pool("refunds", THREADS);
+        let mut conn = pool.get().unwrap();
+        for username in ["alice", "bob"] {
+            User::new(username.to_string(), "hash".to_string(), 100)
+                .insert_into_db_conn(&mut conn)
+                .unwrap();
+        }
+        let original = User::retrieve_from_db_conn("alice", &mut conn)
+            .unwrap()
+            .transfer_to_other_conn("bob", 50, None, None, &TransferPolicy::default(), &mut conn)
+            .unwrap();
+
+        let handles = (0..THREADS)
+            .map(|_| {
+                let pool = pool.clone();
+                std::thread::spawn(move || {
+                    let mut conn = pool.get().unwrap();
+                    let mut bob = User::retrieve_from_db_conn("bob", &mut conn).unwrap();
+                    bob.refund_conn(
+                        original.id,
+                        20,
+                        None,
+                        None,
+                        &TransferPolicy::default(),
+                        &mut conn,
+                    )
+                    .is_ok()
+                })
+            })
+            .collect::<Vec<_>>();
+        let refunded = handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .filter(|refunded| *refunded)
+            .count();
+        // Only two refunds of 20 fit in 50.
+        assert_eq!(refunded, 2);
+        assert_eq!(
+            User::retrieve_from_db_conn("bob", &mut conn)
+                .unwrap()
+                .balance,
+            110
+        );
+
+        drop(conn);
+        drop(pool);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_payment_requests() {
+        const THREADS: usize = 8;
+
+        let (pool, path) = temp_pool("requests", THREADS);
+        let mut conn = pool.get().unwrap();
+        for username in ["alice", "bob"] {
+            User::new(username.to_string(), "hash".to_string(), 100)
+                .insert_into_db_conn(&mut conn)
+                .unwrap();
+        }
+
+        let handles = (1..=THREADS as i32)
+            .map(|amount| {
+                let pool = pool.clone();
+                std::thread::spawn(move || {
+                    let mut conn = pool.get().unwrap();
+                    let request = NewPaymentRequest {
+                        requester: "bob",
+                        payer: "alice",
+                        amount,
+                        memo: None,
+                        status: REQUEST_PENDING,
+                        created_at: chrono::Utc::now().naive_utc(),
+                    };
+                    (
+                        amount,
+                        PaymentRequest::insert_conn(&request, &mut conn).unwrap(),
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+        // Every caller gets its own request back.
+        for handle in handles {
+            let (amount, request) = handle.join().unwrap();
+            assert_eq!(request.amount, amount);
+        }
+
+        drop(conn);
+        drop(pool);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_schedules() {
+        const THREADS: usize = 8;
+
+        let (pool, path) = temp_pool("schedules", THREADS);
+        let mut conn = pool.get().unwrap();
+        for username in ["alice", "bob"] {
+            User::new(username.to_string(), "hash".to_string(), 100)
+                .insert_into_db_conn(&mut conn)
+                .unwrap();
+        }
+
+        let handles = (1..=THREADS as i32)
+            .map(|amount| {
+                let pool = pool.clone();
+                std::thread::spawn(move || {
+                    let mut conn = pool.get().unwrap();
+                    let now = chrono::Utc::now().naive_utc();
+                    let schedule = NewScheduledTransfer {
+                        sender: "alice",
+                        receiver: "bob",
+                        amount,
+                        memo: None,
+                        recurrence: None,
+                        next_run_at: now,
+                        status: SCHEDULE_ACTIVE,
+                        created_at: now,
+                        first_run_at: now,
+                    };
+                    (
+                        amount,
+                        ScheduledTransfer::insert_conn(&schedule, &mut conn).unwrap(),
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            let (amount, schedule) = handle.join().unwrap();
+            assert_eq!(schedule.amount, amount);
+        }
+
+        drop(conn);
+        drop(pool);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_close_account() {
+        let _guard = lock_test_db();
+        let mut conn = test_conn();
+        let policy = TransferPolicy::default();
+
+        for name in ["alice", "bob", "carol"] {
+            User::new(name.to_string(), "hash".to_string(), 100)
+                .insert_into_db_conn(&mut conn)
+                .unwrap();
+        }
+        let now = chrono::Utc::now().naive_utc();
+        let later = now + chrono::Duration::hours(1);
+        let mut alice = User::retrieve_from_db_conn("alice", &mut conn).unwrap();
+        let mut carol = User::retrieve_from_db_conn("carol", &mut conn).unwrap();
+        let request = PaymentRequest::insert_conn(
+            &NewPaymentRequest {
+                requester: "bob",
+                payer: "alice",
+                amount: 10,
+                memo: None,
+                status: REQUEST_PENDING,
+                created_at: now,
+            },
+            &mut conn,
+        )
+        .unwrap();
+        let schedule = ScheduledTransfer::insert_conn(
+            &NewScheduledTransfer {
+                sender: "alice",
+                receiver: "bob",
+                amount: 10,
+                memo: None,
+                recurrence: Some("weekly"),
+                next_run_at: later,
+                status: SCHEDULE_ACTIVE,
+                created_at: now,
+                first_run_at: later,
+            },
+            &mut conn,
+        )
+        .unwrap();
+        let incoming = carol
+            .authorize_hold_conn("alice", 30, None, later, &policy, &mut conn)
+            .unwrap();
+        Session::new(
+            "t1".to_string(),
+            "alice".to_string(),
+            now,
+            chrono::Duration::hours(1),
+        )
+        .insert_into_db_conn(&mut conn)
+        .unwrap();
+
+        // Money is never destroyed, nor held funds released.
+        let err = alice.close_conn(None, now, &policy, &mut conn).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The account still holds 100, name an account to sweep it to"
+        );
+        let outgoing = alice
+            .authorize_hold_conn("bob", 10, None, later, &policy, &mut conn)
+            .unwrap();
+        assert!(alice
+            .close_conn(Some("bob"), now, &policy, &mut conn)
+            .is_err());
+        assert_eq!(alice.closed_at, None);
+        Hold::void_conn(outgoing.id, "bob", &mut conn).unwrap();
+
+        let sweep = alice
+            .close_conn(Some("bob"), now, &policy, &mut conn)
+            .unwrap()
+            .unwrap();
+        assert_eq!((sweep.receiver.as_str(), sweep.amount), ("bob", 100));
+        assert_eq!((alice.balance, alice.closed_at), (0, Some(now)));
+        assert!(!User::list_usernames_conn(&mut conn)
+            .unwrap()
+            .contains(&alice.username));
+        assert_eq!(
+            PaymentRequest::retrieve_from_db_conn(request.id, &mut conn)
+                .unwrap()
+                .status,
+            REQUEST_CANCELLED
+        );
+        assert_eq!(
+            ScheduledTransfer::retrieve_from_db_conn(schedule.id, &mut conn)
+                .unwrap()
+                .status,
+            SCHEDULE_CANCELLED
+        );
+        assert_eq!(
+            Hold::retrieve_from_db_conn(incoming.id, &mut conn)
+                .unwrap()
+                .status,
+            HOLD_VOIDED
+        );
+        assert_eq!(
+            User::retrieve_from_db_conn("carol", &mut conn)
+                .unwrap()
+                .held,
+            0
+        );
+        assert!(Session::retrieve_from_db_conn("t1", &mut conn)
+            .unwrap()
+            .revoked_at
+            .is_some());
+
+        let err = carol
+            .transfer_to_other_conn("alice", 10, None, None, &policy, &mut conn)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Account alice is closed");
+        assert!(alice.close_conn(None, now, &policy, &mut conn).is_err());
+        // The ledger still has the account's history.
+        assert_eq!(
+            alice
+                .list_transactions_conn(&TransactionFilter::default(), &mut conn)
+                .unwrap(),
+            vec![sweep]
+        );
+    }
+
+    #[test]
+    fn test_close_account_policy() {
+        let _guard = lock_test_db();
+        let mut conn = test_conn();
+        let policy = TransferPolicy {
+            max_amount: Some(50),
+            daily_limit: Some(60),
+            blocklist: HashSet::from(["mallory".to_string()]),
+            ..Default::default()
+        };
+
+        for name in ["alice", "bob", "mallory"] {
+            User::new(name.to_string(), "hash".to_string(), 100)
+                .insert_into_db_conn(&mut conn)
+                .unwrap();
+        }
+        let now = chrono::Utc::now().naive_utc();
+        let mut alice = User::retrieve_from_db_conn("alice", &mut conn).unwrap();
+        alice
+            .transfer_to_other_conn("bob", 40, None, None, &policy, &mut conn)
+            .unwrap();
+
+        let err = alice
+            .close_conn(Some("mallory"), now, &policy, &mut conn)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Can't sweep the balance to mallory, transfers to it are not allowed. \
+             Name another account"
+        );
+        assert_eq!(alice.closed_at, None);
+
+        // Above the maximum and past the daily limit, but the balance has to go.
+        let sweep = alice
+            .close_conn(Some("bob"), now, &policy, &mut conn)
+            .unwrap()
+            .unwrap();
+        assert_eq!((sweep.receiver.as_str(), sweep.amount), ("bob", 60));
+        assert_eq!((alice.balance, alice.closed_at), (0, Some(now)));
+    }
+
+    #[test]
+    fn test_audit_log() {
+        let _guard = lock_test_db();
+        let mut conn = test_conn();
+        let now = chrono::Utc::now().naive_utc();
+        let calls = [
+            ("s1", None, "login", AUDIT_ERROR),
+            ("s1", Some("alice"), "login", AUDIT_OK),
+            ("s1", Some("alice"), "transfer", AUDIT_OK),
+            ("s2", Some("bob"), "get_balance", AUDIT_OK),
+        ];
+        for (i, (session_id, username, function_name, outcome)) in calls.into_iter().enumerate() {
+            AuditRecord::insert_conn(
+                &NewAuditRecord {
+                    session_id,
+                    username,
+                    function_name,
+                    arguments: "{}",
+                    outcome,
+                    result: "",
+                    model: "test",
+                    created_at: now + chrono::Duration::seconds(i as i64),
+                },
+                &mut conn,
+            )
+            .unwrap();
+        }
+
+        let names = |filter: &AuditFilter, conn: &mut SqliteConnection| {
+            AuditRecord::list_conn(filter, conn)
+                .unwrap()
+                .into_iter()
+                .map(|record| record.function_name)
+                .collect::<Vec<_>>()
+        };
+        let alice = AuditFilter {
+            username: Some("alice".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(names(&alice, &mut conn), ["login", "transfer"]);
+        let first_session = AuditFilter {
+            session_id: Some("s1".to_string()),
+            limit: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(names(&first_session, &mut conn), ["login", "login"]);
+        let later = AuditFilter {
+            since: Some(now + chrono::Duration::seconds(2)),
+            ..Default::default()
+        };
+        assert_eq!(names(&later, &mut conn), ["transfer", "get_balance"]);
+        let logins = AuditFilter {
+            function_name: Some("login".to_string()),
+            until: Some(now + chrono::Duration::seconds(1)),
+            ..Default::default()
+        };
+        let records = AuditRecord::list_conn(&logins, &mut conn).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].outcome, AUDIT_ERROR);
+        assert_eq!(records[0].username, None);
+    }
+
+    #[test]
+    fn test_postings_balance() {
+        let _guard = lock_test_db();
+        let mut conn = test_conn();
+        let policy = TransferPolicy::default();
+        let before = Posting::account_balances_conn(&mut conn).unwrap();
+        let issued = |balances: &[(String, i64)]| {
+            balances
+                .iter()
+                .find(|(account, _)| account == ISSUANCE_ACCOUNT)
+                .map_or(0, |(_, balance)| *balance)
+        };
+
+        for name in ["alice", "bob"] {
+            User::new(name.to_string(), "hash".to_string(), 100)
+                .insert_into_db_conn(&mut conn)
+                .unwrap();
+        }
+        let mut alice = User::retrieve_from_db_conn("alice", &mut conn).unwrap();
+        let record = alice
+            .transfer_to_other_conn("bob", 30, None, None, &policy, &mut conn)
+            .unwrap();
+        User::adjust_balance_conn("bob", 20, &mut conn).unwrap();
+        User::adjust_balance_conn("alice", -10, &mut conn).unwrap();
+
+        let kinds = Posting::list_conn("alice", &mut conn)
+            .unwrap()
+            .into_iter()
+            .map(|posting| (posting.kind, posting.transaction_id))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                (POSTING_GRANT.to_string(), None),
+                (POSTING_TRANSFER.to_string(), Some(record.id)),
+                (POSTING_BURN.to_string(), None),
+            ]
+        );
+        let balances = Posting::account_balances_conn(&mut conn).unwrap();
+        assert_eq!(balances.iter().map(|(_, balance)| balance).sum::<i64>(), 0);
+        assert_eq!(issued(&balances) - issued(&before), -210);
+        for name in ["alice", "bob"] {
+            let stored = User::retrieve_from_db_conn(name, &mut conn).unwrap();
+            let posted = Posting::list_conn(name, &mut conn)
+                .unwrap()
+                .iter()
+                .map(|posting| posting.change_for(name))
+                .sum::<i64>();
+            assert_eq!(posted, i64::from(stored.balance));
+        }
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let _guard = lock_test_db();
+        let mut conn = test_conn();
+        for name in ["alice", "bob"] {
+            User::new(name.to_string(), "hash".to_string(), 100)
+                .insert_into_db_conn(&mut conn)
+                .unwrap();
+        }
+        diesel::update(users::table.filter(users::username.eq("alice")))
+            .set(users::balance.eq(90))
+            .execute(&mut conn)
+            .unwrap();
+        let now = chrono::Utc::now().naive_utc();
+        Session::new(
+            "t1".to_string(),
+            "ghost".to_string(),
+            now,
+            chrono::Duration::hours(1),
+        )
+        .insert_into_db_conn(&mut conn)
+        .unwrap();
+
+        let ours = |found: Vec<Discrepancy>| {
+            found
+                .into_iter()
+                .filter(|discrepancy| {
+                    let text = discrepancy.to_string();
+                    ["alice", "bob", "ghost"]
+                        .iter()
+                        .any(|name| text.contains(name))
+                })
+                .collect::<Vec<_>>()
+        };
+        let expected = [
+            Discrepancy::BalanceMismatch {
+                username: "alice".to_string(),
+                stored: 90,
+                posted: 100,
+            },
+            Discrepancy::Orphaned {
+                table: "sessions",
+                username: "ghost".to_string(),
+                count: 1,
+            },
+        ];
+        let dry_run = Discrepancy::reconcile_conn(false, &mut conn).unwrap();
+        assert_eq!(ours(dry_run), expected);
+        let repaired = Discrepancy::reconcile_conn(true, &mut conn).unwrap();
+        assert_eq!(ours(repaired), expected);
+        let after = Discrepancy::reconcile_conn(false, &mut conn).unwrap();
+        assert!(ours(after).is_empty());
+        assert_eq!(
+            User::retrieve_from_db_conn("alice", &mut conn)
+                .unwrap()
+                .balance,
+            100
+        );
+        assert!(Session::retrieve_from_db_conn("t1", &mut conn).is_err());
+    }
+
+    #[test]
+    fn test_transfer_batch() {
+        let _guard = lock_test_db();
+        let mut conn = test_conn();
+        let policy = TransferPolicy::default();
+        for name in ["alice", "bob", "carol"] {
+            User::new(name.to_string(), "hash".to_string(), 100)
+                .insert_into_db_conn(&mut conn)
+                .unwrap();
+        }
+        let now = chrono::Utc::now().naive_utc();
+        User::set_frozen_conn("carol", Some(now), &mut conn).unwrap();
+        let mut alice = User::retrieve_from_db_conn("alice", &mut conn).unwrap();
+        let records = [
+            NewTransaction::completed("alice", "bob", 30, None, Some("batch:1")),
+            NewTransaction::completed("alice", "carol", 20, Some("bonus"), Some("batch:2")),
+        ];
+
+        let e = alice
+            .transfer_batch_conn(&records, &policy, &mut conn)
+            .unwrap_err();
+        assert_eq!(e.downcast_ref::<BatchFailed>().unwrap().index, 1);
+        let bob = User::retrieve_from_db_conn("bob", &mut conn).unwrap();
+        assert_eq!(bob.balance, 100);
+        assert_eq!(Posting::list_conn("alice", &mut conn).unwrap().len(), 1);
+
+        User::set_frozen_conn("carol", None, &mut conn).unwrap();
+        let transfers = alice
+            .transfer_batch_conn(&records, &policy, &mut conn)
+            .unwrap();
+        assert_eq!(transfers.len(), 2);
+        assert_eq!(alice.balance, 50);
+        let replayed = alice
+            .transfer_batch_conn(&records, &policy, &mut conn)
+            .unwrap();
+        assert_eq!(replayed, transfers);
+        let alice = User::retrieve_from_db_conn("alice", &mut conn).unwrap();
+        assert_eq!(alice.balance, 50);
+    }
 }
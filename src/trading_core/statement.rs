@@ -0,0 +1,496 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use anyhow::{bail, ensure, Result};
+use chrono::{Duration, NaiveDateTime};
+use lazy_static::lazy_static;
+use serde_json::json;
+
+use super::behaviors::generate_token;
+use super::types::Posting;
+use crate::global;
+
+lazy_static! {
+    /// Rendered statements waiting to be downloaded, by token.
+    static ref DOWNLOADS: Mutex<HashMap<String, Download>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatementFormat {
+    Csv,
+    Json,
+    Ofx,
+}
+
+impl StatementFormat {
+    pub fn parse(format: &str) -> Result<StatementFormat> {
+        match format.to_lowercase().as_str() {
+            "csv" => Ok(StatementFormat::Csv),
+            "json" => Ok(StatementFormat::Json),
+            "ofx" => Ok(StatementFormat::Ofx),
+            _ => bail!("Unknown statement format: {}", format),
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            StatementFormat::Csv => "csv",
+            StatementFormat::Json => "json",
+            StatementFormat::Ofx => "ofx",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            StatementFormat::Csv => "text/csv",
+            StatementFormat::Json => "application/json",
+            StatementFormat::Ofx => "application/x-ofx",
+        }
+    }
+}
+
+/// The postings of one account over a period, with running balances.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Statement {
+    pub username: String,
+    /// Inclusive, from the first posting when unset.
+    pub since: Option<NaiveDateTime>,
+    /// Exclusive, up to the time the statement was made when unset.
+    pub until: Option<NaiveDateTime>,
+    pub opening_balance: i64,
+    pub closing_balance: i64,
+    pub entries: Vec<StatementEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatementEntry {
+    pub posting_id: i32,
+    pub created_at: NaiveDateTime,
+    pub kind: String,
+    /// The other account of the posting.
+    pub counterparty: String,
+    /// Negative when money left the account.
+    pub amount: i64,
+    /// The balance right after this entry.
+    pub balance: i64,
+    pub transaction_id: Option<i32>,
+    pub memo: Option<String>,
+}
+
+impl Statement {
+    /// Builds the statement of `username` from all of its postings, oldest
+    /// first. `memos` are the memos of its transfers, by transfer id. Fails
+    /// if the period has more than `STATEMENT_MAX_ROWS` entries.
+    pub fn new(
+        username: &str,
+        postings: &[Posting],
+        memos: &HashMap<i32, String>,
+        since: Option<NaiveDateTime>,
+        until: Option<NaiveDateTime>,
+    ) -> Result<Statement> {
+        let mut balance = 0;
+        let mut opening_balance = 0;
+        let mut entries = Vec::new();
+        for posting in postings {
+            if until.is_some_and(|until| posting.created_at >= until) {
+                break;
+            }
+            balance += posting.change_for(username);
+            if since.is_some_and(|since| posting.created_at < since) {
+                opening_balance = balance;
+                continue;
+            }
+            ensure!(
+                entries.len() < global::STATEMENT_MAX_ROWS,
+                "The statement has more than {} entries, export a shorter period",
+                global::STATEMENT_MAX_ROWS
+            );
+            let counterparty = if posting.credit_account == username {
+                &posting.debit_account
+            } else {
+                &posting.credit_account
+            };
+            entries.push(StatementEntry {
+                posting_id: posting.id,
+                created_at: posting.created_at,
+                kind: posting.kind.clone(),
+                counterparty: counterparty.clone(),
+                amount: posting.change_for(username),
+                balance,
+                transaction_id: posting.transaction_id,
+                memo: posting
+                    .transaction_id
+                    .and_then(|id| memos.get(&id))
+                    .cloned(),
+            });
+        }
+        Ok(Statement {
+            username: username.to_string(),
+            since,
+            until,
+            opening_balance,
+            closing_balance: balance,
+            entries,
+        })
+    }
+
+    /// A file name like `statement-alice-2023-08-01-2023-08-31.csv`.
+    pub fn filename(&self, format: StatementFormat) -> String {
+        let first = self.since.map_or("start".to_string(), |since| {
+            since.format("%Y-%m-%d").to_string()
+        });
+        let last = self.until.map_or("now".to_string(), |until| {
+            (until - Duration::seconds(1))
+                .format("%Y-%m-%d")
+                .to_string()
+        });
+        format!(
+            "statement-{}-{}-{}.{}",
+            self.username,
+            first,
+            last,
+            format.extension()
+        )
+    }
+
+    pub fn render(&self, format: StatementFormat) -> String {
+        match format {
+            StatementFormat::Csv => self.to_csv(),
+            StatementFormat::Json => self.to_json(),
+            StatementFormat::Ofx => self.to_ofx(),
+        }
+    }
+
+    fn to_csv(&self) -> String {
+        let mut csv = "date,id,type,counterparty,amount,balance,transfer,memo\r\n".to_string();
+        for entry in &self.entries {
+            let fields = [
+                entry.created_at.format("%Y-%m-%d %H:%M:%S").to_string(),
+                entry.posting_id.to_string(),
+                csv_text(&entry.kind),
+                csv_text(&entry.counterparty),
+                entry.amount.to_string(),
+                entry.balance.to_string(),
+                entry
+                    .transaction_id
+                    .map_or(String::new(), |id| id.to_string()),
+                csv_text(entry.memo.as_deref().unwrap_or_default()),
+            ];
+            let fields = fields
+                .iter()
+                .map(|field| csv_field(field))
+                .collect::<Vec<_>>();
+            csv.push_str(&fields.join(","));
+            csv.push_str("\r\n");
+        }
+        csv
+    }
+
+    fn to_json(&self) -> String {
+        let timestamp = |at: NaiveDateTime| at.format("%Y-%m-%dT%H:%M:%S").to_string();
+        let entries = self
+            .entries
+            .iter()
+            .map(|entry| {
+                json!({
+                    "id": entry.posting_id,
+                    "date": timestamp(entry.created_at),
+                    "type": entry.kind,
+                    "counterparty": entry.counterparty,
+                    "amount": entry.amount,
+                    "balance": entry.balance,
+                    "transfer": entry.transaction_id,
+                    "memo": entry.memo,
+                })
+            })
+            .collect::<Vec<_>>();
+        let statement = json!({
+            "username": self.username,
+            "since": self.since.map(timestamp),
+            "until": self.until.map(timestamp),
+            "opening_balance": self.opening_balance,
+            "closing_balance": self.closing_balance,
+            "entries": entries,
+        });
+        serde_json::to_string_pretty(&statement).unwrap()
+    }
+
+    /// OFX 2.2, as read by most personal finance tools.
+    fn to_ofx(&self) -> String {
+        let timestamp = |at: NaiveDateTime| at.format("%Y%m%d%H%M%S").to_string();
+        let now = chrono::Utc::now().naive_utc();
+        let start = self
+            .since
+            .or(self.entries.first().map(|entry| entry.created_at))
+            .unwrap_or(now);
+        let end = self.until.unwrap_or(now);
+
+        let mut transactions = String::new();
+        for entry in &self.entries {
+            let kind = if entry.amount < 0 { "DEBIT" } else { "CREDIT" };
+            transactions.push_str(&format!(
+                "<STMTTRN><TRNTYPE>{}</TRNTYPE><DTPOSTED>{}</DTPOSTED><TRNAMT>{}</TRNAMT>\
+                 <FITID>{}</FITID><NAME>{}</NAME>",
+                kind,
+                timestamp(entry.created_at),
+                entry.amount,
+                entry.posting_id,
+                xml_escape(&entry.counterparty)
+            ));
+            if let Some(memo) = &entry.memo {
+                transactions.push_str(&format!("<MEMO>{}</MEMO>", xml_escape(memo)));
+            }
+            transactions.push_str("</STMTTRN>\n");
+        }
+
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <?OFX OFXHEADER=\"200\" VERSION=\"220\" SECURITY=\"NONE\" OLDFILEUID=\"NONE\" NEWFILEUID=\"NONE\"?>\n\
+             <OFX>\n\
+             <SIGNONMSGSRSV1><SONRS><STATUS><CODE>0</CODE><SEVERITY>INFO</SEVERITY></STATUS>\
+             <DTSERVER>{now}</DTSERVER><LANGUAGE>ENG</LANGUAGE></SONRS></SIGNONMSGSRSV1>\n\
+             <BANKMSGSRSV1><STMTTRNRS><TRNUID>0</TRNUID>\
+             <STATUS><CODE>0</CODE><SEVERITY>INFO</SEVERITY></STATUS>\n\
+             <STMTRS><CURDEF>{currency}</CURDEF>\
+             <BANKACCTFROM><BANKID>{bank}</BANKID><ACCTID>{account}</ACCTID>\
+             <ACCTTYPE>CHECKING</ACCTTYPE></BANKACCTFROM>\n\
+             <BANKTRANLIST><DTSTART>{start}</DTSTART><DTEND>{end}</DTEND>\n\
+             {transactions}\
+             </BANKTRANLIST>\n\
+             <LEDGERBAL><BALAMT>{balance}</BALAMT><DTASOF>{end}</DTASOF></LEDGERBAL>\n\
+             </STMTRS></STMTTRNRS></BANKMSGSRSV1>\n\
+             </OFX>\n",
+            now = timestamp(now),
+            currency = global::STATEMENT_CURRENCY,
+            bank = global::STATEMENT_BANK_ID,
+            account = xml_escape(&self.username),
+            start = timestamp(start),
+            end = timestamp(end),
+            balance = self.closing_balance,
+        )
+    }
+}
+
+/// Quotes the field if it contains anything CSV treats specially.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Keeps spreadsheets from running user text as a formula, by prefixing
+/// what could start one with a quote. Only for text: `-5` is an amount.
+fn csv_text(text: &str) -> String {
+    if text.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{}", text)
+    } else {
+        text.to_string()
+    }
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Where a prepared statement can be downloaded from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatementLink {
+    pub filename: String,
+    pub token: String,
+}
+
+impl StatementLink {
+    pub fn url(&self) -> String {
+        format!("/statements/{}", self.token)
+    }
+}
+
+/// A rendered file, served to whoever follows its link until it expires.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Download {
+    pub filename: String,
+    pub content_type: &'static str,
+    pub body: String,
+    pub expires_at: NaiveDateTime,
+}
+
+impl Download {
+    /// The `Content-Disposition` header value. The file name is reduced to
+    /// `[A-Za-z0-9._-]`, so that nothing in it can break out of the quotes.
+    pub fn content_disposition(&self) -> String {
+        let filename = self
+            .filename
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-') {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect::<String>();
+        format!("attachment; filename=\"{}\"", filename)
+    }
+}
+
+/// Keeps the file for `STATEMENT_DOWNLOAD_TTL_SECS`, returning the token to
+/// fetch it with. The token is the only credential, so it is random.
+pub fn offer_download(
+    filename: String,
+    content_type: &'static str,
+    body: String,
+    now: NaiveDateTime,
+) -> String {
+    let token = generate_token();
+    let download = Download {
+        filename,
+        content_type,
+        body,
+        expires_at: now + Duration::seconds(global::STATEMENT_DOWNLOAD_TTL_SECS),
+    };
+    let mut downloads = DOWNLOADS.lock().unwrap_or_else(|e| e.into_inner());
+    downloads.retain(|_, download| download.expires_at > now);
+    downloads.insert(token.clone(), download);
+    token
+}
+
+/// The download offered under `token`, unless it has expired.
+pub fn find_download(token: &str, now: NaiveDateTime) -> Option<Download> {
+    let downloads = DOWNLOADS.lock().unwrap_or_else(|e| e.into_inner());
+    downloads
+        .get(token)
+        .filter(|download| download.expires_at > now)
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trading_core::types::{POSTING_GRANT, POSTING_TRANSFER};
+
+    fn posting(id: i32, from: &str, to: &str, amount: i32, at: NaiveDateTime) -> Posting {
+        Posting {
+            id,
+            debit_account: from.to_string(),
+            credit_account: to.to_string(),
+            amount,
+            kind: if from == "issuance" {
+                POSTING_GRANT
+            } else {
+                POSTING_TRANSFER
+            }
+            .to_string(),
+            transaction_id: (from != "issuance").then_some(id),
+            created_at: at,
+        }
+    }
+
+    #[test]
+    fn test_statement() {
+        let day = |d: u32| {
+            chrono::NaiveDate::from_ymd_opt(2023, 8, d)
+                .unwrap()
+                .and_hms_opt(12, 0, 0)
+                .unwrap()
+        };
+        let postings = [
+            posting(1, "issuance", "alice", 100, day(1)),
+            posting(2, "alice", "bob", 30, day(2)),
+            posting(3, "bob", "alice", 5, day(3)),
+            posting(4, "alice", "bob", 1, day(9)),
+        ];
+        let memos = HashMap::from([
+            (2, "=1+2".to_string()),
+            (3, "lunch, \"thanks\"".to_string()),
+        ]);
+        let since = day(2).date().and_hms_opt(0, 0, 0);
+        let until = day(4).date().and_hms_opt(0, 0, 0);
+        let statement = Statement::new("alice", &postings, &memos, since, until).unwrap();
+
+        assert_eq!(
+            (statement.opening_balance, statement.closing_balance),
+            (100, 75)
+        );
+        assert_eq!(
+            statement.filename(StatementFormat::Csv),
+            "statement-alice-2023-08-02-2023-08-03.csv"
+        );
+        assert_eq!(
+            statement.render(StatementFormat::Csv),
+            "date,id,type,counterparty,amount,balance,transfer,memo\r\n\
+             2023-08-02 12:00:00,2,transfer,bob,-30,70,2,'=1+2\r\n\
+             2023-08-03 12:00:00,3,transfer,bob,5,75,3,\"lunch, \"\"thanks\"\"\"\r\n"
+        );
+        let json =
+            serde_json::from_str::<serde_json::Value>(&statement.render(StatementFormat::Json))
+                .unwrap();
+        assert_eq!(json["entries"][1]["memo"], "lunch, \"thanks\"");
+        // Only the CSV guards against formulas.
+        assert_eq!(json["entries"][0]["memo"], "=1+2");
+        let ofx = statement.render(StatementFormat::Ofx);
+        assert!(ofx.contains(
+            "<TRNTYPE>DEBIT</TRNTYPE><DTPOSTED>20230802120000</DTPOSTED><TRNAMT>-30</TRNAMT>"
+        ));
+        assert!(ofx.contains("<LEDGERBAL><BALAMT>75</BALAMT>"));
+    }
+
+    #[test]
+    fn test_statement_max_rows() {
+        let at = chrono::NaiveDate::from_ymd_opt(2023, 8, 1)
+            .unwrap()
+            .and_hms_opt(12, 0, 0)
+            .unwrap();
+        let postings = (1..=global::STATEMENT_MAX_ROWS as i32 + 1)
+            .map(|id| posting(id, "issuance", "alice", 1, at))
+            .collect::<Vec<_>>();
+        let memos = HashMap::new();
+        let err = Statement::new("alice", &postings, &memos, None, None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "The statement has more than {} entries, export a shorter period",
+                global::STATEMENT_MAX_ROWS
+            )
+        );
+        // Entries before the period only make up the opening balance.
+        let since = Some(at + Duration::seconds(1));
+        let statement = Statement::new("alice", &postings, &memos, since, None).unwrap();
+        assert_eq!(statement.opening_balance, postings.len() as i64);
+        let last = &postings[..global::STATEMENT_MAX_ROWS];
+        let statement = Statement::new("alice", last, &memos, None, None).unwrap();
+        assert_eq!(statement.entries.len(), global::STATEMENT_MAX_ROWS);
+    }
+
+    #[test]
+    fn test_downloads() {
+        let now = chrono::Utc::now().naive_utc();
+        let token = offer_download("a.csv".to_string(), "text/csv", "x".to_string(), now);
+        assert_eq!(find_download(&token, now).unwrap().body, "x");
+        let later = now + Duration::seconds(global::STATEMENT_DOWNLOAD_TTL_SECS);
+        assert_eq!(find_download(&token, later), None);
+        assert_eq!(find_download("unknown", now), None);
+    }
+
+    #[test]
+    fn test_content_disposition() {
+        let download = |filename: &str| Download {
+            filename: filename.to_string(),
+            content_type: "text/csv",
+            body: String::new(),
+            expires_at: chrono::Utc::now().naive_utc(),
+        };
+        assert_eq!(
+            download("statement-alice-start-now.csv").content_disposition(),
+            "attachment; filename=\"statement-alice-start-now.csv\""
+        );
+        assert_eq!(
+            download("a\"; x=\r\nSet-Cookie: é.csv").content_disposition(),
+            "attachment; filename=\"a___x___Set-Cookie___.csv\""
+        );
+    }
+}
@@ -0,0 +1,529 @@
+use anyhow::{bail, Result};
+use chrono::NaiveDateTime;
+use dotenvy::dotenv;
+use lazy_static::lazy_static;
+
+use super::memory::MemoryStorage;
+#[cfg(feature = "postgres")]
+use super::pg::PgStorage;
+use super::policy::POLICY;
+use super::reconcile::Discrepancy;
+use super::sql::SqliteStorage;
+use super::types::{
+    AuditFilter, AuditRecord, Contact, Direction, Hold, NewAuditRecord, NewPaymentRequest,
+    NewScheduledTransfer, NewTransaction, PasswordReset, PaymentRequest, Posting,
+    ScheduledTransfer, Session, SpendingSummary, Transaction, TransactionFilter, User,
+};
+
+lazy_static! {
+    /// Selected with `STORAGE`: `sqlite` (the default, at `DATABASE_URL`),
+    /// `memory`, which keeps everything in the process and needs no database file,
+    /// or `postgres` (at `DATABASE_URL`) when built with the `postgres` feature.
+    static ref STORAGE: Box<dyn Storage> = {
+        dotenv().ok();
+
+        let backend = std::env::var("STORAGE").unwrap_or_else(|_| "sqlite".to_string());
+        new_storage(&backend).expect("Failed to create storage.")
+    };
+}
+
+fn new_storage(backend: &str) -> Result<Box<dyn Storage>> {
+    match backend {
+        "sqlite" => {
+            let database_url = std::env::var("DATABASE_URL")?;
+            Ok(Box::new(SqliteStorage::new(&database_url, POLICY.clone())?))
+        }
+        "memory" => Ok(Box::new(MemoryStorage::new(POLICY.clone()))),
+        #[cfg(feature = "postgres")]
+        "postgres" => {
+            let database_url = std::env::var("DATABASE_URL")?;
+            Ok(Box::new(PgStorage::new(&database_url, POLICY.clone())?))
+        }
+        _ => bail!("Unknown storage backend: {}", backend),
+    }
+}
+
+/// Persistence for users, the transfer ledger and sessions.
+///
+/// Transfers must be atomic: either both balances change and the ledger entry
+/// is written, or nothing happens. Every backend enforces its transfer policy.
+pub trait Storage: Send + Sync {
+    /// Fails if the username is taken.
+    fn insert_user(&self, user: &User) -> Result<()>;
+    fn retrieve_user(&self, username: &str) -> Result<User>;
+    /// Usernames of every open account, sorted.
+    fn list_usernames(&self) -> Result<Vec<String>>;
+    fn update_user(&self, user: &User) -> Result<()>;
+    /// Updates the password columns only, never the balance.
+    fn update_password(&self, user: &User) -> Result<()>;
+    /// Adds `amount` to the balance of an open account, or takes it away when
+    /// negative, which fails unless that much is available. Returns the user.
+    fn adjust_balance(&self, username: &str, amount: i32) -> Result<User>;
+    /// Freezes the account at `frozen_at`, or unfreezes it with `None`.
+    /// Freezing a frozen account keeps the original time.
+    fn set_frozen(&self, username: &str, frozen_at: Option<NaiveDateTime>) -> Result<User>;
+    fn set_role(&self, username: &str, role: &str) -> Result<User>;
+    /// Removes the row outright, for tests. Users close their account instead.
+    fn delete_user(&self, user: &User) -> Result<()>;
+    /// Marks the account closed, keeping the row for the ledger. A non-zero
+    /// balance is transferred to `sweep_to` or the closure is rejected, as is
+    /// closing with funds on hold. Pending payment requests and schedules
+    /// involving the user are cancelled, holds placed for them voided and
+    /// their sessions revoked. Returns the sweeping transfer, if any.
+    fn close_user(
+        &self,
+        user: &mut User,
+        sweep_to: Option<&str>,
+        now: NaiveDateTime,
+    ) -> Result<Option<Transaction>>;
+
+    /// Moves `amount` from `sender` to `to_username` and refreshes `sender`.
+    /// A repeated `idempotency_key` returns the original ledger entry.
+    fn transfer(
+        &self,
+        sender: &mut User,
+        to_username: &str,
+        amount: i32,
+        memo: Option<&str>,
+        idempotency_key: Option<&str>,
+    ) -> Result<Transaction>;
+    /// Makes every transfer in `records`, all sent by `sender`, in one
+    /// database transaction, in order, and refreshes `sender`. If any fails,
+    /// none is made and the error is a `BatchFailed` naming it. Each record's
+    /// idempotency key works as for `transfer`.
+    fn transfer_batch(
+        &self,
+        sender: &mut User,
+        records: &[NewTransaction],
+    ) -> Result<Vec<Transaction>>;
+    /// Evaluates the transfer policy without moving any money.
+    fn check_transfer_policy(&self, sender: &User, to_username: &str, amount: i32) -> Result<()>;
+    /// Sends `amount` of the transfer `original_id` back to its sender, as a
+    /// transfer linked to it. Only the receiver can refund, and all refunds
+    /// together can't exceed the original amount.
+    fn refund(
+        &self,
+        refunder: &mut User,
+        original_id: i32,
+        amount: i32,
+        memo: Option<&str>,
+        idempotency_key: Option<&str>,
+    ) -> Result<Transaction>;
+    fn retrieve_transaction(&self, id: i32) -> Result<Transaction>;
+    /// Completed refunds of the transfer `original_id`, oldest first.
+    fn list_refunds(&self, original_id: i32) -> Result<Vec<Transaction>>;
+    fn list_transactions(
+        &self,
+        user: &User,
+        filter: &TransactionFilter,
+    ) -> Result<Vec<Transaction>>;
+    fn summarize_spending(
+        &self,
+        user: &User,
+        since: Option<NaiveDateTime>,
+        until: Option<NaiveDateTime>,
+    ) -> Result<SpendingSummary>;
+    /// Postings debiting or crediting `account`, oldest first.
+    fn list_postings(&self, account: &str) -> Result<Vec<Posting>>;
+    /// Credits minus debits of every account with postings, system accounts
+    /// included, sorted by account. They always sum to zero.
+    fn account_balances(&self) -> Result<Vec<(String, i64)>>;
+
+    fn insert_session(&self, session: &Session) -> Result<()>;
+    fn retrieve_session(&self, token: &str) -> Result<Session>;
+    /// Stores the session's `last_seen_at`.
+    fn touch_session(&self, session: &Session) -> Result<()>;
+    fn revoke_session(&self, session: &Session, now: NaiveDateTime) -> Result<()>;
+    /// Revokes every live session of `username`, returning how many there were.
+    fn revoke_all_sessions(&self, username: &str, now: NaiveDateTime) -> Result<usize>;
+
+    /// Stores the password columns and revokes every live session of the user
+    /// in one step, returning how many sessions were revoked.
+    fn change_password(&self, user: &User, now: NaiveDateTime) -> Result<usize>;
+    fn insert_password_reset(&self, reset: &PasswordReset) -> Result<()>;
+    /// Marks an unused, unexpired reset token used and sets the password of its
+    /// user to `password_hash`, revoking their sessions. Returns the user.
+    fn redeem_password_reset(
+        &self,
+        token: &str,
+        password_hash: &str,
+        now: NaiveDateTime,
+    ) -> Result<User>;
+
+    fn insert_audit_record(&self, record: &NewAuditRecord) -> Result<()>;
+    /// Audit records matching `filter`, oldest first.
+    fn list_audit_records(&self, filter: &AuditFilter) -> Result<Vec<AuditRecord>>;
+
+    /// Fails if the owner already has a contact with that alias.
+    fn insert_contact(&self, contact: &Contact) -> Result<()>;
+    /// Fails if the owner has no contact with that alias.
+    fn delete_contact(&self, owner: &str, alias: &str) -> Result<()>;
+    fn retrieve_contact(&self, owner: &str, alias: &str) -> Result<Option<Contact>>;
+    /// Contacts of `owner`, by alias.
+    fn list_contacts(&self, owner: &str) -> Result<Vec<Contact>>;
+
+    fn insert_payment_request(&self, request: &NewPaymentRequest) -> Result<PaymentRequest>;
+    fn retrieve_payment_request(&self, id: i32) -> Result<PaymentRequest>;
+    /// Requests `username` was asked to pay (`Incoming`) or made (`Outgoing`),
+    /// newest first.
+    fn list_payment_requests(
+        &self,
+        username: &str,
+        direction: Option<Direction>,
+        status: Option<&str>,
+    ) -> Result<Vec<PaymentRequest>>;
+    /// Records the outcome of a pending request, returning whether it changed.
+    fn resolve_payment_request(
+        &self,
+        id: i32,
+        status: &str,
+        transaction_id: Option<i32>,
+        now: NaiveDateTime,
+    ) -> Result<bool>;
+    /// Pays the pending request `id` addressed to `payer` and marks it
+    /// accepted, both or neither, then refreshes `payer`. Fails once the
+    /// request is no longer pending, so it is paid at most once and never
+    /// after a decline.
+    fn accept_payment_request(
+        &self,
+        payer: &mut User,
+        id: i32,
+        now: NaiveDateTime,
+    ) -> Result<Transaction>;
+
+    fn insert_scheduled_transfer(
+        &self,
+        schedule: &NewScheduledTransfer,
+    ) -> Result<ScheduledTransfer>;
+    fn retrieve_scheduled_transfer(&self, id: i32) -> Result<ScheduledTransfer>;
+    /// Schedules sent by `username`, newest first.
+    fn list_scheduled_transfers(&self, username: &str) -> Result<Vec<ScheduledTransfer>>;
+    /// Active schedules whose next run is at or before `now`, earliest first.
+    fn due_scheduled_transfers(&self, now: NaiveDateTime) -> Result<Vec<ScheduledTransfer>>;
+    /// Stores the outcome of the run at `ran_at`. Returns false, storing
+    /// nothing, if the schedule was cancelled or ran again in the meantime.
+    fn update_scheduled_transfer(
+        &self,
+        schedule: &ScheduledTransfer,
+        ran_at: NaiveDateTime,
+    ) -> Result<bool>;
+    /// Returns false if the schedule wasn't active.
+    fn cancel_scheduled_transfer(&self, id: i32) -> Result<bool>;
+
+    /// Reserves `amount` of the payer's available balance for `payee` until
+    /// `expires_at`. The in-memory payer is refreshed afterwards.
+    fn authorize_hold(
+        &self,
+        payer: &mut User,
+        payee: &str,
+        amount: i32,
+        memo: Option<&str>,
+        expires_at: NaiveDateTime,
+    ) -> Result<Hold>;
+    /// Transfers `amount`, at most the held amount, to `payee` and releases
+    /// the rest. Only the payee can capture, and only before expiry.
+    fn capture_hold(&self, payee: &User, id: i32, amount: i32) -> Result<Transaction>;
+    /// Releases the hold without moving money. Only the payee can void.
+    fn void_hold(&self, payee: &User, id: i32) -> Result<Hold>;
+    fn retrieve_hold(&self, id: i32) -> Result<Hold>;
+    /// Holds placed by (`Outgoing`) or for (`Incoming`) `username`, newest first.
+    fn list_holds(
+        &self,
+        username: &str,
+        direction: Option<Direction>,
+        status: Option<&str>,
+    ) -> Result<Vec<Hold>>;
+    /// Expires every active hold past its expiry, returning how many.
+    fn expire_holds(&self, now: NaiveDateTime) -> Result<usize>;
+
+    /// Checks the ledger invariants in one transaction, returning every broken
+    /// one. With `repair`, fixes those that can be fixed in that transaction.
+    fn reconcile(&self, repair: bool) -> Result<Vec<Discrepancy>>;
+}
+
+impl User {
+    pub fn insert_into_db(&self) -> Result<()> {
+        STORAGE.insert_user(self)
+    }
+
+    pub fn retrieve_from_db(username: &str) -> Result<User> {
+        STORAGE.retrieve_user(username)
+    }
+
+    pub fn update_to_db(&self) -> Result<()> {
+        STORAGE.update_user(self)
+    }
+
+    /// Usernames of every open account, sorted.
+    pub fn list_usernames_from_db() -> Result<Vec<String>> {
+        STORAGE.list_usernames()
+    }
+
+    pub fn update_password_to_db(&self) -> Result<()> {
+        STORAGE.update_password(self)
+    }
+
+    /// Stores a new password and logs out every session of the user.
+    pub fn change_password_to_db(&self, now: NaiveDateTime) -> Result<usize> {
+        STORAGE.change_password(self, now)
+    }
+
+    /// Mints `amount` into the account, or burns it when negative.
+    pub fn adjust_balance_in_db(username: &str, amount: i32) -> Result<User> {
+        STORAGE.adjust_balance(username, amount)
+    }
+
+    pub fn set_frozen_in_db(username: &str, frozen_at: Option<NaiveDateTime>) -> Result<User> {
+        STORAGE.set_frozen(username, frozen_at)
+    }
+
+    pub fn set_role_in_db(username: &str, role: &str) -> Result<User> {
+        STORAGE.set_role(username, role)
+    }
+
+    pub fn delete_from_db(&self) -> Result<()> {
+        STORAGE.delete_user(self)
+    }
+
+    pub fn close_in_db(
+        &mut self,
+        sweep_to: Option<&str>,
+        now: NaiveDateTime,
+    ) -> Result<Option<Transaction>> {
+        STORAGE.close_user(self, sweep_to, now)
+    }
+
+    pub fn refresh_from_db(&mut self) -> Result<()> {
+        *self = STORAGE.retrieve_user(&self.username)?;
+        Ok(())
+    }
+
+    pub fn transfer_to_other(
+        &mut self,
+        to_username: &str,
+        amount: i32,
+        memo: Option<&str>,
+        idempotency_key: Option<&str>,
+    ) -> Result<Transaction> {
+        STORAGE.transfer(self, to_username, amount, memo, idempotency_key)
+    }
+
+    pub fn transfer_batch_to_others(
+        &mut self,
+        records: &[NewTransaction],
+    ) -> Result<Vec<Transaction>> {
+        STORAGE.transfer_batch(self, records)
+    }
+
+    pub fn refund_to_other(
+        &mut self,
+        original_id: i32,
+        amount: i32,
+        memo: Option<&str>,
+        idempotency_key: Option<&str>,
+    ) -> Result<Transaction> {
+        STORAGE.refund(self, original_id, amount, memo, idempotency_key)
+    }
+
+    /// Evaluates the transfer policy without moving any money.
+    pub fn check_transfer_policy(&self, to_username: &str, amount: i32) -> Result<()> {
+        STORAGE.check_transfer_policy(self, to_username, amount)
+    }
+
+    pub fn list_transactions(&self, filter: &TransactionFilter) -> Result<Vec<Transaction>> {
+        STORAGE.list_transactions(self, filter)
+    }
+
+    pub fn summarize_spending(
+        &self,
+        since: Option<NaiveDateTime>,
+        until: Option<NaiveDateTime>,
+    ) -> Result<SpendingSummary> {
+        STORAGE.summarize_spending(self, since, until)
+    }
+}
+
+impl Posting {
+    pub fn list_from_db(account: &str) -> Result<Vec<Posting>> {
+        STORAGE.list_postings(account)
+    }
+
+    /// Credits minus debits of every account, sorted by account.
+    pub fn account_balances_from_db() -> Result<Vec<(String, i64)>> {
+        STORAGE.account_balances()
+    }
+}
+
+impl Discrepancy {
+    /// Every broken ledger invariant, repaired where possible with `repair`.
+    pub fn reconcile_db(repair: bool) -> Result<Vec<Discrepancy>> {
+        STORAGE.reconcile(repair)
+    }
+}
+
+impl Session {
+    pub fn insert_into_db(&self) -> Result<()> {
+        STORAGE.insert_session(self)
+    }
+
+    pub fn retrieve_from_db(token: &str) -> Result<Session> {
+        STORAGE.retrieve_session(token)
+    }
+
+    pub fn touch_to_db(&self) -> Result<()> {
+        STORAGE.touch_session(self)
+    }
+
+    pub fn revoke_to_db(&self, now: NaiveDateTime) -> Result<()> {
+        STORAGE.revoke_session(self, now)
+    }
+
+    /// Revokes every live session of `username`, returning how many there were.
+    pub fn revoke_all_to_db(username: &str, now: NaiveDateTime) -> Result<usize> {
+        STORAGE.revoke_all_sessions(username, now)
+    }
+}
+
+impl PasswordReset {
+    pub fn insert_into_db(&self) -> Result<()> {
+        STORAGE.insert_password_reset(self)
+    }
+
+    /// Consumes the token and sets the new password, returning the user.
+    pub fn redeem_to_db(token: &str, password_hash: &str, now: NaiveDateTime) -> Result<User> {
+        STORAGE.redeem_password_reset(token, password_hash, now)
+    }
+}
+
+impl AuditRecord {
+    pub fn insert_into_db(record: &NewAuditRecord) -> Result<()> {
+        STORAGE.insert_audit_record(record)
+    }
+
+    /// Audit records matching `filter`, oldest first.
+    pub fn list_from_db(filter: &AuditFilter) -> Result<Vec<AuditRecord>> {
+        STORAGE.list_audit_records(filter)
+    }
+}
+
+impl Contact {
+    pub fn insert_into_db(&self) -> Result<()> {
+        STORAGE.insert_contact(self)
+    }
+
+    pub fn delete_from_db(owner: &str, alias: &str) -> Result<()> {
+        STORAGE.delete_contact(owner, alias)
+    }
+
+    /// The contact `owner` named `alias`, if any.
+    pub fn retrieve_from_db(owner: &str, alias: &str) -> Result<Option<Contact>> {
+        STORAGE.retrieve_contact(owner, alias)
+    }
+
+    pub fn list_from_db(owner: &str) -> Result<Vec<Contact>> {
+        STORAGE.list_contacts(owner)
+    }
+}
+
+impl PaymentRequest {
+    pub fn insert_into_db(request: &NewPaymentRequest) -> Result<PaymentRequest> {
+        STORAGE.insert_payment_request(request)
+    }
+
+    pub fn retrieve_from_db(id: i32) -> Result<PaymentRequest> {
+        STORAGE.retrieve_payment_request(id)
+    }
+
+    pub fn list_from_db(
+        username: &str,
+        direction: Option<Direction>,
+        status: Option<&str>,
+    ) -> Result<Vec<PaymentRequest>> {
+        STORAGE.list_payment_requests(username, direction, status)
+    }
+
+    pub fn resolve_to_db(
+        &self,
+        status: &str,
+        transaction_id: Option<i32>,
+        now: NaiveDateTime,
+    ) -> Result<bool> {
+        STORAGE.resolve_payment_request(self.id, status, transaction_id, now)
+    }
+
+    pub fn accept_to_db(&self, payer: &mut User, now: NaiveDateTime) -> Result<Transaction> {
+        STORAGE.accept_payment_request(payer, self.id, now)
+    }
+}
+
+impl ScheduledTransfer {
+    pub fn insert_into_db(schedule: &NewScheduledTransfer) -> Result<ScheduledTransfer> {
+        STORAGE.insert_scheduled_transfer(schedule)
+    }
+
+    pub fn retrieve_from_db(id: i32) -> Result<ScheduledTransfer> {
+        STORAGE.retrieve_scheduled_transfer(id)
+    }
+
+    pub fn list_from_db(username: &str) -> Result<Vec<ScheduledTransfer>> {
+        STORAGE.list_scheduled_transfers(username)
+    }
+
+    pub fn due_from_db(now: NaiveDateTime) -> Result<Vec<ScheduledTransfer>> {
+        STORAGE.due_scheduled_transfers(now)
+    }
+
+    pub fn update_to_db(&self, ran_at: NaiveDateTime) -> Result<bool> {
+        STORAGE.update_scheduled_transfer(self, ran_at)
+    }
+
+    pub fn cancel_to_db(&self) -> Result<bool> {
+        STORAGE.cancel_scheduled_transfer(self.id)
+    }
+}
+
+impl Hold {
+    pub fn authorize_into_db(
+        payer: &mut User,
+        payee: &str,
+        amount: i32,
+        memo: Option<&str>,
+        expires_at: NaiveDateTime,
+    ) -> Result<Hold> {
+        STORAGE.authorize_hold(payer, payee, amount, memo, expires_at)
+    }
+
+    pub fn capture_to_db(&self, payee: &User, amount: i32) -> Result<Transaction> {
+        STORAGE.capture_hold(payee, self.id, amount)
+    }
+
+    pub fn void_to_db(&self, payee: &User) -> Result<Hold> {
+        STORAGE.void_hold(payee, self.id)
+    }
+
+    pub fn retrieve_from_db(id: i32) -> Result<Hold> {
+        STORAGE.retrieve_hold(id)
+    }
+
+    pub fn list_from_db(
+        username: &str,
+        direction: Option<Direction>,
+        status: Option<&str>,
+    ) -> Result<Vec<Hold>> {
+        STORAGE.list_holds(username, direction, status)
+    }
+
+    pub fn expire_to_db(now: NaiveDateTime) -> Result<usize> {
+        STORAGE.expire_holds(now)
+    }
+}
+
+impl Transaction {
+    pub fn retrieve_from_db(id: i32) -> Result<Transaction> {
+        STORAGE.retrieve_transaction(id)
+    }
+
+    pub fn refunds_from_db(&self) -> Result<Vec<Transaction>> {
+        STORAGE.list_refunds(self.id)
+    }
+}
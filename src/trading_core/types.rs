@@ -1,21 +1,990 @@
+use std::collections::HashMap;
+
+use chrono::{Datelike, Duration, NaiveDateTime};
 use diesel::prelude::*;
 
 #[derive(Queryable, Selectable, Insertable, AsChangeset)]
 #[diesel(table_name = super::schema::users)]
-#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
-#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(
+    not(feature = "postgres"),
+    diesel(check_for_backend(diesel::sqlite::Sqlite))
+)]
+#[cfg_attr(
+    feature = "postgres",
+    diesel(check_for_backend(diesel::sqlite::Sqlite, diesel::pg::Pg))
+)]
+#[diesel(treat_none_as_null = true)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct User {
     pub username: String,
+    /// Legacy plaintext password. Empty once the row has been migrated to `password_hash`.
     pub password: String,
     pub balance: i32,
+    /// Argon2id PHC string.
+    pub password_hash: Option<String>,
+    /// Part of `balance` reserved by active holds.
+    pub held: i32,
+    /// Closed accounts keep their row for the ledger but can't be used.
+    pub closed_at: Option<NaiveDateTime>,
+    /// `ROLE_USER` or `ROLE_ADMIN`.
+    pub role: String,
+    /// Frozen accounts can't send or receive money until unfrozen by an admin.
+    pub frozen_at: Option<NaiveDateTime>,
 }
 
 impl User {
-    pub fn new(username: String, password: String, balance: i32) -> User {
+    pub fn new(username: String, password_hash: String, balance: i32) -> User {
         User {
             username,
-            password,
+            password: String::new(),
             balance,
+            password_hash: Some(password_hash),
+            held: 0,
+            closed_at: None,
+            role: ROLE_USER.to_string(),
+            frozen_at: None,
+        }
+    }
+
+    /// What the user can spend or put on hold.
+    pub fn available(&self) -> i32 {
+        self.balance - self.held
+    }
+
+    pub fn check_open(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.closed_at.is_none(),
+            "Account {} is closed",
+            self.username
+        );
+        Ok(())
+    }
+
+    /// Whether the account can send and receive money.
+    pub fn check_active(&self) -> anyhow::Result<()> {
+        self.check_open()?;
+        anyhow::ensure!(
+            self.frozen_at.is_none(),
+            "Account {} is frozen",
+            self.username
+        );
+        Ok(())
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.role == ROLE_ADMIN
+    }
+
+    pub fn check_admin(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(self.is_admin(), "Only admins can do that");
+        Ok(())
+    }
+}
+
+pub static ROLE_USER: &str = "user";
+pub static ROLE_ADMIN: &str = "admin";
+
+pub static STATUS_COMPLETED: &str = "completed";
+pub static DEFAULT_PAGE_SIZE: i64 = 20;
+/// Most transfers one page may hold when the bot lists them.
+pub static MAX_PAGE_SIZE: i64 = 100;
+
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = super::schema::transactions)]
+#[cfg_attr(
+    not(feature = "postgres"),
+    diesel(check_for_backend(diesel::sqlite::Sqlite))
+)]
+#[cfg_attr(
+    feature = "postgres",
+    diesel(check_for_backend(diesel::sqlite::Sqlite, diesel::pg::Pg))
+)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transaction {
+    pub id: i32,
+    pub sender: String,
+    pub receiver: String,
+    pub amount: i32,
+    pub memo: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub status: String,
+    /// Set by the caller so that a retried transfer isn't executed twice.
+    pub idempotency_key: Option<String>,
+    /// The transfer this one gives money back for.
+    pub refund_of: Option<i32>,
+}
+
+impl Transaction {
+    /// Why `refunder` can't refund this transfer, if they can't.
+    pub fn check_refundable(&self, refunder: &str) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.receiver == refunder,
+            "Only the receiver of transfer #{} can refund it",
+            self.id
+        );
+        anyhow::ensure!(
+            self.refund_of.is_none(),
+            "Transfer #{} is itself a refund",
+            self.id
+        );
+        anyhow::ensure!(
+            self.status == STATUS_COMPLETED,
+            "Transfer #{} is {}",
+            self.id,
+            self.status
+        );
+        Ok(())
+    }
+
+    /// Whether `amount` still fits next to the completed `refunds` of this
+    /// transfer. A refund replayed under `idempotency_key` is already counted.
+    pub fn check_refund_fits(
+        &self,
+        refunds: &[Transaction],
+        amount: i32,
+        idempotency_key: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let refunded = refunds
+            .iter()
+            .filter(|refund| {
+                idempotency_key.is_none() || refund.idempotency_key.as_deref() != idempotency_key
+            })
+            .map(|refund| refund.amount)
+            .sum::<i32>();
+        anyhow::ensure!(
+            refunded + amount <= self.amount,
+            "Only {} of transfer #{} can still be refunded",
+            self.amount - refunded,
+            self.id
+        );
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Transaction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "#{} {} {} -> {}: {} ({})",
+            self.id,
+            self.created_at.format("%Y-%m-%d %H:%M:%S"),
+            self.sender,
+            self.receiver,
+            self.amount,
+            self.status
+        )?;
+        if let Some(original) = self.refund_of {
+            write!(f, " refund of #{}", original)?;
+        }
+        if let Some(memo) = &self.memo {
+            write!(f, " memo: {}", memo)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = super::schema::transactions)]
+pub struct NewTransaction<'a> {
+    pub sender: &'a str,
+    pub receiver: &'a str,
+    pub amount: i32,
+    pub memo: Option<&'a str>,
+    pub created_at: NaiveDateTime,
+    pub status: &'a str,
+    pub idempotency_key: Option<&'a str>,
+    pub refund_of: Option<i32>,
+}
+
+impl<'a> NewTransaction<'a> {
+    /// A plain transfer, completed now.
+    pub fn completed(
+        sender: &'a str,
+        receiver: &'a str,
+        amount: i32,
+        memo: Option<&'a str>,
+        idempotency_key: Option<&'a str>,
+    ) -> NewTransaction<'a> {
+        NewTransaction {
+            sender,
+            receiver,
+            amount,
+            memo,
+            created_at: chrono::Utc::now().naive_utc(),
+            status: STATUS_COMPLETED,
+            idempotency_key,
+            refund_of: None,
+        }
+    }
+}
+
+/// System account that money is created from and burnt back to. Its balance
+/// is minus all the money in circulation.
+pub static ISSUANCE_ACCOUNT: &str = "issuance";
+/// Accounts that aren't users, so no user may take their names.
+pub static SYSTEM_ACCOUNTS: [&str; 1] = [ISSUANCE_ACCOUNT];
+
+pub static POSTING_GRANT: &str = "grant";
+pub static POSTING_TRANSFER: &str = "transfer";
+pub static POSTING_MINT: &str = "mint";
+pub static POSTING_BURN: &str = "burn";
+
+/// One double-entry posting: `amount` leaves `debit_account` and arrives in
+/// `credit_account`, so the postings of every account sum to zero overall.
+/// Written together with the balance change it records, and never changed.
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = super::schema::postings)]
+#[cfg_attr(
+    not(feature = "postgres"),
+    diesel(check_for_backend(diesel::sqlite::Sqlite))
+)]
+#[cfg_attr(
+    feature = "postgres",
+    diesel(check_for_backend(diesel::sqlite::Sqlite, diesel::pg::Pg))
+)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Posting {
+    pub id: i32,
+    pub debit_account: String,
+    pub credit_account: String,
+    pub amount: i32,
+    pub kind: String,
+    /// The transfer behind the posting, if it records one.
+    pub transaction_id: Option<i32>,
+    pub created_at: NaiveDateTime,
+}
+
+impl Posting {
+    /// Credits minus debits per account, sorted by account, from the totals
+    /// debited from and credited to each account.
+    pub fn net_balances(
+        debits: impl IntoIterator<Item = (String, i64)>,
+        credits: impl IntoIterator<Item = (String, i64)>,
+    ) -> Vec<(String, i64)> {
+        let mut balances = std::collections::BTreeMap::new();
+        for (account, amount) in debits {
+            *balances.entry(account).or_insert(0) -= amount;
+        }
+        for (account, amount) in credits {
+            *balances.entry(account).or_insert(0) += amount;
+        }
+        balances.into_iter().collect()
+    }
+
+    /// What the posting adds to the balance of `account`.
+    pub fn change_for(&self, account: &str) -> i64 {
+        let amount = i64::from(self.amount);
+        if account == self.credit_account {
+            amount
+        } else if account == self.debit_account {
+            -amount
+        } else {
+            0
+        }
+    }
+}
+
+impl std::fmt::Display for Posting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "#{} {} {} {} -> {}: {}",
+            self.id,
+            self.created_at.format("%Y-%m-%d %H:%M:%S"),
+            self.kind,
+            self.debit_account,
+            self.credit_account,
+            self.amount
+        )?;
+        if let Some(transaction_id) = self.transaction_id {
+            write!(f, " transfer #{}", transaction_id)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = super::schema::postings)]
+pub struct NewPosting<'a> {
+    pub debit_account: &'a str,
+    pub credit_account: &'a str,
+    pub amount: i32,
+    pub kind: &'a str,
+    pub transaction_id: Option<i32>,
+    pub created_at: NaiveDateTime,
+}
+
+impl<'a> NewPosting<'a> {
+    /// The money a new account starts with, from issuance.
+    pub fn grant(username: &'a str, amount: i32, now: NaiveDateTime) -> NewPosting<'a> {
+        NewPosting {
+            debit_account: ISSUANCE_ACCOUNT,
+            credit_account: username,
+            amount,
+            kind: POSTING_GRANT,
+            transaction_id: None,
+            created_at: now,
+        }
+    }
+
+    pub fn transfer(record: &'a Transaction) -> NewPosting<'a> {
+        NewPosting {
+            debit_account: &record.sender,
+            credit_account: &record.receiver,
+            amount: record.amount,
+            kind: POSTING_TRANSFER,
+            transaction_id: Some(record.id),
+            created_at: record.created_at,
+        }
+    }
+
+    /// A mint from issuance when `amount` is positive, else a burn back to it.
+    pub fn adjustment(username: &'a str, amount: i32, now: NaiveDateTime) -> NewPosting<'a> {
+        let (debit_account, credit_account, kind) = if amount >= 0 {
+            (ISSUANCE_ACCOUNT, username, POSTING_MINT)
+        } else {
+            (username, ISSUANCE_ACCOUNT, POSTING_BURN)
+        };
+        NewPosting {
+            debit_account,
+            credit_account,
+            amount: amount.abs(),
+            kind,
+            transaction_id: None,
+            created_at: now,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+/// Filters for a user's transaction history. `None` fields don't filter.
+#[derive(Debug, Clone, Default)]
+pub struct TransactionFilter {
+    pub direction: Option<Direction>,
+    pub counterparty: Option<String>,
+    /// Inclusive lower bound on `created_at`.
+    pub since: Option<NaiveDateTime>,
+    /// Exclusive upper bound on `created_at`.
+    pub until: Option<NaiveDateTime>,
+    pub status: Option<String>,
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+/// Completed transfers of a user over a period.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpendingSummary {
+    pub total_out: i64,
+    pub total_in: i64,
+    /// Outgoing totals per receiver, largest first.
+    pub by_counterparty: Vec<(String, i64)>,
+}
+
+impl SpendingSummary {
+    /// Totals of `username`'s transfers among `records`.
+    pub fn from_ledger(username: &str, records: &[Transaction]) -> SpendingSummary {
+        let mut totals = HashMap::<&str, i64>::new();
+        let mut total_in = 0;
+        for record in records {
+            if record.sender == username {
+                *totals.entry(&record.receiver).or_default() += i64::from(record.amount);
+            }
+            if record.receiver == username {
+                total_in += i64::from(record.amount);
+            }
+        }
+        let mut by_counterparty = totals
+            .into_iter()
+            .map(|(receiver, total)| (receiver.to_string(), total))
+            .collect::<Vec<_>>();
+        by_counterparty.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        SpendingSummary {
+            total_out: by_counterparty.iter().map(|(_, total)| total).sum(),
+            total_in,
+            by_counterparty,
+        }
+    }
+}
+
+#[derive(Queryable, Selectable, Insertable)]
+#[diesel(table_name = super::schema::sessions)]
+#[cfg_attr(
+    not(feature = "postgres"),
+    diesel(check_for_backend(diesel::sqlite::Sqlite))
+)]
+#[cfg_attr(
+    feature = "postgres",
+    diesel(check_for_backend(diesel::sqlite::Sqlite, diesel::pg::Pg))
+)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    pub token: String,
+    pub username: String,
+    pub created_at: NaiveDateTime,
+    pub last_seen_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+}
+
+impl Session {
+    pub fn new(token: String, username: String, now: NaiveDateTime, ttl: Duration) -> Session {
+        Session {
+            token,
+            username,
+            created_at: now,
+            last_seen_at: now,
+            expires_at: now + ttl,
+            revoked_at: None,
+        }
+    }
+}
+
+/// A one-time token letting a user set a new password without the old one.
+#[derive(Queryable, Selectable, Insertable)]
+#[diesel(table_name = super::schema::password_resets)]
+#[cfg_attr(
+    not(feature = "postgres"),
+    diesel(check_for_backend(diesel::sqlite::Sqlite))
+)]
+#[cfg_attr(
+    feature = "postgres",
+    diesel(check_for_backend(diesel::sqlite::Sqlite, diesel::pg::Pg))
+)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PasswordReset {
+    pub token: String,
+    pub username: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub used_at: Option<NaiveDateTime>,
+}
+
+impl PasswordReset {
+    pub fn new(
+        token: String,
+        username: String,
+        now: NaiveDateTime,
+        ttl: Duration,
+    ) -> PasswordReset {
+        PasswordReset {
+            token,
+            username,
+            created_at: now,
+            expires_at: now + ttl,
+            used_at: None,
+        }
+    }
+
+    /// Whether the token can still be redeemed at `now`.
+    pub fn is_redeemable(&self, now: NaiveDateTime) -> bool {
+        self.used_at.is_none() && self.expires_at > now
+    }
+}
+
+/// A name `owner` gave another user, usable wherever a recipient is expected.
+#[derive(Queryable, Selectable, Insertable)]
+#[diesel(table_name = super::schema::contacts)]
+#[cfg_attr(
+    not(feature = "postgres"),
+    diesel(check_for_backend(diesel::sqlite::Sqlite))
+)]
+#[cfg_attr(
+    feature = "postgres",
+    diesel(check_for_backend(diesel::sqlite::Sqlite, diesel::pg::Pg))
+)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Contact {
+    pub owner: String,
+    /// Stored normalized, see `Contact::normalize_alias`.
+    pub alias: String,
+    pub username: String,
+    pub note: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+impl Contact {
+    /// Aliases are matched ignoring case and surrounding whitespace.
+    pub fn normalize_alias(alias: &str) -> String {
+        alias.trim().to_lowercase()
+    }
+}
+
+impl std::fmt::Display for Contact {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} -> {}", self.alias, self.username)?;
+        if let Some(note) = &self.note {
+            write!(f, " note: {}", note)?;
         }
+        Ok(())
+    }
+}
+
+impl TransactionFilter {
+    /// Whether `record` involves `username` and passes the filter, ignoring pagination.
+    pub fn matches(&self, username: &str, record: &Transaction) -> bool {
+        let involved = match self.direction {
+            Some(Direction::Outgoing) => record.sender == username,
+            Some(Direction::Incoming) => record.receiver == username,
+            None => record.sender == username || record.receiver == username,
+        };
+        involved
+            && self
+                .counterparty
+                .as_ref()
+                .is_none_or(|other| &record.sender == other || &record.receiver == other)
+            && self.since.is_none_or(|since| record.created_at >= since)
+            && self.until.is_none_or(|until| record.created_at < until)
+            && self
+                .status
+                .as_ref()
+                .is_none_or(|status| &record.status == status)
     }
 }
+
+/// A transfer requested through the bot, waiting for the user to confirm it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingTransfer {
+    pub kind: PendingKind,
+    pub to: String,
+    pub amount: i32,
+    pub memo: Option<String>,
+    pub idempotency_key: String,
+    pub expires_at: NaiveDateTime,
+}
+
+impl PendingTransfer {
+    pub fn is_expired(&self, now: NaiveDateTime) -> bool {
+        now >= self.expires_at
+    }
+}
+
+impl std::fmt::Display for PendingTransfer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Transfer {} to {}", self.amount, self.to)?;
+        match &self.kind {
+            PendingKind::Transfer => {}
+            PendingKind::PaymentRequest(id) => write!(f, " for payment request #{}", id)?,
+            PendingKind::Refund(id) => write!(f, " refunding transfer #{}", id)?,
+            PendingKind::Schedule {
+                first_run_at,
+                recurrence,
+            } => {
+                write!(f, " starting {}", first_run_at.format("%Y-%m-%d"))?;
+                if let Some(recurrence) = recurrence {
+                    write!(f, ", repeating {}", recurrence)?;
+                }
+            }
+            PendingKind::Payout { .. } => write!(f, " from a payout file")?,
+        }
+        if let Some(memo) = &self.memo {
+            write!(f, " (memo: {})", memo)?;
+        }
+        Ok(())
+    }
+}
+
+/// What confirming a `PendingTransfer` does.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PendingKind {
+    Transfer,
+    /// Accepts this payment request.
+    PaymentRequest(i32),
+    /// Refunds this transfer.
+    Refund(i32),
+    /// Creates a scheduled transfer.
+    Schedule {
+        first_run_at: NaiveDateTime,
+        recurrence: Option<Recurrence>,
+    },
+    /// Pays every row of this payout file, `to` naming how many recipients.
+    Payout {
+        csv: String,
+    },
+}
+
+pub static REQUEST_PENDING: &str = "pending";
+pub static REQUEST_ACCEPTED: &str = "accepted";
+pub static REQUEST_DECLINED: &str = "declined";
+/// Withdrawn because the requester or the payer closed their account.
+pub static REQUEST_CANCELLED: &str = "cancelled";
+
+/// A user asking another user, the payer, for money.
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = super::schema::payment_requests)]
+#[cfg_attr(
+    not(feature = "postgres"),
+    diesel(check_for_backend(diesel::sqlite::Sqlite))
+)]
+#[cfg_attr(
+    feature = "postgres",
+    diesel(check_for_backend(diesel::sqlite::Sqlite, diesel::pg::Pg))
+)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PaymentRequest {
+    pub id: i32,
+    pub requester: String,
+    pub payer: String,
+    pub amount: i32,
+    pub memo: Option<String>,
+    pub status: String,
+    pub created_at: NaiveDateTime,
+    pub resolved_at: Option<NaiveDateTime>,
+    /// The ledger entry that paid an accepted request.
+    pub transaction_id: Option<i32>,
+}
+
+impl std::fmt::Display for PaymentRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "#{} {} {} asks {} for {} ({})",
+            self.id,
+            self.created_at.format("%Y-%m-%d %H:%M:%S"),
+            self.requester,
+            self.payer,
+            self.amount,
+            self.status
+        )?;
+        if let Some(memo) = &self.memo {
+            write!(f, " memo: {}", memo)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = super::schema::payment_requests)]
+pub struct NewPaymentRequest<'a> {
+    pub requester: &'a str,
+    pub payer: &'a str,
+    pub amount: i32,
+    pub memo: Option<&'a str>,
+    pub status: &'a str,
+    pub created_at: NaiveDateTime,
+}
+
+pub static AUDIT_OK: &str = "ok";
+pub static AUDIT_ERROR: &str = "error";
+
+/// One function the bot called on behalf of a user, and what came of it.
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = super::schema::audit_log)]
+#[cfg_attr(
+    not(feature = "postgres"),
+    diesel(check_for_backend(diesel::sqlite::Sqlite))
+)]
+#[cfg_attr(
+    feature = "postgres",
+    diesel(check_for_backend(diesel::sqlite::Sqlite, diesel::pg::Pg))
+)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    pub id: i32,
+    /// The conversation the call was made in, not a login token.
+    pub session_id: String,
+    /// Who was logged in, if anyone.
+    pub username: Option<String>,
+    pub function_name: String,
+    /// JSON, with secrets redacted.
+    pub arguments: String,
+    /// `AUDIT_OK` or `AUDIT_ERROR`.
+    pub outcome: String,
+    /// What the function returned, or the error.
+    pub result: String,
+    pub model: String,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = super::schema::audit_log)]
+pub struct NewAuditRecord<'a> {
+    pub session_id: &'a str,
+    pub username: Option<&'a str>,
+    pub function_name: &'a str,
+    pub arguments: &'a str,
+    pub outcome: &'a str,
+    pub result: &'a str,
+    pub model: &'a str,
+    pub created_at: NaiveDateTime,
+}
+
+/// Which audit records to return, oldest first. Unset fields don't filter.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AuditFilter {
+    pub username: Option<String>,
+    pub session_id: Option<String>,
+    pub function_name: Option<String>,
+    /// Inclusive lower bound on `created_at`.
+    pub since: Option<NaiveDateTime>,
+    /// Exclusive upper bound on `created_at`.
+    pub until: Option<NaiveDateTime>,
+    /// Everything when unset.
+    pub limit: Option<i64>,
+    pub offset: Option<i64>,
+}
+
+impl AuditFilter {
+    /// Whether `record` passes the filter, ignoring pagination.
+    pub fn matches(&self, record: &AuditRecord) -> bool {
+        self.username
+            .as_ref()
+            .is_none_or(|username| record.username.as_ref() == Some(username))
+            && self
+                .session_id
+                .as_ref()
+                .is_none_or(|session_id| &record.session_id == session_id)
+            && self
+                .function_name
+                .as_ref()
+                .is_none_or(|name| &record.function_name == name)
+            && self.since.is_none_or(|since| record.created_at >= since)
+            && self.until.is_none_or(|until| record.created_at < until)
+    }
+}
+
+impl std::fmt::Display for AuditRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "#{} {} [{}] {} {}({}) {}: {}",
+            self.id,
+            self.created_at.format("%Y-%m-%d %H:%M:%S"),
+            self.session_id,
+            self.username.as_deref().unwrap_or("-"),
+            self.function_name,
+            self.arguments,
+            self.outcome,
+            self.result
+        )
+    }
+}
+
+pub static SCHEDULE_ACTIVE: &str = "active";
+pub static SCHEDULE_COMPLETED: &str = "completed";
+pub static SCHEDULE_CANCELLED: &str = "cancelled";
+pub static SCHEDULE_FAILED: &str = "failed";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recurrence {
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+impl Recurrence {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Recurrence::Daily => "daily",
+            Recurrence::Weekly => "weekly",
+            Recurrence::Monthly => "monthly",
+        }
+    }
+
+    /// The run following one at `run_at`, of a schedule first run at
+    /// `first_run_at`. Monthly runs keep the day of the first run, or take
+    /// the last day of the months too short for it.
+    pub fn next_after(&self, first_run_at: NaiveDateTime, run_at: NaiveDateTime) -> NaiveDateTime {
+        match self {
+            Recurrence::Daily => run_at + Duration::days(1),
+            Recurrence::Weekly => run_at + Duration::weeks(1),
+            Recurrence::Monthly => {
+                // Counted from the first run, so that a day cut short in one
+                // month isn't carried over to the following ones.
+                let month = |at: NaiveDateTime| at.year() * 12 + at.month0() as i32;
+                let mut months = (month(run_at) - month(first_run_at)).max(1) as u32;
+                loop {
+                    let next = first_run_at
+                        .checked_add_months(chrono::Months::new(months))
+                        .expect("date out of range");
+                    if next > run_at {
+                        return next;
+                    }
+                    months += 1;
+                }
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for Recurrence {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "daily" => Ok(Recurrence::Daily),
+            "weekly" => Ok(Recurrence::Weekly),
+            "monthly" => Ok(Recurrence::Monthly),
+            _ => Err(anyhow::anyhow!("Unknown recurrence: {}", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for Recurrence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A transfer the scheduler executes at `next_run_at`, once or repeatedly.
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = super::schema::scheduled_transfers)]
+#[cfg_attr(
+    not(feature = "postgres"),
+    diesel(check_for_backend(diesel::sqlite::Sqlite))
+)]
+#[cfg_attr(
+    feature = "postgres",
+    diesel(check_for_backend(diesel::sqlite::Sqlite, diesel::pg::Pg))
+)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScheduledTransfer {
+    pub id: i32,
+    pub sender: String,
+    pub receiver: String,
+    pub amount: i32,
+    pub memo: Option<String>,
+    /// `None` for a one-off transfer.
+    pub recurrence: Option<String>,
+    pub next_run_at: NaiveDateTime,
+    pub status: String,
+    /// Failed runs in a row.
+    pub failures: i32,
+    pub last_error: Option<String>,
+    pub created_at: NaiveDateTime,
+    /// Where monthly runs take their day of the month from.
+    pub first_run_at: NaiveDateTime,
+}
+
+impl std::fmt::Display for ScheduledTransfer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "#{} {} -> {}: {} {}, next run {} ({})",
+            self.id,
+            self.sender,
+            self.receiver,
+            self.amount,
+            self.recurrence.as_deref().unwrap_or("once"),
+            self.next_run_at.format("%Y-%m-%d %H:%M"),
+            self.status
+        )?;
+        if let Some(memo) = &self.memo {
+            write!(f, " memo: {}", memo)?;
+        }
+        if let Some(error) = &self.last_error {
+            write!(f, " last error: {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = super::schema::scheduled_transfers)]
+pub struct NewScheduledTransfer<'a> {
+    pub sender: &'a str,
+    pub receiver: &'a str,
+    pub amount: i32,
+    pub memo: Option<&'a str>,
+    pub recurrence: Option<&'a str>,
+    pub next_run_at: NaiveDateTime,
+    pub status: &'a str,
+    pub created_at: NaiveDateTime,
+    pub first_run_at: NaiveDateTime,
+}
+
+pub static HOLD_ACTIVE: &str = "active";
+pub static HOLD_CAPTURED: &str = "captured";
+pub static HOLD_VOIDED: &str = "voided";
+pub static HOLD_EXPIRED: &str = "expired";
+
+/// Funds of the payer reserved for the payee until captured, voided or expired.
+#[derive(Queryable, Selectable)]
+#[diesel(table_name = super::schema::holds)]
+#[cfg_attr(
+    not(feature = "postgres"),
+    diesel(check_for_backend(diesel::sqlite::Sqlite))
+)]
+#[cfg_attr(
+    feature = "postgres",
+    diesel(check_for_backend(diesel::sqlite::Sqlite, diesel::pg::Pg))
+)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hold {
+    pub id: i32,
+    pub payer: String,
+    pub payee: String,
+    pub amount: i32,
+    pub memo: Option<String>,
+    pub status: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub resolved_at: Option<NaiveDateTime>,
+    /// The ledger entry of a captured hold.
+    pub transaction_id: Option<i32>,
+}
+
+impl Hold {
+    /// Idempotency key of the transfer capturing this hold.
+    pub fn capture_key(&self) -> String {
+        format!("hold:{}", self.id)
+    }
+
+    /// Why `payee` can't resolve this hold, if they can't.
+    pub fn check_active(&self, payee: &str) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.payee == payee,
+            "Hold #{} isn't yours to resolve",
+            self.id
+        );
+        anyhow::ensure!(
+            self.status == HOLD_ACTIVE,
+            "Hold #{} is already {}",
+            self.id,
+            self.status
+        );
+        Ok(())
+    }
+
+    /// Like `check_active`, and the hold must not have expired by `now`.
+    pub fn check_capturable(&self, payee: &str, now: NaiveDateTime) -> anyhow::Result<()> {
+        self.check_active(payee)?;
+        anyhow::ensure!(now < self.expires_at, "Hold #{} has expired", self.id);
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Hold {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "#{} {} holds {} for {}, expires {} ({})",
+            self.id,
+            self.payer,
+            self.amount,
+            self.payee,
+            self.expires_at.format("%Y-%m-%d %H:%M:%S"),
+            self.status
+        )?;
+        if let Some(memo) = &self.memo {
+            write!(f, " memo: {}", memo)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = super::schema::holds)]
+pub struct NewHold<'a> {
+    pub payer: &'a str,
+    pub payee: &'a str,
+    pub amount: i32,
+    pub memo: Option<&'a str>,
+    pub status: &'a str,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+}